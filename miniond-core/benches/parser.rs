@@ -0,0 +1,59 @@
+//! TMCD response parser benchmarks.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use miniond_core::tmcc::parser::Response;
+
+/// Generate an `accounts` response similar to what a large project returns.
+fn accounts_response(users: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    lines.push("ADDGROUP NAME=project-PG0 GID=6418".to_string());
+
+    for i in 0..users {
+        lines.push(format!(
+            r#"ADDUSER LOGIN=user{i} PSWD=* UID={uid} GID=6418 ROOT=1 NAME="User Number {i}" HOMEDIR=/users/user{i} GLIST="" SERIAL=1630039457 EMAIL="user{i}@example.com" SHELL=bash"#,
+            i = i, uid = 20000 + i,
+        ));
+
+        for k in 0..3 {
+            lines.push(format!(
+                r#"PUBKEY LOGIN=user{i} KEY="ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOmittedOmittedOmittedOmittedOmittedOmitted{k} user{i}@laptop""#,
+                i = i, k = k,
+            ));
+        }
+    }
+
+    lines
+}
+
+fn bench_accounts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("accounts");
+
+    for users in [10, 100, 1000] {
+        let lines = accounts_response(users);
+        let bytes: usize = lines.iter().map(|l| l.len()).sum();
+
+        group.throughput(Throughput::Bytes(bytes as u64));
+        group.bench_function(format!("{} users", users), |b| {
+            b.iter(|| {
+                for line in &lines {
+                    black_box(Response::parse(black_box(line)).unwrap());
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_line(c: &mut Criterion) {
+    let adduser = r#"ADDUSER LOGIN=zhaofeng PSWD=* UID=20001 GID=12345 ROOT=1 NAME="Zhaofeng Li" HOMEDIR=/users/zhaofeng GLIST="" SERIAL=1630039457 EMAIL="root@localhost" SHELL=bash"#;
+    let mount = r#"REMOTE=nfs.emulab:/proj/project-PG0 LOCAL=/proj/project-PG0"#;
+
+    c.bench_function("adduser line", |b| b.iter(|| Response::parse(black_box(adduser)).unwrap()));
+    c.bench_function("mount line", |b| b.iter(|| Response::parse(black_box(mount)).unwrap()));
+}
+
+criterion_group!(benches, bench_accounts, bench_line);
+criterion_main!(benches);
@@ -0,0 +1,250 @@
+//! Account models.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::redact;
+
+/// Type of a UID.
+pub type Uid = u16;
+
+/// Type of a GID.
+pub type Gid = u16;
+
+/// Account information returned by TMCD.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Accounts {
+    /// Users to be configured.
+    pub users: HashMap<String, User>,
+
+    /// Groups to be configured.
+    pub groups: HashMap<String, Group>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// A user account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    /// UNIX login.
+    login: String,
+
+    /// UID.
+    uid: Uid,
+
+    /// Primary group ID.
+    gid: Gid,
+
+    /// Whether the user has root access.
+    root: bool,
+
+    /// Home directory.
+    home: PathBuf,
+
+    /// Supplementary group IDs.
+    #[serde(default)]
+    groups: Vec<Gid>,
+
+    /// SSH public keys.
+    ssh_keys: Vec<String>,
+
+    /// Login shell.
+    shell: String,
+
+    /// Password hash.
+    ///
+    /// This is never cached.
+    #[serde(skip)]
+    password: Option<PasswordHash>,
+
+    /// Opaque serial number.
+    ///
+    /// This indicates when the account information is changed.
+    serial: String,
+}
+
+impl User {
+    /// Create a new user account.
+    ///
+    /// This does not actually create the account in the system.
+    pub fn new(login: String, uid: Uid, gid: Gid, serial: String) -> Self {
+        let home = format!("/users/{}", &login).into();
+
+        Self {
+            login,
+            uid,
+            gid,
+            root: false,
+            home,
+            groups: Vec::new(),
+            ssh_keys: Vec::new(),
+            shell: "bash".to_string(),
+            password: None,
+            serial,
+        }
+    }
+
+    /// Returns the login of the user.
+    pub fn login(&self) -> &str {
+        &self.login
+    }
+
+    /// Returns the UID of the user.
+    pub fn uid(&self) -> Uid {
+        self.uid
+    }
+
+    /// Returns the primary group ID of the user.
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    /// Returns whether the user has root privileges.
+    pub fn is_root(&self) -> bool {
+        self.root
+    }
+
+    /// Returns the user's home.
+    pub fn home_dir(&self) -> &Path {
+        &self.home
+    }
+
+    /// Returns the supplementary group IDs of the user.
+    pub fn supplementary_groups(&self) -> &[Gid] {
+        &self.groups
+    }
+
+    /// Returns the SSH public keys of the user.
+    pub fn ssh_keys(&self) -> &[String] {
+        &self.ssh_keys
+    }
+
+    /// Returns the name of the user's login shell (e.g., `bash`).
+    pub fn login_shell(&self) -> &str {
+        &self.shell
+    }
+
+    /// Returns the password hash of the user, if the user has a password.
+    pub fn password_hash(&self) -> Option<&PasswordHash> {
+        self.password.as_ref()
+    }
+
+    /// Returns the serial number of the account information.
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    /// Add an SSH key.
+    ///
+    /// A `public_key` is a line in `authorized_keys`.
+    pub fn add_ssh_key(&mut self, public_key: String) -> &mut Self {
+        self.ssh_keys.push(public_key);
+        self
+    }
+
+    /// Set whether the user has root privileges.
+    pub fn root(&mut self, root: bool) -> &mut Self {
+        self.root = root;
+        self
+    }
+
+    /// Set the user's home.
+    pub fn home(&mut self, home: PathBuf) -> &mut Self {
+        self.home = home;
+        self
+    }
+
+    /// Set the user's supplementary groups.
+    pub fn groups(&mut self, groups: Vec<Gid>) -> &mut Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Set the user's login shell.
+    pub fn shell(&mut self, shell: String) -> &mut Self {
+        self.shell = shell;
+        self
+    }
+
+    /// Set the user's password hash.
+    pub fn password(&mut self, password: Option<PasswordHash>) -> &mut Self {
+        self.password = password;
+        self
+    }
+}
+
+/// A crypt(3) password hash.
+///
+/// The hash is never printed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    /// Wrap a password hash from `PSWD`.
+    ///
+    /// Returns `None` if the user has no password (e.g., `*`).
+    pub fn new(hash: String) -> Option<Self> {
+        let usable = !hash.is_empty()
+            && !hash.starts_with('*')
+            && !hash.starts_with('!')
+            && !hash.contains(|c: char| c == ':' || c.is_whitespace() || c.is_control());
+
+        if usable {
+            Some(Self(hash))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the hash.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PasswordHash")
+            .field(&redact::REDACTED)
+            .finish()
+    }
+}
+
+/// A group account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    /// Name.
+    name: String,
+
+    /// GID.
+    gid: Gid,
+}
+
+impl Group {
+    /// Create a new group.
+    ///
+    /// This does not actually create the group in the system.
+    pub fn new(name: String, gid: Gid) -> Self {
+        Self {
+            name,
+            gid,
+        }
+    }
+
+    /// Returns the name of the group.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the GID of the group.
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+}
@@ -0,0 +1,90 @@
+//! Exponential backoff.
+//!
+//! Retries wait twice as long after each consecutive failure, up to a
+//! maximum. Delays are jittered, so the nodes of a large experiment
+//! that lost the boss at the same time don't all come back at once.
+
+use std::time::Duration;
+
+/// An exponential backoff policy.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// The first delay.
+    initial: Duration,
+
+    /// The maximum delay.
+    max: Duration,
+
+    /// Whether to randomize delays.
+    jitter: bool,
+
+    /// The next delay, before jitter.
+    current: Duration,
+}
+
+impl Backoff {
+    /// Create a backoff policy doubling from `initial` up to `max`.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        let max = max.max(initial);
+
+        Self {
+            initial,
+            max,
+            jitter: true,
+            current: initial,
+        }
+    }
+
+    /// Set whether delays are randomized.
+    ///
+    /// With jitter, each delay is picked between half and all of the
+    /// exponential delay.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Returns the maximum delay.
+    pub fn max_delay(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns how long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+
+        if self.jitter {
+            let half = delay / 2;
+            half + half.mul_f64(fastrand::f64())
+        } else {
+            delay
+        }
+    }
+
+    /// Start over from the first delay.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5)).jitter(false);
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 5, 5], delays);
+
+        backoff.reset();
+        assert_eq!(Duration::from_secs(1), backoff.next_delay());
+
+        let mut backoff = Backoff::new(Duration::from_secs(4), Duration::from_secs(4));
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+}
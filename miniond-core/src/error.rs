@@ -0,0 +1,212 @@
+//! Error types.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Serialize;
+use snafu::Snafu;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Whether an error may go away by trying again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The error may go away by itself (e.g., a network timeout).
+    Transient,
+
+    /// Trying again won't help (e.g., a malformed response).
+    Fatal,
+}
+
+impl Severity {
+    /// Classify an I/O error.
+    pub fn of_io(error: &io::Error) -> Self {
+        use io::ErrorKind::*;
+
+        match error.kind() {
+            TimedOut | ConnectionRefused | ConnectionReset | ConnectionAborted
+            | NotConnected | AddrNotAvailable | BrokenPipe | UnexpectedEof
+            | Interrupted | WouldBlock => Self::Transient,
+            _ => Self::Fatal,
+        }
+    }
+}
+
+/// An error.
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to discover TMCD boss node"))]
+    TmcdFailedToDiscoverBossNode,
+
+    #[snafu(display("Got Non-UTF8 TMCD response"))]
+    TmcdInvalidUtf8,
+
+    #[snafu(display("Bad TMCD response (position {}): {}", position, line))]
+    TmcdBadLine { line: String, position: usize },
+
+    #[snafu(display("Required key {} missing from TMCD response: {}", key, line))]
+    TmcdMissingKey { key: String, line: String },
+
+    #[snafu(display("Duplicate user {} in TMCD response", login))]
+    TmcdDuplicateUser { login: String },
+
+    #[snafu(display("Duplicate group {} in TMCD response", name))]
+    TmcdDuplicateGroup { name: String },
+
+    #[snafu(display("Out-of-order line in TMCD response: {}", line))]
+    TmcdOutOfOrder { line: String },
+
+    #[snafu(display("Missing directive in TMCD response: {}", line))]
+    TmcdMissingDirective { line: String },
+
+    #[snafu(display("Unknown directive {} in TMCD response: {}", directive, line))]
+    TmcdUnknownDirective { directive: String, line: String },
+
+    #[snafu(display("Invalid value {} from TMCD response: {}", value, parse_error))]
+    TmcdBadValue { value: String, parse_error: Box<dyn std::error::Error + Send + Sync> },
+
+    #[snafu(display("Failed to deserialize TMCD response: {}", message))]
+    TmcdDeserialize { message: String },
+
+    #[snafu(display("Failed to serialize TMCD response: {}", message))]
+    TmcdSerialize { message: String },
+
+    #[snafu(display("Mount point {:?} from TMCD response is not an absolute path", local))]
+    TmcdRelativeMountPoint { local: PathBuf },
+
+    #[snafu(display("Invalid environment variable name {:?} from TMCD response", name))]
+    TmcdInvalidEnvName { name: String },
+
+    #[snafu(display("Console key from TMCD response has {} bytes, expected {}", actual, expected))]
+    TmcdBadKeyLength { expected: usize, actual: usize },
+
+    #[snafu(display("Invalid user {} from TMCD response", login))]
+    TmcdNoSuchUser { login: String },
+
+    #[snafu(display("TMCD refused the request: {} (is miniond running as root on a testbed node, connecting from its control network address?)", message))]
+    TmcdPermissionDenied { message: String },
+
+    #[snafu(display("TMCD does not know this node: {} (check `tmcc.boss` and `tmcc.vnode`)", message))]
+    TmcdUnknownNode { message: String },
+
+    #[snafu(display("TMCD does not support our protocol version: {} (the testbed may need a newer miniond, or vice versa)", message))]
+    TmcdVersionTooOld { message: String },
+
+    #[snafu(display("TMCD returned an error: {}", message))]
+    TmcdServerError { message: String },
+
+    #[snafu(display("Response to TMCD command {} is larger than {} bytes (raise `tmcc.max-response-size` if this is expected)", command, limit))]
+    TmcdResponseTooLarge { command: String, limit: u64 },
+
+    #[snafu(display("Too many redirects for TMCD command {} (last to {})", command, target))]
+    TmcdRedirectLoop { command: String, target: String },
+
+    #[snafu(display("{} (response dumped to {:?})", source, path))]
+    TmcdDumped { source: Box<Error>, path: PathBuf },
+
+    #[snafu(display("Parsing responses to TMCD command {} is unsupported", command))]
+    TmcdUnsupportedCommand { command: String },
+
+    #[snafu(display("TMCD has no blob named {}", name))]
+    TmcdNoBlob { name: String },
+
+    #[snafu(display("Blob {} has SHA-256 {} instead of {}", name, actual, expected))]
+    TmcdBlobChecksum { name: String, expected: String, actual: String },
+
+    #[snafu(display("TMCD returned blank GENI response"))]
+    TmcdGeniBlankResponse,
+
+    #[snafu(display("TMCD returned unknown GENI error"))]
+    TmcdGeniError,
+
+    #[snafu(display("GENI parsing error: {}", error))]
+    GeniParseError { error: serde_xml_rs::Error },
+
+    #[snafu(display("GENI manifest is nested more than {} levels deep", max))]
+    GeniTooDeep { max: usize },
+
+    #[snafu(display("GENI manifest has non-contiguous netmask {} on {}", netmask, interface))]
+    GeniBadNetmask { interface: String, netmask: Ipv4Addr },
+
+    #[snafu(display("The current node does not exist in the GENI manifest (has the reservastion expired?)"))]
+    GeniNoSuchNode,
+
+    /// The SRV record indicates that a boss node is definitely not available.
+    ///
+    /// This is returned when a SRV lookup returns "." as the result.
+    ///
+    /// - <https://datatracker.ietf.org/doc/html/rfc2782>
+    #[snafu(display("SRV record indicates the absence of a boss node"))]
+    EmulabBossSrvNotAvailable,
+
+    #[snafu(display("The supplied boss node cannot be resolved: {:?}", host_port))]
+    EmulabBossUnresolvable { host_port: (String, u16) },
+
+    #[snafu(display("Failed to connect to TMCD at {}: {}", addr, source))]
+    TmcdConnect { addr: SocketAddr, source: io::Error },
+
+    #[snafu(display("Timed out connecting to TMCD at {} after {:?}", boss, timeout))]
+    TmcdConnectTimeout { boss: String, timeout: Duration },
+
+    #[snafu(display("TLS handshake with TMCD at {} failed: {}", addr, source))]
+    TmcdTlsHandshake { addr: SocketAddr, source: io::Error },
+
+    #[snafu(display("Invalid TMCD TLS configuration in {:?}: {}", path, reason))]
+    TmcdTlsConfig { path: PathBuf, reason: String },
+
+    #[snafu(display("TMCD over TLS is not supported by this build (enable the `tls` feature)"))]
+    TmcdTlsUnsupported,
+
+    #[snafu(display("Failed to exchange TMCD command {} with {}: {}", command, boss, source))]
+    TmcdIo { command: String, boss: String, source: io::Error },
+
+    #[snafu(display("I/O error: {}", error))]
+    IoError { error: io::Error },
+
+    #[snafu(display("DNS lookup error: {}", error))]
+    DnsLookupError { error: trust_dns_resolver::error::ResolveError },
+}
+
+impl Error {
+    /// Returns whether the error may go away by trying again.
+    ///
+    /// Network and DNS failures are transient, while bad responses
+    /// and refusals by TMCD are fatal.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::TmcdFailedToDiscoverBossNode
+            | Self::TmcdConnect { .. }
+            | Self::TmcdConnectTimeout { .. }
+            | Self::TmcdServerError { .. }
+            | Self::TmcdGeniBlankResponse
+            | Self::EmulabBossUnresolvable { .. }
+            | Self::DnsLookupError { .. } => Severity::Transient,
+
+            Self::TmcdIo { source, .. } => Severity::of_io(source),
+            Self::IoError { error } => Severity::of_io(error),
+            Self::TmcdDumped { source, .. } => source.severity(),
+
+            _ => Severity::Fatal,
+        }
+    }
+
+    /// Returns whether the error may go away by trying again.
+    pub fn is_transient(&self) -> bool {
+        self.severity() == Severity::Transient
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::IoError { error }
+    }
+}
+
+impl From<trust_dns_resolver::error::ResolveError> for Error {
+    fn from(error: trust_dns_resolver::error::ResolveError) -> Self {
+        Self::DnsLookupError { error }
+    }
+}
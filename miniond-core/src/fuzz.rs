@@ -0,0 +1,47 @@
+//! Fuzzing entry points.
+//!
+//! This module is only available with the `fuzzing` feature. The
+//! functions here are driven by the cargo-fuzz harnesses under
+//! `fuzz/`. TMCD responses and GENI manifests come from the network
+//! and are parsed as root, so any input must either parse or fail
+//! with an error. Panics and hangs are bugs.
+//!
+//! Lines that parse must also survive a round trip through their
+//! canonical form.
+
+use crate::tmcc::{self, parser::Response};
+
+/// Feed arbitrary bytes to the TMCD response parsers.
+///
+/// The input is parsed as a single line, as well as a complete
+/// response to each command whose response we understand.
+pub fn response(data: &[u8]) {
+    if let Ok(line) = std::str::from_utf8(data) {
+        if let Ok(response) = Response::parse(line) {
+            let _ = response.response_type();
+
+            for (key, _) in response.pairs() {
+                let _ = response.get(key);
+                let _ = response.get_parsed::<u32>(key);
+            }
+
+            if let Ok(canonical) = response.to_line() {
+                let again = Response::parse(&canonical)
+                    .expect("Canonical line fails to parse");
+
+                assert_eq!(response.response_type(), again.response_type());
+                assert_eq!(response.pairs(), again.pairs());
+                assert_eq!(canonical, again.to_line().unwrap());
+            }
+        }
+    }
+
+    for command in ["accounts", "mounts", "status", "ifconfig", "bootwhat", "rootkeys", "syncserver", "tiptunnels"] {
+        let _ = tmcc::validate(command, data);
+    }
+}
+
+/// Feed arbitrary bytes to the GENI manifest parser.
+pub fn geni_manifest(data: &[u8]) {
+    let _ = tmcc::validate("geni_manifest", data);
+}
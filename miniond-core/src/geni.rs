@@ -0,0 +1,384 @@
+//! GENI models.
+//!
+//! We just do the bare mininum that's enough to get the full
+//! FQDN and the interfaces and addresses on experiment links.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::net::{normalize_mac, InterfaceAddress, InterfaceConfig};
+
+/// Maximum nesting depth of elements in a manifest.
+///
+/// Real manifests are only a few levels deep, and the XML parser
+/// slows down quadratically with the depth.
+const MAX_DEPTH: usize = 64;
+
+/// GENI Resource Specification.
+///
+/// <https://groups.geni.net/geni/wiki/GENIExperimenter/RSpecs>.
+#[derive(Debug, Deserialize)]
+pub struct RSpec {
+    #[serde(rename = "node", default)]
+    nodes: Vec<Node>,
+
+    #[serde(rename = "link", default)]
+    links: Vec<Link>,
+
+    /// When the slice expires (e.g., `2021-09-01T00:00:00Z`).
+    expires: Option<String>,
+}
+
+impl RSpec {
+    /// Parse a manifest.
+    pub fn parse(xml: &str) -> Result<Self> {
+        check_depth(xml)?;
+
+        let rspec: Self = serde_xml_rs::from_str(xml)
+            .map_err(|error| Error::GeniParseError { error })?;
+
+        // Interfaces are configured with the prefix length
+        for interface in rspec.nodes.iter().flat_map(|node| &node.interfaces) {
+            if let Some(netmask) = interface.ip.as_ref().and_then(|ip| ip.netmask) {
+                let bits = u32::from(netmask);
+                if bits.leading_ones() + bits.trailing_zeros() != 32 {
+                    return Err(Error::GeniBadNetmask {
+                        interface: interface.client_id.clone(),
+                        netmask,
+                    });
+                }
+            }
+        }
+
+        Ok(rspec)
+    }
+
+    pub fn get_node(&self, client_id: &str) -> Option<&Node> {
+        self.nodes.iter().find(|e| e.client_id == client_id)
+    }
+
+    /// Returns the addresses of other nodes on the links of a node.
+    pub fn peers(&self, client_id: &str) -> Vec<Peer> {
+        let node = match self.get_node(client_id) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+
+        let mut peers = Vec::new();
+
+        for link in &self.links {
+            let ours = link.interface_refs.iter()
+                .any(|r| node.interfaces.iter().any(|i| i.client_id == r.client_id));
+
+            if !ours {
+                continue;
+            }
+
+            for other in &self.nodes {
+                if other.client_id == node.client_id {
+                    continue;
+                }
+
+                for interface in &other.interfaces {
+                    let on_link = link.interface_refs.iter().any(|r| r.client_id == interface.client_id);
+
+                    if let (true, Some(ip)) = (on_link, &interface.ip) {
+                        peers.push(Peer {
+                            link: link.client_id.clone(),
+                            node: other.client_id.clone(),
+                            address: ip.address,
+                        });
+                    }
+                }
+            }
+        }
+
+        peers
+    }
+
+    /// Returns the experiment interfaces of a node.
+    pub fn interfaces(&self, client_id: &str) -> Vec<InterfaceConfig> {
+        let node = match self.get_node(client_id) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+
+        node.interfaces.iter().map(|interface| {
+            let lan = self.links.iter()
+                .find(|link| link.interface_refs.iter().any(|r| r.client_id == interface.client_id))
+                .map(|link| link.client_id.clone());
+
+            let addresses = interface.ip.iter().map(|ip| {
+                let prefix_len = ip.netmask
+                    .map(|mask| u32::from(mask).leading_ones() as u8)
+                    .unwrap_or(32);
+
+                InterfaceAddress {
+                    address: IpAddr::V4(ip.address),
+                    prefix_len,
+                }
+            }).collect();
+
+            InterfaceConfig {
+                name: None,
+                mac: interface.mac_address.as_deref().and_then(normalize_mac),
+                addresses,
+                mtu: None,
+                vlan: None,
+                parent: None,
+                lan,
+            }
+        }).collect()
+    }
+
+    /// Returns when the slice expires, as given in the manifest.
+    pub fn expires(&self) -> Option<&str> {
+        self.expires.as_deref()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Node {
+    client_id: String,
+    host: Host,
+
+    #[serde(rename = "interface", default)]
+    interfaces: Vec<Interface>,
+}
+
+impl Node {
+    /// Returns the FQDN of the node.
+    pub fn fqdn(&self) -> String {
+        self.host.name.clone()
+    }
+
+    /// Returns the IPv4 address of the node, if any.
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.host.ipv4
+    }
+
+    /// Returns the IPv6 address of the node, if any.
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.host.ipv6
+    }
+
+    /// Returns all control network addresses of the node.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.host.ipv4.map(IpAddr::V4).into_iter()
+            .chain(self.host.ipv6.map(IpAddr::V6))
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Host {
+    name: String,
+
+    /// Missing on IPv6-only control networks.
+    ipv4: Option<Ipv4Addr>,
+
+    ipv6: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Interface {
+    client_id: String,
+    mac_address: Option<String>,
+    ip: Option<Ip>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Ip {
+    address: Ipv4Addr,
+    netmask: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Link {
+    client_id: String,
+
+    #[serde(rename = "interface_ref", default)]
+    interface_refs: Vec<InterfaceRef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InterfaceRef {
+    client_id: String,
+}
+
+/// Another node on an experiment link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    /// Name of the link.
+    pub link: String,
+
+    /// Name of the other node.
+    pub node: String,
+
+    /// Address of the other node on the link.
+    pub address: Ipv4Addr,
+}
+
+/// Ensure that elements aren't nested too deeply.
+///
+/// This is a rough scan and not a validating parser. Malformed
+/// documents are left for the real parser to reject.
+fn check_depth(xml: &str) -> Result<()> {
+    let bytes = xml.as_bytes();
+    let mut depth = 0usize;
+    let mut pos = 0;
+
+    let skip_past = |pos: usize, needle: &[u8]| {
+        bytes[pos..].windows(needle.len())
+            .position(|w| w == needle)
+            .map(|i| pos + i + needle.len())
+            .unwrap_or(bytes.len())
+    };
+
+    while let Some(i) = bytes[pos..].iter().position(|b| *b == b'<') {
+        pos += i + 1;
+
+        let rest = &bytes[pos..];
+        if rest.starts_with(b"!--") {
+            pos = skip_past(pos, b"-->");
+        } else if rest.starts_with(b"![CDATA[") {
+            pos = skip_past(pos, b"]]>");
+        } else if rest.starts_with(b"?") || rest.starts_with(b"!") {
+            pos = skip_past(pos, b">");
+        } else if rest.starts_with(b"/") {
+            depth = depth.saturating_sub(1);
+            pos = skip_past(pos, b">");
+        } else {
+            // Find the end of the tag, skipping over quoted attributes
+            let mut quote = None;
+            let mut end = pos;
+            while end < bytes.len() {
+                match (quote, bytes[end]) {
+                    (None, b'"') | (None, b'\'') => quote = Some(bytes[end]),
+                    (Some(q), b) if q == b => quote = None,
+                    (None, b'>') => break,
+                    _ => {}
+                }
+                end += 1;
+            }
+
+            if end < bytes.len() && bytes[end - 1] != b'/' {
+                depth += 1;
+
+                if depth > MAX_DEPTH {
+                    return Err(Error::GeniTooDeep { max: MAX_DEPTH });
+                }
+            }
+
+            pos = (end + 1).min(bytes.len());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_addresses() {
+        let xml = r#"<rspec><node client_id="node0"><host name="n" ipv6="2001:db8::1"/></node></rspec>"#;
+        let rspec = RSpec::parse(xml).unwrap();
+        let node = rspec.get_node("node0").unwrap();
+
+        assert_eq!(None, node.ipv4());
+        assert_eq!(vec!["2001:db8::1".parse::<IpAddr>().unwrap()], node.addresses());
+    }
+
+    #[test]
+    fn test_depth() {
+        let ok = r#"<?xml version="1.0"?><rspec><!-- <a><b> --><node client_id="a>b"><host name="n" ipv4="1.2.3.4"/></node></rspec>"#;
+        check_depth(ok).expect("Shallow manifest should pass");
+
+        let deep = format!("<rspec>{}{}</rspec>", "<a>".repeat(MAX_DEPTH), "</a>".repeat(MAX_DEPTH));
+        match check_depth(&deep) {
+            Err(Error::GeniTooDeep { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_expires() {
+        let xml = r#"<rspec type="manifest" expires="2021-09-01T00:00:00Z"><node client_id="node0"><host name="n" ipv4="1.2.3.4"/></node></rspec>"#;
+        let rspec = RSpec::parse(xml).unwrap();
+
+        assert_eq!(Some("2021-09-01T00:00:00Z"), rspec.expires());
+        assert_eq!("n", rspec.get_node("node0").unwrap().fqdn());
+    }
+
+    #[test]
+    fn test_peers() {
+        let xml = r#"<rspec type="manifest">
+            <node client_id="node0">
+                <interface client_id="node0:if0"><ip address="10.10.1.1" netmask="255.255.255.0" type="ipv4"/></interface>
+                <host name="node0.example.net" ipv4="1.2.3.4"/>
+            </node>
+            <node client_id="node1">
+                <interface client_id="node1:if0"><ip address="10.10.1.2" netmask="255.255.255.0" type="ipv4"/></interface>
+                <interface client_id="node1:if1"><ip address="10.10.2.1" netmask="255.255.255.0" type="ipv4"/></interface>
+                <host name="node1.example.net" ipv4="1.2.3.5"/>
+            </node>
+            <node client_id="node2">
+                <interface client_id="node2:if0"><ip address="10.10.2.2" netmask="255.255.255.0" type="ipv4"/></interface>
+                <host name="node2.example.net" ipv4="1.2.3.6"/>
+            </node>
+            <link client_id="link-0"><interface_ref client_id="node0:if0"/><interface_ref client_id="node1:if0"/></link>
+            <link client_id="link-1"><interface_ref client_id="node1:if1"/><interface_ref client_id="node2:if0"/></link>
+        </rspec>"#;
+        let rspec = RSpec::parse(xml).unwrap();
+
+        let peers = rspec.peers("node0");
+        assert_eq!(1, peers.len());
+        assert_eq!("link-0", peers[0].link);
+        assert_eq!("node1", peers[0].node);
+        assert_eq!(Ipv4Addr::new(10, 10, 1, 2), peers[0].address);
+
+        assert_eq!(2, rspec.peers("node1").len());
+        assert!(rspec.peers("node3").is_empty());
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let xml = r#"<rspec type="manifest">
+            <node client_id="node0">
+                <interface client_id="node0:if0" mac_address="0002B3651E1D"><ip address="10.10.1.1" netmask="255.255.255.0" type="ipv4"/></interface>
+                <interface client_id="node0:if1"/>
+                <host name="node0.example.net" ipv4="1.2.3.4"/>
+            </node>
+            <link client_id="link-0"><interface_ref client_id="node0:if0"/></link>
+        </rspec>"#;
+        let rspec = RSpec::parse(xml).unwrap();
+
+        let interfaces = rspec.interfaces("node0");
+        assert_eq!(2, interfaces.len());
+        assert_eq!(Some("00:02:b3:65:1e:1d"), interfaces[0].mac.as_deref());
+        assert_eq!(Some("link-0"), interfaces[0].lan.as_deref());
+        assert_eq!(vec![InterfaceAddress { address: IpAddr::V4(Ipv4Addr::new(10, 10, 1, 1)), prefix_len: 24 }], interfaces[0].addresses);
+
+        assert_eq!(None, interfaces[1].mac);
+        assert!(interfaces[1].addresses.is_empty());
+    }
+
+    #[test]
+    fn test_bad_netmask() {
+        let xml = r#"<rspec type="manifest">
+            <node client_id="node0">
+                <interface client_id="node0:if0"><ip address="10.10.1.1" netmask="255.0.255.0" type="ipv4"/></interface>
+                <host name="node0.example.net" ipv4="1.2.3.4"/>
+            </node>
+        </rspec>"#;
+
+        match RSpec::parse(xml) {
+            Err(Error::GeniBadNetmask { interface, .. }) => assert_eq!("node0:if0", interface),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Client for the Emulab Testbed Master Control Daemon (TMCD).
+//!
+//! This is the protocol half of `miniond`: Boss node discovery, the
+//! TMCD client and response parsers, and the GENI manifest models.
+//! It does not touch the system, so it can be used by other tools
+//! that need to talk to TMCD.
+
+#![deny(
+    unused_imports,
+    unused_must_use,
+    unreachable_patterns,
+)]
+
+// Most error variants are named after what failed.
+#![allow(
+    clippy::enum_variant_names,
+)]
+
+pub mod account;
+pub mod backoff;
+mod error;
+pub mod geni;
+pub mod mount;
+pub mod net;
+pub mod redact;
+pub mod tmcc;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+pub use error::{Error, Result, Severity};
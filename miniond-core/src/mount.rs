@@ -0,0 +1,134 @@
+//! Mount models.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// A mount.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mount {
+    remote: String,
+    local: PathBuf,
+
+    #[serde(default)]
+    filesystem: Filesystem,
+
+    #[serde(default)]
+    options: Vec<String>,
+
+    /// Never serialized, so it doesn't end up in saved state.
+    #[serde(default, skip_serializing)]
+    credentials: Option<Credentials>,
+}
+
+/// The kind of file system being mounted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "fstype", rename_all = "lowercase")]
+pub enum Filesystem {
+    /// An NFS export (e.g., `ops.emulab.net:/share`).
+    #[default]
+    Nfs,
+
+    /// An SMB share (e.g., `//fs.example.net/share`).
+    Smb,
+
+    /// A local block device (e.g., a blockstore) with the given
+    /// file system type (e.g., `ext4`).
+    Block(String),
+}
+
+/// Credentials to mount a file system with.
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Mount {
+    /// Create an NFS mount.
+    pub fn new(remote: String, local: PathBuf) -> Self {
+        Self {
+            remote,
+            local,
+            filesystem: Filesystem::Nfs,
+            options: Vec::new(),
+            credentials: None,
+        }
+    }
+
+    /// Returns the remote filesystem (e.g., `ops.emulab.net:/share`).
+    ///
+    /// For block devices, this is the path of the device.
+    pub fn remote(&self) -> &str {
+        &self.remote
+    }
+
+    /// Returns the absolute path of the mount point.
+    pub fn local(&self) -> &Path {
+        &self.local
+    }
+
+    /// Returns the kind of file system.
+    pub fn filesystem(&self) -> &Filesystem {
+        &self.filesystem
+    }
+
+    /// Returns the mount options (e.g., `ro`).
+    pub fn options(&self) -> &[String] {
+        &self.options
+    }
+
+    /// Returns the credentials, if any.
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Set the kind of file system.
+    pub fn set_filesystem(&mut self, filesystem: Filesystem) -> &mut Self {
+        self.filesystem = filesystem;
+        self
+    }
+
+    /// Add a mount option.
+    pub fn add_option(&mut self, option: String) -> &mut Self {
+        self.options.push(option);
+        self
+    }
+
+    /// Replace all mount options.
+    pub fn set_options(&mut self, options: Vec<String>) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Set the credentials to mount with.
+    pub fn set_credentials(&mut self, credentials: Credentials) -> &mut Self {
+        self.credentials = Some(credentials);
+        self
+    }
+}
+
+impl Filesystem {
+    /// Returns the file system type passed to `mount -t`.
+    pub fn fs_type(&self) -> &str {
+        match self {
+            Self::Nfs => "nfs",
+            Self::Smb => "cifs",
+            Self::Block(fs_type) => fs_type,
+        }
+    }
+
+    /// Returns whether the file system is on the network.
+    pub fn is_network(&self) -> bool {
+        !matches!(self, Self::Block(_))
+    }
+}
+
+// Keep passwords out of logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,54 @@
+//! Network interface models.
+
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+/// Configuration of a network interface in the experiment.
+///
+/// Interfaces are identified by their MAC address, since the name
+/// assigned by the testbed rarely matches the one given by the OS.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InterfaceConfig {
+    /// Name of the interface, if known.
+    pub name: Option<String>,
+
+    /// MAC address (e.g., `00:02:b3:65:1e:1d`).
+    pub mac: Option<String>,
+
+    /// Addresses to assign.
+    pub addresses: Vec<InterfaceAddress>,
+
+    /// MTU, if not the default.
+    pub mtu: Option<u32>,
+
+    /// VLAN tag, for VLAN interfaces.
+    pub vlan: Option<u16>,
+
+    /// MAC address of the physical interface, for VLAN interfaces.
+    pub parent: Option<String>,
+
+    /// Name of the link or LAN in the experiment.
+    pub lan: Option<String>,
+}
+
+/// An address with its prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct InterfaceAddress {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+}
+
+/// Normalize a MAC address (e.g., `0002B3651E1D` or `00:02:b3:65:1e:1d`).
+///
+/// Returns `None` if it's not a MAC address.
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let hex: String = mac.chars().filter(|c| *c != ':').collect();
+
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let octets: Vec<&str> = (0..6).map(|i| &hex[i * 2..i * 2 + 2]).collect();
+    Some(octets.join(":").to_ascii_lowercase())
+}
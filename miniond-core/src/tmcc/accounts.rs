@@ -0,0 +1,281 @@
+//! Incremental parsing of `accounts` responses.
+//!
+//! TMCD sends all `ADDGROUP` lines first, followed by each `ADDUSER`
+//! line with the user's `PUBKEY` lines right after it. This lets us
+//! hand out complete users in chunks while the response is still
+//! being received, with all groups delivered in the very first chunk.
+//!
+//! Without a chunk size, everything is handed out at the end, so the
+//! order does not matter.
+
+use std::collections::HashSet;
+
+use crate::account::{PasswordHash, User, Group};
+use crate::error::{Error, Result};
+use crate::redact;
+use super::models::{AddUser, PubKey, AddGroup};
+use super::parser::Response;
+
+/// A chunk of account information.
+#[derive(Debug, Clone, Default)]
+pub struct AccountsChunk {
+    /// Groups to be configured.
+    ///
+    /// These are only present in the first chunk.
+    pub groups: Vec<Group>,
+
+    /// Users to be configured.
+    pub users: Vec<User>,
+
+    /// Position of the chunk in the response, starting at 0.
+    pub index: usize,
+
+    /// Whether this is the last chunk.
+    pub last: bool,
+}
+
+/// An incremental `accounts` parser.
+pub struct AccountsParser {
+    /// Maximum number of users in a chunk, if chunked.
+    chunk_size: Option<usize>,
+
+    /// Number of chunks handed out.
+    chunks: usize,
+
+    /// Groups that haven't been handed out.
+    groups: Vec<Group>,
+
+    /// Whether the groups were handed out already.
+    groups_done: bool,
+
+    /// Complete users that haven't been handed out.
+    users: Vec<User>,
+
+    /// The user whose keys we are receiving.
+    current: Option<User>,
+
+    seen_users: HashSet<String>,
+    seen_groups: HashSet<String>,
+}
+
+impl AccountsParser {
+    /// Create a parser handing out chunks of up to `chunk_size` users.
+    ///
+    /// With `usize::MAX`, everything is handed out in a single chunk
+    /// by `finish`.
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size: Some(chunk_size.max(1)).filter(|&size| size != usize::MAX),
+            chunks: 0,
+            groups: Vec::new(),
+            groups_done: false,
+            users: Vec::new(),
+            current: None,
+            seen_users: HashSet::new(),
+            seen_groups: HashSet::new(),
+        }
+    }
+
+    /// Feed a line, returning a chunk if one is ready.
+    pub fn feed(&mut self, line: &str) -> Result<Option<AccountsChunk>> {
+        let parsed = Response::parse(line)?;
+        match parsed.response_type() {
+            Some("ADDGROUP") => {
+                let AddGroup { mut name, gid } = parsed.deserialize()?;
+
+                // Here we convert the group name to lowercase for
+                // compatibility. The shadow-utils implementation of
+                // groupadd does not allow group names to contain
+                // upper-case letters.
+                name.make_ascii_lowercase();
+
+                if !self.seen_groups.insert(name.clone()) {
+                    return Err(Error::TmcdDuplicateGroup {
+                        name,
+                    });
+                }
+
+                if self.groups_done {
+                    return Err(Error::TmcdOutOfOrder {
+                        line: redact::line(line).to_string(),
+                    });
+                }
+
+                self.groups.push(Group::new(name, gid));
+
+                Ok(None)
+            }
+            Some("ADDUSER") => {
+                let adduser: AddUser = parsed.deserialize()?;
+
+                if !self.seen_users.insert(adduser.login.clone()) {
+                    return Err(Error::TmcdDuplicateUser {
+                        login: adduser.login,
+                    });
+                }
+
+                let mut user = User::new(
+                    adduser.login,
+                    adduser.uid,
+                    adduser.gid,
+                    adduser.serial,
+                );
+
+                user
+                    .root(adduser.root)
+                    .home(adduser.homedir)
+                    .groups(adduser.glist)
+                    .password(adduser.pswd.and_then(PasswordHash::new))
+                    .shell(adduser.shell);
+
+                if let Some(previous) = self.current.replace(user) {
+                    self.users.push(previous);
+                }
+
+                Ok(self.take_chunk(false))
+            }
+            Some("PUBKEY") => {
+                let PubKey { login, key } = parsed.deserialize()?;
+
+                let user = self.current.iter_mut()
+                    .chain(self.users.iter_mut())
+                    .find(|u| u.login() == login);
+
+                match user {
+                    Some(user) => {
+                        user.add_ssh_key(key);
+                        Ok(None)
+                    }
+                    None if self.seen_users.contains(&login) => {
+                        // The user was already handed out
+                        Err(Error::TmcdOutOfOrder {
+                            line: redact::line(line).to_string(),
+                        })
+                    }
+                    None => {
+                        Err(Error::TmcdNoSuchUser {
+                            login,
+                        })
+                    }
+                }
+            }
+            Some("SFSKEY") => {
+                log::warn!("Received unsupported SFSKEY directive");
+                Ok(None)
+            }
+            Some(directive) => {
+                Err(Error::TmcdUnknownDirective {
+                    directive: directive.to_string(),
+                    line: redact::line(line).to_string(),
+                })
+            }
+            None => {
+                Err(Error::TmcdMissingDirective {
+                    line: redact::line(line).to_string(),
+                })
+            }
+        }
+    }
+
+    /// Finish parsing, returning the last chunk.
+    pub fn finish(mut self) -> AccountsChunk {
+        if let Some(user) = self.current.take() {
+            self.users.push(user);
+        }
+
+        self.take_chunk(true).unwrap()
+    }
+
+    fn take_chunk(&mut self, last: bool) -> Option<AccountsChunk> {
+        let chunk_size = match self.chunk_size {
+            Some(chunk_size) => chunk_size,
+            None if last => usize::MAX,
+            None => return None,
+        };
+
+        // Groups must be applied before any user
+        let groups = if !self.groups_done && (last || self.current.is_some()) {
+            self.groups_done = true;
+            std::mem::take(&mut self.groups)
+        } else if last || self.users.len() >= chunk_size {
+            Vec::new()
+        } else {
+            return None;
+        };
+
+        let chunk = AccountsChunk {
+            groups,
+            users: std::mem::take(&mut self.users),
+            index: self.chunks,
+            last,
+        };
+        self.chunks += 1;
+
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &[&str] = &[
+        r#"ADDGROUP NAME=project-PG0 GID=6418"#,
+        r#"ADDGROUP NAME=subgroup GID=6419"#,
+        r#"ADDUSER LOGIN=alice PSWD=* UID=20001 GID=6418 ROOT=1 NAME="Alice" HOMEDIR=/users/alice GLIST="6419" SERIAL=1 EMAIL="alice@localhost" SHELL=bash"#,
+        r#"PUBKEY LOGIN=alice KEY="ssh-ed25519 AAAA alice""#,
+        r#"ADDUSER LOGIN=bob PSWD=* UID=20002 GID=6418 ROOT=0 NAME="Bob" HOMEDIR=/users/bob GLIST="" SERIAL=1 EMAIL="bob@localhost" SHELL=tcsh"#,
+        r#"PUBKEY LOGIN=bob KEY="ssh-ed25519 AAAA bob""#,
+        r#"ADDUSER LOGIN=carol PSWD=$6$salt$c4r0l UID=20003 GID=6418 ROOT=0 NAME="Carol" HOMEDIR=/users/carol GLIST="" SERIAL=1 EMAIL="carol@localhost" SHELL=bash"#,
+    ];
+
+    #[test]
+    fn test_chunks() {
+        let mut parser = AccountsParser::new(1);
+        let mut chunks = Vec::new();
+
+        for line in RESPONSE {
+            if let Some(chunk) = parser.feed(line).expect("Failed to parse") {
+                chunks.push(chunk);
+            }
+        }
+        chunks.push(parser.finish());
+
+        // groups, alice, bob, carol
+        assert_eq!(4, chunks.len());
+        assert_eq!(2, chunks[0].groups.len());
+        assert!(chunks[0].users.is_empty());
+        assert!(chunks.iter().enumerate().all(|(i, chunk)| chunk.index == i));
+        assert_eq!("alice", chunks[1].users[0].login());
+        assert_eq!(&[6419], chunks[1].users[0].supplementary_groups());
+        assert_eq!("bob", chunks[2].users[0].login());
+        assert!(chunks[2].users[0].supplementary_groups().is_empty());
+        assert!(chunks[2].users[0].password_hash().is_none());
+        assert_eq!("carol", chunks[3].users[0].login());
+        assert_eq!(Some("$6$salt$c4r0l"), chunks[3].users[0].password_hash().map(|hash| hash.as_str()));
+        assert!(!format!("{:?}", chunks[3].users[0]).contains("c4r0l"));
+        assert!(chunks[3].last);
+    }
+
+    #[test]
+    fn test_late_group() {
+        let late = r#"ADDGROUP NAME=late GID=6420"#;
+
+        // Everything is handed out at the end without chunking
+        let mut parser = AccountsParser::new(usize::MAX);
+        for line in RESPONSE.iter().chain(&[late]) {
+            assert!(parser.feed(line).expect("Failed to parse").is_none());
+        }
+        let chunk = parser.finish();
+        assert_eq!(3, chunk.groups.len());
+        assert_eq!(3, chunk.users.len());
+        assert!(chunk.last);
+
+        // Groups were already handed out with chunking
+        let mut parser = AccountsParser::new(1);
+        for line in RESPONSE {
+            parser.feed(line).expect("Failed to parse");
+        }
+        assert!(matches!(parser.feed(late), Err(Error::TmcdOutOfOrder { .. })));
+    }
+}
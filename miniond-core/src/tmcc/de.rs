@@ -0,0 +1,255 @@
+//! Serde data format for TMCD responses.
+//!
+//! This allows a parsed key-value [`Response`] to be deserialized
+//! directly into typed structs:
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! #[serde(rename_all = "UPPERCASE")]
+//! struct MountEntry {
+//!     remote: String,
+//!     local: PathBuf,
+//! }
+//!
+//! let entry: MountEntry = Response::parse(line)?.deserialize()?;
+//! ```
+//!
+//! All values are strings on the wire. Numbers are parsed with
+//! `FromStr`, booleans are `1`/`0`, and sequences are comma-separated.
+
+use std::fmt::Display;
+use std::slice;
+use std::str::FromStr;
+
+use serde::de::{
+    self,
+    Deserializer,
+    DeserializeSeed,
+    IntoDeserializer,
+    MapAccess,
+    Visitor,
+};
+use serde::forward_to_deserialize_any;
+
+use crate::error::{Error, Result};
+use crate::redact;
+use super::parser::Response;
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::TmcdDeserialize {
+            message: msg.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        // The line is filled in by `Response::deserialize`
+        Error::TmcdMissingKey {
+            key: field.to_string(),
+            line: String::new(),
+        }
+    }
+}
+
+/// Deserializer for a whole response line.
+pub struct ResponseDeserializer<'a, 'de> {
+    response: &'a Response<'de>,
+}
+
+impl<'a, 'de> ResponseDeserializer<'a, 'de> {
+    pub fn new(response: &'a Response<'de>) -> Self {
+        Self { response }
+    }
+}
+
+impl<'a, 'de> Deserializer<'de> for ResponseDeserializer<'a, 'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(KvAccess {
+            iter: self.response.pairs().iter(),
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct enum identifier ignored_any
+    }
+}
+
+struct KvAccess<'a, 'de> {
+    iter: slice::Iter<'a, (&'de str, &'de str)>,
+    value: Option<(&'de str, &'de str)>,
+}
+
+impl<'a, 'de> MapAccess<'de> for KvAccess<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.iter.next() {
+            Some(&(key, value)) => {
+                self.value = Some((key, value));
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let (key, value) = self.value.take()
+            .ok_or_else(|| <Error as de::Error>::custom("value requested before key"))?;
+
+        seed.deserialize(ValueDeserializer { key, value })
+    }
+}
+
+/// Deserializer for a single value.
+struct ValueDeserializer<'de> {
+    key: &'de str,
+    value: &'de str,
+}
+
+impl<'de> ValueDeserializer<'de> {
+    fn parse<F: FromStr>(&self) -> Result<F>
+        where <F as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.value.parse::<F>().map_err(|e| Error::TmcdBadValue {
+            value: format!("{}={}", self.key, redact::value(self.key, self.value)),
+            parse_error: Box::new(e),
+        })
+    }
+}
+
+macro_rules! deserialize_from_str {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_str(self.value)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            "1" | "true" | "yes" => visitor.visit_bool(true),
+            "0" | "false" | "no" | "" => visitor.visit_bool(false),
+            _ => Err(<Error as de::Error>::invalid_value(de::Unexpected::Str(self.value), &"a boolean")),
+        }
+    }
+
+    deserialize_from_str! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let items = self.value.split(',')
+            .filter(|s| !s.is_empty())
+            .map(|value| ValueDeserializer { key: self.key, value });
+
+        visitor.visit_seq(de::value::SeqDeserializer::new(items))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        visitor.visit_enum(self.value.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for ValueDeserializer<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "UPPERCASE")]
+    struct Sample {
+        login: String,
+        uid: u16,
+        root: bool,
+        homedir: PathBuf,
+        glist: Vec<u16>,
+        email: Option<String>,
+        missing: Option<String>,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let r = Response::parse(r#"ADDUSER LOGIN=zhaofeng UID=20001 ROOT=1 HOMEDIR=/users/zhaofeng GLIST="1,2" EMAIL="root@localhost""#)
+            .expect("Failed to parse");
+
+        let sample: Sample = r.deserialize().expect("Failed to deserialize");
+
+        assert_eq!("zhaofeng", sample.login);
+        assert_eq!(20001, sample.uid);
+        assert!(sample.root);
+        assert_eq!(PathBuf::from("/users/zhaofeng"), sample.homedir);
+        assert_eq!(vec![1, 2], sample.glist);
+        assert_eq!(Some("root@localhost".to_string()), sample.email);
+        assert_eq!(None, sample.missing);
+    }
+
+    #[test]
+    fn test_missing_key() {
+        let r = Response::parse(r#"ADDUSER LOGIN=zhaofeng"#)
+            .expect("Failed to parse");
+
+        match r.deserialize::<Sample>() {
+            Err(Error::TmcdMissingKey { key, line }) => {
+                assert_eq!("UID", key);
+                assert_eq!("ADDUSER LOGIN=zhaofeng", line);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
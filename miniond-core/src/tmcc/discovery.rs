@@ -11,7 +11,7 @@ use crate::error::{Error, Result};
 use super::BossNode;
 
 /// Name of the SRV record that contains the boss node address.
-const EMULAB_BOSS_SRV: &'static str = "_emulab_boss";
+const EMULAB_BOSS_SRV: &str = "_emulab_boss";
 
 /// Discover the boss node automatically.
 pub async fn discover() -> Result<BossNode> {
@@ -29,14 +29,11 @@ pub async fn discover() -> Result<BossNode> {
     ];
 
     for file in files {
-        match read_to_string(&file).await {
-            Ok(boss) => {
-                let boss = boss.trim();
+        if let Ok(boss) = read_to_string(&file).await {
+            let boss = boss.trim();
 
-                log::info!("Discovered boss node from {}: {}", file, boss);
-                return Ok(BossNode::host(boss.to_string()));
-            }
-            Err(_) => {}
+            log::info!("Discovered boss node from {}: {}", file, boss);
+            return Ok(BossNode::host(boss.to_string()));
         }
     }
 
@@ -108,7 +105,12 @@ async fn discover_from_resolv_conf() -> Option<String> {
             }
         }
         ScopedIp::V6(addr, _) => {
-            Some(addr.to_string())
+            // Link-local addresses are useless without the scope
+            if (addr.segments()[0] & 0xffc0) == 0xfe80 || addr.is_loopback() {
+                None
+            } else {
+                Some(addr.to_string())
+            }
         }
     }
 }
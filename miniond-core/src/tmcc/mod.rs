@@ -0,0 +1,2370 @@
+//! The Testbed Master Control Client (tmcc).
+//!
+//! The corresponding applet is `src/applet/tmcc.rs` in `miniond`.
+//!
+//! ## Resources
+//!
+//! - <https://wiki.emulab.net/wiki/TmcdApi>
+
+mod accounts;
+mod de;
+mod discovery;
+pub mod models;
+pub mod parser;
+mod ser;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::AsRef;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::io::{
+    BufStream,
+    AsyncBufReadExt,
+    AsyncReadExt,
+    AsyncWriteExt,
+};
+use tokio::sync::{mpsc, Semaphore, SemaphorePermit};
+
+use crate::account::{Accounts, User};
+use crate::backoff::Backoff;
+use crate::error::{Error, Result};
+use crate::geni::RSpec;
+use crate::mount::{Credentials, Filesystem, Mount};
+use crate::net::{normalize_mac, InterfaceAddress, InterfaceConfig};
+use crate::redact;
+use accounts::AccountsParser;
+use models::{BootwhatLine, InterfaceLine, MountEntry, RpmLine, StartupLine, StatusLine, StorageLine, SyncserverLine, TarballLine, TiplineLine, TiptunnelLine, VinterfaceLine};
+use parser::Response;
+use transport::{Recorder, Replay, TimeoutStream, Transport};
+
+pub use accounts::AccountsChunk;
+pub use parser::{Records, ResponseBuf};
+pub use ser::to_line;
+pub use transport::Stream;
+
+/// The default TMCD port.
+pub const TMCD_PORT: u16 = 7777;
+
+/// The default port of TMCD over TLS.
+pub const TMCD_TLS_PORT: u16 = 7772;
+
+/// The TMCD protocol version.
+///
+/// This number is from
+/// <https://gitlab.flux.utah.edu/emulab/emulab-devel/-/blob/223096154f87ac7708a0f87a1bb63a20ef0fbde7/clientside/lib/tmcd/tmcd.h#L49>.
+pub const TMCD_VERSION: usize = 44;
+
+/// A boss node.
+pub enum BossNode {
+    /// A host-port tuple.
+    HostPort((String, u16)),
+
+    /*
+    /// A well-typed SocketAddr.
+    SocketAddr(SocketAddr),
+    */
+}
+
+impl BossNode {
+    /// Create a boss node from a host name or address and a port.
+    ///
+    /// IPv6 addresses may be enclosed in brackets.
+    pub fn host_port(host: &str, port: u16) -> Self {
+        let host = host.strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+
+        Self::HostPort((host.to_string(), port))
+    }
+
+    fn host(host: String) -> Self {
+        Self::host_port(&host, TMCD_PORT)
+    }
+
+    /// Automatically discover the boss node.
+    pub async fn discover() -> Result<Self> {
+        discovery::discover().await
+    }
+
+    /// Resolve the address of the boss node.
+    ///
+    /// On dual-stack networks, IPv6 addresses are picked over IPv4
+    /// ones if `prefer_ipv6` is set, and the other way around if not.
+    pub async fn resolve(self, prefer_ipv6: bool) -> Result<SocketAddr> {
+        match self {
+            Self::HostPort(host_port) => {
+                let addrs: Vec<SocketAddr> = lookup_host(host_port.clone()).await?.collect();
+
+                addrs.iter()
+                    .find(|sa| sa.is_ipv6() == prefer_ipv6)
+                    .or_else(|| addrs.first())
+                    .copied()
+                    .ok_or(Error::EmulabBossUnresolvable { host_port })
+            }
+            /*
+            Self::SocketAddr(sa) => Ok(sa),
+            */
+        }
+    }
+}
+
+/// The default maximum number of concurrent TMCD connections.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 4;
+
+/// The maximum number of redirects to follow for a command.
+pub const MAX_REDIRECTS: usize = 4;
+
+/// The default maximum size of a response, in bytes.
+///
+/// This is generous, since account lists of large projects and GENI
+/// manifests of large experiments can get big.
+pub const DEFAULT_MAX_RESPONSE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The default number of times to retry a failed connection to TMCD.
+pub const DEFAULT_CONNECT_RETRIES: u32 = 3;
+
+/// The default delay before the first connection retry, doubled each time.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The default maximum delay between connection retries.
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// The default time to wait for a connection to TMCD.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default time to wait for a response to make progress.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The maximum size of a UDP response, in bytes.
+const MAX_UDP_RESPONSE_SIZE: usize = 65507;
+
+/// A function wrapping each connection to TMCD.
+pub type Layer = Box<dyn Fn(Box<dyn Stream>) -> Box<dyn Stream> + Send + Sync>;
+
+/// A function called with the command, duration and success of each
+/// connection to TMCD.
+pub type Observer = Box<dyn Fn(&str, Duration, bool) + Send + Sync>;
+
+/// A TMCD client.
+///
+/// TMCD answers exactly one command per connection and signals the end
+/// of the response by closing it, so commands cannot be multiplexed over
+/// a single connection. Instead, the client limits the number of
+/// connections that may be open at the same time, so a reload does not
+/// hit the boss with all of its commands at once. Connections are never
+/// held while waiting on the caller, so commands issued while consuming
+/// a stream cannot wait on each other forever.
+pub struct Tmcc {
+    transport: Transport,
+
+    /// Recorder of all traffic, if enabled.
+    recorder: Option<Recorder>,
+
+    /// Directory to dump lines that fail to parse to, if enabled.
+    dump_dir: Option<PathBuf>,
+
+    /// Wrappers of each connection, innermost first.
+    layers: Vec<Layer>,
+
+    /// Observer of each connection, if any.
+    observer: Option<Observer>,
+
+    /// Permits for concurrent connections.
+    connections: Semaphore,
+
+    /// Virtual node to act as, if any.
+    vnode: Option<String>,
+
+    /// Maximum size of a response, in bytes.
+    max_response_size: u64,
+
+    /// Maximum sizes of responses to specific commands, in bytes.
+    response_size_limits: HashMap<String, u64>,
+
+    /// Number of times to retry transient connection failures.
+    connect_retries: u32,
+
+    /// Delays between connection retries.
+    backoff: Backoff,
+
+    /// How long to wait for a connection.
+    connect_timeout: Duration,
+
+    /// How long to wait for a response to make progress.
+    read_timeout: Duration,
+
+    /// How long to wait for UDP responses, if enabled.
+    udp_timeout: Option<Duration>,
+}
+
+impl Tmcc {
+    /// Create a new testbed master control client with a specific boss node.
+    pub async fn new(boss: BossNode) -> Result<Self> {
+        let sa = boss.resolve(false).await?;
+
+        Ok(Self::from_addr(sa))
+    }
+
+    /// Create a new testbed master control client with a resolved boss address.
+    pub fn from_addr(sa: SocketAddr) -> Self {
+        Self {
+            transport: Transport::Tcp(sa),
+            recorder: None,
+            dump_dir: None,
+            layers: Vec::new(),
+            observer: None,
+            connections: Semaphore::new(DEFAULT_MAX_CONNECTIONS),
+            vnode: None,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            response_size_limits: HashMap::new(),
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            backoff: Backoff::new(DEFAULT_RETRY_DELAY, DEFAULT_MAX_RETRY_DELAY),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            udp_timeout: None,
+        }
+    }
+
+    /// Create a client replaying responses recorded with [`Tmcc::record_dir`].
+    ///
+    /// The directory may also contain fixtures named after commands
+    /// (e.g., `accounts.txt`).
+    pub fn replay(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            transport: Transport::Replay(Replay::new(dir)?),
+            recorder: None,
+            dump_dir: None,
+            layers: Vec::new(),
+            observer: None,
+            connections: Semaphore::new(DEFAULT_MAX_CONNECTIONS),
+            vnode: None,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            response_size_limits: HashMap::new(),
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            backoff: Backoff::new(DEFAULT_RETRY_DELAY, DEFAULT_MAX_RETRY_DELAY),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            udp_timeout: None,
+        })
+    }
+
+    /// Returns the address of the boss, unless responses are replayed.
+    pub fn boss_addr(&self) -> Option<SocketAddr> {
+        match &self.transport {
+            Transport::Tcp(addr) => Some(*addr),
+            #[cfg(feature = "tls")]
+            Transport::Tls(tls) => Some(tls.addr),
+            Transport::Replay(_) => None,
+        }
+    }
+
+    /// Talk to the boss over TLS on another port.
+    ///
+    /// The node authenticates with its client certificate and key
+    /// (both in `certificate`), and the boss is verified against the
+    /// CA. If `fallback` is set, plaintext TCP on the original port
+    /// is used if TLS fails. Replayed clients are left alone.
+    ///
+    /// This fails with [`Error::TmcdTlsUnsupported`] unless the `tls`
+    /// feature is enabled.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables, unused_mut))]
+    pub fn tls(mut self, certificate: &Path, ca: &Path, port: u16, fallback: bool) -> Result<Self> {
+        #[cfg(feature = "tls")]
+        {
+            if let Transport::Tcp(addr) = self.transport {
+                self.transport = Transport::Tls(transport::Tls {
+                    addr: SocketAddr::new(addr.ip(), port),
+                    connector: tls::connector(certificate, ca)?,
+                    fallback: if fallback { Some(addr) } else { None },
+                    fell_back: Default::default(),
+                });
+            }
+
+            Ok(self)
+        }
+
+        #[cfg(not(feature = "tls"))]
+        Err(Error::TmcdTlsUnsupported)
+    }
+
+    /// Record all requests and responses to a directory.
+    pub fn record_dir(mut self, dir: PathBuf) -> Result<Self> {
+        self.recorder = Some(Recorder::new(dir)?);
+        Ok(self)
+    }
+
+    /// Dump responses that fail to parse to a directory.
+    ///
+    /// The dump is referenced in the returned error.
+    pub fn dump_dir(mut self, dir: PathBuf) -> Self {
+        self.dump_dir = Some(dir);
+        self
+    }
+
+    /// Wrap each connection to the boss.
+    ///
+    /// This can be used to inspect or alter the traffic (e.g., to
+    /// inject faults). Recordings made with [`Tmcc::record_dir`]
+    /// capture the traffic as seen through all layers.
+    pub fn layer<F>(mut self, layer: F) -> Self
+        where F: Fn(Box<dyn Stream>) -> Box<dyn Stream> + Send + Sync + 'static,
+    {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Observe each connection to the boss (e.g., for metrics).
+    ///
+    /// The observer is called once the connection is closed, with the
+    /// command, how long it took from the first connection attempt, and
+    /// whether it completed without connection or I/O errors. Commands
+    /// answered over UDP are not observed.
+    pub fn observe<F>(mut self, observer: F) -> Self
+        where F: Fn(&str, Duration, bool) + Send + Sync + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Set the maximum number of concurrent connections to the boss.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.connections = Semaphore::new(max.max(1));
+        self
+    }
+
+    /// Set the maximum size of a response, in bytes.
+    ///
+    /// Reading a larger response fails with
+    /// [`Error::TmcdResponseTooLarge`] instead of buffering it all.
+    pub fn max_response_size(mut self, max: u64) -> Self {
+        self.max_response_size = max;
+        self
+    }
+
+    /// Set the maximum size of responses to a specific command, in bytes.
+    ///
+    /// This overrides [`Tmcc::max_response_size`] for the command.
+    pub fn command_max_response_size(mut self, command: &str, max: u64) -> Self {
+        self.response_size_limits.insert(command.to_string(), max);
+        self
+    }
+
+    /// Set the number of times to retry a failed connection to the boss.
+    ///
+    /// Only transient failures (e.g., a refused connection while the
+    /// boss restarts) are retried, with a doubling delay in between.
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// Set the delays between connection retries.
+    pub fn retry_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set how long to wait for a connection to the boss.
+    ///
+    /// Connections that time out are retried like refused ones.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set how long to wait for a response to make progress.
+    ///
+    /// This bounds the time between reads rather than the whole
+    /// response, so large responses still go through.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Send idempotent commands (`state` and `status`) over UDP.
+    ///
+    /// This saves the TCP connection setup on busy boss nodes. If no
+    /// response arrives within the timeout, the command is sent over
+    /// TCP instead, so a state may be reported twice. Only plaintext
+    /// TCP clients use UDP, and UDP traffic goes through neither the
+    /// layers nor the recorder.
+    pub fn udp(mut self, timeout: Duration) -> Self {
+        self.udp_timeout = Some(timeout);
+        self
+    }
+
+    /// Act as a virtual node hosted on this machine.
+    ///
+    /// TMCD identifies nodes by their address, so physical hosts
+    /// name the virtual node in each command instead.
+    pub fn vnode(mut self, vnode: String) -> Self {
+        self.vnode = Some(vnode);
+        self
+    }
+
+    /// Automatically discover the boss node.
+    pub async fn discover() -> Result<Self> {
+        let boss = BossNode::discover().await?;
+
+        Self::new(boss).await
+    }
+
+    /// Retrieve accounts that should be configured.
+    pub async fn accounts(&self) -> Result<Accounts> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut accounts = Accounts::new();
+
+        let (res, _) = tokio::join!(
+            self.stream_accounts(usize::MAX, tx),
+            async {
+                while let Some(chunk) = rx.recv().await {
+                    for group in chunk.groups {
+                        accounts.groups.insert(group.name().to_string(), group);
+                    }
+                    for user in chunk.users {
+                        accounts.users.insert(user.login().to_string(), user);
+                    }
+                }
+            },
+        );
+        res?;
+
+        Ok(accounts)
+    }
+
+    /// Retrieve accounts that should be configured in chunks.
+    ///
+    /// Chunks of up to `chunk_size` users are sent as soon as they are
+    /// complete. All groups are sent in the first chunk.
+    ///
+    /// The response is read in full before any chunk is sent, so the
+    /// connection is not held while the receiver works through a chunk
+    /// (which may need connections of its own).
+    pub async fn stream_accounts(&self, chunk_size: usize, tx: mpsc::Sender<AccountsChunk>) -> Result<()> {
+        let mut socket = self.connect("accounts").await?;
+
+        socket.send(self.command("accounts")).await?;
+
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await?;
+
+        // Release our connection before handing out any chunks
+        drop(socket);
+
+        let response = String::from_utf8(response).or(Err(Error::TmcdInvalidUtf8))?;
+
+        let mut parser = AccountsParser::new(chunk_size);
+
+        for line in response.split_inclusive('\n') {
+            let chunk = parser.feed(line.trim())
+                .map_err(|e| self.dump("accounts", line, e))?;
+
+            if let Some(chunk) = chunk {
+                if tx.send(chunk).await.is_err() {
+                    // The receiver is gone
+                    return Ok(());
+                }
+            }
+        }
+
+        // also get root account info
+        let root = self.root_account().await?;
+
+        let mut last = parser.finish();
+        last.users.push(root);
+        tx.send(last).await.ok();
+
+        Ok(())
+    }
+
+    /// Retrieve root account information.
+    async fn root_account(&self) -> Result<User> {
+        use users::os::unix::UserExt;
+
+        let localization = self.localization().await?;
+
+        let root_sys = users::get_user_by_uid(0)
+            .ok_or(Error::TmcdNoSuchUser { login: "root".to_string() })?;
+
+        let mut root = User::new(
+            "root".to_string(),
+            0, 0,
+            "".to_string(),
+        );
+        root.home(root_sys.home_dir().to_path_buf());
+
+        for pubkey in localization.root_pubkeys {
+            root.add_ssh_key(pubkey);
+        }
+
+        Ok(root)
+    }
+
+    /// Retrieve cluster-wide settings.
+    pub async fn localization(&self) -> Result<Localization> {
+        let mut socket = self.connect("localization").await?;
+
+        socket.send(self.command("localization")).await?;
+
+        let mut localization = Localization::default();
+        let mut records = Records::new();
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            let record = if len == 0 {
+                std::mem::take(&mut records).finish()
+            } else {
+                records.push(&line)
+            };
+
+            if let Some(record) = record {
+                match Response::parse(&record) {
+                    Ok(r) => localization.add(&r),
+                    Err(_) => {
+                        // Not logged, since it may be part of a secret
+                        log::debug!("Ignoring a LOCALIZATION record that is not key-value pairs");
+                    }
+                }
+            }
+
+            if len == 0 {
+                break;
+            }
+
+            line.clear();
+        }
+
+        Ok(localization)
+    }
+
+    /// Retrieve mounts that should be configured.
+    pub async fn mounts(&self) -> Result<Vec<Mount>> {
+        let mut socket = self.connect("mounts").await?;
+        let mut mounts = Vec::new();
+
+        socket.send(self.command("mounts")).await?;
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            let mount = parse_mount(line.trim())
+                .map_err(|e| self.dump("mounts", &line, e))?;
+
+            if let Some(mount) = mount {
+                mounts.push(mount);
+            }
+
+            line.clear();
+        }
+
+        Ok(mounts)
+    }
+
+    /// Inform the testbed of our new state.
+    pub async fn state(&self, state: &State) -> Result<()> {
+        let command = self.command("state")
+            .arg(state.as_ref());
+        if self.exchange_udp("state", command).await.is_some() {
+            return Ok(());
+        }
+
+        let mut socket = self.connect("state").await?;
+
+        let command = self.command("state")
+            .arg(state.as_ref());
+        socket.send(command).await?;
+
+        Ok(())
+    }
+
+    /// Upload a log to be shown with the node on the testbed.
+    ///
+    /// This replaces the log from the previous upload.
+    pub async fn bootlog(&self, log: &str) -> Result<()> {
+        let mut socket = self.connect("bootlog").await?;
+
+        let command = self.command("bootlog")
+            .arg(log);
+        socket.send(command).await?;
+
+        Ok(())
+    }
+
+    /// Retrieve the allocation status for the current node.
+    pub async fn allocation_status(&self) -> Result<Option<AllocationStatus>> {
+        if let Some(response) = self.exchange_udp("status", self.command("status")).await {
+            let line = String::from_utf8_lossy(&response);
+            let line = line.lines().next().unwrap_or_default();
+
+            return parse_status(line.trim())
+                .map_err(|e| self.dump("status", line, e));
+        }
+
+        let mut socket = self.connect("status").await?;
+
+        socket.send(self.command("status")).await?;
+
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+
+        parse_status(line.trim())
+            .map_err(|e| self.dump("status", &line, e))
+    }
+
+    /// Retrieve environment variables for the experiment.
+    pub async fn userenv(&self) -> Result<Vec<(String, String)>> {
+        let mut socket = self.connect("userenv").await?;
+        let mut env = Vec::new();
+
+        socket.send(self.command("userenv")).await?;
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            if !line.trim().is_empty() {
+                let var = parse_userenv(line.trim_end_matches(&['\r', '\n'][..]))
+                    .map_err(|e| self.dump("userenv", &line, e))?;
+
+                env.push(var);
+            }
+
+            line.clear();
+        }
+
+        Ok(env)
+    }
+
+    /// Retrieve the experiment interfaces of the current node.
+    pub async fn ifconfig(&self) -> Result<Vec<InterfaceConfig>> {
+        let mut socket = self.connect("ifconfig").await?;
+        let mut interfaces = Vec::new();
+
+        socket.send(self.command("ifconfig")).await?;
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            if let Some(interface) = parse_interface(line.trim()).map_err(|e| self.dump("ifconfig", &line, e))? {
+                interfaces.push(interface);
+            }
+
+            line.clear();
+        }
+
+        Ok(interfaces)
+    }
+
+    /// Retrieve what the current node is supposed to boot.
+    ///
+    /// Returns `None` if the testbed doesn't know.
+    pub async fn bootwhat(&self) -> Result<Option<BootInfo>> {
+        let mut socket = self.connect("bootwhat").await?;
+
+        socket.send(self.command("bootwhat")).await?;
+
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+
+        parse_bootwhat(line.trim())
+            .map_err(|e| self.dump("bootwhat", &line, e))
+    }
+
+    /// Retrieve the barrier synchronization server of the experiment.
+    ///
+    /// Returns `None` if the experiment has none.
+    pub async fn syncserver(&self) -> Result<Option<SyncServer>> {
+        let mut socket = self.connect("syncserver").await?;
+
+        socket.send(self.command("syncserver")).await?;
+
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+
+        parse_syncserver(line.trim())
+            .map_err(|e| self.dump("syncserver", &line, e))
+    }
+
+    /// Retrieve the console line of the current node.
+    ///
+    /// Returns `None` if the node has no console line.
+    pub async fn tipline_info(&self) -> Result<Option<Tipline>> {
+        let mut socket = self.connect("tiplineinfo").await?;
+
+        socket.send(self.command("tiplineinfo")).await?;
+
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+
+        parse_tipline(line.trim())
+            .map_err(|e| self.dump("tiplineinfo", &line, e))
+    }
+
+    /// Retrieve the console lines to tunnel to this node.
+    ///
+    /// Each tunnel makes the console of another node (usually a
+    /// virtual node) reachable locally.
+    pub async fn tiptunnels(&self) -> Result<Vec<Tipline>> {
+        let mut socket = self.connect("tiptunnels").await?;
+        let mut tunnels = Vec::new();
+
+        socket.send(self.command("tiptunnels")).await?;
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            let tunnel = parse_tiptunnel(line.trim())
+                .map_err(|e| self.dump("tiptunnels", &line, e))?;
+
+            if let Some(tunnel) = tunnel {
+                tunnels.push(tunnel);
+            }
+
+            line.clear();
+        }
+
+        Ok(tunnels)
+    }
+
+    /// Retrieve the tarballs to unpack on the node.
+    pub async fn tarballs(&self) -> Result<Vec<Tarball>> {
+        let mut socket = self.connect("tarballs").await?;
+        let mut tarballs = Vec::new();
+
+        socket.send(self.command("tarballs")).await?;
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            if !line.trim().is_empty() {
+                let TarballLine { dir, tarball } = Response::parse(line.trim())
+                    .and_then(|response| response.deserialize())
+                    .map_err(|e| self.dump("tarballs", &line, e))?;
+
+                tarballs.push(Tarball { dir, source: tarball });
+            }
+
+            line.clear();
+        }
+
+        Ok(tarballs)
+    }
+
+    /// Retrieve the RPMs to install on the node.
+    pub async fn rpms(&self) -> Result<Vec<Rpm>> {
+        let mut socket = self.connect("rpms").await?;
+        let mut rpms = Vec::new();
+
+        socket.send(self.command("rpms")).await?;
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            if !line.trim().is_empty() {
+                let RpmLine { rpm } = Response::parse(line.trim())
+                    .and_then(|response| response.deserialize())
+                    .map_err(|e| self.dump("rpms", &line, e))?;
+
+                rpms.push(Rpm { source: rpm });
+            }
+
+            line.clear();
+        }
+
+        Ok(rpms)
+    }
+
+    /// Retrieve the local blockstores of the node.
+    ///
+    /// Remote blockstores are skipped.
+    pub async fn storageconfig(&self) -> Result<Vec<LocalVolume>> {
+        let mut socket = self.connect("storageconfig").await?;
+        let mut volumes = Vec::new();
+
+        socket.send(self.command("storageconfig")).await?;
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            if !line.trim().is_empty() {
+                let storage: StorageLine = Response::parse(line.trim())
+                    .and_then(|response| response.deserialize())
+                    .map_err(|e| self.dump("storageconfig", &line, e))?;
+
+                let local = storage.cmd == "SLICE"
+                    && storage.class.as_deref().is_some_and(|class| class.eq_ignore_ascii_case("local"));
+
+                if local {
+                    let index = storage.idx;
+                    volumes.push(LocalVolume {
+                        index,
+                        name: storage.volname.unwrap_or_else(|| format!("bs{}", index)),
+                        size_mib: storage.volsize,
+                        bsid: storage.bsid.unwrap_or_else(|| "ANY".to_string()),
+                        mount_point: storage.mountpoint,
+                        fs_type: storage.fstype,
+                    });
+                } else {
+                    log::warn!("Skipping unsupported blockstore {} ({} {})", storage.idx, storage.cmd, storage.class.unwrap_or_default());
+                }
+            }
+
+            line.clear();
+        }
+
+        Ok(volumes)
+    }
+
+    /// Retrieve the startup command of the node.
+    ///
+    /// Returns `None` if the experiment has none.
+    pub async fn startupcmd(&self) -> Result<Option<StartupCommand>> {
+        let mut socket = self.connect("startupcmd").await?;
+
+        socket.send(self.command("startupcmd")).await?;
+
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let StartupLine { cmd, uid } = Response::parse(line.trim())
+            .and_then(|response| response.deserialize())
+            .map_err(|e| self.dump("startupcmd", &line, e))?;
+
+        if cmd.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(StartupCommand { command: cmd, user: uid }))
+    }
+
+    /// Report the exit status of the startup command.
+    pub async fn startstatus(&self, status: i32) -> Result<()> {
+        let mut socket = self.connect("startstatus").await?;
+
+        let command = self.command("startstatus")
+            .arg(&status.to_string());
+        socket.send(command).await?;
+
+        Ok(())
+    }
+
+    /// Retrieve the GENI manifest.
+    ///
+    /// Adapted from the `/usr/bin/geni-get` script.
+    pub async fn geni_manifest(&self) -> Result<RSpec> {
+        let response = self.geni_get("geni_manifest").await?;
+
+        parse_manifest(&response)
+            .map_err(|e| self.dump("geni_manifest", &String::from_utf8_lossy(&response), e))
+    }
+
+    /// Retrieve the root SSH keypair of the experiment.
+    ///
+    /// The same keypair is given to all nodes in the experiment, so
+    /// root can log into the other nodes without a password. Returns
+    /// `None` if the experiment has none.
+    pub async fn root_keypair(&self) -> Result<Option<RootKeypair>> {
+        let mut socket = self.connect("rootkeys").await?;
+
+        socket.send(self.command("rootkeys")).await?;
+
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await?;
+
+        // Not dumped, since it's a secret
+        parse_root_keypair(&response)
+    }
+
+    /// Send an arbitrary command, returning the parsed response records.
+    ///
+    /// This is for commands without first-class support. Values may
+    /// span several lines (see [`Records`]), and records that are not
+    /// key-value pairs are skipped.
+    pub async fn raw(&self, command: &str, args: &[&str]) -> Result<Vec<ResponseBuf>> {
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut responses = Vec::new();
+
+        let (res, _) = tokio::join!(
+            self.stream_raw(command, args, tx),
+            async {
+                while let Some(response) = rx.recv().await {
+                    responses.push(response);
+                }
+            },
+        );
+        res?;
+
+        Ok(responses)
+    }
+
+    /// Send an arbitrary command, streaming the parsed response records.
+    ///
+    /// Each record is sent as soon as it's complete.
+    pub async fn stream_raw(&self, command: &str, args: &[&str], tx: mpsc::Sender<ResponseBuf>) -> Result<()> {
+        if command.is_empty() || command.contains(char::is_whitespace) {
+            return Err(Error::TmcdUnsupportedCommand { command: command.to_string() });
+        }
+
+        let mut socket = self.connect(command).await?;
+
+        let mut request = self.command(command);
+        for arg in args {
+            request = request.arg(arg);
+        }
+        socket.send(request).await?;
+
+        let mut records = Records::new();
+
+        let mut line = String::new();
+        loop {
+            let len = socket.read_line(&mut line).await?;
+
+            let record = if len == 0 {
+                std::mem::take(&mut records).finish()
+            } else {
+                records.push(&line)
+            };
+
+            if let Some(record) = record {
+                match Response::parse(&record) {
+                    Ok(response) => {
+                        if tx.send(response.to_buf()).await.is_err() {
+                            // The receiver is gone
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        // Not logged, since it may be part of a secret
+                        log::debug!("Skipping a record of {} that is not key-value pairs", command);
+                    }
+                }
+            }
+
+            if len == 0 {
+                break;
+            }
+
+            line.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Retrieve a named blob (e.g., a config file shipped with the
+    /// profile).
+    ///
+    /// If `sha256` is given, the contents are verified against it.
+    pub async fn blob(&self, name: &str, sha256: Option<&str>) -> Result<Blob> {
+        let mut socket = self.connect("blob").await?;
+
+        socket.send(self.command("blob").arg(name)).await?;
+
+        let mut contents = Vec::new();
+        socket.read_to_end(&mut contents).await?;
+
+        if contents.is_empty() {
+            return Err(Error::TmcdNoBlob { name: name.to_string() });
+        }
+
+        if let Some(expected) = sha256 {
+            let actual = sha256_hex(&contents);
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                return Err(Error::TmcdBlobChecksum {
+                    name: name.to_string(),
+                    expected: expected.to_string(),
+                    actual,
+                });
+            }
+        }
+
+        Ok(Blob {
+            name: name.to_string(),
+            contents,
+        })
+    }
+
+    /// Retrieve the private key of the experiment.
+    ///
+    /// The same key is given to all nodes in the experiment.
+    pub async fn geni_key(&self) -> Result<String> {
+        let response = self.geni_get("geni_key").await?;
+        parse_pem(&response)
+    }
+
+    /// Retrieve the certificate of the experiment.
+    pub async fn geni_certificate(&self) -> Result<String> {
+        let response = self.geni_get("geni_certificate").await?;
+        parse_pem(&response)
+    }
+
+    /// Send a `geni-get` command, returning the raw response.
+    async fn geni_get(&self, command: &str) -> Result<Vec<u8>> {
+        let mut socket = self.connect(command).await?;
+
+        socket.send(self.command(command)).await?;
+
+        let mut buf = Vec::new();
+        let first_byte_len = socket.read_until(0, &mut buf).await?;
+
+        if first_byte_len > 1 {
+            // Dump everything
+            Ok(buf)
+        } else if first_byte_len == 1 {
+            buf.clear();
+            let rest_len = socket.read_until(0, &mut buf).await?;
+
+            if rest_len == 1 {
+                return Err(Error::TmcdGeniError);
+            }
+
+            Ok(buf)
+        } else {
+            Err(Error::TmcdGeniBlankResponse)
+        }
+    }
+
+    /// Dump a response that failed to parse, if enabled.
+    ///
+    /// If the response is an error from TMCD, the typed error is
+    /// returned instead, and nothing is dumped.
+    fn dump(&self, command: &str, response: &str, error: Error) -> Error {
+        if let Some(error) = server_error(response) {
+            return error;
+        }
+
+        let dir = match &self.dump_dir {
+            Some(dir) => dir,
+            None => return error,
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let path = dir.join(format!("{}-{}.txt", timestamp, command));
+
+        let mut contents = String::new();
+        contents.push_str(&format!("command: {}\n", command));
+        contents.push_str(&format!("boss: {}\n", self.transport));
+        contents.push_str(&format!("timestamp: {}\n", timestamp));
+        contents.push_str(&format!("version: {}\n", env!("CARGO_PKG_VERSION")));
+        contents.push_str(&format!("error: {}\n\n", error));
+        contents.push_str(&redact::text(response));
+
+        // This is rare, so we don't bother moving it off the runtime
+        let res = std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(&path, contents));
+
+        match res {
+            Ok(()) => Error::TmcdDumped {
+                source: Box::new(error),
+                path,
+            },
+            Err(e) => {
+                log::warn!("Failed to dump TMCD response to {:?}: {}", path, e);
+                error
+            }
+        }
+    }
+
+    /// Create a command on behalf of our node.
+    fn command(&self, command: &str) -> Command {
+        Command::new(command, self.vnode.as_deref())
+    }
+
+    /// Wait for a permit to open a connection.
+    async fn permit(&self) -> SemaphorePermit<'_> {
+        self.connections.acquire().await.expect("Connection semaphore closed")
+    }
+
+    /// Connect to TMCD to send a command.
+    async fn connect(&self, command: &str) -> Result<Connection<'_>> {
+        let permit = self.permit().await;
+
+        let started = Instant::now();
+        let mut backoff = self.backoff.clone();
+        let mut attempt = 0;
+        let stream = loop {
+            let connect = tokio::time::timeout(self.connect_timeout, self.transport.connect());
+            let res = connect.await.unwrap_or_else(|_| Err(Error::TmcdConnectTimeout {
+                boss: self.transport.to_string(),
+                timeout: self.connect_timeout,
+            }));
+
+            match res {
+                Ok(stream) => break self.wrap(stream),
+                Err(e) if e.is_transient() && attempt < self.connect_retries => {
+                    let delay = backoff.next_delay();
+                    log::warn!("{} - Retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+
+                    attempt += 1;
+                }
+                Err(e) => {
+                    if let Some(observer) = &self.observer {
+                        observer(command, started.elapsed(), false);
+                    }
+
+                    return Err(e);
+                }
+            }
+        };
+
+        let limit = self.response_size_limits.get(command)
+            .copied()
+            .unwrap_or(self.max_response_size);
+
+        Ok(Connection {
+            tmcc: self,
+            stream: BufStream::new(stream),
+            command: command.to_string(),
+            server: self.transport.to_string(),
+            request: Vec::new(),
+            pending: Vec::new(),
+            redirects_followed: false,
+            limit,
+            received: 0,
+            started,
+            failed: AtomicBool::new(false),
+            _permit: permit,
+        })
+    }
+
+    /// Exchange a command over UDP, if enabled.
+    ///
+    /// Returns `None` if UDP is disabled or failed, in which case the
+    /// command should be sent over TCP.
+    async fn exchange_udp(&self, name: &str, command: Command) -> Option<Vec<u8>> {
+        let timeout = self.udp_timeout?;
+        let addr = match &self.transport {
+            Transport::Tcp(addr) => *addr,
+            _ => return None,
+        };
+
+        let exchange = async {
+            let bind: SocketAddr = if addr.is_ipv6() {
+                (Ipv6Addr::UNSPECIFIED, 0).into()
+            } else {
+                (Ipv4Addr::UNSPECIFIED, 0).into()
+            };
+
+            let socket = UdpSocket::bind(bind).await?;
+            socket.connect(addr).await?;
+            socket.send(&command.finalize()).await?;
+
+            let mut buf = vec![0; MAX_UDP_RESPONSE_SIZE];
+            let len = socket.recv(&mut buf).await?;
+            buf.truncate(len);
+
+            io::Result::Ok(buf)
+        };
+
+        match tokio::time::timeout(timeout, exchange).await {
+            Ok(Ok(response)) => Some(response),
+            Ok(Err(e)) => {
+                log::debug!("Failed to send {} over UDP: {} - Using TCP", name, e);
+                None
+            }
+            Err(_) => {
+                log::debug!("No UDP response to {} within {:?} - Using TCP", name, timeout);
+                None
+            }
+        }
+    }
+
+    /// Apply the layers and the recorder to a new connection.
+    fn wrap(&self, mut stream: Box<dyn Stream>) -> Box<dyn Stream> {
+        for layer in &self.layers {
+            stream = layer(stream);
+        }
+
+        if let Some(recorder) = &self.recorder {
+            stream = recorder.wrap(stream);
+        }
+
+        Box::new(TimeoutStream::new(stream, self.read_timeout))
+    }
+}
+
+/// Check that a raw response to a command can be parsed.
+///
+/// This parses the response the same way the client does, so captures
+/// from real testbeds can be validated without connecting to them.
+pub fn validate(command: &str, response: &[u8]) -> Result<()> {
+    let text = std::str::from_utf8(response)
+        .or(Err(Error::TmcdInvalidUtf8))?;
+
+    validate_text(command, response, text).map_err(|error| match error {
+        Error::TmcdUnsupportedCommand { .. } => error,
+        error => server_error(text).unwrap_or(error),
+    })
+}
+
+fn validate_text(command: &str, response: &[u8], text: &str) -> Result<()> {
+    match command {
+        "accounts" => {
+            let mut parser = AccountsParser::new(usize::MAX);
+            for line in text.lines() {
+                parser.feed(line.trim())?;
+            }
+            parser.finish();
+        }
+        "mounts" => {
+            for line in text.lines() {
+                parse_mount(line.trim())?;
+            }
+        }
+        "status" => {
+            parse_status(text.lines().next().unwrap_or_default().trim())?;
+        }
+        "ifconfig" => {
+            for line in text.lines() {
+                parse_interface(line.trim())?;
+            }
+        }
+        "bootwhat" => {
+            parse_bootwhat(text.lines().next().unwrap_or_default().trim())?;
+        }
+        "rootkeys" => {
+            parse_root_keypair(response)?;
+        }
+        "syncserver" => {
+            parse_syncserver(text.lines().next().unwrap_or_default().trim())?;
+        }
+        "tiptunnels" => {
+            for line in text.lines() {
+                parse_tiptunnel(line.trim())?;
+            }
+        }
+        "geni_manifest" => {
+            parse_manifest(response)?;
+        }
+        _ => {
+            return Err(Error::TmcdUnsupportedCommand {
+                command: command.to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a line from `mounts`.
+///
+/// Returns `None` if the line does not describe a mount.
+fn parse_mount(line: &str) -> Result<Option<Mount>> {
+    let parsed = Response::parse(line)?;
+
+    if parsed.get("REMOTE").is_ok() {
+        let MountEntry { remote, local, fstype, options, username, password } = parsed.deserialize()?;
+
+        // Mount units are named after the absolute mount point
+        if !local.is_absolute() {
+            return Err(Error::TmcdRelativeMountPoint { local });
+        }
+
+        let filesystem = match fstype.as_deref().map(str::to_ascii_lowercase).as_deref() {
+            None | Some("") | Some("nfs") | Some("nfs4") => Filesystem::Nfs,
+            Some("smb") | Some("cifs") => Filesystem::Smb,
+            Some(other) => {
+                if !Path::new(&remote).is_absolute() {
+                    return Err(Error::TmcdBadValue {
+                        value: remote,
+                        parse_error: "block device must be an absolute path".into(),
+                    });
+                }
+
+                Filesystem::Block(other.to_string())
+            }
+        };
+
+        let mut mount = Mount::new(remote, local);
+        mount.set_filesystem(filesystem);
+
+        if fstype.as_deref() == Some("nfs4") {
+            mount.add_option("vers=4".to_string());
+        }
+
+        for option in options.iter().flat_map(|options| options.split(',')) {
+            if !option.is_empty() {
+                mount.add_option(option.to_string());
+            }
+        }
+
+        if let Some(username) = username {
+            mount.set_credentials(Credentials {
+                username,
+                password: password.unwrap_or_default(),
+            });
+        }
+
+        Ok(Some(mount))
+    } else {
+        log::debug!("Non mountpoint line: {}", redact::line(line));
+        Ok(None)
+    }
+}
+
+/// Parse the response to `status`.
+fn parse_status(line: &str) -> Result<Option<AllocationStatus>> {
+    let parsed = Response::parse(line)?;
+
+    if let Some("FREE") = parsed.response_type() {
+        // Not allocated
+        Ok(None)
+    } else {
+        // Allocated
+        let StatusLine { allocated, nickname } = parsed.deserialize()?;
+        let status = AllocationStatus {
+            experiment: allocated,
+            node_name: nickname,
+        };
+
+        Ok(Some(status))
+    }
+}
+
+/// Recognize an error from TMCD in a response that failed to parse.
+///
+/// TMCD reports errors as free text instead of key-value pairs.
+fn server_error(response: &str) -> Option<Error> {
+    let line = response.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?;
+
+    let lower = line.to_ascii_lowercase();
+    let message = line.to_string();
+
+    if lower.contains("permission denied") || lower.contains("access denied") || lower.contains("not allowed") {
+        Some(Error::TmcdPermissionDenied { message })
+    } else if lower.contains("no such node") || lower.contains("unknown node") || lower.contains("invalid node") {
+        Some(Error::TmcdUnknownNode { message })
+    } else if lower.contains("version") && (lower.contains("too old") || lower.contains("not supported") || lower.contains("unsupported")) {
+        Some(Error::TmcdVersionTooOld { message })
+    } else if lower.starts_with("error") {
+        Some(Error::TmcdServerError { message })
+    } else {
+        None
+    }
+}
+
+/// Parse the response to `syncserver`.
+fn parse_syncserver(line: &str) -> Result<Option<SyncServer>> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let SyncserverLine { server, isserver } = Response::parse(line)?.deserialize()?;
+
+    Ok(Some(SyncServer {
+        server,
+        is_server: isserver,
+    }))
+}
+
+/// Parse the response to `bootwhat`.
+fn parse_bootwhat(line: &str) -> Result<Option<BootInfo>> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let BootwhatLine { status, kind, what, cmdline } = Response::parse(line)?.deserialize()?;
+
+    if status != "success" {
+        return Ok(None);
+    }
+
+    let bad_value = |value: String| Error::TmcdBadValue {
+        value,
+        parse_error: "unknown boot type".into(),
+    };
+
+    let what = match kind.as_deref().map(|kind| kind.to_ascii_uppercase()).as_deref() {
+        Some("PART") => BootWhat::Partition(parse_number(what)?.ok_or_else(|| bad_value(String::new()))?),
+        Some("SYSID") => BootWhat::Sysid(parse_number(what)?.ok_or_else(|| bad_value(String::new()))?),
+        Some("MB") => BootWhat::Multiboot(what.unwrap_or_default()),
+        Some("MFS") => BootWhat::Mfs(what.unwrap_or_default()),
+        Some("WAIT") => BootWhat::Wait,
+        Some("REBOOT") => BootWhat::Reboot,
+        Some("AUTO") => BootWhat::Auto,
+        _ => return Err(bad_value(kind.unwrap_or_default())),
+    };
+
+    Ok(Some(BootInfo {
+        what,
+        cmdline: non_empty(cmdline),
+    }))
+}
+
+/// Parse a line from `ifconfig`.
+///
+/// Returns `None` for lines that don't describe an interface
+/// (e.g., `ROUTE`).
+fn parse_interface(line: &str) -> Result<Option<InterfaceConfig>> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let line = &quote_empty_values(line);
+    let parsed = Response::parse(line)?;
+
+    match parsed.response_type() {
+        Some("INTERFACE") => {
+            let InterfaceLine { inet, mask, mac, iface, lan, mtu } = parsed.deserialize()?;
+
+            Ok(Some(InterfaceConfig {
+                name: non_empty(iface),
+                mac: Some(parse_mac(&mac)?),
+                addresses: parse_address(&inet, &mask)?.into_iter().collect(),
+                mtu: parse_number(mtu)?,
+                vlan: None,
+                parent: None,
+                lan: non_empty(lan),
+            }))
+        }
+        Some("VINTERFACE") => {
+            let VinterfaceLine { lan, vmac, inet, mask, vtype, vtag, pmac, mtu } = parsed.deserialize()?;
+
+            if vtype != "vlan" {
+                log::debug!("Unsupported virtual interface type {}: {}", vtype, redact::line(line));
+                return Ok(None);
+            }
+
+            Ok(Some(InterfaceConfig {
+                name: None,
+                mac: non_empty(vmac).map(|mac| parse_mac(&mac)).transpose()?,
+                addresses: parse_address(&inet, &mask)?.into_iter().collect(),
+                mtu: parse_number(mtu)?,
+                vlan: parse_number(vtag)?,
+                parent: non_empty(pmac).map(|mac| parse_mac(&mac)).transpose()?,
+                lan: non_empty(lan),
+            }))
+        }
+        _ => {
+            log::debug!("Non interface line: {}", redact::line(line));
+            Ok(None)
+        }
+    }
+}
+
+/// Quote empty values (e.g., `IFACE= `).
+///
+/// The parser rejects them as truncated lines, but TMCD leaves
+/// unknown values empty in `ifconfig`.
+fn quote_empty_values(line: &str) -> String {
+    let mut quoted = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        quoted.push(c);
+
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '=') if matches!(chars.peek(), None | Some(' ')) => quoted.push_str("\"\""),
+            _ => {}
+        }
+    }
+
+    quoted
+}
+
+/// Parse an address and netmask from `ifconfig`.
+///
+/// Returns `None` if the interface has no address.
+fn parse_address(inet: &str, mask: &str) -> Result<Option<InterfaceAddress>> {
+    if inet.is_empty() {
+        return Ok(None);
+    }
+
+    let bad_value = |value: &str, error: Box<dyn std::error::Error + Send + Sync>| Error::TmcdBadValue {
+        value: value.to_string(),
+        parse_error: error,
+    };
+
+    let address: Ipv4Addr = inet.parse().map_err(|e| bad_value(inet, Box::new(e)))?;
+    let mask: Ipv4Addr = mask.parse().map_err(|e| bad_value(mask, Box::new(e)))?;
+
+    let bits = u32::from(mask);
+    if bits.leading_ones() + bits.trailing_zeros() != 32 {
+        return Err(bad_value(&mask.to_string(), "not a contiguous netmask".into()));
+    }
+
+    Ok(Some(InterfaceAddress {
+        address: IpAddr::V4(address),
+        prefix_len: bits.leading_ones() as u8,
+    }))
+}
+
+/// Parse a MAC address from TMCD (e.g., `0002b3651e1d`).
+fn parse_mac(mac: &str) -> Result<String> {
+    normalize_mac(mac).ok_or_else(|| Error::TmcdBadValue {
+        value: mac.to_string(),
+        parse_error: "not a MAC address".into(),
+    })
+}
+
+/// Parse an optional number, treating empty values as absent.
+fn parse_number<T>(value: Option<String>) -> Result<Option<T>>
+    where T: std::str::FromStr,
+          T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match non_empty(value) {
+        Some(value) => {
+            let number = value.parse()
+                .map_err(|e| Error::TmcdBadValue { value: value.clone(), parse_error: Box::new(e) })?;
+            Ok(Some(number))
+        }
+        None => Ok(None),
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.is_empty())
+}
+
+/// Parse a line from `userenv`.
+///
+/// Values are not quoted and extend to the end of the line.
+fn parse_userenv(line: &str) -> Result<(String, String)> {
+    let (name, value) = line.split_once('=')
+        .ok_or_else(|| Error::TmcdInvalidEnvName { name: line.to_string() })?;
+
+    let valid = name.chars().enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+
+    if name.is_empty() || !valid {
+        return Err(Error::TmcdInvalidEnvName { name: name.to_string() });
+    }
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parse the response to `tiplineinfo`.
+fn parse_tipline(line: &str) -> Result<Option<Tipline>> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let TiplineLine { tipline, server, port, keylen, key } = Response::parse(line)?.deserialize()?;
+
+    if key.len() != keylen {
+        return Err(Error::TmcdBadKeyLength { expected: keylen, actual: key.len() });
+    }
+
+    Ok(Some(Tipline {
+        name: tipline,
+        server,
+        port,
+        key,
+    }))
+}
+
+/// Parse a line of the response to `tiptunnels`.
+fn parse_tiptunnel(line: &str) -> Result<Option<Tipline>> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let TiptunnelLine { vnode, server, port, keylen, key } = Response::parse(line)?.deserialize()?;
+
+    if key.len() != keylen {
+        return Err(Error::TmcdBadKeyLength { expected: keylen, actual: key.len() });
+    }
+
+    Ok(Some(Tipline {
+        name: vnode,
+        server,
+        port,
+        key,
+    }))
+}
+
+/// Parse the response to `rootkeys`.
+///
+/// The private key is a quoted value spanning multiple lines. If the
+/// response can't be split into records, we fall back to looking for
+/// the PEM block and the OpenSSH public key, wherever they are.
+fn parse_root_keypair(response: &[u8]) -> Result<Option<RootKeypair>> {
+    let text = std::str::from_utf8(response)
+        .or(Err(Error::TmcdInvalidUtf8))?;
+
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+
+    if let Some(keypair) = parse_root_keypair_records(text) {
+        return Ok(Some(keypair));
+    }
+
+    let (begin, end) = find_private_key(text)?;
+    let private_key = format!("{}\n", &text[begin..end]);
+
+    let public_key = text[end..].lines()
+        .chain(text[..begin].lines())
+        .find_map(|line| {
+            let start = line.find("ssh-").or_else(|| line.find("ecdsa-"))?;
+            let key = line[start..].trim_end_matches(|c: char| c == '\'' || c == '"' || c.is_whitespace());
+            Some(key.to_string())
+        });
+
+    Ok(Some(RootKeypair {
+        private_key,
+        public_key,
+    }))
+}
+
+/// Parse the response to `rootkeys` as key-value records.
+fn parse_root_keypair_records(text: &str) -> Option<RootKeypair> {
+    let mut records = Records::new();
+    let mut private_key = None;
+    let mut public_key = None;
+
+    let mut add = |record: &str| {
+        let response = match Response::parse(record) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+
+        for (_, value) in response.pairs() {
+            if value.contains("-----BEGIN ") {
+                if let Ok((begin, end)) = find_private_key(value) {
+                    private_key = Some(format!("{}\n", &value[begin..end]));
+                }
+            } else if value.starts_with("ssh-") || value.starts_with("ecdsa-") {
+                public_key = Some(value.trim_end().to_string());
+            }
+        }
+    };
+
+    for line in text.split_inclusive('\n') {
+        if let Some(record) = records.push(line) {
+            add(&record);
+        }
+    }
+    if let Some(record) = records.finish() {
+        add(&record);
+    }
+
+    Some(RootKeypair {
+        private_key: private_key?,
+        public_key,
+    })
+}
+
+/// Returns where the first PEM-encoded private key in a response
+/// begins and ends.
+fn find_private_key(text: &str) -> Result<(usize, usize)> {
+    let bad_value = |reason: &str| Error::TmcdBadValue {
+        // Don't leak the key in errors
+        value: String::new(),
+        parse_error: reason.into(),
+    };
+
+    let begin = text.find("-----BEGIN ")
+        .ok_or_else(|| bad_value("no private key"))?;
+    let end_marker = text[begin..].find("-----END ")
+        .map(|end| begin + end)
+        .ok_or_else(|| bad_value("unterminated private key"))?;
+    let end = text[end_marker + 9..].find("-----")
+        .map(|end| end_marker + 9 + end + 5)
+        .ok_or_else(|| bad_value("unterminated private key"))?;
+
+    Ok((begin, end))
+}
+
+/// Parse the `ROOTKEY` value of a `localization` response.
+///
+/// The public key comes separately, if at all.
+fn root_keypair_from_pem(value: &str) -> Option<RootKeypair> {
+    match find_private_key(value) {
+        Ok((begin, end)) => Some(RootKeypair {
+            private_key: format!("{}\n", &value[begin..end]),
+            public_key: None,
+        }),
+        Err(_) => {
+            log::debug!("Ignoring malformed ROOTKEY in LOCALIZATION");
+            None
+        }
+    }
+}
+
+/// Returns the SHA-256 digest of some bytes in hex.
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parse a PEM-encoded key or certificate from `geni-get`.
+fn parse_pem(response: &[u8]) -> Result<String> {
+    let pem = std::str::from_utf8(response)
+        .or(Err(Error::TmcdInvalidUtf8))?
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace());
+
+    if pem.is_empty() {
+        return Err(Error::TmcdGeniBlankResponse);
+    }
+
+    Ok(format!("{}\n", pem))
+}
+
+/// Parse a redirect to another server, returning the target.
+fn parse_redirect(line: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?.trim();
+    let target = line.strip_prefix("REDIRECT=")?;
+
+    if target.is_empty() || target.contains(char::is_whitespace) {
+        return None;
+    }
+
+    Some(target.to_string())
+}
+
+/// Returns the server a redirect points to.
+///
+/// The target is `<host>[:<port>]`, where IPv6 addresses must be
+/// enclosed in brackets if a port is given.
+fn redirect_boss(target: &str) -> BossNode {
+    if let Ok(sa) = target.parse::<SocketAddr>() {
+        return BossNode::host_port(&sa.ip().to_string(), sa.port());
+    }
+
+    if target.parse::<IpAddr>().is_ok() {
+        return BossNode::host(target.to_string());
+    }
+
+    match target.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => BossNode::host_port(host, port),
+            Err(_) => BossNode::host(target.to_string()),
+        },
+        None => BossNode::host(target.to_string()),
+    }
+}
+
+/// Parse a GENI manifest.
+fn parse_manifest(response: &[u8]) -> Result<RSpec> {
+    let xml = std::str::from_utf8(response)
+        .or(Err(Error::TmcdInvalidUtf8))?;
+
+    RSpec::parse(xml)
+}
+
+/// A connection to TMCD.
+///
+/// The connection slot is released when this is dropped. Requests
+/// must be sent and responses read with the methods here, which
+/// follow redirects and enforce the size limit.
+struct Connection<'a> {
+    tmcc: &'a Tmcc,
+    stream: BufStream<Box<dyn Stream>>,
+
+    /// The command sent, for diagnostics.
+    command: String,
+
+    /// The server we are connected to, for diagnostics.
+    server: String,
+
+    /// The raw request, to resend when redirected.
+    request: Vec<u8>,
+
+    /// Data read while checking for a redirect, not yet returned.
+    pending: Vec<u8>,
+
+    /// Whether redirects were followed.
+    redirects_followed: bool,
+
+    /// Maximum size of the response, in bytes.
+    limit: u64,
+
+    /// Number of bytes of the response read so far.
+    received: u64,
+
+    /// When the first connection attempt was made.
+    started: Instant,
+
+    /// Whether a connection or I/O error occurred.
+    failed: AtomicBool,
+
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        if let Some(observer) = &self.tmcc.observer {
+            observer(&self.command, self.started.elapsed(), !*self.failed.get_mut());
+        }
+    }
+}
+
+impl<'a> Connection<'a> {
+    /// Send a command.
+    async fn send(&mut self, command: Command) -> Result<()> {
+        self.send_raw(command.finalize()).await
+    }
+
+    /// Send a raw request.
+    async fn send_raw(&mut self, request: Vec<u8>) -> Result<()> {
+        self.stream.write_all(&request).await
+            .map_err(|e| self.io_error(e))?;
+        self.stream.flush().await
+            .map_err(|e| self.io_error(e))?;
+        self.request = request;
+
+        Ok(())
+    }
+
+    /// Follow redirects to other servers, if any.
+    ///
+    /// Some deployments answer with `REDIRECT=<host>[:<port>]` to
+    /// send the node to another server (e.g., a subboss). Redirects
+    /// are checked for when the response starts to be read, so they
+    /// are not followed for commands whose responses are ignored.
+    async fn follow_redirects(&mut self) -> Result<()> {
+        if self.redirects_followed {
+            return Ok(());
+        }
+        self.redirects_followed = true;
+
+        let mut visited: Vec<SocketAddr> = self.tmcc.boss_addr().into_iter().collect();
+
+        loop {
+            let mut line = Vec::new();
+            let remaining = self.remaining();
+            let len = (&mut self.stream).take(remaining)
+                .read_until(b'\n', &mut line).await
+                .map_err(|e| self.io_error(e))?;
+
+            self.consume(len, &line)?;
+
+            let target = match parse_redirect(&line) {
+                Some(target) => target,
+                None => {
+                    self.pending = line;
+                    return Ok(());
+                }
+            };
+
+            // Stay on the same address family as the boss
+            let prefer_ipv6 = self.tmcc.boss_addr().is_some_and(|addr| addr.is_ipv6());
+            let addr = redirect_boss(&target).resolve(prefer_ipv6).await
+                .inspect_err(|_| self.failed.store(true, Ordering::Relaxed))?;
+
+            if visited.len() > MAX_REDIRECTS || visited.contains(&addr) {
+                return Err(Error::TmcdRedirectLoop {
+                    command: self.command.clone(),
+                    target,
+                });
+            }
+            visited.push(addr);
+
+            log::info!("TMCD redirected command {} to {} ({})", self.command, target, addr);
+
+            let timeout = self.tmcc.connect_timeout;
+            let stream = tokio::time::timeout(timeout, self.tmcc.transport.redirect(addr)).await
+                .unwrap_or_else(|_| Err(Error::TmcdConnectTimeout { boss: addr.to_string(), timeout }))
+                .inspect_err(|_| self.failed.store(true, Ordering::Relaxed))?;
+            self.stream = BufStream::new(self.tmcc.wrap(stream));
+            self.server = addr.to_string();
+            self.received = 0;
+
+            self.stream.write_all(&self.request).await
+                .map_err(|e| self.io_error(e))?;
+            self.stream.flush().await
+                .map_err(|e| self.io_error(e))?;
+        }
+    }
+
+    /// Read a line into `buf`, returning the number of bytes read.
+    async fn read_line(&mut self, buf: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        let len = self.read_until(b'\n', &mut bytes).await?;
+
+        buf.push_str(std::str::from_utf8(&bytes).or(Err(Error::TmcdInvalidUtf8))?);
+
+        Ok(len)
+    }
+
+    /// Read into `buf` until `byte`, returning the number of bytes read.
+    async fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<usize> {
+        self.follow_redirects().await?;
+
+        let mut pending = 0;
+        if !self.pending.is_empty() {
+            if let Some(pos) = self.pending.iter().position(|&b| b == byte) {
+                buf.extend(self.pending.drain(..=pos));
+                return Ok(pos + 1);
+            }
+
+            pending = self.pending.len();
+            buf.append(&mut self.pending);
+        }
+
+        let start = buf.len();
+        let remaining = self.remaining();
+        let len = (&mut self.stream).take(remaining)
+            .read_until(byte, buf).await
+            .map_err(|e| self.io_error(e))?;
+
+        self.consume(len, &buf[start..])?;
+
+        Ok(pending + len)
+    }
+
+    /// Read the rest of the response into `buf`.
+    async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        self.follow_redirects().await?;
+
+        let pending = self.pending.len();
+        buf.append(&mut self.pending);
+
+        let start = buf.len();
+        let remaining = self.remaining();
+        let len = (&mut self.stream).take(remaining)
+            .read_to_end(buf).await
+            .map_err(|e| self.io_error(e))?;
+
+        self.consume(len, &buf[start..])?;
+
+        Ok(pending + len)
+    }
+
+    /// Add context to an I/O error.
+    fn io_error(&self, source: io::Error) -> Error {
+        self.failed.store(true, Ordering::Relaxed);
+
+        Error::TmcdIo {
+            command: self.command.clone(),
+            boss: self.server.clone(),
+            source,
+        }
+    }
+
+    /// Returns the number of bytes that may still be read.
+    ///
+    /// One more byte than the limit is allowed, to detect responses
+    /// that exceed it.
+    fn remaining(&self) -> u64 {
+        (self.limit + 1).saturating_sub(self.received)
+    }
+
+    /// Account for `len` bytes that were just read.
+    fn consume(&mut self, len: usize, data: &[u8]) -> Result<()> {
+        self.received += len as u64;
+
+        if self.received <= self.limit {
+            return Ok(());
+        }
+
+        // Help tell a runaway response from a legitimately large one
+        let tail = &data[data.len().saturating_sub(64)..];
+        log::warn!(
+            "Response to TMCD command {} was truncated after {} bytes, ending with {:?}",
+            self.command, self.limit, String::from_utf8_lossy(tail),
+        );
+
+        Err(Error::TmcdResponseTooLarge {
+            command: self.command.clone(),
+            limit: self.limit,
+        })
+    }
+}
+
+/// The node allocation status.
+#[derive(Debug, Clone)]
+pub struct AllocationStatus {
+    pub experiment: String,
+    pub node_name: String,
+}
+
+impl AllocationStatus {
+    /// Returns whether two statuses refer to the same allocation.
+    pub fn same_as(&self, other: &Self) -> bool {
+        self.experiment == other.experiment && self.node_name == other.node_name
+    }
+}
+
+/// Cluster-wide settings from `localization`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Localization {
+    /// Public keys allowed to log in as root.
+    pub root_pubkeys: Vec<String>,
+
+    /// Timezone of the cluster (e.g., `America/Denver`).
+    pub timezone: Option<String>,
+
+    /// Locale of the cluster (e.g., `en_US.UTF-8`).
+    pub locale: Option<String>,
+
+    /// Proxy for external HTTP access.
+    pub http_proxy: Option<String>,
+
+    /// Proxy for external HTTPS access.
+    pub https_proxy: Option<String>,
+
+    /// Hosts and domains to reach without the proxy.
+    pub no_proxy: Option<String>,
+
+    /// Serial console of the node.
+    pub console: Option<ConsoleSettings>,
+
+    /// Site-specific settings we don't know about, by key.
+    pub settings: BTreeMap<String, String>,
+
+    /// The root SSH keypair from the `ROOTKEY` block, which is never
+    /// serialized.
+    #[serde(skip)]
+    pub root_keypair: Option<RootKeypair>,
+}
+
+/// Serial console settings from `localization`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConsoleSettings {
+    /// The serial device (e.g., `ttyS0`).
+    pub tty: Option<String>,
+
+    /// The baud rate (e.g., `115200`).
+    pub speed: Option<u32>,
+}
+
+impl Localization {
+    /// Add the settings on a line of the response.
+    ///
+    /// A line may carry several settings.
+    fn add(&mut self, r: &Response) {
+        for &(key, value) in r.pairs() {
+            match key {
+                "ROOTPUBKEY" => self.root_pubkeys.push(value.to_string()),
+
+                // Private keys must not end up with the other settings
+                "ROOTKEY" => self.root_keypair = root_keypair_from_pem(value),
+
+                "TIMEZONE" => self.timezone = Some(value.to_string()),
+                "LOCALE" => self.locale = Some(value.to_string()),
+                "HTTP_PROXY" => self.http_proxy = Some(value.to_string()),
+                "HTTPS_PROXY" => self.https_proxy = Some(value.to_string()),
+                "NO_PROXY" => self.no_proxy = Some(value.to_string()),
+                "CONSOLE_TTY" => {
+                    self.console.get_or_insert_with(Default::default).tty = Some(value.to_string());
+                }
+                "CONSOLE_SPEED" => match value.parse() {
+                    Ok(speed) => self.console.get_or_insert_with(Default::default).speed = Some(speed),
+                    Err(_) => log::debug!("Ignoring invalid console speed {:?}", value),
+                },
+                _ => {
+                    self.settings.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+
+        if let Some(directive) = r.response_type() {
+            log::debug!("Skipping unknown LOCALIZATION directive: {}", directive);
+        }
+    }
+}
+
+/// A console line.
+///
+/// Users connect to the console through `server:port`, and present
+/// the key before anything is relayed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tipline {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub key: String,
+}
+
+/// The root SSH keypair of an experiment.
+#[derive(Clone, PartialEq)]
+pub struct RootKeypair {
+    /// The PEM-encoded private key.
+    pub private_key: String,
+
+    /// The public key, as a line in `authorized_keys`.
+    pub public_key: Option<String>,
+}
+
+impl fmt::Debug for RootKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RootKeypair")
+            .field("private_key", &redact::REDACTED)
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+/// A named blob from the testbed.
+#[derive(Clone, PartialEq)]
+pub struct Blob {
+    /// Name of the blob.
+    pub name: String,
+
+    /// The contents, which may be binary.
+    pub contents: Vec<u8>,
+}
+
+impl fmt::Debug for Blob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blob")
+            .field("name", &self.name)
+            .field("size", &self.contents.len())
+            .finish()
+    }
+}
+
+/// A tarball to unpack, from `tarballs`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Tarball {
+    /// Directory to unpack into.
+    pub dir: PathBuf,
+
+    /// Path (e.g., on `/proj`) or URL of the tarball.
+    pub source: String,
+}
+
+/// An RPM to install, from `rpms`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Rpm {
+    /// Path (e.g., on `/proj`) or URL of the RPM.
+    pub source: String,
+}
+
+/// A local blockstore, from `storageconfig`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalVolume {
+    /// Index of the blockstore on the node.
+    pub index: u32,
+
+    /// Name of the blockstore (e.g., `bs1`).
+    pub name: String,
+
+    /// Requested size in MiB.
+    pub size_mib: Option<u64>,
+
+    /// Which disks the blockstore may use (e.g., `ANY`, `SYSVOL` or
+    /// `NONSYSVOL`).
+    pub bsid: String,
+
+    /// Where to mount it, if anywhere.
+    pub mount_point: Option<PathBuf>,
+
+    /// The file system to create (e.g., `ext4`), if requested.
+    pub fs_type: Option<String>,
+}
+
+/// The startup command of a node, from `startupcmd`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StartupCommand {
+    /// The command, run with `/bin/sh -c`.
+    pub command: String,
+
+    /// The login of the user to run the command as.
+    pub user: String,
+}
+
+/// The barrier synchronization server of an experiment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncServer {
+    /// Host name of the server.
+    pub server: String,
+
+    /// Whether the current node is the server.
+    pub is_server: bool,
+}
+
+/// What a node is supposed to boot, from `bootwhat`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BootInfo {
+    pub what: BootWhat,
+
+    /// Kernel command line, if any.
+    pub cmdline: Option<String>,
+}
+
+/// What to boot.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "what", rename_all = "lowercase")]
+pub enum BootWhat {
+    /// A partition of the boot disk.
+    Partition(u32),
+
+    /// The first partition with a system ID.
+    Sysid(u32),
+
+    /// A multiboot kernel (e.g., `server:/path`).
+    Multiboot(String),
+
+    /// A memory file system (e.g., to reload the disk).
+    Mfs(String),
+
+    /// Wait for the testbed to decide.
+    Wait,
+
+    /// Reboot.
+    Reboot,
+
+    /// Whatever is installed on the disk.
+    Auto,
+}
+
+impl BootWhat {
+    /// Returns whether this is an OS on the disk, as opposed to a
+    /// directive to do something else (e.g., to reload the disk).
+    pub fn is_disk(&self) -> bool {
+        matches!(self, Self::Partition(_) | Self::Sysid(_) | Self::Auto)
+    }
+}
+
+/// Current state of the system.
+///
+/// These are the states of the Emulab node state machine that the
+/// node itself reports. In configuration, they are written as
+/// reported (e.g., `TBSETUP`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum State {
+    /// The system is up.
+    #[serde(rename = "ISUP")]
+    Up,
+
+    /// The system is being set up from an MFS.
+    #[serde(rename = "MFSSETUP")]
+    Setup,
+
+    /// The system is being set up from the disk.
+    #[serde(rename = "TBSETUP")]
+    TbSetup,
+
+    /// The system is booting.
+    #[serde(rename = "BOOTING")]
+    Booting,
+
+    /// The system is (being) shut down.
+    #[serde(rename = "SHUTDOWN")]
+    Shutdown,
+
+    /// The disk is about to be reloaded from a reload MFS.
+    #[serde(rename = "RELOADSETUP")]
+    ReloadSetup,
+
+    /// The disk is being reloaded.
+    #[serde(rename = "RELOADING")]
+    Reloading,
+
+    /// The disk has been reloaded.
+    #[serde(rename = "RELOADDONE")]
+    ReloadDone,
+
+    /// The node is waiting in PXE for something to do.
+    #[serde(rename = "PXEWAIT")]
+    PxeWait,
+
+    /// The node failed to set up.
+    #[serde(rename = "TBFAILED")]
+    Failed,
+}
+
+impl AsRef<str> for State {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Up => "ISUP",
+            Self::Setup => "MFSSETUP",
+            Self::TbSetup => "TBSETUP",
+            Self::Booting => "BOOTING",
+            Self::Shutdown => "SHUTDOWN",
+            Self::ReloadSetup => "RELOADSETUP",
+            Self::Reloading => "RELOADING",
+            Self::ReloadDone => "RELOADDONE",
+            Self::PxeWait => "PXEWAIT",
+            Self::Failed => "TBFAILED",
+        }
+    }
+}
+
+/// A TMCD command.
+struct Command {
+    bytes: Vec<u8>,
+}
+
+impl Command {
+    /// Create a new command, optionally for a virtual node.
+    pub fn new(command: &str, vnode: Option<&str>) -> Self {
+        let mut bytes = format!("VERSION={} ", TMCD_VERSION).into_bytes();
+
+        if let Some(vnode) = vnode {
+            bytes.extend_from_slice(format!("VNODEID={} ", vnode).as_bytes());
+        }
+
+        bytes.extend_from_slice(command.as_bytes());
+        Self {
+            bytes,
+        }
+    }
+
+    /// Add an argument.
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.bytes.push(b' ');
+        self.bytes.extend_from_slice(arg.as_bytes());
+        self
+    }
+
+    /// Finalize the command, returning the bytes to be sent.
+    pub fn finalize(mut self) -> Vec<u8> {
+        self.bytes.push(b' ');
+        self.bytes
+    }
+}
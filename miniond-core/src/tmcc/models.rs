@@ -0,0 +1,197 @@
+//! Typed TMCD response lines.
+//!
+//! These are deserialized from parsed [`Response`](super::parser::Response)s,
+//! and can be serialized back with [`to_line`](super::to_line).
+//! Field names correspond to the upper-case keys on the wire.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::{Uid, Gid};
+
+/// An `ADDUSER` line from `accounts`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct AddUser {
+    pub login: String,
+
+    /// Password hash, or `*` without a password.
+    #[serde(default)]
+    pub pswd: Option<String>,
+
+    pub uid: Uid,
+    pub gid: Gid,
+    pub root: bool,
+    pub homedir: PathBuf,
+
+    /// Supplementary groups, comma-separated.
+    #[serde(default)]
+    pub glist: Vec<Gid>,
+
+    pub shell: String,
+    pub serial: String,
+}
+
+/// A `PUBKEY` line from `accounts`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct PubKey {
+    pub login: String,
+    pub key: String,
+}
+
+/// An `ADDGROUP` line from `accounts`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct AddGroup {
+    pub name: String,
+    pub gid: Gid,
+}
+
+/// A line from `mounts`.
+///
+/// Only `REMOTE` and `LOCAL` are sent for NFS mounts.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct MountEntry {
+    pub remote: String,
+    pub local: PathBuf,
+    pub fstype: Option<String>,
+
+    /// Comma-separated mount options.
+    pub options: Option<String>,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// The response to `status` for an allocated node.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct StatusLine {
+    /// `$project/$experiment`.
+    pub allocated: String,
+    pub nickname: String,
+}
+
+/// The response to `tiplineinfo`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct TiplineLine {
+    pub tipline: String,
+    pub server: String,
+    pub port: u16,
+    pub keylen: usize,
+    pub key: String,
+}
+
+/// A line from `tiptunnels`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct TiptunnelLine {
+    pub vnode: String,
+    pub server: String,
+    pub port: u16,
+    pub keylen: usize,
+    pub key: String,
+}
+
+/// An `INTERFACE` line from `ifconfig`.
+///
+/// Empty values are common (e.g., `IFACE=`), so optional numbers
+/// are kept as strings.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct InterfaceLine {
+    pub inet: String,
+    pub mask: String,
+    pub mac: String,
+    pub iface: Option<String>,
+    pub lan: Option<String>,
+    pub mtu: Option<String>,
+}
+
+/// A `VINTERFACE` line from `ifconfig`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct VinterfaceLine {
+    pub lan: Option<String>,
+    pub vmac: Option<String>,
+    pub inet: String,
+    pub mask: String,
+    pub vtype: String,
+    pub vtag: Option<String>,
+    pub pmac: Option<String>,
+    pub mtu: Option<String>,
+}
+
+/// The response to `bootwhat`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct BootwhatLine {
+    pub status: String,
+    #[serde(rename = "TYPE")]
+    pub kind: Option<String>,
+    pub what: Option<String>,
+    pub cmdline: Option<String>,
+}
+
+/// A line from `tarballs`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct TarballLine {
+    /// Directory to unpack into.
+    pub dir: PathBuf,
+
+    /// Path (e.g., on `/proj`) or URL of the tarball.
+    pub tarball: String,
+}
+
+/// A line from `storageconfig`.
+///
+/// Only local blockstores (`CMD=SLICE CLASS=local`) are used. Remote
+/// blockstores (e.g., iSCSI) come with other keys, which are ignored.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct StorageLine {
+    pub cmd: String,
+    pub idx: u32,
+    pub class: Option<String>,
+    pub volname: Option<String>,
+
+    /// Size in MiB.
+    pub volsize: Option<u64>,
+
+    /// Which disks the blockstore may use (e.g., `ANY` or `SYSVOL`).
+    pub bsid: Option<String>,
+
+    pub mountpoint: Option<PathBuf>,
+    pub fstype: Option<String>,
+}
+
+/// A line from `rpms`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct RpmLine {
+    /// Path (e.g., on `/proj`) or URL of the RPM.
+    pub rpm: String,
+}
+
+/// The response to `startupcmd`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct StartupLine {
+    pub cmd: String,
+
+    /// The login of the user to run the command as.
+    pub uid: String,
+}
+
+/// The response to `syncserver`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct SyncserverLine {
+    pub server: String,
+    pub isserver: bool,
+}
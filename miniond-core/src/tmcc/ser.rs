@@ -0,0 +1,430 @@
+//! Serializer for TMCD responses.
+//!
+//! This is the inverse of the [`de`](super::de) module. Structs and
+//! maps are written as canonical response lines:
+//!
+//! - Values are only quoted when they have to be (i.e., when they are
+//!   empty, contain a space, or start with a quote). Double quotes are
+//!   preferred.
+//! - Booleans are `1`/`0`, and sequences are comma-separated.
+//! - `None` values are left out.
+//!
+//! Parsing a canonical line and serializing it again yields the
+//! same line.
+
+use std::borrow::Cow;
+use std::fmt::Display;
+
+use serde::ser::{
+    self,
+    Impossible,
+    Serialize,
+    SerializeMap,
+    SerializeSeq,
+    SerializeStruct,
+    Serializer,
+};
+
+use crate::error::{Error, Result};
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::TmcdSerialize {
+            message: msg.to_string(),
+        }
+    }
+}
+
+fn error(message: impl Display) -> Error {
+    <Error as ser::Error>::custom(message)
+}
+
+/// Serialize a struct or map into a canonical response line.
+///
+/// The line starts with the `directive` (e.g., `ADDUSER`), if any.
+pub fn to_line<T: Serialize + ?Sized>(directive: Option<&str>, value: &T) -> Result<String> {
+    let mut line = String::new();
+
+    if let Some(directive) = directive {
+        check_key(directive)?;
+        line.push_str(directive);
+    }
+
+    value.serialize(LineSerializer { line: &mut line })?;
+
+    Ok(line)
+}
+
+/// Append a key-value pair to a line.
+pub(super) fn push_pair(line: &mut String, key: &str, value: &str) -> Result<()> {
+    check_key(key)?;
+
+    if !line.is_empty() {
+        line.push(' ');
+    }
+
+    line.push_str(key);
+    line.push('=');
+    line.push_str(&encode_value(value)?);
+
+    Ok(())
+}
+
+/// Ensure that a key or directive can be parsed back.
+fn check_key(key: &str) -> Result<()> {
+    let mut chars = key.chars();
+
+    let valid = chars.next().map(|c| c.is_ascii_uppercase()).unwrap_or(false)
+        && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(error(format!("invalid key {:?}", key)))
+    }
+}
+
+/// Encode a value, quoting it if needed.
+fn encode_value(value: &str) -> Result<Cow<'_, str>> {
+    if value.contains('\n') {
+        return Err(error(format!("value {:?} spans multiple lines", value)));
+    }
+
+    let bare = !value.is_empty()
+        && !value.contains(' ')
+        && !value.starts_with('"')
+        && !value.starts_with('\'');
+
+    if bare {
+        Ok(Cow::Borrowed(value))
+    } else if !value.contains('"') {
+        Ok(Cow::Owned(format!("\"{}\"", value)))
+    } else if !value.contains('\'') {
+        Ok(Cow::Owned(format!("'{}'", value)))
+    } else {
+        Err(error(format!("value {:?} contains both kinds of quotes", value)))
+    }
+}
+
+/// Serializer for a whole response line.
+struct LineSerializer<'a> {
+    line: &'a mut String,
+}
+
+impl<'a> LineSerializer<'a> {
+    fn unsupported<T>(&self) -> Result<T> {
+        Err(error("only structs and maps can be serialized as TMCD responses"))
+    }
+}
+
+macro_rules! unsupported {
+    ($($method:ident($($arg:ty),*),)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<Self::Ok> {
+                self.unsupported()
+            }
+        )*
+    };
+}
+
+impl<'a> Serializer for LineSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Impossible<(), Error>;
+    type SerializeTuple = Impossible<(), Error>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = PairSerializer<'a>;
+    type SerializeStruct = PairSerializer<'a>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    unsupported! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str),
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T) -> Result<()> {
+        self.unsupported()
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        self.unsupported()
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        self.unsupported()
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct> {
+        self.unsupported()
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant> {
+        self.unsupported()
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(PairSerializer { line: self.line, key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Ok(PairSerializer { line: self.line, key: None })
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant> {
+        self.unsupported()
+    }
+}
+
+/// Serializer for the key-value pairs of a line.
+struct PairSerializer<'a> {
+    line: &'a mut String,
+
+    /// The key of a map entry whose value is pending.
+    key: Option<String>,
+}
+
+impl<'a> PairSerializer<'a> {
+    fn push(&mut self, key: &str, value: Option<String>) -> Result<()> {
+        match value {
+            Some(value) => push_pair(self.line, key, &value),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> SerializeStruct for PairSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        let value = value.serialize(ValueSerializer)?;
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> SerializeMap for PairSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        let key = key.serialize(ValueSerializer)?
+            .ok_or_else(|| error("keys must not be empty"))?;
+
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take()
+            .ok_or_else(|| error("value serialized before key"))?;
+        let value = value.serialize(ValueSerializer)?;
+
+        self.push(&key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializer for a single value.
+///
+/// Values that should be left out (e.g., `None`) are serialized
+/// to `None`.
+struct ValueSerializer;
+
+macro_rules! serialize_to_string {
+    ($($method:ident($ty:ty),)*) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Self::Ok> {
+                Ok(Some(v.to_string()))
+            }
+        )*
+    };
+}
+
+impl Serializer for ValueSerializer {
+    type Ok = Option<String>;
+    type Error = Error;
+
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = Impossible<Option<String>, Error>;
+    type SerializeTupleStruct = Impossible<Option<String>, Error>;
+    type SerializeTupleVariant = Impossible<Option<String>, Error>;
+    type SerializeMap = Impossible<Option<String>, Error>;
+    type SerializeStruct = Impossible<Option<String>, Error>;
+    type SerializeStructVariant = Impossible<Option<String>, Error>;
+
+    serialize_to_string! {
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok> {
+        Ok(Some(if v { "1" } else { "0" }.to_string()))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok> {
+        Err(error("bytes cannot be serialized as TMCD values"))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Self::Ok> {
+        Ok(Some(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T) -> Result<Self::Ok> {
+        Err(error("enums with data cannot be serialized as TMCD values"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Ok(ListSerializer { items: Vec::new() })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(error("tuples cannot be serialized as TMCD values"))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct> {
+        Err(error("tuples cannot be serialized as TMCD values"))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant> {
+        Err(error("enums with data cannot be serialized as TMCD values"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(error("nested maps cannot be serialized as TMCD values"))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(error("nested structs cannot be serialized as TMCD values"))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant> {
+        Err(error("enums with data cannot be serialized as TMCD values"))
+    }
+}
+
+/// Serializer for a comma-separated list.
+struct ListSerializer {
+    items: Vec<String>,
+}
+
+impl SerializeSeq for ListSerializer {
+    type Ok = Option<String>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        if let Some(item) = value.serialize(ValueSerializer)? {
+            if item.is_empty() || item.contains(',') {
+                return Err(error(format!("list item {:?} cannot be represented", item)));
+            }
+
+            self.items.push(item);
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        Ok(Some(self.items.join(",")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::models::AddUser;
+    use super::super::parser::Response;
+
+    #[test]
+    fn test_round_trip() {
+        let adduser = AddUser {
+            login: "zhaofeng".to_string(),
+            pswd: None,
+            uid: 20001,
+            gid: 6418,
+            root: true,
+            homedir: "/users/zhaofeng".into(),
+            glist: vec![6419, 6420],
+            shell: "bash".to_string(),
+            serial: "".to_string(),
+        };
+
+        let line = to_line(Some("ADDUSER"), &adduser).expect("Failed to serialize");
+        assert_eq!(r#"ADDUSER LOGIN=zhaofeng UID=20001 GID=6418 ROOT=1 HOMEDIR=/users/zhaofeng GLIST=6419,6420 SHELL=bash SERIAL="""#, line);
+
+        let parsed: AddUser = Response::parse(&line).unwrap()
+            .deserialize().expect("Failed to deserialize");
+        assert_eq!(adduser, parsed);
+    }
+
+    #[test]
+    fn test_quoting() {
+        assert_eq!("a\"b", encode_value("a\"b").unwrap());
+        assert_eq!("\"a b\"", encode_value("a b").unwrap());
+        assert_eq!("'\"a b\"'", encode_value("\"a b\"").unwrap());
+        assert!(encode_value("'a' \"b\"").is_err());
+        assert!(encode_value("a\nb").is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! Connections to TMCD.
+//!
+//! Besides plain TCP connections to the boss, the client can record
+//! all traffic to a directory, and later replay the recorded responses
+//! without a testbed. This is useful for reproducing parse failures
+//! that only happen with the data of a particular cluster.
+//!
+//! Each exchange is recorded as a pair of files:
+//!
+//! - `<timestamp>-<seq>-<command>.request`
+//! - `<timestamp>-<seq>-<command>.response`
+//!
+//! During replay, the responses to each command are served in the
+//! order they were recorded, and the last one is repeated once all
+//! were served. Other files in the directory are used as fixtures,
+//! serving the response to the command they are named after (e.g.,
+//! `accounts.txt` for `accounts`).
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "tls")]
+use std::sync::atomic::AtomicBool;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Sleep;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+
+use crate::error::{Error, Result};
+
+/// A bidirectional byte stream.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Where connections go.
+pub enum Transport {
+    /// Connect to the boss over TCP.
+    Tcp(SocketAddr),
+
+    /// Connect to the boss over TLS.
+    #[cfg(feature = "tls")]
+    Tls(Tls),
+
+    /// Serve recorded responses.
+    Replay(Replay),
+}
+
+/// TLS connections to the boss.
+#[cfg(feature = "tls")]
+pub struct Tls {
+    pub addr: SocketAddr,
+    pub connector: TlsConnector,
+
+    /// Plaintext address to fall back to if TLS fails, if any.
+    pub fallback: Option<SocketAddr>,
+
+    /// Whether we fell back to plaintext.
+    pub fell_back: AtomicBool,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(feature = "tls")]
+            Self::Tls(tls) if tls.fell_back.load(Ordering::Relaxed) => write!(f, "{}", tls.fallback.unwrap()),
+            #[cfg(feature = "tls")]
+            Self::Tls(tls) => write!(f, "{} (TLS)", tls.addr),
+            Self::Replay(_) => write!(f, "(replay)"),
+        }
+    }
+}
+
+impl Transport {
+    /// Open a connection.
+    pub async fn connect(&self) -> Result<Box<dyn Stream>> {
+        match self {
+            Self::Tcp(addr) => connect_tcp(*addr).await,
+            #[cfg(feature = "tls")]
+            Self::Tls(tls) => tls.connect().await,
+            Self::Replay(replay) => {
+                Ok(Box::new(ReplayStream {
+                    replay: replay.clone(),
+                    request: Vec::new(),
+                    response: None,
+                    pos: 0,
+                }))
+            }
+        }
+    }
+
+    /// Open a connection to another server, following a redirect.
+    ///
+    /// During replay, the next recorded response is served instead.
+    pub async fn redirect(&self, addr: SocketAddr) -> Result<Box<dyn Stream>> {
+        match self {
+            Self::Tcp(_) => connect_tcp(addr).await,
+            #[cfg(feature = "tls")]
+            Self::Tls(tls) if tls.fell_back.load(Ordering::Relaxed) => connect_tcp(addr).await,
+            #[cfg(feature = "tls")]
+            Self::Tls(tls) => super::tls::connect(&tls.connector, addr).await,
+            Self::Replay(_) => self.connect().await,
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Tls {
+    /// Open a connection, falling back to plaintext if enabled.
+    ///
+    /// Once we fell back, we stick with plaintext.
+    async fn connect(&self) -> Result<Box<dyn Stream>> {
+        let fallback = match self.fallback {
+            Some(fallback) if self.fell_back.load(Ordering::Relaxed) => return connect_tcp(fallback).await,
+            fallback => fallback,
+        };
+
+        match super::tls::connect(&self.connector, self.addr).await {
+            Ok(stream) => Ok(stream),
+            Err(e) => match fallback {
+                Some(fallback) => {
+                    log::warn!("{} - Falling back to plaintext TMCD at {}", e, fallback);
+                    self.fell_back.store(true, Ordering::Relaxed);
+                    connect_tcp(fallback).await
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// A stream failing reads that make no progress for too long.
+pub struct TimeoutStream {
+    inner: Box<dyn Stream>,
+    timeout: Duration,
+
+    /// When the pending read times out, if any.
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl TimeoutStream {
+    pub fn new(inner: Box<dyn Stream>, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: None,
+        }
+    }
+}
+
+impl AsyncRead for TimeoutStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if let Poll::Ready(res) = Pin::new(&mut self.inner).poll_read(cx, buf) {
+            self.deadline = None;
+            return Poll::Ready(res);
+        }
+
+        let timeout = self.timeout;
+        let deadline = self.deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.deadline = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no data received for {:?}", timeout),
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for TimeoutStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn connect_tcp(addr: SocketAddr) -> Result<Box<dyn Stream>> {
+    let stream = TcpStream::connect(addr).await
+        .and_then(|stream| stream.set_nodelay(true).map(|_| stream))
+        .map_err(|source| Error::TmcdConnect { addr, source })?;
+
+    Ok(Box::new(stream))
+}
+
+/// Returns the command of a raw request.
+///
+/// Options like `VERSION=44` precede the command.
+fn command_name(request: &[u8]) -> String {
+    String::from_utf8_lossy(request)
+        .split_whitespace()
+        .find(|token| !token.contains('='))
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+/// Records TMCD traffic to a directory.
+#[derive(Clone)]
+pub struct Recorder {
+    dir: PathBuf,
+    seq: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        log::info!("Recording TMCD traffic to {:?}", dir);
+
+        Ok(Self {
+            dir,
+            seq: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Wrap a connection to record its traffic.
+    pub fn wrap(&self, stream: Box<dyn Stream>) -> Box<dyn Stream> {
+        Box::new(RecordingStream {
+            inner: stream,
+            recorder: self.clone(),
+            request: Vec::new(),
+            response: Vec::new(),
+        })
+    }
+
+    fn save(&self, request: &[u8], response: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+
+        let stem = format!("{}-{:04}-{}", timestamp, seq, command_name(request));
+
+        fs::write(self.dir.join(format!("{}.request", stem)), request)?;
+        fs::write(self.dir.join(format!("{}.response", stem)), response)?;
+
+        Ok(())
+    }
+}
+
+/// A connection whose traffic is recorded once it's closed.
+struct RecordingStream {
+    inner: Box<dyn Stream>,
+    recorder: Recorder,
+    request: Vec<u8>,
+    response: Vec<u8>,
+}
+
+impl AsyncRead for RecordingStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = res {
+            let new = buf.filled()[filled..].to_vec();
+            self.response.extend_from_slice(&new);
+        }
+
+        res
+    }
+}
+
+impl AsyncWrite for RecordingStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(len)) = res {
+            self.request.extend_from_slice(&buf[..len]);
+        }
+
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Drop for RecordingStream {
+    fn drop(&mut self) {
+        // The recordings are small and this is a debugging aid, so
+        // we don't bother moving the writes off the runtime
+        if let Err(e) = self.recorder.save(&self.request, &self.response) {
+            log::warn!("Failed to record TMCD traffic: {}", e);
+        }
+    }
+}
+
+/// Recorded responses to be replayed.
+#[derive(Clone)]
+pub struct Replay {
+    responses: Arc<Mutex<HashMap<String, VecDeque<PathBuf>>>>,
+}
+
+impl Replay {
+    /// Load recordings or fixtures from a directory.
+    pub fn new(dir: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+
+        // Timestamps and sequence numbers sort in recording order
+        files.sort();
+
+        let mut responses: HashMap<String, VecDeque<PathBuf>> = HashMap::new();
+        for path in files {
+            let stem = path.file_stem().unwrap().to_string_lossy().to_string();
+            let is_response = path.extension().map(|e| e == "response").unwrap_or(false);
+
+            let command = match stem.splitn(3, '-').collect::<Vec<_>>()[..] {
+                [timestamp, seq, command] if timestamp.parse::<u128>().is_ok() && seq.parse::<usize>().is_ok() => {
+                    if !is_response {
+                        continue;
+                    }
+                    command.to_string()
+                }
+                _ => stem,
+            };
+
+            responses.entry(command).or_default().push_back(path);
+        }
+
+        log::info!("Replaying TMCD traffic from {:?} ({} commands)", dir, responses.len());
+
+        Ok(Self {
+            responses: Arc::new(Mutex::new(responses)),
+        })
+    }
+
+    /// Returns the next response to a command.
+    fn next(&self, command: &str) -> io::Result<Vec<u8>> {
+        let mut responses = self.responses.lock().unwrap();
+
+        let path = match responses.get_mut(command) {
+            Some(queue) if queue.len() > 1 => queue.pop_front(),
+            Some(queue) => queue.front().cloned(),
+            None => None,
+        };
+
+        match path {
+            Some(path) => fs::read(path),
+            None => {
+                // Unknown commands get an empty response from TMCD
+                log::warn!("No recorded response to {}", command);
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+/// A connection serving a recorded response.
+struct ReplayStream {
+    replay: Replay,
+    request: Vec<u8>,
+    response: Option<Vec<u8>>,
+    pos: usize,
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        // The request is complete once the client starts reading
+        if self.response.is_none() {
+            let command = command_name(&self.request);
+            self.response = Some(self.replay.next(&command)?);
+        }
+
+        let this = &mut *self;
+        let response = this.response.as_ref().unwrap();
+        let len = buf.remaining().min(response.len() - this.pos);
+
+        buf.put_slice(&response[this.pos..this.pos + len]);
+        this.pos += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReplayStream {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.request.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,25 +1,34 @@
-//! Account management models.
+//! Account management.
+//!
+//! The models come from `miniond_core`. Here we apply them to the system.
 
 use std::collections::HashMap;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
-use tokio::fs::{File, OpenOptions, create_dir_all};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use serde::Deserialize;
+use snafu::ResultExt;
+use tokio::fs::{File, create_dir_all, metadata, read_to_string, remove_file, rename, set_permissions};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
-use nix::unistd::{self, chown};
+use users::os::unix::UserExt;
 use users::{
     get_user_by_name,
     get_user_by_uid,
+    get_group_by_gid,
     get_group_by_name,
 };
 
-use crate::error::{Error, Result};
+use crate::blocking;
+use crate::command::{command_output, run_command, run_command_with_input};
+use crate::error::{Error, FileSnafu, Result};
+use crate::passwd::Database;
+use crate::plan::{self, Action};
+use crate::tmcc::RootKeypair;
 
-/// Type of a UID.
-pub type Uid = u16;
-
-/// Type of a GID.
-pub type Gid = u16;
+pub use miniond_core::account::{Accounts, Gid, Group, Uid, User};
 
 /// The fallback shell.
 ///
@@ -30,290 +39,1289 @@ const FALLBACK_SHELL: &str = "/bin/sh";
 /// Path to the list of allowed shells.
 const SHELLS_FILE: &str = "/etc/shells";
 
-/// Account information returned by TMCD.
-#[derive(Debug, Clone)]
-pub struct Accounts {
-    /// Users to be configured.
-    pub users: HashMap<String, User>,
+/// Permissions of `authorized_keys` files.
+const AUTHORIZED_KEYS_MODE: u32 = 0o600;
+
+/// Permissions of the sudoers drop-in, as `visudo` expects.
+const SUDOERS_MODE: u32 = 0o440;
+
+/// Directory of the account databases for the `files` backend.
+pub(crate) const ETC_DIR: &str = "/etc";
+
+/// Skeleton directory of new home directories with the `files`
+/// backend, unless configured.
+const DEFAULT_SKELETON: &str = "/etc/skel";
+
+/// Umask of new home directories with the `files` backend, unless
+/// configured. This is the default of `useradd`.
+const DEFAULT_UMASK: u32 = 0o022;
+
+/// How accounts are applied to the system.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum Backend {
+    /// Run `useradd` and friends from shadow-utils.
+    #[default]
+    #[serde(rename = "commands")]
+    Commands,
+
+    /// Edit `/etc/passwd`, `/etc/shadow` and `/etc/group` directly,
+    /// for systems without shadow-utils.
+    #[serde(rename = "files")]
+    Files,
+}
+
+/// Common shell paths to probe for when there is no `/etc/shells`.
+const COMMON_SHELLS: &[&str] = &[
+    "/bin/sh",
+    "/bin/bash",
+    "/usr/bin/bash",
+    "/usr/local/bin/bash",
+    "/bin/dash",
+    "/usr/bin/dash",
+    "/bin/zsh",
+    "/usr/bin/zsh",
+    "/usr/local/bin/zsh",
+    "/bin/tcsh",
+    "/usr/bin/tcsh",
+    "/bin/csh",
+    "/usr/bin/csh",
+    "/bin/ksh",
+    "/usr/bin/ksh",
+    "/usr/bin/fish",
+];
+
+/// Login shell resolution.
+///
+/// The testbed names login shells (e.g., `bash`), which we map to the
+/// paths listed in `/etc/shells`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ShellConfig {
+    /// Paths of specific shells, overriding `/etc/shells`.
+    map: HashMap<String, PathBuf>,
+
+    /// Directories to prefer when `/etc/shells` lists a shell more
+    /// than once, in order.
+    ///
+    /// Otherwise, the first entry wins.
+    #[serde(rename = "prefer-dirs")]
+    prefer_dirs: Vec<PathBuf>,
+
+    /// Shell of users whose shell is not installed.
+    fallback: PathBuf,
 
-    /// Groups to be configured.
-    pub groups: HashMap<String, Group>,
+    /// More shell paths to probe for when there is no `/etc/shells`,
+    /// in addition to common ones.
+    probe: Vec<PathBuf>,
 }
 
-impl Accounts {
-    pub fn new() -> Self {
+impl Default for ShellConfig {
+    fn default() -> Self {
         Self {
-            users: HashMap::new(),
-            groups: HashMap::new(),
+            map: HashMap::new(),
+            prefer_dirs: vec![PathBuf::from("/bin"), PathBuf::from("/usr/bin")],
+            fallback: PathBuf::from(FALLBACK_SHELL),
+            probe: Vec::new(),
+        }
+    }
+}
+
+/// A quota applied to each user on a local file system.
+///
+/// Shared scratch space on nodes can otherwise be exhausted by a
+/// single user. Limits of 0 mean no limit.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuotaConfig {
+    /// Mount point of the file system, which must have quotas enabled.
+    filesystem: PathBuf,
+
+    /// Soft limit on disk space, in KiB.
+    #[serde(rename = "block-soft", default)]
+    block_soft: u64,
+
+    /// Hard limit on disk space, in KiB.
+    #[serde(rename = "block-hard", default)]
+    block_hard: u64,
+
+    /// Soft limit on the number of files.
+    #[serde(rename = "inode-soft", default)]
+    inode_soft: u64,
+
+    /// Hard limit on the number of files.
+    #[serde(rename = "inode-hard", default)]
+    inode_hard: u64,
+
+    /// Program that sets the quota.
+    #[serde(default)]
+    tool: QuotaTool,
+}
+
+/// A program that sets quotas.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub enum QuotaTool {
+    /// `setquota` from quota-tools, for most file systems.
+    #[default]
+    #[serde(rename = "setquota")]
+    Setquota,
+
+    /// `xfs_quota` from xfsprogs, for XFS with project quotas or
+    /// without quota-tools.
+    #[serde(rename = "xfs_quota")]
+    XfsQuota,
+}
+
+impl QuotaConfig {
+    /// Returns the command that applies the quota to a user.
+    fn command(&self, login: &str) -> Command {
+        match self.tool {
+            QuotaTool::Setquota => {
+                let mut setquota = Command::new("setquota");
+                setquota
+                    .args(["-u", login])
+                    .arg(self.block_soft.to_string())
+                    .arg(self.block_hard.to_string())
+                    .arg(self.inode_soft.to_string())
+                    .arg(self.inode_hard.to_string())
+                    .arg(&self.filesystem);
+
+                setquota
+            }
+            QuotaTool::XfsQuota => {
+                let mut xfs_quota = Command::new("xfs_quota");
+                xfs_quota
+                    .arg("-x")
+                    .arg("-c")
+                    .arg(format!("limit -u bsoft={}k bhard={}k isoft={} ihard={} {}",
+                        self.block_soft, self.block_hard, self.inode_soft, self.inode_hard, login))
+                    .arg(&self.filesystem);
+
+                xfs_quota
+            }
+        }
+    }
+
+    /// Returns the commands that put a home directory in a project
+    /// and limit the project.
+    ///
+    /// Files created in the directory later inherit the project.
+    fn project_commands(&self, project: u32, home: &Path) -> Vec<Command> {
+        match self.tool {
+            QuotaTool::Setquota => {
+                // ext4 with the `project` feature
+                let mut chattr = Command::new("chattr");
+                chattr
+                    .args(["-R", "+P", "-p", &project.to_string()])
+                    .arg(home);
+
+                let mut setquota = Command::new("setquota");
+                setquota
+                    .args(["-P", &project.to_string()])
+                    .arg(self.block_soft.to_string())
+                    .arg(self.block_hard.to_string())
+                    .arg(self.inode_soft.to_string())
+                    .arg(self.inode_hard.to_string())
+                    .arg(&self.filesystem);
+
+                vec![chattr, setquota]
+            }
+            QuotaTool::XfsQuota => {
+                let mut setup = Command::new("xfs_quota");
+                setup
+                    .arg("-x")
+                    .arg("-c")
+                    .arg(format!("project -s -p {} {}", home.display(), project))
+                    .arg(&self.filesystem);
+
+                let mut limit = Command::new("xfs_quota");
+                limit
+                    .arg("-x")
+                    .arg("-c")
+                    .arg(format!("limit -p bsoft={}k bhard={}k isoft={} ihard={} {}",
+                        self.block_soft, self.block_hard, self.inode_soft, self.inode_hard, project))
+                    .arg(&self.filesystem);
+
+                vec![setup, limit]
+            }
         }
     }
 }
 
-/// A user account.
-#[derive(Debug, Clone)]
-pub struct User {
-    /// UNIX login.
-    login: String,
+/// Home directory creation and permissions.
+///
+/// By default, home directories are left as `useradd` creates them.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HomeConfig {
+    /// Skeleton directory copied into new home directories, instead
+    /// of the system default (usually `/etc/skel`).
+    skeleton: Option<PathBuf>,
+
+    /// Umask used to create new home directories (e.g., `0o077`).
+    umask: Option<u32>,
+
+    /// Project quota on new home directories, with the UID of the
+    /// user as the project ID.
+    ///
+    /// Unlike `quotas`, this limits everything under the home
+    /// directory, whoever owns it.
+    quota: Option<QuotaConfig>,
+
+    /// Mode to enforce on home directories (e.g., `0o700`, or `0o750`
+    /// to let the project group in).
+    mode: Option<u32>,
+
+    /// Whether to give the primary (project) group of users read
+    /// access to their home directories with ACLs, including default
+    /// ACLs for new files.
+    #[serde(rename = "project-acl")]
+    project_acl: bool,
+
+    /// Whether to recursively fix the ownership of home directories
+    /// owned by another UID or GID (e.g., after a user was recreated).
+    #[serde(rename = "fix-ownership")]
+    fix_ownership: bool,
+}
 
-    /// UID.
-    uid: Uid,
+/// Options prepended to the SSH keys of users in `authorized_keys`.
+///
+/// Sites can constrain testbed-provisioned access on sensitive nodes
+/// (e.g., with `restrict` or `from="10.0.0.0/8"`). See `sshd(8)` for
+/// the available options.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct KeyOptionsConfig {
+    /// Options for the keys of users without root privileges.
+    #[serde(rename = "non-root")]
+    non_root: Option<String>,
+
+    /// Options for the keys of specific users, by login.
+    ///
+    /// These take precedence over `non-root`.
+    users: HashMap<String, String>,
+}
 
-    /// Primary group ID.
-    gid: Gid,
+impl KeyOptionsConfig {
+    /// Returns the options for the keys of a user, if any.
+    fn get(&self, user: &User) -> Option<&str> {
+        match self.users.get(user.login()) {
+            Some(options) => Some(options.as_str()),
+            None if !user.is_root() => self.non_root.as_deref(),
+            None => None,
+        }
+        .map(str::trim)
+        .filter(|options| !options.is_empty())
+    }
+}
 
-    /// Whether the user has root access.
-    root: bool,
+/// Apply a user account to the system.
+///
+/// The user account will be created or modified as needed.
+/// User creation is complicated to get right, so we just run
+/// the `useradd` / `usermod` commands in the PATH. With the `files`
+/// backend, the databases in `/etc` are edited directly instead.
+///
+/// Users are added to their supplementary groups from `GLIST`. Groups
+/// they are already in are kept, since they may be local to the node.
+///
+/// ## Resources
+///
+/// The shadow-utils and FreeBSD implementations of `useradd` and `usermod`
+/// accept slightly different parameters. Here we use the common
+/// parameters supported by both implementations.
+///
+/// - [shadow-utils useradd](https://www.mankier.com/8/useradd)
+/// - [FreeBSD
+///   useradd](https://www.freebsd.org/cgi/man.cgi?query=useradd&apropos=0&sektion=8&manpath=CentOS+6.0&arch=default&format=html)
+pub async fn apply_user(user: &User, system: &SystemConfiguration) -> Result<()> {
+    let shell: &Path = match system.shells.get(user.login_shell()) {
+        Some(path) => path,
+        None => {
+            log::warn!("{}'s preferred login shell \"{}\" is not installed. Using {:?} instead..."
+                       , user.login(), user.login_shell(), system.fallback_shell);
+
+            &system.fallback_shell
+        }
+    };
 
-    /// Home directory.
-    home: PathBuf,
+    let (existing, supplementary) = lookup_user(user, system.backend).await?;
 
-    /// SSH public keys.
-    ssh_keys: Vec<String>,
+    match existing {
+        Some(Existing { uid, shell: current_shell, groups }) => {
+            // Already exists
+            if uid != u32::from(user.uid()) {
+                return Err(Error::UidChangeUnsupported);
+            }
 
-    /// Login shell.
-    shell: String,
+            let mut new_groups = groups.iter()
+                .filter(|gn| user.is_root() || *gn != &system.admin_group)
+                .cloned()
+                .collect::<Vec<String>>();
 
-    /// Opaque serial number.
-    ///
-    /// This indicates when the account information is changed.
-    #[allow(dead_code)]
-    serial: String,
+            if user.is_root() && !new_groups.contains(&system.admin_group) {
+                new_groups.push(system.admin_group.clone());
+            }
+
+            for group in &supplementary {
+                if !new_groups.contains(group) {
+                    new_groups.push(group.clone());
+                }
+            }
+
+            // Only run usermod if something actually changed
+            let mut changes = Vec::new();
+
+            if current_shell != shell {
+                changes.push(format!("shell {:?} -> {:?}", current_shell, shell));
+            }
+
+            let added: Vec<&str> = new_groups.iter()
+                .filter(|g| !groups.contains(g))
+                .map(|g| g.as_str())
+                .collect();
+            if !added.is_empty() {
+                changes.push(format!("+groups {}", added.join(",")));
+            }
+
+            let removed: Vec<&str> = groups.iter()
+                .filter(|g| !new_groups.contains(g))
+                .map(|g| g.as_str())
+                .collect();
+            if !removed.is_empty() {
+                changes.push(format!("-groups {}", removed.join(",")));
+            }
+
+            if changes.is_empty() {
+                log::debug!("User {} is up to date", user.login());
+            } else {
+                log::info!("Updating user {} with UID {} ({})...", user.login(), user.uid(), changes.join("; "));
+
+                if plan::is_dry_run() {
+                    plan::record(Action::ModifyUser {
+                        login: user.login().to_string(),
+                        shell: shell.to_path_buf(),
+                        groups: new_groups,
+                    });
+
+                    apply_home(user, &system.home).await?;
+                    apply_quotas(user, &system.quotas).await?;
+                    apply_password(user, system).await?;
+                    return apply_authorized_keys(user, system).await;
+                }
+
+                match system.backend {
+                    Backend::Commands => {
+                        let mut usermod = Command::new("usermod");
+                        usermod
+                            .arg("-s").arg(shell)
+                            .args(["-G", &new_groups.join(",")])
+                            .arg(user.login());
+
+                        run_command(&mut usermod).await
+                            .map_err(|reason| Error::UserUpdate { login: user.login().to_string(), reason })?;
+                    }
+                    Backend::Files => {
+                        let login = user.login().to_string();
+                        let shell = shell.to_path_buf();
+
+                        Database::edit(&system.database_dir, move |database| {
+                            database.set_shell(&login, &shell)
+                                .map_err(|reason| Error::UserUpdate { login: login.clone(), reason })?;
+                            database.set_groups(&login, &new_groups);
+                            Ok(())
+                        }).await?;
+                    }
+                }
+            }
+
+            apply_home(user, &system.home).await?;
+            apply_quotas(user, &system.quotas).await?;
+            apply_password(user, system).await?;
+            apply_authorized_keys(user, system).await?;
+
+            Ok(())
+        }
+        None => {
+            // New user
+            if let Some(existing_login) = login_by_uid(user.uid().into(), system.backend).await? {
+                return Err(Error::DuplicateUid {
+                    login: user.login().to_string(),
+                    uid: user.uid(),
+                    existing_login,
+                });
+            }
+
+            let mut groups = supplementary;
+            if user.is_root() && !groups.contains(&system.admin_group) {
+                groups.push(system.admin_group.clone());
+            }
+
+            log::info!("Creating user {} with UID {}...", user.login(), user.uid());
+
+            if plan::is_dry_run() {
+                plan::record(Action::CreateUser {
+                    login: user.login().to_string(),
+                    uid: user.uid(),
+                    gid: user.gid(),
+                    home: user.home_dir().to_path_buf(),
+                    shell: shell.to_path_buf(),
+                    groups,
+                });
+
+                apply_home(user, &system.home).await?;
+                apply_home_quota(user, &system.home).await?;
+                apply_quotas(user, &system.quotas).await?;
+                apply_password(user, system).await?;
+                return apply_authorized_keys(user, system).await;
+            }
+
+            match system.backend {
+                Backend::Commands => {
+                    let mut useradd = Command::new("useradd");
+
+                    useradd
+                        .arg("--badname")
+                        .arg("-md").arg(user.home_dir())
+                        .args(["-u", &user.uid().to_string()])
+                        .args(["-g", &user.gid().to_string()])
+                        .arg("-s").arg(shell)
+                        .arg("-N") // --no-user-group
+                        .arg(user.login());
+
+                    if !groups.is_empty() {
+                        useradd.args(["-G", &groups.join(",")]);
+                    }
+
+                    if let Some(skeleton) = &system.home.skeleton {
+                        useradd.arg("-k").arg(skeleton);
+                    }
+
+                    if let Some(umask) = system.home.umask {
+                        useradd.args(["-K", &format!("UMASK={:04o}", umask)]);
+                    }
+
+                    run_command(&mut useradd).await
+                        .map_err(|reason| Error::UserCreation { login: user.login().to_string(), reason })?;
+                }
+                Backend::Files => {
+                    let login = user.login().to_string();
+                    let (uid, gid) = (user.uid().into(), user.gid().into());
+                    let home = user.home_dir().to_path_buf();
+                    let shell = shell.to_path_buf();
+
+                    Database::edit(&system.database_dir, move |database| {
+                        database.add_user(&login, uid, gid, &home, &shell)
+                            .map_err(|reason| Error::UserCreation { login: login.clone(), reason })?;
+                        database.set_groups(&login, &groups);
+                        Ok(())
+                    }).await?;
+
+                    create_home(user, &system.home).await?;
+                }
+            }
+
+            apply_home(user, &system.home).await?;
+            apply_home_quota(user, &system.home).await?;
+            apply_quotas(user, &system.quotas).await?;
+            apply_password(user, system).await?;
+            apply_authorized_keys(user, system).await?;
+
+            Ok(())
+        }
+    }
 }
 
-impl User {
-    /// Create a new user account.
-    ///
-    /// This does not actually create the account in the system.
-    pub fn new(login: String, uid: Uid, gid: Gid, serial: String) -> Self {
-        let home = format!("/users/{}", &login).into();
+/// A user that exists on the system.
+struct Existing {
+    uid: u32,
+    shell: PathBuf,
 
-        Self {
-            login,
-            uid,
-            gid,
-            root: false,
-            home,
-            ssh_keys: Vec::new(),
-            shell: "bash".to_string(),
-            serial,
+    /// Names of the groups of the user.
+    groups: Vec<String>,
+}
+
+/// Look up a user, and the names of their supplementary groups from
+/// the testbed.
+///
+/// Groups that can't be resolved (e.g., not created in dry-run) are
+/// named by GID, which `useradd` and `usermod` accept.
+async fn lookup_user(user: &User, backend: Backend) -> Result<(Option<Existing>, Vec<String>)> {
+    let login = user.login().to_string();
+    let gids: Vec<u32> = user.supplementary_groups().iter()
+        .filter(|gid| **gid != user.gid())
+        .map(|gid| u32::from(*gid))
+        .collect();
+
+    match backend {
+        Backend::Commands => {
+            // NSS lookups may block (e.g., LDAP)
+            blocking::run("getpwnam", move || {
+                let existing = get_user_by_name(&login).map(|passwd| Existing {
+                    uid: passwd.uid(),
+                    shell: passwd.shell().to_path_buf(),
+                    groups: passwd.groups()
+                        .expect("User somehow disappeared")
+                        .iter()
+                        .map(|g| g.name().to_str().unwrap().to_string())
+                        .collect(),
+                });
+
+                let supplementary = gids.iter()
+                    .map(|gid| match get_group_by_gid(*gid) {
+                        Some(group) => group.name().to_string_lossy().to_string(),
+                        None => gid.to_string(),
+                    })
+                    .collect();
+
+                Ok((existing, supplementary))
+            }).await
+        }
+        Backend::Files => {
+            let database = Database::read(Path::new(ETC_DIR)).await?;
+
+            let existing = database.user(&login).map(|passwd| Existing {
+                uid: passwd.uid,
+                shell: passwd.shell,
+                groups: database.groups_of(&login),
+            });
+
+            let supplementary = gids.iter()
+                .map(|gid| database.group_name(*gid).unwrap_or_else(|| gid.to_string()))
+                .collect();
+
+            Ok((existing, supplementary))
         }
     }
+}
 
-    /// Add an SSH key.
-    ///
-    /// A `public_key` is a line in `authorized_keys`.
-    pub fn add_ssh_key(&mut self, public_key: String) -> &mut Self {
-        self.ssh_keys.push(public_key);
-        self
+/// Returns the login of the user with a UID, if any.
+async fn login_by_uid(uid: u32, backend: Backend) -> Result<Option<String>> {
+    match backend {
+        Backend::Commands => {
+            blocking::run("getpwuid", move || {
+                Ok(get_user_by_uid(uid).map(|existing| existing.name().to_string_lossy().to_string()))
+            }).await
+        }
+        Backend::Files => {
+            Ok(Database::read(Path::new(ETC_DIR)).await?.login_by_uid(uid))
+        }
     }
+}
 
-    /// Set whether the user has root privileges.
-    pub fn root(&mut self, root: bool) -> &mut Self {
-        self.root = root;
-        self
+/// Returns which of the logins exist on the system.
+pub async fn existing_logins(logins: Vec<String>, system: &SystemConfiguration) -> Result<Vec<String>> {
+    match system.backend {
+        Backend::Commands => {
+            // NSS lookups may block (e.g., LDAP)
+            blocking::run("getpwnam", move || {
+                Ok(logins.into_iter()
+                    .filter(|login| get_user_by_name(login).is_some())
+                    .collect())
+            }).await
+        }
+        Backend::Files => {
+            let database = Database::read(Path::new(ETC_DIR)).await?;
+            Ok(logins.into_iter()
+                .filter(|login| database.user(login).is_some())
+                .collect())
+        }
     }
+}
+
+/// Create the home directory of a new user, like `useradd -m`.
+///
+/// The skeleton directory is copied in, and everything is owned by
+/// the user. Existing home directories (e.g., on NFS) are left alone.
+async fn create_home(user: &User, config: &HomeConfig) -> Result<()> {
+    let home = user.home_dir().to_path_buf();
+    let skeleton = config.skeleton.clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SKELETON));
+    let mode = 0o777 & !config.umask.unwrap_or(DEFAULT_UMASK);
+    let (uid, gid) = (user.uid().into(), user.gid().into());
+
+    blocking::run("create-home", move || {
+        if let Some(parent) = home.parent() {
+            std::fs::create_dir_all(parent)
+                .context(FileSnafu { action: "create", path: parent })?;
+        }
+
+        match std::fs::create_dir(&home) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(()),
+            Err(e) => return Err(e).context(FileSnafu { action: "create", path: &home }),
+        }
+
+        if skeleton.is_dir() {
+            copy_tree(&skeleton, &home, uid, gid)?;
+        }
 
-    /// Set the user's home.
-    pub fn home(&mut self, home: PathBuf) -> &mut Self {
-        self.home = home;
-        self
+        std::os::unix::fs::chown(&home, Some(uid), Some(gid))
+            .context(FileSnafu { action: "change the owner of", path: &home })?;
+        std::fs::set_permissions(&home, Permissions::from_mode(mode))
+            .context(FileSnafu { action: "chmod", path: &home })
+    }).await
+}
+
+/// Copy the contents of a directory, owned by `uid` and `gid`.
+///
+/// Special files are skipped.
+fn copy_tree(from: &Path, to: &Path, uid: u32, gid: u32) -> Result<()> {
+    let entries = std::fs::read_dir(from)
+        .context(FileSnafu { action: "read", path: from })?;
+
+    for entry in entries {
+        let entry = entry.context(FileSnafu { action: "read", path: from })?;
+        let source = entry.path();
+        let target = to.join(entry.file_name());
+
+        let metadata = std::fs::symlink_metadata(&source)
+            .context(FileSnafu { action: "stat", path: &source })?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            std::fs::create_dir(&target)
+                .and_then(|_| std::fs::set_permissions(&target, metadata.permissions()))
+                .context(FileSnafu { action: "create", path: &target })?;
+
+            copy_tree(&source, &target, uid, gid)?;
+        } else if file_type.is_symlink() {
+            std::fs::read_link(&source)
+                .and_then(|link| std::os::unix::fs::symlink(link, &target))
+                .context(FileSnafu { action: "create", path: &target })?;
+        } else if file_type.is_file() {
+            std::fs::copy(&source, &target)
+                .context(FileSnafu { action: "create", path: &target })?;
+        } else {
+            continue;
+        }
+
+        std::os::unix::fs::lchown(&target, Some(uid), Some(gid))
+            .context(FileSnafu { action: "change the owner of", path: &target })?;
     }
 
-    /// Set the user's login shell.
-    pub fn shell(&mut self, shell: String) -> &mut Self {
-        self.shell = shell;
-        self
+    Ok(())
+}
+
+/// Apply the configured permissions to the home directory of a user.
+///
+/// Home directories that don't exist yet (e.g., in dry-run) are
+/// skipped, and so are all of them if nothing is configured.
+async fn apply_home(user: &User, config: &HomeConfig) -> Result<()> {
+    let home = user.home_dir();
+    let uid = u32::from(user.uid());
+    let gid = u32::from(user.gid());
+
+    if uid == 0 || (!config.fix_ownership && config.mode.is_none() && !config.project_acl) {
+        return Ok(());
     }
 
-    /// Apply the configuration to the system.
-    ///
-    /// The user account will be created or modified as needed.
-    /// User creation is complicated to get right, so we just run
-    /// the `useradd` / `usermod` commands in the PATH.
-    ///
-    /// ## Resources
-    ///
-    /// The shadow-utils and FreeBSD implementations of `useradd` and `usermod`
-    /// accept slightly different parameters. Here we use the common
-    /// parameters supported by both implementations.
-    ///
-    /// - [shadow-utils useradd](https://www.mankier.com/8/useradd)
-    /// - [FreeBSD
-    /// useradd](https://www.freebsd.org/cgi/man.cgi?query=useradd&apropos=0&sektion=8&manpath=CentOS+6.0&arch=default&format=html)
-    pub async fn apply(&self, system: &SystemConfiguration) -> Result<()> {
-        let shell: &Path = match system.shells.get(&self.shell) {
-            Some(path) => path,
-            None => {
-                log::warn!("{}'s preferred login shell \"{}\" is not installed. Using {} instead..."
-                           , self.login, self.shell, FALLBACK_SHELL);
+    let existing = match metadata(home).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(FileSnafu { action: "stat", path: home }),
+    };
+
+    if config.fix_ownership && (existing.uid() != uid || existing.gid() != gid) {
+        log::warn!("Home directory {:?} of {} is owned by {}:{} - Fixing ownership...",
+            home, user.login(), existing.uid(), existing.gid());
+
+        if plan::is_dry_run() {
+            plan::record(Action::SetOwner { path: home.to_path_buf(), uid: user.uid(), gid: user.gid() });
+        } else {
+            let mut chown = Command::new("chown");
+            chown
+                .arg("-R")
+                .arg(format!("{}:{}", uid, gid))
+                .arg(home);
+
+            run_command(&mut chown).await
+                .map_err(|reason| Error::HomeSetup { login: user.login().to_string(), reason })?;
+        }
+    }
+
+    if let Some(mode) = config.mode {
+        if existing.mode() & 0o7777 != mode {
+            log::debug!("Setting mode of {:?} to {:o}...", home, mode);
 
-                Path::new(FALLBACK_SHELL)
+            if plan::is_dry_run() {
+                plan::record(Action::SetMode { path: home.to_path_buf(), mode });
+            } else {
+                set_permissions(home, Permissions::from_mode(mode)).await
+                    .context(FileSnafu { action: "chmod", path: home })?;
             }
-        };
+        }
+    }
 
-        match get_user_by_name(&self.login) {
-            Some(user) => {
-                // Already exists
-                let new_groups = user.groups()
-                    .expect("User somehow disappeared")
-                    .iter()
-                    .map(|g| g.name().to_str().unwrap().to_string())
-                    .filter(|gn| self.root || gn != &system.admin_group)
-                    .collect::<Vec<String>>()
-                    .join(",");
-
-                if user.uid() != self.uid.into() {
-                    return Err(Error::UidChangeUnsupported);
-                }
+    if config.project_acl {
+        let acl = project_acl(gid);
+
+        if plan::is_dry_run() {
+            plan::record(Action::SetAcl { path: home.to_path_buf(), acl });
+        } else {
+            let mut setfacl = Command::new("setfacl");
+            setfacl.arg("-m").arg(acl).arg(home);
+
+            run_command(&mut setfacl).await
+                .map_err(|reason| Error::HomeSetup { login: user.login().to_string(), reason })?;
+        }
+    }
 
-                log::info!("Updating user {} with UID {}...", self.login, self.uid);
+    Ok(())
+}
 
-                let status = Command::new("usermod")
-                    .arg("-s").arg(shell)
-                    .args(&["-G", &new_groups])
-                    .arg(&self.login)
-                    .status().await?;
+/// Returns the ACL giving a group read access to a home directory,
+/// including files created later, in `setfacl` syntax.
+fn project_acl(gid: u32) -> String {
+    format!("g:{}:rX,d:g:{}:rX", gid, gid)
+}
 
-                if !status.success() {
-                    return Err(Error::UserUpdate);
-                }
+/// Apply the configured project quota to the new home directory of a
+/// user.
+///
+/// This is only done when the user is created, since putting an
+/// existing home directory in a project walks all of its files.
+async fn apply_home_quota(user: &User, config: &HomeConfig) -> Result<()> {
+    let quota = match &config.quota {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    let project = u32::from(user.uid());
+    if project == 0 {
+        return Ok(());
+    }
 
-                self.apply_authorized_keys().await?;
+    let home = user.home_dir();
+    log::debug!("Setting project quota of {:?} on {:?}...", home, quota.filesystem);
+
+    if plan::is_dry_run() {
+        plan::record(Action::SetProjectQuota {
+            path: home.to_path_buf(),
+            project,
+            filesystem: quota.filesystem.clone(),
+            block_soft: quota.block_soft,
+            block_hard: quota.block_hard,
+            inode_soft: quota.inode_soft,
+            inode_hard: quota.inode_hard,
+        });
+
+        return Ok(());
+    }
 
-                Ok(())
-            }
-            None => {
-                // New user
-                if let Some(existing) = get_user_by_uid(self.uid.into()) {
-                    return Err(Error::DuplicateUid {
-                        login: self.login.clone(),
-                        uid: self.uid,
-                        existing_login: existing.name().to_string_lossy().to_string(),
-                    });
-                }
+    for mut command in quota.project_commands(project, home) {
+        run_command(&mut command).await
+            .map_err(|reason| Error::QuotaSetting {
+                login: user.login().to_string(),
+                filesystem: quota.filesystem.clone(),
+                reason,
+            })?;
+    }
 
-                let mut useradd = Command::new("useradd");
+    Ok(())
+}
 
-                useradd
-                    .arg("--badname")
-                    .arg("-md").arg(&self.home)
-                    .args(&["-u", &self.uid.to_string()])
-                    .args(&["-g", &self.gid.to_string()])
-                    .arg("-s").arg(shell)
-                    .arg("-N") // --no-user-group
-                    .arg(&self.login);
+/// Set the password of a user from the testbed.
+///
+/// Users without a password are left alone, so passwords set on the
+/// node are kept.
+async fn apply_password(user: &User, system: &SystemConfiguration) -> Result<()> {
+    if !system.apply_passwords || user.uid() == 0 {
+        return Ok(());
+    }
 
-                if self.root {
-                    useradd.args(&["-G", &system.admin_group]);
-                }
+    let hash = match user.password_hash() {
+        Some(hash) => hash,
+        None => return Ok(()),
+    };
 
-                log::info!("Creating user {} with UID {}...", self.login, self.uid);
+    log::info!("Setting the password of user {}...", user.login());
 
-                let status = useradd
-                    .status().await?;
+    if plan::is_dry_run() {
+        plan::record(Action::SetPassword {
+            login: user.login().to_string(),
+        });
 
-                if !status.success() {
-                    return Err(Error::UserCreation);
-                }
+        return Ok(());
+    }
 
-                self.apply_authorized_keys().await?;
+    match system.backend {
+        Backend::Commands => {
+            // The hash goes through stdin, so it doesn't show up in ps
+            let input = format!("{}:{}\n", user.login(), hash.as_str());
+            let mut chpasswd = Command::new("chpasswd");
+            chpasswd.arg("-e");
 
-                Ok(())
-            }
+            run_command_with_input(&mut chpasswd, input.into_bytes()).await
+                .map_err(|reason| Error::UserUpdate { login: user.login().to_string(), reason })
+        }
+        Backend::Files => {
+            let login = user.login().to_string();
+            let hash = hash.as_str().to_string();
+
+            Database::edit(&system.database_dir, move |database| {
+                database.set_password(&login, &hash)
+                    .map_err(|reason| Error::UserUpdate { login: login.clone(), reason })
+            }).await
         }
     }
+}
 
-    /// Apply the SSH public key configuration to the system.
-    async fn apply_authorized_keys(&self) -> Result<()> {
-        let authorized_keys = self.home.join(".ssh/authorized_keys");
-        let ssh_dir = self.home.join(".ssh");
+/// Apply the configured file system quotas to a user.
+///
+/// Quotas are set every time the user is applied, so changed limits
+/// take effect. UID 0 is never limited.
+async fn apply_quotas(user: &User, quotas: &[QuotaConfig]) -> Result<()> {
+    if u32::from(user.uid()) == 0 {
+        return Ok(());
+    }
+
+    for quota in quotas {
+        log::debug!("Setting quota of {} on {:?}...", user.login(), quota.filesystem);
+
+        if plan::is_dry_run() {
+            plan::record(Action::SetQuota {
+                login: user.login().to_string(),
+                filesystem: quota.filesystem.clone(),
+                block_soft: quota.block_soft,
+                block_hard: quota.block_hard,
+                inode_soft: quota.inode_soft,
+                inode_hard: quota.inode_hard,
+            });
 
-        create_dir_all(&ssh_dir).await?;
+            continue;
+        }
 
-        log::info!("Updating SSH keys for user {}...", self.login);
+        run_command(&mut quota.command(user.login())).await
+            .map_err(|reason| Error::QuotaSetting {
+                login: user.login().to_string(),
+                filesystem: quota.filesystem.clone(),
+                reason,
+            })?;
+    }
 
-        let mut file = OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&authorized_keys)
-            .await?;
+    Ok(())
+}
 
-        file.write_all("# This file was automatically generated by miniond\n".as_bytes()).await?;
-        file.write_all("# Please add your keys using the testbed web interface.\n\n".as_bytes()).await?;
+/// Returns the contents of the `authorized_keys` file of a user.
+fn authorized_keys_contents(user: &User, key_options: &KeyOptionsConfig) -> String {
+    let mut contents = String::new();
+    contents.push_str("# This file was automatically generated by miniond\n");
+    contents.push_str("# Please add your keys using the testbed web interface.\n\n");
 
-        for key in &self.ssh_keys {
-            file.write_all(key.as_bytes()).await?;
-            file.write_all("\n".as_bytes()).await?;
+    let options = key_options.get(user);
+
+    for key in user.ssh_keys() {
+        if let Some(options) = options {
+            contents.push_str(options);
+            contents.push(' ');
         }
 
-        drop(file);
+        contents.push_str(key);
+        contents.push('\n');
+    }
+
+    contents
+}
+
+/// Check whether a user we applied drifted from what the testbed wants.
+///
+/// Returns what changed, if anything.
+pub async fn check_user(user: &User, system: &SystemConfiguration) -> Result<Option<String>> {
+    if existing_logins(vec![user.login().to_string()], system).await?.is_empty() {
+        return Ok(Some(format!("user {} was deleted", user.login())));
+    }
+
+    let authorized_keys = user.home_dir().join(".ssh/authorized_keys");
+    match read_to_string(&authorized_keys).await {
+        Ok(existing) if existing == authorized_keys_contents(user, &system.key_options) => Ok(None),
+        _ => Ok(Some(format!("SSH keys of user {} were changed", user.login()))),
+    }
+}
+
+async fn apply_authorized_keys(user: &User, system: &SystemConfiguration) -> Result<()> {
+    let authorized_keys = user.home_dir().join(".ssh/authorized_keys");
+    let ssh_dir = user.home_dir().join(".ssh");
 
-        {
-            let uid = unistd::Uid::from_raw(self.uid.into());
-            let gid = unistd::Gid::from_raw(self.gid.into());
-            chown(&authorized_keys, Some(uid), Some(gid))?;
-            chown(&ssh_dir, Some(uid), Some(gid))?;
+    let contents = authorized_keys_contents(user, &system.key_options);
+
+    if let Ok(existing) = read_to_string(&authorized_keys).await {
+        if existing == contents {
+            log::debug!("SSH keys for user {} are up to date", user.login());
+            return Ok(());
         }
+    }
+
+    log::info!("Updating SSH keys for user {}...", user.login());
 
-        Ok(())
+    if plan::is_dry_run() {
+        plan::record(Action::WriteFile {
+            path: authorized_keys,
+            contents,
+        });
+
+        return Ok(());
     }
+
+    create_dir_all(&ssh_dir).await
+        .context(FileSnafu { action: "create", path: &ssh_dir })?;
+
+    blocking::write_private(authorized_keys, contents.into_bytes(), AUTHORIZED_KEYS_MODE, user.uid().into(), user.gid().into()).await?;
+    blocking::chown(vec![ssh_dir], user.uid().into(), user.gid().into()).await?;
+
+    Ok(())
+}
+
+/// Install an SSH keypair as the default identity of a user.
+///
+/// The private key is written to `~/.ssh/id_rsa`, and the public key
+/// to `~/.ssh/id_rsa.pub`.
+pub async fn install_keypair(user: &User, keypair: &RootKeypair) -> Result<()> {
+    let ssh_dir = user.home_dir().join(".ssh");
+    install_identity(user.login(), &ssh_dir, user.uid().into(), user.gid().into(), keypair).await
 }
 
-/// A group account.
-#[derive(Debug, Clone)]
-pub struct Group {
-    /// Name.
-    name: String,
+/// Install the root keypair of the experiment into root's `~/.ssh`.
+///
+/// This works without account management. The public key is also
+/// authorized, so root on the other nodes can log in.
+pub async fn install_root_keypair(keypair: &RootKeypair) -> Result<()> {
+    let home = blocking::run("getpwuid", || {
+        get_user_by_uid(0)
+            .map(|root| root.home_dir().to_path_buf())
+            .ok_or(Error::UnknownOwner { name: "root".to_string() })
+    }).await?;
+    let ssh_dir = home.join(".ssh");
+
+    if !plan::is_dry_run() {
+        create_dir_all(&ssh_dir).await
+            .context(FileSnafu { action: "create", path: &ssh_dir })?;
+        set_permissions(&ssh_dir, Permissions::from_mode(0o700)).await
+            .context(FileSnafu { action: "chmod", path: &ssh_dir })?;
+    }
+
+    install_identity("root", &ssh_dir, 0, 0, keypair).await?;
+
+    let public_key = match &keypair.public_key {
+        Some(public_key) => public_key.clone(),
+        None => match read_to_string(ssh_dir.join("id_rsa.pub")).await {
+            Ok(public_key) => public_key.trim().to_string(),
+            Err(_) => return Ok(()),
+        },
+    };
+
+    let authorized_keys = ssh_dir.join("authorized_keys");
+    let mut contents = read_to_string(&authorized_keys).await.unwrap_or_default();
+    if contents.lines().any(|line| line.trim() == public_key) {
+        return Ok(());
+    }
+
+    log::info!("Authorizing the root keypair in {:?}...", authorized_keys);
 
-    /// GID.
-    gid: Gid,
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&public_key);
+    contents.push('\n');
+
+    if plan::is_dry_run() {
+        plan::record(Action::WriteFile {
+            path: authorized_keys,
+            contents,
+        });
+
+        return Ok(());
+    }
+
+    blocking::write_private(authorized_keys, contents.into_bytes(), AUTHORIZED_KEYS_MODE, 0, 0).await
 }
 
-impl Group {
-    /// Create a new group.
-    ///
-    /// This does not actually create the group in the system.
-    pub fn new(name: String, gid: Gid) -> Self {
-        Self {
-            name,
-            gid,
+/// Install an SSH keypair into an `.ssh` directory.
+async fn install_identity(login: &str, ssh_dir: &Path, uid: u32, gid: u32, keypair: &RootKeypair) -> Result<()> {
+    let private_path = ssh_dir.join("id_rsa");
+    let public_path = ssh_dir.join("id_rsa.pub");
+
+    // Path, contents, mode, and whether the contents are secret
+    let mut files = vec![(private_path, keypair.private_key.clone(), 0o600, true)];
+    if let Some(public_key) = &keypair.public_key {
+        files.push((public_path, format!("{}\n", public_key), 0o644, false));
+    }
+
+    for (path, contents, mode, secret) in files {
+        if let Ok(existing) = read_to_string(&path).await {
+            if existing == contents {
+                continue;
+            }
         }
+
+        log::info!("Installing {:?} for user {}...", path, login);
+
+        if plan::is_dry_run() {
+            let contents = if secret { String::new() } else { contents };
+            plan::record(Action::WriteFile {
+                path,
+                contents,
+            });
+            continue;
+        }
+
+        create_dir_all(ssh_dir).await?;
+        blocking::write_private(path, contents.into_bytes(), mode, uid, gid).await?;
     }
 
-    /// Apply the configuration to the system.
-    ///
-    /// We currently do not allow changes to a group.
-    pub async fn apply(&self) -> Result<()> {
-        match get_group_by_name(&self.name) {
-            Some(group) => {
-                // Existing group
-                if group.gid() != self.gid.into() {
-                    return Err(Error::GidChangeUnsupported);
-                }
+    if keypair.public_key.is_none() && !plan::is_dry_run() {
+        derive_public_key(login, ssh_dir, uid, gid).await;
+    }
+
+    Ok(())
+}
+
+/// Derive the public key of an installed private key that came
+/// without one.
+///
+/// Keys encrypted with a passphrase are left as they are, since only
+/// the user can unlock them.
+async fn derive_public_key(login: &str, ssh_dir: &Path, uid: u32, gid: u32) {
+    let public_path = ssh_dir.join("id_rsa.pub");
+    if metadata(&public_path).await.is_ok() {
+        return;
+    }
+
+    // An empty passphrase keeps ssh-keygen from prompting
+    let mut ssh_keygen = Command::new("ssh-keygen");
+    ssh_keygen
+        .args(["-y", "-P", ""])
+        .arg("-f").arg(ssh_dir.join("id_rsa"));
+
+    let public_key = match command_output(&mut ssh_keygen).await {
+        Ok(public_key) => public_key,
+        Err(reason) => {
+            log::warn!("Not deriving the public key of {}'s keypair (encrypted with a passphrase?): {}", login, reason);
+            return;
+        }
+    };
 
-                Ok(())
+    log::info!("Installing {:?} for user {}...", public_path, login);
+
+    if let Err(e) = blocking::write_private(public_path, public_key.into_bytes(), 0o644, uid, gid).await {
+        log::warn!("{}", e);
+    }
+}
+
+/// Apply a group account to the system.
+///
+/// We currently do not allow changes to a group.
+pub async fn apply_group(group: &Group, system: &SystemConfiguration) -> Result<()> {
+    let name = group.name().to_string();
+    let existing = match system.backend {
+        Backend::Commands => {
+            blocking::run("getgrnam", move || Ok(get_group_by_name(&name).map(|existing| existing.gid()))).await?
+        }
+        Backend::Files => Database::read(Path::new(ETC_DIR)).await?.gid(&name),
+    };
+
+    match existing {
+        Some(gid) => {
+            // Existing group
+            if gid != u32::from(group.gid()) {
+                return Err(Error::GidChangeUnsupported);
             }
-            None => {
-                // New group
-                log::info!("Creating group {} with GID {}", self.name, self.gid);
 
-                let status = Command::new("groupadd")
-                    .args(&["-g", &self.gid.to_string()])
-                    .arg(&self.name)
-                    .status().await?;
+            Ok(())
+        }
+        None => {
+            // New group
+            log::info!("Creating group {} with GID {}", group.name(), group.gid());
+
+            if plan::is_dry_run() {
+                plan::record(Action::CreateGroup {
+                    name: group.name().to_string(),
+                    gid: group.gid(),
+                });
 
-                if !status.success() {
-                    return Err(Error::GroupCreation);
-                }
+                return Ok(());
+            }
 
-                Ok(())
+            match system.backend {
+                Backend::Commands => {
+                    let mut groupadd = Command::new("groupadd");
+                    groupadd
+                        .args(["-g", &group.gid().to_string()])
+                        .arg(group.name());
+
+                    run_command(&mut groupadd).await
+                        .map_err(|reason| Error::GroupCreation { name: group.name().to_string(), reason })?;
+                }
+                Backend::Files => {
+                    let name = group.name().to_string();
+                    let gid = group.gid().into();
+
+                    Database::edit(&system.database_dir, move |database| {
+                        database.add_group(&name, gid)
+                            .map_err(|reason| Error::GroupCreation { name: name.clone(), reason })
+                    }).await?;
+                }
             }
+
+            Ok(())
         }
     }
 }
 
+/// Remove a user account from the system.
+///
+/// Unless asked, the home directory is left alone, since it's usually
+/// shared over NFS.
+pub async fn remove_user(login: &str, remove_home: bool, system: &SystemConfiguration) -> Result<()> {
+    log::info!("Removing user {}...", login);
+
+    if plan::is_dry_run() {
+        plan::record(Action::RemoveUser {
+            login: login.to_string(),
+            home: remove_home,
+        });
+
+        return Ok(());
+    }
+
+    if system.backend == Backend::Files {
+        let home = {
+            let login = login.to_string();
+            Database::edit(&system.database_dir, move |database| {
+                let home = database.home(&login);
+                database.remove_user(&login)
+                    .map_err(|reason| Error::UserDeletion { login: login.clone(), reason })?;
+                Ok(home)
+            }).await?
+        };
+
+        if let (true, Some(home)) = (remove_home, home) {
+            blocking::run("remove-home", move || match std::fs::remove_dir_all(&home) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context(FileSnafu { action: "remove", path: &home }),
+                _ => Ok(()),
+            }).await?;
+        }
+
+        return Ok(());
+    }
+
+    run_command(&mut userdel(login, remove_home)).await
+        .map_err(|reason| Error::UserDeletion { login: login.to_string(), reason })?;
+
+    Ok(())
+}
+
+/// Returns the command that removes a user, and their home directory
+/// if asked.
+fn userdel(login: &str, remove_home: bool) -> Command {
+    let mut userdel = Command::new("userdel");
+    if remove_home {
+        userdel.arg("--remove");
+    }
+
+    userdel.arg(login);
+    userdel
+}
+
+/// Remove a group account from the system.
+pub async fn remove_group(name: &str, system: &SystemConfiguration) -> Result<()> {
+    log::info!("Removing group {}...", name);
+
+    if plan::is_dry_run() {
+        plan::record(Action::RemoveGroup {
+            name: name.to_string(),
+        });
+
+        return Ok(());
+    }
+
+    match system.backend {
+        Backend::Commands => {
+            run_command(Command::new("groupdel").arg(name)).await
+                .map_err(|reason| Error::GroupDeletion { name: name.to_string(), reason })?;
+        }
+        Backend::Files => {
+            let name = name.to_string();
+            Database::edit(&system.database_dir, move |database| {
+                database.remove_group(&name)
+                    .map_err(|reason| Error::GroupDeletion { name: name.clone(), reason })
+            }).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Grant passwordless sudo to users in a sudoers drop-in.
+///
+/// The drop-in is validated with `visudo -c` before it replaces the
+/// old one, since a broken file under `/etc/sudoers.d` breaks sudo for
+/// everyone. Without logins, the drop-in is removed.
+pub async fn apply_sudoers(path: &Path, logins: &[&str]) -> Result<()> {
+    let existing = match read_to_string(path).await {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context(FileSnafu { action: "read", path }),
+    };
+
+    if logins.is_empty() {
+        if existing.is_none() {
+            return Ok(());
+        }
+
+        log::info!("Removing sudoers drop-in {:?}...", path);
+
+        if plan::is_dry_run() {
+            plan::record(Action::RemoveFile { path: path.to_path_buf() });
+            return Ok(());
+        }
+
+        return remove_file(path).await
+            .context(FileSnafu { action: "remove", path });
+    }
+
+    let contents = sudoers_contents(logins);
+    if existing.as_deref() == Some(contents.as_str()) {
+        log::debug!("Sudoers drop-in {:?} is up to date", path);
+        return Ok(());
+    }
+
+    log::info!("Granting sudo to {} users in {:?}...", logins.len(), path);
+
+    if plan::is_dry_run() {
+        plan::record(Action::WriteFile { path: path.to_path_buf(), contents });
+        return Ok(());
+    }
+
+    // sudo ignores files with a dot in their name, so the candidate
+    // is never picked up before it's validated
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    let candidate = path.with_file_name(name);
+
+    blocking::write_private(candidate.clone(), contents.into_bytes(), SUDOERS_MODE, 0, 0).await?;
+
+    let mut visudo = Command::new("visudo");
+    visudo.arg("-c").arg("-f").arg(&candidate);
+
+    if let Err(reason) = run_command(&mut visudo).await {
+        let _ = remove_file(&candidate).await;
+        return Err(Error::Sudoers { path: path.to_path_buf(), reason });
+    }
+
+    rename(&candidate, path).await
+        .context(FileSnafu { action: "replace", path })
+}
+
+/// Returns the contents of the sudoers drop-in.
+fn sudoers_contents(logins: &[&str]) -> String {
+    let mut contents = String::from("# generated by miniond\n");
+
+    for login in logins {
+        contents.push_str(&format!("{} ALL=(ALL) NOPASSWD: ALL\n", login));
+    }
+
+    contents
+}
+
 /// System account configurations.
 #[derive(Debug)]
 pub struct SystemConfiguration {
@@ -324,55 +1332,329 @@ pub struct SystemConfiguration {
     /// paths.
     shells: HashMap<String, PathBuf>,
 
+    /// Shell of users whose shell is not installed.
+    fallback_shell: PathBuf,
+
     /// Group name for admins.
     ///
     /// Normally this would be "wheel" or "sudo".
     admin_group: String,
-}
 
-impl SystemConfiguration {
-    pub async fn new(admin_group: Option<String>) -> Result<Self> {
-        let file = File::open(SHELLS_FILE).await?;
-        let reader = BufReader::new(file);
-        let mut lines = reader.lines();
+    /// Quotas to apply to users.
+    quotas: Vec<QuotaConfig>,
 
-        let mut shells = HashMap::new();
+    /// Permissions of home directories.
+    home: HomeConfig,
 
-        while let Some(line) = lines.next_line().await? {
-            let path = Path::new(&line);
-            let name = path.file_name()
-                .ok_or(Error::InvalidShellsFile)?
-                .to_str()
-                .ok_or(Error::InvalidShellsFile)?;
+    /// Options of SSH keys in `authorized_keys`.
+    key_options: KeyOptionsConfig,
 
-            if !shells.contains_key(name) {
-                shells.insert(name.to_string(), path.to_path_buf());
+    /// Whether to set passwords from the testbed.
+    apply_passwords: bool,
+
+    /// How accounts are applied.
+    backend: Backend,
+
+    /// Writable directory of the account databases for the `files`
+    /// backend, which holds the lock and temporary files of edits.
+    database_dir: PathBuf,
+}
+
+impl SystemConfiguration {
+    pub async fn new(admin_group: Option<String>, shells: &ShellConfig, quotas: &[QuotaConfig], home: &HomeConfig, key_options: &KeyOptionsConfig, apply_passwords: bool, backend: Backend) -> Result<Self> {
+        let entries = match File::open(SHELLS_FILE).await {
+            Ok(file) => read_shells(file).await?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::warn!("{} does not exist - Looking for shells in common places", SHELLS_FILE);
+                probe_shells(&shells.probe).await
+            }
+            Err(e) => {
+                return Err(e).context(FileSnafu { action: "open", path: SHELLS_FILE });
             }
+        };
+
+        if entries.is_empty() && shells.map.is_empty() {
+            return Err(Error::NoLoginShells);
         }
 
+        let fallback_shell = shells.fallback.clone();
+        let shells = resolve_shells(&entries, shells)?;
+
         let admin_group = match admin_group {
             None => {
-                let mut admin_group = "root".to_string();
-
-                let group_candidates = vec![
-                    "wheel",
-                    "sudo",
-                ];
-
-                for group in group_candidates {
-                    if get_group_by_name(group).is_some() {
-                        admin_group = group.to_string();
+                let database = match backend {
+                    Backend::Commands => None,
+                    Backend::Files => Some(Database::read(Path::new(ETC_DIR)).await?),
+                };
+
+                // NSS lookups may block, so they shouldn't hold up
+                // boss discovery which runs concurrently
+                blocking::run("admin-group", move || {
+                    let mut admin_group = "root".to_string();
+
+                    let group_candidates = vec![
+                        "wheel",
+                        "sudo",
+                    ];
+
+                    for group in group_candidates {
+                        let exists = match &database {
+                            Some(database) => database.gid(group).is_some(),
+                            None => get_group_by_name(group).is_some(),
+                        };
+
+                        if exists {
+                            admin_group = group.to_string();
+                        }
                     }
-                }
 
-                admin_group
+                    Ok(admin_group)
+                }).await?
             }
             Some(g) => g,
         };
 
         Ok(Self {
             shells,
+            fallback_shell,
             admin_group,
+            quotas: quotas.to_vec(),
+            home: home.clone(),
+            key_options: key_options.clone(),
+            apply_passwords,
+            backend,
+            database_dir: PathBuf::from(ETC_DIR),
         })
     }
+
+    /// Edit the account databases of the `files` backend in another
+    /// directory (e.g., a writable overlay of a read-only `/etc`).
+    pub fn set_database_dir(&mut self, dir: PathBuf) {
+        self.database_dir = dir;
+    }
+}
+
+/// Read the shells listed in `/etc/shells`.
+async fn read_shells(file: File) -> Result<Vec<PathBuf>> {
+    let mut lines = BufReader::new(file).lines();
+    let mut entries = Vec::new();
+
+    while let Some(line) = lines.next_line().await
+        .context(FileSnafu { action: "read", path: SHELLS_FILE })?
+    {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        entries.push(PathBuf::from(line));
+    }
+
+    Ok(entries)
+}
+
+/// Returns the common and configured shell paths that exist.
+async fn probe_shells(extra: &[PathBuf]) -> Vec<PathBuf> {
+    let candidates = COMMON_SHELLS.iter()
+        .map(PathBuf::from)
+        .chain(extra.iter().cloned());
+
+    let mut entries = Vec::new();
+    for path in candidates {
+        if metadata(&path).await.map(|m| m.is_file()).unwrap_or(false) {
+            log::debug!("Found shell {:?}", path);
+            entries.push(path);
+        }
+    }
+
+    entries
+}
+
+/// Map shell names to paths.
+///
+/// Explicitly mapped shells are taken as is. Otherwise, the entry
+/// in the first preferred directory wins, then the first entry.
+fn resolve_shells(entries: &[PathBuf], config: &ShellConfig) -> Result<HashMap<String, PathBuf>> {
+    let rank = |path: &Path| {
+        config.prefer_dirs.iter()
+            .position(|dir| path.parent() == Some(dir.as_path()))
+            .unwrap_or(config.prefer_dirs.len())
+    };
+
+    let mut shells: HashMap<String, PathBuf> = HashMap::new();
+
+    for path in entries {
+        let name = path.file_name()
+            .ok_or(Error::InvalidShellsFile)?
+            .to_str()
+            .ok_or(Error::InvalidShellsFile)?;
+
+        match shells.get(name) {
+            Some(existing) if rank(existing) <= rank(path) => {}
+            _ => {
+                shells.insert(name.to_string(), path.to_path_buf());
+            }
+        }
+    }
+
+    for (name, path) in &config.map {
+        shells.insert(name.clone(), path.clone());
+    }
+
+    Ok(shells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_shells() {
+        let entries: Vec<PathBuf> = ["/usr/local/bin/bash", "/bin/sh", "/usr/bin/bash", "/bin/bash", "/usr/local/bin/fish", "/usr/bin/fish"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        let mut config = ShellConfig::default();
+        config.map.insert("tcsh".to_string(), PathBuf::from("/opt/tcsh/bin/tcsh"));
+
+        let shells = resolve_shells(&entries, &config).unwrap();
+        assert_eq!(Path::new("/bin/bash"), shells["bash"]);
+        assert_eq!(Path::new("/bin/sh"), shells["sh"]);
+        assert_eq!(Path::new("/usr/bin/fish"), shells["fish"]);
+        assert_eq!(Path::new("/opt/tcsh/bin/tcsh"), shells["tcsh"]);
+
+        // Without preferences, the first entry wins
+        config.prefer_dirs.clear();
+        let shells = resolve_shells(&entries, &config).unwrap();
+        assert_eq!(Path::new("/usr/local/bin/bash"), shells["bash"]);
+    }
+
+    #[tokio::test]
+    async fn test_read_shells() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shells");
+        std::fs::write(&path, "# /etc/shells: valid login shells\n\n/bin/sh\n  /usr/bin/zsh  \n").unwrap();
+
+        let entries = read_shells(File::open(&path).await.unwrap()).await.unwrap();
+        assert_eq!(vec![PathBuf::from("/bin/sh"), PathBuf::from("/usr/bin/zsh")], entries);
+    }
+
+    #[tokio::test]
+    async fn test_probe_shells() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell = dir.path().join("fish");
+        std::fs::write(&shell, "").unwrap();
+
+        let missing = dir.path().join("nosuchsh");
+        let entries = probe_shells(&[shell.clone(), missing.clone(), dir.path().to_path_buf()]).await;
+
+        assert!(entries.contains(&shell));
+        assert!(!entries.contains(&missing));
+        assert!(!entries.iter().any(|path| path == dir.path()));
+
+        // Only what exists is found
+        assert!(entries.iter().all(|path| path.is_file()));
+    }
+
+    /// Returns a command as it would be run.
+    fn render(command: &Command) -> Vec<String> {
+        let command = command.as_std();
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_quota_command() {
+        let quota = QuotaConfig {
+            filesystem: PathBuf::from("/scratch"),
+            block_soft: 1024,
+            block_hard: 2048,
+            inode_soft: 0,
+            inode_hard: 1000,
+            tool: QuotaTool::Setquota,
+        };
+
+        assert_eq!(
+            vec!["setquota", "-u", "alice", "1024", "2048", "0", "1000", "/scratch"],
+            render(&quota.command("alice")),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("alice");
+        std::fs::create_dir(&home).unwrap();
+        std::fs::set_permissions(&home, Permissions::from_mode(0o755)).unwrap();
+
+        let mut alice = User::new("alice".to_string(), 20001, 6000, "1".to_string());
+        alice.home(home.clone());
+
+        // Left as it is by default
+        apply_home(&alice, &HomeConfig::default()).await.unwrap();
+        assert_eq!(0o755, std::fs::metadata(&home).unwrap().mode() & 0o7777);
+
+        // Not even looked at by default
+        let mut carol = alice.clone();
+        carol.home(PathBuf::from("/dev/null/carol"));
+        apply_home(&carol, &HomeConfig::default()).await.unwrap();
+
+        let config = HomeConfig {
+            mode: Some(0o750),
+            ..HomeConfig::default()
+        };
+        apply_home(&alice, &config).await.unwrap();
+        assert_eq!(0o750, std::fs::metadata(&home).unwrap().mode() & 0o7777);
+
+        // Homes that don't exist yet are skipped
+        alice.home(dir.path().join("bob"));
+        apply_home(&alice, &config).await.unwrap();
+
+        assert_eq!("g:6000:rX,d:g:6000:rX", project_acl(6000));
+    }
+
+    #[test]
+    fn test_userdel() {
+        assert_eq!(vec!["userdel", "alice"], render(&userdel("alice", false)));
+        assert_eq!(vec!["userdel", "--remove", "alice"], render(&userdel("alice", true)));
+    }
+
+    #[test]
+    fn test_key_options() {
+        let mut alice = User::new("alice".to_string(), 20001, 6000, "1".to_string());
+        alice.add_ssh_key("ssh-ed25519 AAAA alice@laptop".to_string());
+
+        let mut root = User::new("bob".to_string(), 20002, 6000, "1".to_string());
+        root.root(true);
+        root.add_ssh_key("ssh-ed25519 BBBB bob@laptop".to_string());
+
+        let mut config = KeyOptionsConfig::default();
+        assert!(authorized_keys_contents(&alice, &config).ends_with("\nssh-ed25519 AAAA alice@laptop\n"));
+
+        config.non_root = Some("restrict".to_string());
+        assert!(authorized_keys_contents(&alice, &config).ends_with("\nrestrict ssh-ed25519 AAAA alice@laptop\n"));
+        assert!(authorized_keys_contents(&root, &config).ends_with("\nssh-ed25519 BBBB bob@laptop\n"));
+
+        config.users.insert("bob".to_string(), "from=\"10.0.0.0/8\"".to_string());
+        assert!(authorized_keys_contents(&root, &config).ends_with("\nfrom=\"10.0.0.0/8\" ssh-ed25519 BBBB bob@laptop\n"));
+
+        // Blank options are left out, so they exempt a user
+        config.users.insert("alice".to_string(), "  ".to_string());
+        assert!(authorized_keys_contents(&alice, &config).ends_with("\nssh-ed25519 AAAA alice@laptop\n"));
+
+        config.non_root = Some(" restrict,pty ".to_string());
+        config.users.clear();
+        assert!(authorized_keys_contents(&alice, &config).ends_with("\nrestrict,pty ssh-ed25519 AAAA alice@laptop\n"));
+    }
+
+    #[test]
+    fn test_sudoers_contents() {
+        assert_eq!(
+            "# generated by miniond\nalice ALL=(ALL) NOPASSWD: ALL\nbob ALL=(ALL) NOPASSWD: ALL\n",
+            sudoers_contents(&["alice", "bob"]),
+        );
+    }
 }
@@ -0,0 +1,229 @@
+//! AppArmor awareness.
+//!
+//! Some distributions ship AppArmor profiles for `sshd` and the
+//! shadow-utils binaries that only allow access to home directories
+//! under `/home`. Testbed users live under `/users`, so a confined
+//! `sshd` silently refuses to read their `authorized_keys`.
+//!
+//! We detect such profiles and log actionable diagnostics. Optionally,
+//! we can append rules for miniond-managed paths to the profiles'
+//! `local/` include files and reload them.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use snafu::ResultExt;
+use tokio::fs;
+use tokio::process::Command;
+use which::which;
+
+use crate::command::run_command;
+use crate::config::Config;
+use crate::error::{FileSnafu, Result};
+use crate::overlay;
+
+/// Path to the AppArmor "enabled" parameter.
+const APPARMOR_ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+
+/// Path to the list of loaded AppArmor profiles.
+const APPARMOR_PROFILES: &str = "/sys/kernel/security/apparmor/profiles";
+
+/// Marker for the block we manage in local profile snippets.
+const MARKER: &str = "# the following is generated by miniond";
+
+/// Programs whose confinement matters to us, and the access they need.
+const PROGRAMS: &[(&str, &str)] = &[
+    ("sshd", "r"),
+    ("useradd", "rwlk"),
+    ("usermod", "rwlk"),
+];
+
+/// AppArmor configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AppArmorConfig {
+    /// Whether to install local profile snippets for managed paths.
+    #[serde(rename = "install-local")]
+    install_local: bool,
+
+    /// Directory containing AppArmor profiles.
+    #[serde(rename = "profile-dir")]
+    profile_dir: PathBuf,
+
+    /// Paths managed by miniond that confined programs need to access.
+    paths: Vec<String>,
+}
+
+impl Default for AppArmorConfig {
+    fn default() -> Self {
+        Self {
+            install_local: false,
+            profile_dir: PathBuf::from("/etc/apparmor.d"),
+            paths: vec![
+                "/users/".to_string(),
+                "/users/**".to_string(),
+            ],
+        }
+    }
+}
+
+/// A loaded profile in enforce mode.
+#[derive(Debug)]
+struct EnforcedProfile {
+    /// Name of the profile (usually the path of the binary).
+    name: String,
+
+    /// Access the program needs to miniond-managed paths.
+    access: &'static str,
+}
+
+/// Check for AppArmor profiles that may interfere with us.
+pub async fn check(config: &Config) -> Result<()> {
+    let enabled = fs::read_to_string(APPARMOR_ENABLED).await
+        .map(|s| s.trim() == "Y")
+        .unwrap_or(false);
+
+    if !enabled {
+        return Ok(());
+    }
+
+    let profiles = match fs::read_to_string(APPARMOR_PROFILES).await {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            log::debug!("AppArmor is enabled but the profile list is unreadable: {}", e);
+            return Ok(());
+        }
+    };
+
+    let enforced = enforced_profiles(&profiles);
+
+    for profile in &enforced {
+        if config.apparmor.install_local {
+            log::info!("Installing local AppArmor rules for {}...", profile.name);
+            install_local(config, profile).await?;
+        } else {
+            log::warn!("AppArmor profile {} is in enforce mode and may block access to {}",
+                profile.name, config.apparmor.paths.join(", "));
+            log::warn!("Set `apparmor.install-local = true` to let miniond add local rules, or run `aa-complain {}`", profile.name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Find loaded profiles that confine programs we care about.
+///
+/// Each line of the profile list looks like `/usr/sbin/sshd (enforce)`.
+fn enforced_profiles(profiles: &str) -> Vec<EnforcedProfile> {
+    profiles.lines()
+        .filter_map(|line| {
+            let (name, mode) = line.rsplit_once(' ')?;
+            if mode != "(enforce)" {
+                return None;
+            }
+
+            let base = name.rsplit('/').next()?;
+            let (_, access) = PROGRAMS.iter().find(|(program, _)| *program == base)?;
+
+            Some(EnforcedProfile {
+                name: name.to_string(),
+                access,
+            })
+        })
+        .collect()
+}
+
+/// Append rules for managed paths to the profile's local include.
+async fn install_local(config: &Config, profile: &EnforcedProfile) -> Result<()> {
+    // Profile files are named after the binary path, with slashes
+    // replaced by dots (e.g., `usr.sbin.sshd`).
+    let file_name = profile.name.trim_start_matches('/').replace('/', ".");
+    let profile_dir = &config.apparmor.profile_dir;
+    let local_dir = overlay::writable_dir(&config.overlay, &profile_dir.join("local")).await?;
+    let local = local_dir.join(&file_name);
+
+    let existing = match fs::read_to_string(&local).await {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(FileSnafu { action: "read", path: &local }),
+    };
+
+    let mut contents = String::new();
+    for line in existing.lines() {
+        if line == MARKER {
+            break;
+        }
+        contents.push_str(line);
+        contents.push('\n');
+    }
+
+    contents.push_str(MARKER);
+    contents.push('\n');
+    for path in &config.apparmor.paths {
+        contents.push_str(&format!("{} {},\n", path, profile.access));
+    }
+
+    if !overlay::update_file(&config.overlay, &local, contents).await? {
+        return Ok(());
+    }
+
+    reload_profile(&profile_dir.join(&file_name)).await;
+
+    Ok(())
+}
+
+/// Reload a profile so the local rules take effect.
+async fn reload_profile(path: &Path) {
+    if which("apparmor_parser").is_err() {
+        log::warn!("`apparmor_parser` is not in PATH. Local rules will take effect when {:?} is reloaded", path);
+        return;
+    }
+
+    if let Err(reason) = run_command(Command::new("apparmor_parser").arg("-r").arg(path)).await {
+        log::warn!("Failed to reload AppArmor profile {:?}: {}", path, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enforced_profiles() {
+        let profiles = enforced_profiles("/usr/sbin/sshd (enforce)\n/usr/sbin/useradd (complain)\nlsb_release (enforce)\n");
+
+        assert_eq!(1, profiles.len());
+        assert_eq!("/usr/sbin/sshd", profiles[0].name);
+        assert_eq!("r", profiles[0].access);
+    }
+
+    #[tokio::test]
+    async fn test_install_local() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: crate::config::ConfigInner = toml::from_str(&format!(
+            "[apparmor]\ninstall-local = true\nprofile-dir = {:?}\npaths = [\"/users/**\"]\n[overlay]\nmode = \"off\"\n",
+            dir.path(),
+        )).expect("Failed to parse config");
+        let config = std::sync::Arc::new(config);
+
+        let profile = EnforcedProfile {
+            name: "/usr/sbin/sshd".to_string(),
+            access: "r",
+        };
+        let local = dir.path().join("local/usr.sbin.sshd");
+        std::fs::create_dir_all(local.parent().unwrap()).unwrap();
+        std::fs::write(&local, "owner /srv/** r,\n").unwrap();
+
+        install_local(&config, &profile).await.unwrap();
+        assert_eq!(
+            format!("owner /srv/** r,\n{}\n/users/** r,\n", MARKER),
+            std::fs::read_to_string(&local).unwrap(),
+        );
+
+        // Unreadable snippets are never overwritten
+        std::fs::write(&local, b"\xff\xfe\n").unwrap();
+        assert!(install_local(&config, &profile).await.is_err());
+        assert_eq!(b"\xff\xfe\n".to_vec(), std::fs::read(&local).unwrap());
+    }
+}
@@ -0,0 +1,597 @@
+//! The `api` applet.
+//!
+//! A small HTTP API on localhost for experiment frameworks that
+//! orchestrate nodes over HTTP:
+//!
+//! - `POST /reload`: Reload information from the testbed
+//! - `GET /accounts`: Accounts applied to the system
+//! - `GET /mounts`: Mounts applied to the system
+//! - `GET /bootwhat`: What the node is supposed to boot, or `null`
+//! - `GET /events`: Recent events followed by new ones, as NDJSON
+//!
+//! Requests must carry `Authorization: Bearer <token>` with the token
+//! from `token-file`, which is readable by root and `group`. If the
+//! file doesn't exist, a random token is generated. A token file that
+//! others can read is refused.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+use crate::account::{Group, User};
+use crate::blocking;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::mount::Mount;
+use crate::state;
+use crate::tmcc::BootInfo;
+use super::{Applet, Sender, Message, constant_time_eq, send, recv};
+
+/// Maximum size of a request head.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long to wait for a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `api` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Address to listen on.
+    ///
+    /// With port 0, an ephemeral port is picked and the address is
+    /// saved as `api` in the state directory.
+    pub(crate) listen: SocketAddr,
+
+    /// Path to the token file.
+    #[serde(rename = "token-file")]
+    token_file: PathBuf,
+
+    /// Group allowed to read the token file.
+    ///
+    /// If unset, only root can read it.
+    group: Option<String>,
+
+    /// Number of recent events to keep.
+    events: usize,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 7780)),
+            token_file: PathBuf::from("/etc/miniond/api-token"),
+            group: None,
+            events: 100,
+        }
+    }
+}
+
+/// An applied account, as returned by `/accounts`.
+#[derive(Debug, Clone, Serialize)]
+struct UserView {
+    login: String,
+    uid: u16,
+    gid: u16,
+    root: bool,
+    home: PathBuf,
+    shell: String,
+}
+
+/// An applied group, as returned by `/accounts`.
+#[derive(Debug, Clone, Serialize)]
+struct GroupView {
+    name: String,
+    gid: u16,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(super) struct AccountsView {
+    users: Vec<UserView>,
+    groups: Vec<GroupView>,
+}
+
+/// A mount, as returned by `/mounts`.
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct MountView {
+    remote: String,
+    local: PathBuf,
+
+    #[serde(rename = "type")]
+    fs_type: String,
+}
+
+impl From<&Mount> for MountView {
+    fn from(mount: &Mount) -> Self {
+        Self {
+            remote: mount.remote().to_string(),
+            local: mount.local().to_path_buf(),
+            fs_type: mount.filesystem().fs_type().to_string(),
+        }
+    }
+}
+
+/// An event, as streamed by `/events`.
+#[derive(Debug, Clone, Serialize)]
+struct Event {
+    /// Seconds since the epoch.
+    time: u64,
+
+    event: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+/// The accounts and mounts applied to the system.
+///
+/// Updates only count once the applets acknowledged them.
+#[derive(Debug, Default)]
+pub(super) struct Applied {
+    /// Accounts being applied, and whether all chunks arrived.
+    pending_accounts: Mutex<(AccountsView, bool)>,
+    accounts: Mutex<AccountsView>,
+
+    pending_mounts: Mutex<Vec<MountView>>,
+    mounts: Mutex<Vec<MountView>>,
+}
+
+impl Applied {
+    /// Track a message from the bus.
+    pub(super) fn update(&self, message: &Message) {
+        match message {
+            Message::UpdateAccounts(accounts) => {
+                let view = AccountsView {
+                    users: accounts.users.values().map(user_view).collect(),
+                    groups: accounts.groups.values().map(group_view).collect(),
+                };
+
+                *self.pending_accounts.lock().unwrap() = (view, true);
+            }
+
+            Message::UpdateAccountsChunk(chunk) => {
+                let mut pending = self.pending_accounts.lock().unwrap();
+
+                // The first chunk of a new batch
+                if pending.1 {
+                    *pending = (AccountsView::default(), false);
+                }
+
+                pending.0.users.extend(chunk.users.iter().map(user_view));
+                pending.0.groups.extend(chunk.groups.iter().map(group_view));
+                pending.1 = chunk.last;
+            }
+
+            Message::UpdateAccountsOk => {
+                let pending = self.pending_accounts.lock().unwrap();
+                *self.accounts.lock().unwrap() = pending.0.clone();
+            }
+
+            Message::RemoveAccounts => {
+                *self.accounts.lock().unwrap() = AccountsView::default();
+            }
+
+            Message::UpdateMounts(mounts) => {
+                *self.pending_mounts.lock().unwrap() = mounts.iter().map(MountView::from).collect();
+            }
+
+            Message::UpdateMountsOk => {
+                let pending = self.pending_mounts.lock().unwrap().clone();
+                *self.mounts.lock().unwrap() = pending;
+            }
+
+            Message::RemoveMounts => {
+                self.mounts.lock().unwrap().clear();
+            }
+
+            _ => {}
+        }
+    }
+
+    /// Returns the applied accounts.
+    pub(super) fn accounts(&self) -> AccountsView {
+        self.accounts.lock().unwrap().clone()
+    }
+
+    /// Returns the applied mounts.
+    pub(super) fn mounts(&self) -> Vec<MountView> {
+        self.mounts.lock().unwrap().clone()
+    }
+
+    /// Returns the numbers of applied users, groups and mounts.
+    pub(super) fn counts(&self) -> (usize, usize, usize) {
+        let accounts = self.accounts.lock().unwrap();
+        (accounts.users.len(), accounts.groups.len(), self.mounts.lock().unwrap().len())
+    }
+}
+
+/// State shared with the connections.
+#[derive(Debug)]
+struct State {
+    tx: Sender,
+    token: String,
+
+    applied: Applied,
+
+    boot: Mutex<Option<BootInfo>>,
+
+    recent: Mutex<VecDeque<Event>>,
+    events: broadcast::Sender<Event>,
+}
+
+/// The `api` applet.
+#[derive(Debug)]
+pub struct Api {
+    config: Config,
+    tx: Sender,
+}
+
+impl Api {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        if config.api.enable && !config.api.listen.ip().is_loopback() {
+            log::warn!("The API listens on {}, which is not a loopback address", config.api.listen);
+        }
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Api {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.api.enable {
+            log::info!("api applet disabled in config");
+            return Ok(());
+        }
+
+        let config = &self.config.api;
+
+        let token = load_token(config).await?;
+        let listener = TcpListener::bind(config.listen).await?;
+        let listen = listener.local_addr()?;
+
+        log::info!("Serving the API on {}", listen);
+
+        if config.listen.port() == 0 {
+            state::save(&self.config.state, "api", &listen).await?;
+        }
+
+        let (events, _) = broadcast::channel(config.events.max(1));
+        let state = Arc::new(State {
+            tx: self.tx.clone(),
+            token,
+            applied: Applied::default(),
+            boot: Mutex::new(None),
+            recent: Mutex::new(VecDeque::new()),
+            events,
+        });
+
+        let server = {
+            let state = state.clone();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream, &state).await {
+                            log::debug!("API connection failed: {}", e);
+                        }
+                    });
+                }
+            })
+        };
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+
+            // Published under the lock, so a new stream sees each event
+            // either in its snapshot or live, never both
+            if let Some(event) = event(&message) {
+                let mut recent = state.recent.lock().unwrap();
+                if recent.len() >= config.events {
+                    recent.pop_front();
+                }
+                recent.push_back(event.clone());
+
+                let _ = state.events.send(event);
+            }
+
+            state.applied.update(&message);
+
+            match message {
+                Message::Shutdown(_) => {
+                    server.abort();
+                    break;
+                }
+
+                Message::UpdateBootInfo(boot) => {
+                    *state.boot.lock().unwrap() = Some(boot);
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn user_view(user: &User) -> UserView {
+    UserView {
+        login: user.login().to_string(),
+        uid: user.uid(),
+        gid: user.gid(),
+        root: user.is_root(),
+        home: user.home_dir().to_path_buf(),
+        shell: user.login_shell().to_string(),
+    }
+}
+
+fn group_view(group: &Group) -> GroupView {
+    GroupView {
+        name: group.name().to_string(),
+        gid: group.gid(),
+    }
+}
+
+/// Returns the event for a message, if it's interesting.
+fn event(message: &Message) -> Option<Event> {
+    let (event, detail) = match message {
+        Message::Shutdown(_) => ("shutdown", None),
+        Message::ReloadTestbed => ("reload", None),
+        Message::ReloadTestbedOk => ("reload-ok", None),
+        Message::UpdateAccountsOk => ("accounts-applied", None),
+        Message::UpdateMountsOk => ("mounts-applied", None),
+        Message::UpdateCanonicalOk => ("hostname-applied", None),
+        Message::UpdateSoftwareOk => ("software-installed", None),
+        Message::StartupFinished(status) => ("startup-finished", Some(status.to_string())),
+        Message::UpdateAllocation(allocation) => {
+            ("allocation", Some(format!("{} {}", allocation.experiment, allocation.node_name)))
+        }
+        Message::StateReported(state) => ("state", Some(format!("{:?}", state))),
+        Message::AppletFailed(applet, error, severity) => ("failure", Some(format!("{}: {} ({:?})", applet, error, severity))),
+        Message::DriftRepaired(applet, fixes) => ("drift-repaired", Some(format!("{}: {}", applet, fixes.join("; ")))),
+        Message::Swapout => ("swapout", None),
+        Message::Swapin => ("swapin", None),
+        Message::LinkTestResults(results) => {
+            let reachable = results.iter().filter(|r| r.reachable).count();
+            ("linktest", Some(format!("{}/{} reachable", reachable, results.len())))
+        }
+        _ => return None,
+    };
+
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    Some(Event {
+        time,
+        event,
+        detail,
+    })
+}
+
+/// Read the token, generating one if it doesn't exist.
+async fn load_token(config: &ApiConfig) -> Result<String> {
+    match tokio::fs::File::open(&config.token_file).await {
+        Ok(mut file) => {
+            // The group may only read it
+            let mode = file.metadata().await?.permissions().mode() & 0o777;
+            let exposed = if config.group.is_some() { 0o027 } else { 0o077 };
+            if mode & exposed != 0 {
+                return Err(Error::InsecureFile { path: config.token_file.clone(), mode });
+            }
+
+            let mut token = String::new();
+            file.read_to_string(&mut token).await?;
+
+            let token = token.trim().to_string();
+            if !token.is_empty() {
+                return Ok(token);
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    log::info!("Generating API token at {:?}...", config.token_file);
+
+    let mut random = [0u8; 32];
+    tokio::fs::File::open("/dev/urandom").await?
+        .read_exact(&mut random).await?;
+
+    let token: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let gid = match &config.group {
+        Some(group) => {
+            let group = group.clone();
+            blocking::run("resolve-group", move || {
+                users::get_group_by_name(&group)
+                    .map(|g| g.gid())
+                    .ok_or(Error::UnknownOwner { name: group })
+            }).await?
+        }
+        None => 0,
+    };
+
+    if let Some(parent) = config.token_file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    blocking::write_private(config.token_file.clone(), format!("{}\n", token).into_bytes(), 0o640, 0, gid).await?;
+
+    Ok(token)
+}
+
+/// Handle a connection.
+async fn handle(mut stream: TcpStream, state: &State) -> Result<()> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()),
+    };
+
+    let head = match head {
+        Some(head) => head,
+        None => return respond(&mut stream, 400, "Bad Request", "{\"error\":\"bad request\"}").await,
+    };
+
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let authorized = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "))
+        .map(|token| constant_time_eq(token.trim().as_bytes(), state.token.as_bytes()))
+        .unwrap_or(false);
+
+    if !authorized {
+        return respond(&mut stream, 401, "Unauthorized", "{\"error\":\"unauthorized\"}").await;
+    }
+
+    match (method, path) {
+        ("POST", "/reload") => {
+            log::info!("Reload requested over the API");
+
+            send(&state.tx, Message::ReloadTestbed);
+            respond(&mut stream, 202, "Accepted", "{\"status\":\"accepted\"}").await
+        }
+        ("GET", "/accounts") => {
+            let json = serde_json::to_string(&state.applied.accounts())
+                .expect("Failed to serialize accounts");
+            respond(&mut stream, 200, "OK", &json).await
+        }
+        ("GET", "/mounts") => {
+            let json = serde_json::to_string(&state.applied.mounts())
+                .expect("Failed to serialize mounts");
+            respond(&mut stream, 200, "OK", &json).await
+        }
+        ("GET", "/bootwhat") => {
+            let json = serde_json::to_string(&*state.boot.lock().unwrap())
+                .expect("Failed to serialize boot information");
+            respond(&mut stream, 200, "OK", &json).await
+        }
+        ("GET", "/events") => stream_events(&mut stream, state).await,
+        (_, "/reload") | (_, "/accounts") | (_, "/mounts") | (_, "/bootwhat") | (_, "/events") => {
+            respond(&mut stream, 405, "Method Not Allowed", "{\"error\":\"method not allowed\"}").await
+        }
+        _ => respond(&mut stream, 404, "Not Found", "{\"error\":\"not found\"}").await,
+    }
+}
+
+/// Read the request line and headers.
+///
+/// Returns `None` if the request is too large or malformed.
+pub(super) async fn read_head(stream: &mut TcpStream) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+
+    loop {
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        buf.extend_from_slice(&chunk[..len]);
+
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            buf.truncate(end);
+            return Ok(String::from_utf8(buf).ok());
+        }
+
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+    }
+}
+
+/// Send a JSON response and close the connection.
+async fn respond(stream: &mut TcpStream, code: u16, reason: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code, reason, body.len(), body,
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Stream recent and new events until the client goes away.
+async fn stream_events(stream: &mut TcpStream, state: &State) -> Result<()> {
+    // Events are published under the lock, so none falls in between
+    // or shows up twice
+    let (recent, mut events) = {
+        let recent = state.recent.lock().unwrap();
+        (recent.iter().cloned().collect::<Vec<Event>>(), state.events.subscribe())
+    };
+
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n").await?;
+
+    for event in recent {
+        write_event(stream, &event).await?;
+    }
+
+    loop {
+        match events.recv().await {
+            Ok(event) => write_event(stream, &event).await?,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_event(stream: &mut TcpStream, event: &Event) -> Result<()> {
+    let line = serde_json::to_string(event).expect("Failed to serialize event") + "\n";
+    stream.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ApiConfig {
+            token_file: dir.path().join("api-token"),
+            ..ApiConfig::default()
+        };
+
+        std::fs::write(&config.token_file, "secret\n").unwrap();
+
+        std::fs::set_permissions(&config.token_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(load_token(&config).await, Err(Error::InsecureFile { mode: 0o644, .. })));
+
+        std::fs::set_permissions(&config.token_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!("secret", load_token(&config).await.expect("Failed to load token"));
+    }
+}
@@ -0,0 +1,155 @@
+//! The `autoblob` applet.
+//!
+//! It places named blobs from the testbed at configured paths, so
+//! profiles can ship config files and small artifacts to nodes
+//! without hosting them elsewhere:
+//!
+//! ```toml
+//! [[autoblob.files]]
+//! name = "app-config"
+//! path = "/etc/app/config.toml"
+//! sha256 = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+//! ```
+//!
+//! The blobs are retrieved by the `tmcc` applet and verified against
+//! their checksums before they get here. Files are only written if
+//! they changed.
+
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::blocking;
+use crate::config::Config;
+use crate::error::Result;
+use crate::plan::{self, Action};
+use crate::tmcc::Blob;
+use super::{Applet, Sender, Message, recv};
+
+/// `autoblob` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutoblobConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Blobs to place.
+    pub(super) files: Vec<BlobFile>,
+}
+
+impl Default for AutoblobConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            files: Vec::new(),
+        }
+    }
+}
+
+/// A blob to place.
+#[derive(Debug, Deserialize)]
+pub(super) struct BlobFile {
+    /// Name of the blob on the testbed.
+    pub(super) name: String,
+
+    /// Where to place it.
+    path: PathBuf,
+
+    /// Expected SHA-256 digest of the contents, in hex.
+    pub(super) sha256: Option<String>,
+
+    /// Permissions of the file (e.g., `0o644`).
+    #[serde(default = "default_mode")]
+    mode: u32,
+}
+
+fn default_mode() -> u32 {
+    0o644
+}
+
+/// The `autoblob` applet.
+#[derive(Debug)]
+pub struct Autoblob {
+    config: Config,
+    tx: Sender,
+}
+
+impl Autoblob {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autoblob {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autoblob.enable || self.config.autoblob.files.is_empty() {
+            log::info!("autoblob applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateBlob(blob) => {
+                    if let Err(e) = self.place(&blob).await {
+                        log::warn!("{}", e);
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autoblob {
+    /// Place a blob at all paths it's configured for.
+    async fn place(&self, blob: &Blob) -> Result<()> {
+        let files = self.config.autoblob.files.iter()
+            .filter(|file| file.name == blob.name);
+
+        for file in files {
+            if let Ok(existing) = fs::read(&file.path).await {
+                if existing == blob.contents {
+                    log::debug!("Blob {} at {:?} is up to date", blob.name, file.path);
+                    continue;
+                }
+            }
+
+            log::info!("Placing blob {} at {:?}...", blob.name, file.path);
+
+            if plan::is_dry_run() {
+                plan::record(Action::WriteFile {
+                    path: file.path.clone(),
+                    contents: String::from_utf8_lossy(&blob.contents).to_string(),
+                });
+                continue;
+            }
+
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+
+            blocking::write_private(file.path.clone(), blob.contents.clone(), file.mode, 0, 0).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! The `autocert` applet.
+//!
+//! It installs the key and certificate of the experiment, which are
+//! the same on all nodes, at configured paths. This allows for mutual
+//! TLS between nodes without copying files around by hand.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use miniond_core::redact;
+use serde::Deserialize;
+
+use crate::blocking;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::plan::{self, Action};
+use super::{Applet, Sender, Message, recv};
+
+/// `autocert` applet configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AutocertConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Files to install.
+    files: Vec<SecretFile>,
+}
+
+/// A file to install.
+#[derive(Debug, Deserialize)]
+struct SecretFile {
+    /// What to install.
+    source: SecretSource,
+
+    /// Where to install it.
+    path: PathBuf,
+
+    /// Owner of the file.
+    #[serde(default = "default_owner")]
+    owner: String,
+
+    /// Group of the file.
+    ///
+    /// If unset, the primary group of the owner is used.
+    group: Option<String>,
+
+    /// Permissions of the file (e.g., `0o600`).
+    #[serde(default = "default_mode")]
+    mode: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum SecretSource {
+    /// The private key of the experiment.
+    #[serde(rename = "key")]
+    Key,
+
+    /// The certificate of the experiment.
+    #[serde(rename = "certificate")]
+    Certificate,
+}
+
+fn default_owner() -> String {
+    "root".to_string()
+}
+
+fn default_mode() -> u32 {
+    0o600
+}
+
+/// Secrets of the experiment.
+#[derive(Default)]
+pub struct Secrets {
+    pub key: Option<String>,
+    pub certificate: Option<String>,
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Messages end up in debug logs
+        f.debug_struct("Secrets")
+            .field("key", &self.key.as_ref().map(|_| redact::REDACTED))
+            .field("certificate", &self.certificate.as_ref().map(|_| redact::REDACTED))
+            .finish()
+    }
+}
+
+/// The `autocert` applet.
+#[derive(Debug)]
+pub struct Autocert {
+    config: Config,
+    tx: Sender,
+
+    /// Secrets waiting for the accounts to be applied.
+    pending: Mutex<Option<Arc<Secrets>>>,
+
+    /// Whether the owners of the files may exist.
+    accounts_ready: AtomicBool,
+}
+
+impl Autocert {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        // Owners may be testbed users, created by `autouser`
+        let accounts_ready = !config.autouser.enable;
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            pending: Mutex::new(None),
+            accounts_ready: AtomicBool::new(accounts_ready),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autocert {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autocert.enable {
+            log::info!("autocert applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            let secrets = match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+                Message::UpdateSecrets(secrets) => {
+                    if !self.accounts_ready.load(Ordering::Relaxed) {
+                        *self.pending.lock().unwrap() = Some(secrets);
+                        continue;
+                    }
+                    secrets
+                }
+                Message::UpdateAccountsOk => {
+                    self.accounts_ready.store(true, Ordering::Relaxed);
+
+                    match self.pending.lock().unwrap().take() {
+                        Some(secrets) => secrets,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            self.install(&secrets).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Autocert {
+    /// Install all configured files.
+    async fn install(&self, secrets: &Secrets) -> Result<()> {
+        for file in &self.config.autocert.files {
+            let contents = match file.source {
+                SecretSource::Key => &secrets.key,
+                SecretSource::Certificate => &secrets.certificate,
+            };
+
+            let contents = match contents {
+                Some(contents) => contents.clone(),
+                None => {
+                    log::warn!("The testbed did not provide the {:?} for {:?}", file.source, file.path);
+                    continue;
+                }
+            };
+
+            let (uid, gid) = resolve_owner(&file.owner, file.group.as_deref()).await?;
+
+            log::info!("Installing {:?} for {}...", file.path, file.owner);
+
+            if plan::is_dry_run() {
+                // The contents are secret
+                plan::record(Action::WriteFile {
+                    path: file.path.clone(),
+                    contents: String::new(),
+                });
+                continue;
+            }
+
+            if let Some(parent) = file.path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            blocking::write_private(file.path.clone(), contents.into_bytes(), file.mode, uid, gid).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the UID and GID for a file owner.
+async fn resolve_owner(owner: &str, group: Option<&str>) -> Result<(u32, u32)> {
+    let owner = owner.to_string();
+    let group = group.map(str::to_string);
+
+    blocking::run("resolve-owner", move || {
+        let user = users::get_user_by_name(&owner)
+            .ok_or_else(|| Error::UnknownOwner { name: owner.clone() })?;
+
+        let gid = match group {
+            Some(group) => {
+                users::get_group_by_name(&group)
+                    .ok_or(Error::UnknownOwner { name: group })?
+                    .gid()
+            }
+            None => user.primary_group_id(),
+        };
+
+        Ok((user.uid(), gid))
+    }).await
+}
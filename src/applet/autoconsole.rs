@@ -0,0 +1,375 @@
+//! The `autoconsole` applet.
+//!
+//! It makes the node's console reachable through the testbed console
+//! infrastructure (`console <node>` on ops), taking the role of
+//! `capture` for the node's own console line. The line is described
+//! by `tiplineinfo`, and connections must present its key first.
+//! Unless configured otherwise, it's only served on the control
+//! interface, i.e., the one that routes to the console server.
+//!
+//! The console is either a device (e.g., one end of a serial line)
+//! or a program run on a pty for each connection.
+//!
+//! It can also tunnel the console lines of other nodes described by
+//! `tiptunnels` (e.g., virtual nodes hosted here), making each one
+//! available as a Unix socket in `tunnel-dir`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use which::which;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::plan;
+use crate::tmcc::Tipline;
+use super::{Applet, Sender, Message, constant_time_eq, recv};
+
+/// Replies to the key, from `capture.h` in Emulab.
+const CAPOK: i32 = 0;
+const CAPBUSY: i32 = 1;
+const CAPNOPERM: i32 = 2;
+
+/// How long a connection may take to present the key.
+const KEY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `autoconsole` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutoconsoleConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Console device to relay.
+    ///
+    /// If unset, `command` is run on a pty for each connection.
+    device: Option<PathBuf>,
+
+    /// Command to run on a pty for each connection.
+    command: String,
+
+    /// Address to serve the console line on.
+    ///
+    /// If unset, the address of the interface that routes to the
+    /// console server is used.
+    listen: Option<IpAddr>,
+
+    /// Directory to create console tunnel sockets in.
+    ///
+    /// If unset, no tunnels are established.
+    #[serde(rename = "tunnel-dir")]
+    pub(super) tunnel_dir: Option<PathBuf>,
+}
+
+impl Default for AutoconsoleConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            device: None,
+            command: "/bin/login".to_string(),
+            listen: None,
+            tunnel_dir: None,
+        }
+    }
+}
+
+/// The `autoconsole` applet.
+#[derive(Debug)]
+pub struct Autoconsole {
+    config: Config,
+    tx: Sender,
+
+    /// The console line we serve.
+    serving: Mutex<Option<(Tipline, JoinHandle<()>)>>,
+
+    /// The console lines we tunnel, by name.
+    tunnels: Mutex<HashMap<String, (Tipline, JoinHandle<()>)>>,
+}
+
+impl Autoconsole {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        // `script` allocates the pty for us
+        if config.autoconsole.enable && config.autoconsole.device.is_none() && which("script").is_err() {
+            log::error!("The `script` binary must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            serving: Mutex::new(None),
+            tunnels: Mutex::new(HashMap::new()),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autoconsole {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autoconsole.enable {
+            log::info!("autoconsole applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    if let Some((_, task)) = self.serving.lock().unwrap().take() {
+                        task.abort();
+                    }
+                    for (_, (_, task)) in self.tunnels.lock().unwrap().drain() {
+                        task.abort();
+                    }
+                    break;
+                }
+
+                Message::UpdateTipline(tipline) => {
+                    self.serve(tipline).await?;
+                }
+
+                Message::UpdateTiptunnels(tunnels) => {
+                    self.tunnel(tunnels).await?;
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autoconsole {
+    /// Start serving a console line, replacing the previous one.
+    async fn serve(&self, tipline: Tipline) -> Result<()> {
+        if let Some((current, _)) = &*self.serving.lock().unwrap() {
+            if current == &tipline {
+                return Ok(());
+            }
+        }
+
+        if plan::is_dry_run() {
+            log::info!("Would serve console line {} on port {}", tipline.name, tipline.port);
+            return Ok(());
+        }
+
+        if let Some((_, task)) = self.serving.lock().unwrap().take() {
+            task.abort();
+        }
+
+        let address = match self.config.autoconsole.listen {
+            Some(address) => address,
+            None => control_address(&tipline).await?,
+        };
+
+        log::info!("Serving console line {} on {}:{}...", tipline.name, address, tipline.port);
+
+        let listener = TcpListener::bind((address, tipline.port)).await?;
+        let key = Arc::new(tipline.key.clone());
+        let device = self.config.autoconsole.device.clone();
+        let command = self.config.autoconsole.command.clone();
+
+        let task = tokio::spawn(async move {
+            // Only one user may hold the console at a time
+            let busy = Arc::new(Semaphore::new(1));
+
+            while let Ok((stream, peer)) = listener.accept().await {
+                let key = key.clone();
+                let device = device.clone();
+                let command = command.clone();
+                let busy = busy.clone();
+
+                tokio::spawn(async move {
+                    log::info!("Console connection from {}", peer);
+
+                    if let Err(e) = relay(stream, &key, &busy, device, &command).await {
+                        log::warn!("Console connection from {} failed: {}", peer, e);
+                    }
+                });
+            }
+        });
+
+        *self.serving.lock().unwrap() = Some((tipline, task));
+
+        Ok(())
+    }
+}
+
+impl Autoconsole {
+    /// Establish console tunnels, replacing the previous ones.
+    async fn tunnel(&self, tunnels: Vec<Tipline>) -> Result<()> {
+        let dir = match &self.config.autoconsole.tunnel_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        // Stop tunnels that are gone or changed
+        self.tunnels.lock().unwrap().retain(|name, (current, task)| {
+            let keep = tunnels.iter().any(|t| t == current);
+            if !keep {
+                log::info!("Stopping console tunnel for {}", name);
+                task.abort();
+            }
+            keep
+        });
+
+        for tipline in tunnels {
+            if self.tunnels.lock().unwrap().contains_key(&tipline.name) {
+                continue;
+            }
+
+            let path = dir.join(&tipline.name);
+
+            if plan::is_dry_run() {
+                log::info!("Would tunnel console line {} from {}:{} to {:?}", tipline.name, tipline.server, tipline.port, path);
+                continue;
+            }
+
+            log::info!("Tunneling console line {} from {}:{} to {:?}...", tipline.name, tipline.server, tipline.port, path);
+
+            fs::create_dir_all(dir).await?;
+            remove_socket(&path).await?;
+
+            let listener = UnixListener::bind(&path)?;
+            let remote = tipline.clone();
+
+            let task = tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let remote = remote.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = forward(stream, &remote).await {
+                            log::warn!("Console tunnel for {} failed: {}", remote.name, e);
+                        }
+                    });
+                }
+            });
+
+            self.tunnels.lock().unwrap().insert(tipline.name.clone(), (tipline, task));
+        }
+
+        Ok(())
+    }
+}
+
+/// Remove a stale socket left by a previous run.
+async fn remove_socket(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Forward a local connection to a remote console, presenting its key.
+async fn forward(mut local: UnixStream, remote: &Tipline) -> io::Result<()> {
+    let mut stream = TcpStream::connect((remote.server.as_str(), remote.port)).await?;
+    stream.write_all(remote.key.as_bytes()).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+
+    match i32::from_be_bytes(reply) {
+        CAPOK => {}
+        CAPBUSY => {
+            local.write_all(b"The console is in use\r\n").await?;
+            return Ok(());
+        }
+        _ => {
+            local.write_all(b"The console refused the key\r\n").await?;
+            return Ok(());
+        }
+    }
+
+    io::copy_bidirectional(&mut local, &mut stream).await?;
+
+    Ok(())
+}
+
+/// Returns the address of the interface that routes to the console server.
+///
+/// Connecting a UDP socket only picks the route, nothing is sent.
+async fn control_address(tipline: &Tipline) -> io::Result<IpAddr> {
+    let server = tokio::net::lookup_host((tipline.server.as_str(), tipline.port)).await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", tipline.server)))?;
+
+    let unspecified: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+
+    let socket = UdpSocket::bind(unspecified).await?;
+    socket.connect(server).await?;
+
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Relay a connection to the console after checking its key.
+async fn relay(mut stream: TcpStream, key: &str, busy: &Semaphore, device: Option<PathBuf>, command: &str) -> io::Result<()> {
+    let mut presented = vec![0; key.len()];
+    tokio::time::timeout(KEY_TIMEOUT, stream.read_exact(&mut presented)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the key"))??;
+
+    if !constant_time_eq(&presented, key.as_bytes()) {
+        stream.write_all(&CAPNOPERM.to_be_bytes()).await?;
+        return Ok(());
+    }
+
+    let _permit = match busy.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            stream.write_all(&CAPBUSY.to_be_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    stream.write_all(&CAPOK.to_be_bytes()).await?;
+
+    match device {
+        Some(device) => {
+            let mut console = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(device).await?;
+
+            io::copy_bidirectional(&mut stream, &mut console).await?;
+        }
+        None => {
+            let mut child = Command::new("script")
+                .args(["-qfec", command, "/dev/null"])
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+
+            let mut stdin = child.stdin.take().unwrap();
+            let mut stdout = child.stdout.take().unwrap();
+            let (mut reader, mut writer) = stream.split();
+
+            // Either side hanging up ends the session
+            tokio::select! {
+                res = io::copy(&mut reader, &mut stdin) => { res?; }
+                res = io::copy(&mut stdout, &mut writer) => { res?; }
+            }
+        }
+    }
+
+    Ok(())
+}
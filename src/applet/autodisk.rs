@@ -0,0 +1,376 @@
+//! The `autodisk` applet.
+//!
+//! It sets up the local blockstores of the experiment
+//! (`storageconfig`), like `rc.storage` of the official clientside:
+//! each blockstore gets an unused local disk, which is formatted and
+//! mounted at the requested mount point. Once all of them are in
+//! place, `UpdateStorageOk` is broadcast, so the startup command can
+//! wait for them.
+//!
+//! Blockstores always take a whole disk, either from `devices` or
+//! discovered with `lsblk`. Which disk each blockstore got is saved to
+//! the state directory, so it keeps its disk (and data) across
+//! reboots. Disks that already have a file system are never
+//! reformatted, and disks with a partition table, partitions or mounts
+//! are never formatted.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::command::{command_output, command_output_unless, run_command};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::mount::{self, Filesystem, Mount};
+use crate::plan::{self, Action};
+use crate::state;
+use crate::tmcc::LocalVolume;
+use super::{Applet, Sender, Message, send, recv};
+
+/// `autodisk` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutodiskConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Disks to use for blockstores, in order.
+    ///
+    /// By default, whole disks without partitions or file systems are
+    /// used.
+    devices: Vec<PathBuf>,
+
+    /// File system to create if the blockstore doesn't ask for one.
+    #[serde(rename = "fs-type")]
+    fs_type: String,
+}
+
+impl Default for AutodiskConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            devices: Vec::new(),
+            fs_type: "ext4".to_string(),
+        }
+    }
+}
+
+/// A blockstore we set up, as saved in the state directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Provisioned {
+    name: String,
+    device: PathBuf,
+}
+
+/// The `autodisk` applet.
+#[derive(Debug)]
+pub struct Autodisk {
+    config: Config,
+    tx: Sender,
+
+    /// Blockstores we set up.
+    provisioned: Mutex<Vec<Provisioned>>,
+}
+
+impl Autodisk {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        let provisioned = if config.autodisk.enable {
+            state::load(&config.state, "storage").await
+        } else {
+            Vec::new()
+        };
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            provisioned: Mutex::new(provisioned),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autodisk {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autodisk.enable {
+            log::info!("autodisk applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateStorage(volumes) => {
+                    self.apply(&volumes).await?;
+                    send(&self.tx, Message::UpdateStorageOk);
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autodisk {
+    /// Set up all blockstores.
+    async fn apply(&self, volumes: &[LocalVolume]) -> Result<()> {
+        let _lock = state::lock(&self.config.state, "storage").await?;
+
+        // Only discovered if a blockstore needs a new disk
+        let mut candidates: Option<Vec<PathBuf>> = None;
+
+        for volume in volumes {
+            let existing = self.provisioned.lock().unwrap().iter()
+                .find(|provisioned| provisioned.name == volume.name)
+                .map(|provisioned| provisioned.device.clone());
+
+            let device = match existing {
+                Some(device) => device,
+                None => {
+                    let candidates = match &mut candidates {
+                        Some(candidates) => candidates,
+                        None => {
+                            let discovered = if self.config.autodisk.devices.is_empty() {
+                                unused_disks().await
+                                    .map_err(|reason| Error::Storage { name: volume.name.clone(), reason })?
+                            } else {
+                                self.config.autodisk.devices.clone()
+                            };
+
+                            candidates.insert(discovered)
+                        }
+                    };
+
+                    let taken: Vec<PathBuf> = self.provisioned.lock().unwrap().iter()
+                        .map(|provisioned| provisioned.device.clone())
+                        .collect();
+                    candidates.retain(|device| !taken.contains(device));
+
+                    if candidates.is_empty() {
+                        return Err(Error::Storage {
+                            name: volume.name.clone(),
+                            reason: "no unused local disk".to_string(),
+                        });
+                    }
+
+                    let device = candidates.remove(0);
+                    self.provisioned.lock().unwrap().push(Provisioned {
+                        name: volume.name.clone(),
+                        device: device.clone(),
+                    });
+
+                    device
+                }
+            };
+
+            self.apply_one(volume, device).await?;
+        }
+
+        let provisioned = self.provisioned.lock().unwrap().clone();
+        state::save(&self.config.state, "storage", &provisioned).await?;
+
+        Ok(())
+    }
+
+    /// Format and mount a blockstore on a disk.
+    async fn apply_one(&self, volume: &LocalVolume, device: PathBuf) -> Result<()> {
+        let storage_error = |reason| Error::Storage { name: volume.name.clone(), reason };
+        let fs_type = volume.fs_type.clone()
+            .unwrap_or_else(|| self.config.autodisk.fs_type.clone());
+
+        let existing = existing_fs_type(&device).await
+            .map_err(storage_error)?;
+
+        let fs_type = match existing {
+            Some(existing) if existing == fs_type => {
+                log::debug!("{:?} already has a {} file system", device, existing);
+                existing
+            }
+            Some(existing) => {
+                log::warn!("Keeping the {} file system on {:?} for blockstore {} instead of {}", existing, device, volume.name, fs_type);
+                existing
+            }
+            None => {
+                if let Some(reason) = device_in_use(&device).await.map_err(storage_error)? {
+                    return Err(storage_error(format!("refusing to format {:?}: {}", device, reason)));
+                }
+
+                log::info!("Creating a {} file system for blockstore {} on {:?}...", fs_type, volume.name, device);
+
+                if plan::is_dry_run() {
+                    plan::record(Action::FormatDisk {
+                        device: device.clone(),
+                        fs_type: fs_type.clone(),
+                    });
+                } else {
+                    run_command(Command::new("mkfs").args(["-t", &fs_type]).arg(&device)).await
+                        .map_err(storage_error)?;
+                }
+
+                fs_type
+            }
+        };
+
+        let mount_point = match &volume.mount_point {
+            Some(mount_point) => mount_point,
+            None => return Ok(()),
+        };
+
+        let mut mount = Mount::new(device.to_string_lossy().to_string(), mount_point.clone());
+        mount.set_filesystem(Filesystem::Block(fs_type));
+
+        mount::mount_local(&mount).await
+    }
+}
+
+/// Columns of `lsblk` needed to tell whether a device is in use.
+const LSBLK_COLUMNS: &str = "NAME,TYPE,FSTYPE,PTTYPE,MOUNTPOINT";
+
+/// Returns the file system type on a device, if it has one.
+async fn existing_fs_type(device: &Path) -> std::result::Result<Option<String>, String> {
+    // blkid exits with 2 if it finds nothing
+    let fs_type = command_output_unless(Command::new("blkid").args(["-o", "value", "-s", "TYPE"]).arg(device), &[2]).await?;
+
+    Ok(fs_type
+        .map(|fs_type| fs_type.trim().to_string())
+        .filter(|fs_type| !fs_type.is_empty()))
+}
+
+/// Returns why a device must not be formatted, if it's in use.
+async fn device_in_use(device: &Path) -> std::result::Result<Option<String>, String> {
+    let output = command_output(Command::new("lsblk").args(["-J", "-p", "-o", LSBLK_COLUMNS]).arg(device)).await?;
+
+    parse_in_use(&output)
+        .map_err(|e| format!("bad lsblk output: {}", e))
+}
+
+/// Returns the whole disks without partitions, file systems or mounts.
+async fn unused_disks() -> std::result::Result<Vec<PathBuf>, String> {
+    let output = command_output(Command::new("lsblk").args(["-J", "-p", "-o", LSBLK_COLUMNS])).await?;
+
+    parse_lsblk(&output)
+        .map_err(|e| format!("bad lsblk output: {}", e))
+}
+
+/// The output of `lsblk -J`.
+#[derive(Debug, Deserialize)]
+struct Lsblk {
+    blockdevices: Vec<BlockDevice>,
+}
+
+/// A block device in the output of `lsblk -J`.
+#[derive(Debug, Deserialize)]
+struct BlockDevice {
+    name: PathBuf,
+
+    #[serde(rename = "type")]
+    device_type: String,
+
+    fstype: Option<String>,
+
+    #[serde(default)]
+    pttype: Option<String>,
+
+    mountpoint: Option<String>,
+
+    #[serde(default)]
+    children: Vec<BlockDevice>,
+}
+
+impl BlockDevice {
+    /// Returns why the device is in use, if it is.
+    fn in_use(&self) -> Option<String> {
+        if let Some(pttype) = &self.pttype {
+            Some(format!("it has a {} partition table", pttype))
+        } else if !self.children.is_empty() {
+            Some("it has partitions".to_string())
+        } else if let Some(fstype) = &self.fstype {
+            Some(format!("it has a {} file system", fstype))
+        } else {
+            self.mountpoint.as_ref()
+                .map(|mountpoint| format!("it is mounted at {}", mountpoint))
+        }
+    }
+}
+
+/// Returns why the device in the output of `lsblk -J` is in use, if it is.
+fn parse_in_use(output: &str) -> serde_json::Result<Option<String>> {
+    let lsblk: Lsblk = serde_json::from_str(output)?;
+
+    Ok(lsblk.blockdevices.iter().find_map(BlockDevice::in_use))
+}
+
+/// Returns the unused whole disks in the output of `lsblk -J`.
+fn parse_lsblk(output: &str) -> serde_json::Result<Vec<PathBuf>> {
+    let lsblk: Lsblk = serde_json::from_str(output)?;
+
+    Ok(lsblk.blockdevices.into_iter()
+        .filter(|device| device.device_type == "disk" && device.in_use().is_none())
+        .map(|device| device.name)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lsblk() {
+        let output = r#"{
+            "blockdevices": [
+                {"name": "/dev/sda", "type": "disk", "fstype": null, "mountpoint": null,
+                    "children": [
+                        {"name": "/dev/sda1", "type": "part", "fstype": "ext4", "mountpoint": "/"}
+                    ]
+                },
+                {"name": "/dev/sdb", "type": "disk", "fstype": null, "mountpoint": null},
+                {"name": "/dev/sdc", "type": "disk", "fstype": "xfs", "mountpoint": null},
+                {"name": "/dev/sdd", "type": "disk", "fstype": null, "pttype": "gpt", "mountpoint": null},
+                {"name": "/dev/sr0", "type": "rom", "fstype": null, "mountpoint": null}
+            ]
+        }"#;
+
+        assert_eq!(vec![PathBuf::from("/dev/sdb")], parse_lsblk(output).unwrap());
+    }
+
+    #[test]
+    fn test_parse_in_use() {
+        let partitioned = r#"{
+            "blockdevices": [
+                {"name": "/dev/sdb", "type": "disk", "fstype": null, "pttype": "gpt", "mountpoint": null,
+                    "children": [
+                        {"name": "/dev/sdb1", "type": "part", "fstype": null, "pttype": "gpt", "mountpoint": null}
+                    ]
+                }
+            ]
+        }"#;
+        assert_eq!(Some("it has a gpt partition table".to_string()), parse_in_use(partitioned).unwrap());
+
+        let mounted = r#"{
+            "blockdevices": [
+                {"name": "/dev/sdc", "type": "disk", "fstype": null, "pttype": null, "mountpoint": "/mnt"}
+            ]
+        }"#;
+        assert_eq!(Some("it is mounted at /mnt".to_string()), parse_in_use(mounted).unwrap());
+
+        let unused = r#"{
+            "blockdevices": [
+                {"name": "/dev/sdd", "type": "disk", "fstype": null, "pttype": null, "mountpoint": null}
+            ]
+        }"#;
+        assert_eq!(None, parse_in_use(unused).unwrap());
+    }
+}
@@ -0,0 +1,154 @@
+//! The `autoenv` applet.
+//!
+//! It makes experiment parameters visible to all shells by writing
+//! environment variables from `userenv` (and the configuration) to
+//! `/etc/profile.d/miniond.sh` or `/etc/environment`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::overlay;
+use super::{Applet, Sender, Message, recv};
+
+/// `autoenv` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutoenvConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Where to write the variables.
+    target: EnvTarget,
+
+    /// Path to the profile script, for the `profile` target.
+    profile: PathBuf,
+
+    /// Additional variables, overriding those from the testbed.
+    vars: BTreeMap<String, String>,
+}
+
+impl Default for AutoenvConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            target: EnvTarget::Profile,
+            profile: PathBuf::from("/etc/profile.d/miniond.sh"),
+            vars: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum EnvTarget {
+    /// A script sourced by login shells.
+    #[serde(rename = "profile")]
+    Profile,
+
+    /// `/etc/environment`, read by `pam_env` for all sessions.
+    #[serde(rename = "environment")]
+    Environment,
+}
+
+/// The `autoenv` applet.
+#[derive(Debug)]
+pub struct Autoenv {
+    config: Config,
+    tx: Sender,
+
+    /// Whether we wrote any variables.
+    written: AtomicBool,
+}
+
+impl Autoenv {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+            written: AtomicBool::new(false),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autoenv {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autoenv.enable {
+            log::info!("autoenv applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateEnvironment(env) => {
+                    self.apply(env).await?;
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autoenv {
+    /// Write the variables from the testbed and the configuration.
+    async fn apply(&self, env: Vec<(String, String)>) -> Result<()> {
+        let config = &self.config.autoenv;
+
+        let mut vars: BTreeMap<String, String> = env.into_iter().collect();
+        vars.extend(config.vars.clone());
+
+        // Most experiments have none, and we only need to clear
+        // those we wrote
+        if vars.is_empty() && !self.written.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        log::info!("Got {} environment variables", vars.len());
+
+        match config.target {
+            EnvTarget::Profile => {
+                let mut script = String::from("# generated by miniond\n");
+                for (name, value) in &vars {
+                    script.push_str(&format!("export {}={}\n", name, shell_quote(value)));
+                }
+
+                overlay::update_file(&self.config.overlay, &config.profile, script).await?;
+            }
+            EnvTarget::Environment => {
+                let mut section = String::new();
+                for (name, value) in &vars {
+                    section.push_str(&format!("{}=\"{}\"\n", name, value.replace('"', "\\\"")));
+                }
+
+                overlay::update_section(&self.config.overlay, Path::new("/etc/environment"), "env", &section).await?;
+            }
+        }
+
+        self.written.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+/// Quote a value for POSIX shells.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
@@ -1,27 +1,42 @@
 //! The `autohost` applet.
 //!
-//! It sets up the system hostname as well as `/etc/hosts`.
+//! It sets up the system hostname, `/etc/hostname` and `/etc/hosts`.
+//! The hostname is either the FQDN or the short name of the node.
+//!
+//! The last applied name is saved to the state directory, so the
+//! hostname is not set again after a reboot if nothing changed.
 
+use std::io::ErrorKind;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
 use async_trait::async_trait;
-use serde::Deserialize;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, SeekFrom};
+use serde::{Deserialize, Serialize};
+use tokio::fs::read_to_string;
 
 use crate::config::Config;
 use crate::error::Result;
-use super::{Applet, Sender, Message};
+use crate::overlay;
+use crate::plan::{self, Action};
+use crate::state;
+use super::{Applet, Sender, Message, send, recv};
 
 /// `autohost` applet configuration.
 #[derive(Debug, Deserialize)]
 #[serde(default)]
 pub struct AutohostConfig {
     /// Whether to enable the applet or not.
-    enable: bool,
+    pub(super) enable: bool,
 
     /// Path to the hosts file to update (normally /etc/hosts).
     etc_hosts: PathBuf,
+
+    /// Path to the file with the hostname to set at boot, if any.
+    #[serde(rename = "etc-hostname")]
+    etc_hostname: Option<PathBuf>,
+
+    /// Whether to use the FQDN or the short name as the hostname.
+    hostname: HostnameStyle,
 }
 
 impl Default for AutohostConfig {
@@ -29,22 +44,53 @@ impl Default for AutohostConfig {
         Self {
             enable: true,
             etc_hosts: PathBuf::from("/etc/hosts"),
+            etc_hostname: Some(PathBuf::from("/etc/hostname")),
+            hostname: HostnameStyle::Fqdn,
         }
     }
 }
 
+/// The style of the hostname.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostnameStyle {
+    /// The fully-qualified domain name (e.g., `node0.exp.proj.emulab.net`).
+    Fqdn,
+
+    /// The short name (e.g., `node0`).
+    Short,
+}
+
 /// The `autohost` applet.
 #[derive(Debug)]
 pub struct Autohost {
     config: Config,
     tx: Sender,
+
+    /// Canonical name we applied.
+    applied: Mutex<Canonical>,
+}
+
+/// Canonical name of the node, as saved in the state directory.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Canonical {
+    fqdn: String,
+    addresses: Vec<String>,
 }
 
 impl Autohost {
     pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        let applied = if config.autohost.enable {
+            state::load(&config.state, "host").await
+        } else {
+            Canonical::default()
+        };
+
         Ok(Box::new(Self {
             config,
             tx,
+            applied: Mutex::new(applied),
         }))
     }
 }
@@ -60,51 +106,48 @@ impl Applet for Autohost {
         }
 
         loop {
-            let message = rx.recv().await.unwrap();
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
             match message {
                 Message::Shutdown(_) => {
                     break;
                 }
 
-                Message::UpdateCanonical(fqdn, ipv4) => {
-                    log::info!("Updating system hostname...");
-
-                    hostname::set(&fqdn)?;
-
-                    // We add an entry to /etc/hosts so it can be resolved
-                    // instantly
-                    let file = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .create(true)
-                        .open(&self.config.autohost.etc_hosts)
-                        .await?;
+                Message::UpdateCanonical(fqdn, addresses) => {
+                    let canonical = Canonical {
+                        fqdn,
+                        addresses: addresses.iter().map(ToString::to_string).collect(),
+                    };
 
-                    let (mut file, existing_hosts) = {
-                        let mut bytes = Vec::new();
+                    self.apply(canonical).await?;
+                    send(&self.tx, Message::UpdateCanonicalOk);
+                }
 
-                        let reader = BufReader::new(file);
-                        let mut lines = reader.lines();
+                Message::CheckDrift => {
+                    let canonical = self.applied.lock().unwrap().clone();
+                    if canonical.fqdn.is_empty() {
+                        continue;
+                    }
 
-                        // Read everything until our marker
-                        while let Some(line) = lines.next_line().await? {
-                            if line.find("miniond").is_some() {
-                                break;
-                            }
+                    let mut fixes = Vec::new();
 
-                            bytes.extend_from_slice(line.as_bytes());
-                            bytes.push('\n' as u8);
-                        }
+                    let name = self.hostname(&canonical);
+                    if !hostname::get().is_ok_and(|current| current == name.as_str()) {
+                        fixes.push(format!("hostname was changed from {}", name));
+                    }
 
-                        (lines.into_inner().into_inner(), bytes)
-                    };
+                    let existing = self.read_hosts().await?;
+                    if render_hosts(&existing, &canonical) != existing {
+                        fixes.push(format!("entries for {} in {:?} were changed", canonical.fqdn, self.config.autohost.etc_hosts));
+                    }
 
-                    file.seek(SeekFrom::Start(0)).await?;
-                    file.set_len(0).await?;
+                    if !fixes.is_empty() {
+                        self.apply(canonical).await?;
 
-                    file.write_all(&existing_hosts).await?;
-                    file.write_all("# the following is generated by miniond\n".as_bytes()).await?;
-                    file.write_all(format!("{} {}\n", ipv4, fqdn).as_bytes()).await?;
+                        send(&self.tx, Message::DriftRepaired("autohost", fixes));
+                    }
                 }
 
                 _ => {}
@@ -114,3 +157,90 @@ impl Applet for Autohost {
         Ok(())
     }
 }
+
+impl Autohost {
+    /// Set the hostname and update the hosts file.
+    async fn apply(&self, canonical: Canonical) -> Result<()> {
+        let _lock = state::lock(&self.config.state, "host").await?;
+
+        let name = self.hostname(&canonical);
+        let unchanged = *self.applied.lock().unwrap() == canonical
+            && hostname::get().is_ok_and(|current| current == name.as_str());
+
+        if unchanged {
+            log::debug!("System hostname is up to date");
+        } else {
+            log::info!("Updating system hostname to {}...", name);
+
+            if plan::is_dry_run() {
+                plan::record(Action::SetHostname {
+                    hostname: name.clone(),
+                });
+            } else {
+                hostname::set(&name)?;
+            }
+        }
+
+        if let Some(path) = &self.config.autohost.etc_hostname {
+            overlay::update_file(&self.config.overlay, path, format!("{}\n", name)).await?;
+        }
+
+        let hosts = render_hosts(&self.read_hosts().await?, &canonical);
+        overlay::update_file(&self.config.overlay, &self.config.autohost.etc_hosts, hosts).await?;
+
+        state::save(&self.config.state, "host", &canonical).await?;
+        *self.applied.lock().unwrap() = canonical;
+
+        Ok(())
+    }
+
+    /// Returns the hostname to set.
+    fn hostname(&self, canonical: &Canonical) -> String {
+        match self.config.autohost.hostname {
+            HostnameStyle::Fqdn => canonical.fqdn.clone(),
+            HostnameStyle::Short => short_name(&canonical.fqdn).to_string(),
+        }
+    }
+
+    /// Returns the contents of the hosts file.
+    async fn read_hosts(&self) -> Result<String> {
+        match read_to_string(&self.config.autohost.etc_hosts).await {
+            Ok(s) => Ok(s),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Returns the hosts file with our entries.
+///
+/// We add an entry to /etc/hosts so it can be resolved instantly,
+/// replacing everything after our marker.
+fn render_hosts(existing: &str, canonical: &Canonical) -> String {
+    let mut hosts = String::new();
+    for line in existing.lines() {
+        if line.contains("miniond") {
+            break;
+        }
+
+        hosts.push_str(line);
+        hosts.push('\n');
+    }
+
+    hosts.push_str("# the following is generated by miniond\n");
+    let short = short_name(&canonical.fqdn);
+    for address in &canonical.addresses {
+        if short == canonical.fqdn {
+            hosts.push_str(&format!("{} {}\n", address, canonical.fqdn));
+        } else {
+            hosts.push_str(&format!("{} {} {}\n", address, canonical.fqdn, short));
+        }
+    }
+
+    hosts
+}
+
+/// Returns the short name of an FQDN.
+fn short_name(fqdn: &str) -> &str {
+    fqdn.split('.').next().unwrap_or(fqdn)
+}
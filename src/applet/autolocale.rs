@@ -0,0 +1,207 @@
+//! The `autolocale` applet.
+//!
+//! It sets the timezone and locale of the system from the
+//! cluster-wide settings in `localization`, so logs and cron
+//! schedules match what the testbed expects.
+
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::fs;
+use which::which;
+
+use crate::config::Config;
+use crate::command::run_command;
+use crate::error::{Error, Result};
+use crate::overlay;
+use crate::plan::{self, Action};
+use crate::tmcc::Localization;
+use super::{Applet, Sender, Message, recv};
+
+/// `autolocale` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutolocaleConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Whether to set the timezone.
+    timezone: bool,
+
+    /// Whether to set the locale.
+    locale: bool,
+
+    /// Path to the timezone database.
+    #[serde(rename = "zoneinfo-dir")]
+    zoneinfo_dir: PathBuf,
+}
+
+impl Default for AutolocaleConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            timezone: true,
+            locale: true,
+            zoneinfo_dir: PathBuf::from("/usr/share/zoneinfo"),
+        }
+    }
+}
+
+/// The `autolocale` applet.
+#[derive(Debug)]
+pub struct Autolocale {
+    config: Config,
+    tx: Sender,
+
+    /// Whether to go through systemd (`timedatectl`, `localectl`).
+    systemd: bool,
+}
+
+impl Autolocale {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        let systemd = Path::new("/run/systemd/system").exists()
+            && which("timedatectl").is_ok()
+            && which("localectl").is_ok();
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            systemd,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autolocale {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autolocale.enable {
+            log::info!("autolocale applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateLocalization(localization) => {
+                    self.apply(&localization).await?;
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autolocale {
+    /// Apply the settings that the testbed specifies.
+    async fn apply(&self, localization: &Localization) -> Result<()> {
+        let config = &self.config.autolocale;
+
+        if let (true, Some(timezone)) = (config.timezone, &localization.timezone) {
+            self.set_timezone(timezone).await?;
+        }
+
+        if let (true, Some(locale)) = (config.locale, &localization.locale) {
+            self.set_locale(locale).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the timezone, unless it's already in effect.
+    async fn set_timezone(&self, timezone: &str) -> Result<()> {
+        // The name is used as a path in the timezone database
+        let relative = Path::new(timezone);
+        let zoneinfo = self.config.autolocale.zoneinfo_dir.join(relative);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) || !zoneinfo.is_file() {
+            return Err(Error::InvalidTimezone { timezone: timezone.to_string() });
+        }
+
+        if fs::read_link("/etc/localtime").await.ok().as_deref() == Some(&zoneinfo) {
+            log::debug!("Timezone is already {}", timezone);
+            return Ok(());
+        }
+
+        log::info!("Setting timezone to {}...", timezone);
+
+        if plan::is_dry_run() {
+            plan::record(Action::SetTimezone {
+                timezone: timezone.to_string(),
+            });
+            return Ok(());
+        }
+
+        if self.systemd {
+            run_command(Command::new("timedatectl").arg("set-timezone").arg(timezone)).await
+                .map_err(|reason| Error::Timezone { timezone: timezone.to_string(), reason })?;
+        } else {
+            let localtime = Path::new("/etc/localtime");
+            let tmp = localtime.with_file_name(".localtime.miniond");
+
+            let _ = fs::remove_file(&tmp).await;
+            fs::symlink(&zoneinfo, &tmp).await?;
+            fs::rename(&tmp, localtime).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the default locale, unless it's already in effect.
+    async fn set_locale(&self, locale: &str) -> Result<()> {
+        if current_locale().await.as_deref() == Some(locale) {
+            log::debug!("Locale is already {}", locale);
+            return Ok(());
+        }
+
+        log::info!("Setting locale to {}...", locale);
+
+        if plan::is_dry_run() {
+            plan::record(Action::SetLocale {
+                locale: locale.to_string(),
+            });
+            return Ok(());
+        }
+
+        if self.systemd {
+            run_command(Command::new("localectl").arg("set-locale").arg(format!("LANG={}", locale))).await
+                .map_err(|reason| Error::Locale { locale: locale.to_string(), reason })?;
+        } else {
+            let contents = format!("LANG={}\n", locale);
+            overlay::update_file(&self.config.overlay, Path::new("/etc/locale.conf"), contents).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the default locale of the system, if set.
+///
+/// `localectl` writes `/etc/default/locale` on Debian derivatives.
+async fn current_locale() -> Option<String> {
+    for path in ["/etc/locale.conf", "/etc/default/locale"] {
+        if let Ok(contents) = fs::read_to_string(path).await {
+            let lang = contents.lines()
+                .filter_map(|line| line.trim().strip_prefix("LANG="))
+                .next_back();
+
+            if let Some(lang) = lang {
+                return Some(lang.trim_matches('"').to_string());
+            }
+        }
+    }
+
+    None
+}
@@ -0,0 +1,185 @@
+//! The `automotd` applet.
+//!
+//! It renders `/etc/motd` (and optionally `/etc/issue`) with
+//! information about the experiment the node is allocated to.
+//!
+//! The template may contain the following placeholders:
+//!
+//! - `{node}`: Nickname of the node
+//! - `{experiment}`: Name of the experiment
+//! - `{project}`: Name of the project
+//! - `{expires}`: When the experiment expires
+//! - `{mounts}`: Mounted paths, one per line
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::fs::read_to_string;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::overlay;
+use crate::tmcc::AllocationStatus;
+use super::{Applet, Sender, Message, recv};
+
+const DEFAULT_TEMPLATE: &str = "\
+This is {node} of experiment {experiment} in project {project}.
+The experiment expires at {expires}.
+
+Mounted filesystems:
+{mounts}
+";
+
+const UNALLOCATED: &str = "This node is not allocated to any experiment.\n";
+
+/// `automotd` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutomotdConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Path to the motd file.
+    motd: PathBuf,
+
+    /// Path to the issue file.
+    ///
+    /// If unset, the issue file is left alone.
+    issue: Option<PathBuf>,
+
+    /// Path to the template.
+    ///
+    /// If unset, a built-in template is used.
+    template: Option<PathBuf>,
+}
+
+impl Default for AutomotdConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            motd: PathBuf::from("/etc/motd"),
+            issue: None,
+            template: None,
+        }
+    }
+}
+
+/// The `automotd` applet.
+#[derive(Debug)]
+pub struct Automotd {
+    config: Config,
+    tx: Sender,
+    template: String,
+
+    /// What we know about the experiment.
+    info: Mutex<ExperimentInfo>,
+}
+
+/// Information shown in the motd.
+#[derive(Debug, Default)]
+struct ExperimentInfo {
+    allocation: Option<AllocationStatus>,
+    expires: Option<String>,
+    mounts: Vec<PathBuf>,
+}
+
+impl Automotd {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        let template = match &config.automotd.template {
+            Some(path) if config.automotd.enable => read_to_string(path).await?,
+            _ => DEFAULT_TEMPLATE.to_string(),
+        };
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            template,
+            info: Mutex::new(ExperimentInfo::default()),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Automotd {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.automotd.enable {
+            log::info!("automotd applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            {
+                let mut info = self.info.lock().unwrap();
+
+                match message {
+                    Message::Shutdown(_) => {
+                        break;
+                    }
+                    Message::UpdateAllocation(allocation) => {
+                        info.allocation = Some(allocation);
+                    }
+                    Message::UpdateExpiration(expires) => {
+                        info.expires = Some(expires);
+                    }
+                    Message::UpdateMounts(mounts) => {
+                        info.mounts = mounts.iter().map(|mount| mount.local().to_path_buf()).collect();
+                    }
+                    Message::Swapout => {
+                        *info = ExperimentInfo::default();
+                    }
+                    _ => continue,
+                }
+            }
+
+            self.apply().await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Automotd {
+    /// Render and write the motd and issue files.
+    async fn apply(&self) -> Result<()> {
+        let contents = render(&self.template, &self.info.lock().unwrap());
+
+        overlay::update_file(&self.config.overlay, &self.config.automotd.motd, contents.clone()).await?;
+
+        if let Some(issue) = &self.config.automotd.issue {
+            overlay::update_file(&self.config.overlay, issue, contents).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Render the template.
+fn render(template: &str, info: &ExperimentInfo) -> String {
+    let allocation = match &info.allocation {
+        Some(allocation) => allocation,
+        None => return UNALLOCATED.to_string(),
+    };
+
+    // `$project/$experiment`
+    let (project, experiment) = allocation.experiment.split_once('/')
+        .unwrap_or(("", &allocation.experiment));
+
+    let mounts: Vec<String> = info.mounts.iter()
+        .map(|local| format!("  {}", local.display()))
+        .collect();
+
+    template
+        .replace("{node}", &allocation.node_name)
+        .replace("{experiment}", experiment)
+        .replace("{project}", project)
+        .replace("{expires}", info.expires.as_deref().unwrap_or("an unknown time"))
+        .replace("{mounts}", &mounts.join("\n"))
+}
@@ -1,6 +1,12 @@
 //! The `automount` applet.
 //!
 //! It mounts NFS shares configured in the experiment profile.
+//!
+//! The mounts are saved to the state directory, so shares that were
+//! removed from the experiment are unmounted even across reboots.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
 
 use async_trait::async_trait;
 use serde::Deserialize;
@@ -8,18 +14,50 @@ use which::which;
 
 use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::mount::Backend;
-use super::{Applet, Sender, Message};
+use crate::mount::{self, Backend, FscacheConfig, Mount, NfsConfig};
+use crate::overlay;
+use crate::plan;
+use crate::state;
+use super::{Applet, Sender, Message, send, recv};
 
 /// `autouser` applet configuration.
 #[derive(Debug, Deserialize)]
 #[serde(default)]
 pub struct AutomountConfig {
     /// Whether to enable the applet or not.
-    enable: bool,
+    pub(super) enable: bool,
 
     /// The backend to use for mounting.
     backend: BackendConfig,
+
+    /// Path of `fstab` for the `fstab` backend.
+    fstab: PathBuf,
+
+    /// Directory of SMB credential files for the `fstab` and `autofs`
+    /// backends.
+    #[serde(rename = "credentials-dir")]
+    credentials_dir: PathBuf,
+
+    /// Path of the `auto.master` snippet for the `autofs` backend.
+    #[serde(rename = "autofs-master")]
+    autofs_master: PathBuf,
+
+    /// Path of the direct map for the `autofs` backend.
+    #[serde(rename = "autofs-map")]
+    autofs_map: PathBuf,
+
+    /// Caching of NFS shares on local disk.
+    fscache: FscacheConfig,
+
+    /// Options of NFS mounts.
+    nfs: NfsConfig,
+
+    /// Whether to unmount shares that were removed from the experiment.
+    ///
+    /// This also removes mount units we generated that are not in the
+    /// saved state, so instances sharing a unit directory (e.g., for
+    /// virtual nodes) should disable it.
+    cleanup: bool,
 }
 
 impl Default for AutomountConfig {
@@ -27,6 +65,13 @@ impl Default for AutomountConfig {
         Self {
             enable: true,
             backend: BackendConfig::Systemd,
+            fstab: PathBuf::from("/etc/fstab"),
+            credentials_dir: PathBuf::from("/etc/miniond/credentials"),
+            autofs_master: PathBuf::from("/etc/auto.master.d/miniond.autofs"),
+            autofs_map: PathBuf::from("/etc/auto.miniond"),
+            fscache: FscacheConfig::default(),
+            nfs: NfsConfig::default(),
+            cleanup: true,
         }
     }
 }
@@ -36,6 +81,14 @@ pub enum BackendConfig {
     /// Use systemd for mounting.
     #[serde(rename = "systemd")]
     Systemd,
+
+    /// Manage `/etc/fstab` and run `mount` directly.
+    #[serde(rename = "fstab")]
+    Fstab,
+
+    /// Mount on demand with autofs.
+    #[serde(rename = "autofs")]
+    Autofs,
 }
 
 /// The `autouser` applet.
@@ -43,20 +96,43 @@ pub enum BackendConfig {
 pub struct Automount {
     config: Config,
     tx: Sender,
+
+    /// Mounts we applied.
+    mounts: Mutex<Vec<Mount>>,
 }
 
 impl Automount {
     pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
-        if config.automount.backend == BackendConfig::Systemd {
-            if which("systemctl").is_err() {
-                log::error!("The `systemctl` binary must be in PATH");
-                return Err(Error::UnmetSystemRequirements);
-            }
+        if config.automount.enable && !plan::is_dry_run() && config.automount.backend == BackendConfig::Systemd && which("systemctl").is_err() {
+            log::error!("The `systemctl` binary must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        if config.automount.enable && !plan::is_dry_run() && config.automount.backend == BackendConfig::Fstab && (which("mount").is_err() || which("umount").is_err()) {
+            log::error!("The `mount` and `umount` binaries must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        if config.automount.enable && !plan::is_dry_run() && config.automount.backend == BackendConfig::Autofs && (which("automount").is_err() || which("systemctl").is_err()) {
+            log::error!("The `automount` and `systemctl` binaries must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        if config.automount.enable && !plan::is_dry_run() && config.automount.fscache.enable && which("cachefilesd").is_err() {
+            log::error!("The `cachefilesd` binary must be in PATH to use FS-Cache");
+            return Err(Error::UnmetSystemRequirements);
         }
 
+        let mounts = if config.automount.enable {
+            state::load(&config.state, "mounts").await
+        } else {
+            Vec::new()
+        };
+
         Ok(Box::new(Self {
             config,
             tx,
+            mounts: Mutex::new(mounts),
         }))
     }
 }
@@ -72,24 +148,93 @@ impl Applet for Automount {
         }
 
         let backend = match self.config.automount.backend {
-            BackendConfig::Systemd => Backend::Systemd(self.config.systemd.unit_dir.clone()),
+            BackendConfig::Systemd => {
+                let unit_dir = if plan::is_dry_run() {
+                    self.config.systemd.unit_dir.clone()
+                } else {
+                    overlay::writable_dir(&self.config.overlay, &self.config.systemd.unit_dir).await?
+                };
+                Backend::Systemd(unit_dir)
+            }
+            BackendConfig::Fstab => Backend::Fstab {
+                fstab: self.config.automount.fstab.clone(),
+                credentials_dir: self.config.automount.credentials_dir.clone(),
+            },
+            BackendConfig::Autofs => Backend::Autofs {
+                master: self.config.automount.autofs_master.clone(),
+                map: self.config.automount.autofs_map.clone(),
+                credentials_dir: self.config.automount.credentials_dir.clone(),
+            },
         };
 
+        if self.config.automount.fscache.enable {
+            mount::setup_fscache(&self.config.overlay, &self.config.automount.fscache).await?;
+        }
+
         loop {
-            let message = rx.recv().await.unwrap();
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
             match message {
                 Message::Shutdown(_) => {
                     break;
                 }
 
-                Message::UpdateMounts(mounts) => {
+                Message::UpdateMounts(mut mounts) => {
                     log::info!("Got new mount configurations ({} mounts)", mounts.len());
 
-                    for mount in mounts {
-                        mount.apply(backend.clone()).await?;
+                    mount::apply_nfs_options(&mut mounts, &self.config.automount.nfs);
+
+                    if self.config.automount.fscache.enable {
+                        mount::enable_fscache(&mut mounts);
                     }
 
-                    self.tx.send(Message::UpdateMountsOk).unwrap();
+                    let _lock = state::lock(&self.config.state, "mounts").await?;
+
+                    let previous = std::mem::take(&mut *self.mounts.lock().unwrap());
+                    let mut stale: Vec<Mount> = previous.into_iter()
+                        .filter(|old| !mounts.iter().any(|new| new.local() == old.local()))
+                        .collect();
+
+                    if self.config.automount.cleanup {
+                        if let Backend::Systemd(unit_dir) = &backend {
+                            let known: Vec<Mount> = mounts.iter().chain(&stale).cloned().collect();
+                            stale.extend(mount::orphaned_units(unit_dir, &known).await?);
+                        }
+
+                        mount::remove_all(&stale, backend.clone(), &self.config.overlay).await?;
+                    } else if !stale.is_empty() {
+                        log::info!("Leaving {} mounts removed from the experiment in place", stale.len());
+                    }
+                    mount::apply_all(&mounts, backend.clone(), &self.config.overlay).await?;
+
+                    state::save(&self.config.state, "mounts", &mounts).await?;
+                    *self.mounts.lock().unwrap() = mounts;
+
+                    send(&self.tx, Message::UpdateMountsOk);
+                }
+
+                Message::CheckDrift => {
+                    let _lock = state::lock(&self.config.state, "mounts").await?;
+                    let mounts = self.mounts.lock().unwrap().clone();
+                    let unmounted = mount::unmounted(&mounts).await?;
+
+                    if !unmounted.is_empty() {
+                        mount::start_all(&unmounted, backend.clone()).await?;
+
+                        let fixes = unmounted.iter()
+                            .map(|mount| format!("{:?} was not mounted", mount.local()))
+                            .collect();
+                        send(&self.tx, Message::DriftRepaired("automount", fixes));
+                    }
+                }
+
+                Message::RemoveMounts => {
+                    let _lock = state::lock(&self.config.state, "mounts").await?;
+                    let mounts = std::mem::take(&mut *self.mounts.lock().unwrap());
+                    mount::remove_all(&mounts, backend.clone(), &self.config.overlay).await?;
+                    state::save(&self.config.state, "mounts", &Vec::<Mount>::new()).await?;
                 }
 
                 _ => {}
@@ -0,0 +1,167 @@
+//! The `autonet` applet.
+//!
+//! It configures the experiment interfaces (`ifconfig`), like
+//! `rc.ifconfig` of the official clientside: addresses and MTUs are
+//! set on the physical links, and VLAN interfaces are created on top
+//! of them.
+//!
+//! Interfaces can be configured directly with `ip`, or through
+//! systemd-networkd files so the configuration survives restarts of
+//! networkd. The configured interfaces are saved to the state
+//! directory, so interfaces that were removed from the experiment are
+//! unconfigured even across reboots.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use which::which;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::network::{self, Backend, Link};
+use crate::net::InterfaceConfig;
+use crate::overlay;
+use crate::plan;
+use crate::state;
+use super::{Applet, Sender, Message, send, recv};
+
+/// `autonet` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutonetConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// The backend to use for configuring interfaces.
+    backend: BackendConfig,
+
+    /// Directory of systemd-networkd files for the `networkd` backend.
+    #[serde(rename = "network-dir")]
+    network_dir: PathBuf,
+}
+
+impl Default for AutonetConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            backend: BackendConfig::Direct,
+            network_dir: PathBuf::from("/etc/systemd/network"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum BackendConfig {
+    /// Run `ip` directly.
+    #[serde(rename = "direct")]
+    Direct,
+
+    /// Write systemd-networkd files.
+    #[serde(rename = "networkd")]
+    Networkd,
+}
+
+/// The `autonet` applet.
+#[derive(Debug)]
+pub struct Autonet {
+    config: Config,
+    tx: Sender,
+
+    /// Links we configured.
+    links: Mutex<Vec<Link>>,
+}
+
+impl Autonet {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        if config.autonet.enable && !plan::is_dry_run() && config.autonet.backend == BackendConfig::Direct && which("ip").is_err() {
+            log::error!("The `ip` binary must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        if config.autonet.enable && !plan::is_dry_run() && config.autonet.backend == BackendConfig::Networkd && which("networkctl").is_err() {
+            log::error!("The `networkctl` binary must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        let links = if config.autonet.enable {
+            state::load(&config.state, "interfaces").await
+        } else {
+            Vec::new()
+        };
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            links: Mutex::new(links),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autonet {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autonet.enable {
+            log::info!("autonet applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateInterfaces(interfaces) => {
+                    self.apply(&interfaces).await?;
+                    send(&self.tx, Message::UpdateInterfacesOk);
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autonet {
+    /// Configure the experiment interfaces.
+    async fn apply(&self, interfaces: &[InterfaceConfig]) -> Result<()> {
+        let _lock = state::lock(&self.config.state, "interfaces").await?;
+
+        let links = network::links().await?;
+        let links = network::resolve(interfaces, &links);
+
+        let backend = match self.config.autonet.backend {
+            BackendConfig::Direct => Backend::Direct,
+            BackendConfig::Networkd => {
+                let network_dir = if plan::is_dry_run() {
+                    self.config.autonet.network_dir.clone()
+                } else {
+                    overlay::writable_dir(&self.config.overlay, &self.config.autonet.network_dir).await?
+                };
+                Backend::Networkd(network_dir)
+            }
+        };
+
+        let stale: Vec<Link> = self.links.lock().unwrap().iter()
+            .filter(|old| !links.contains(old))
+            .cloned()
+            .collect();
+
+        network::apply_all(&links, &stale, &backend).await?;
+
+        *self.links.lock().unwrap() = links.clone();
+        state::save(&self.config.state, "interfaces", &links).await?;
+
+        Ok(())
+    }
+}
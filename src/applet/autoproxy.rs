@@ -0,0 +1,244 @@
+//! The `autoproxy` applet.
+//!
+//! Some clusters only allow external access through an HTTP(S)
+//! proxy. This applet configures the proxy in `/etc/environment`
+//! and for package managers and Docker, so users don't need to
+//! rediscover it on every node.
+//!
+//! The proxy comes from `localization`, and can be overridden in
+//! the configuration.
+
+use std::path::Path;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::process::Command;
+use which::which;
+
+use crate::config::Config;
+use crate::command::run_command;
+use crate::error::{Error, Result};
+use crate::overlay;
+use crate::plan;
+use crate::tmcc::Localization;
+use super::{Applet, Sender, Message, recv};
+
+/// `autoproxy` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutoproxyConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Proxy for HTTP, overriding the testbed.
+    #[serde(rename = "http-proxy")]
+    http_proxy: Option<String>,
+
+    /// Proxy for HTTPS, overriding the testbed.
+    #[serde(rename = "https-proxy")]
+    https_proxy: Option<String>,
+
+    /// Hosts to reach without the proxy, overriding the testbed.
+    #[serde(rename = "no-proxy")]
+    no_proxy: Option<String>,
+
+    /// Whether to configure `/etc/environment`.
+    environment: bool,
+
+    /// Whether to configure apt (if installed).
+    apt: bool,
+
+    /// Whether to configure dnf (if installed).
+    dnf: bool,
+
+    /// Whether to configure Docker (if installed).
+    docker: bool,
+}
+
+impl Default for AutoproxyConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+            environment: true,
+            apt: true,
+            dnf: true,
+            docker: true,
+        }
+    }
+}
+
+/// The `autoproxy` applet.
+#[derive(Debug)]
+pub struct Autoproxy {
+    config: Config,
+    tx: Sender,
+}
+
+/// Proxy settings in effect.
+#[derive(Debug)]
+struct Proxy {
+    http: Option<String>,
+    https: Option<String>,
+    no_proxy: Option<String>,
+}
+
+impl Autoproxy {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autoproxy {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autoproxy.enable {
+            log::info!("autoproxy applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateLocalization(localization) => {
+                    self.apply(&localization).await?;
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autoproxy {
+    /// Configure the proxy, if there is one.
+    async fn apply(&self, localization: &Localization) -> Result<()> {
+        let config = &self.config.autoproxy;
+
+        let proxy = Proxy {
+            http: config.http_proxy.clone().or_else(|| localization.http_proxy.clone()),
+            https: config.https_proxy.clone().or_else(|| localization.https_proxy.clone()),
+            no_proxy: config.no_proxy.clone().or_else(|| localization.no_proxy.clone()),
+        };
+
+        if proxy.http.is_none() && proxy.https.is_none() {
+            log::debug!("No proxy to configure");
+            return Ok(());
+        }
+
+        if config.environment {
+            overlay::update_section(&self.config.overlay, Path::new("/etc/environment"), "proxy", &environment(&proxy)).await?;
+        }
+
+        let apt_dir = Path::new("/etc/apt/apt.conf.d");
+        if config.apt && apt_dir.is_dir() {
+            overlay::update_file(&self.config.overlay, &apt_dir.join("95miniond-proxy"), apt(&proxy)).await?;
+        }
+
+        // dnf has no drop-in directory for its main section, which
+        // is normally the only section in dnf.conf, so we append to it
+        let dnf_conf = Path::new("/etc/dnf/dnf.conf");
+        if config.dnf && dnf_conf.is_file() {
+            overlay::update_section(&self.config.overlay, dnf_conf, "proxy", &dnf(&proxy)).await?;
+        }
+
+        if config.docker && which("dockerd").is_ok() {
+            self.update_docker(&proxy).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a drop-in for the Docker daemon.
+    ///
+    /// Docker only picks it up when it's restarted, which we leave
+    /// to the user to not disrupt running containers.
+    async fn update_docker(&self, proxy: &Proxy) -> Result<()> {
+        let drop_in = self.config.systemd.unit_dir.join("docker.service.d/http-proxy.conf");
+
+        if !overlay::update_file(&self.config.overlay, &drop_in, docker(proxy)).await? {
+            return Ok(());
+        }
+
+        log::info!("Docker will use the proxy after it's restarted");
+
+        if plan::is_dry_run() {
+            return Ok(());
+        }
+
+        run_command(Command::new("systemctl").arg("daemon-reload")).await
+            .map_err(|reason| Error::ServiceReload { unit: "docker.service".to_string(), reason })?;
+
+        Ok(())
+    }
+}
+
+/// Returns the variables for `/etc/environment`.
+///
+/// Programs disagree on the case of the names, so we set both.
+fn environment(proxy: &Proxy) -> String {
+    let mut env = String::new();
+
+    for (name, value) in [("http_proxy", &proxy.http), ("https_proxy", &proxy.https), ("no_proxy", &proxy.no_proxy)] {
+        if let Some(value) = value {
+            env.push_str(&format!("{}=\"{}\"\n", name, value));
+            env.push_str(&format!("{}=\"{}\"\n", name.to_uppercase(), value));
+        }
+    }
+
+    env
+}
+
+/// Returns the apt configuration.
+fn apt(proxy: &Proxy) -> String {
+    let mut conf = String::from("// generated by miniond\n");
+
+    if let Some(http) = &proxy.http {
+        conf.push_str(&format!("Acquire::http::Proxy \"{}\";\n", http));
+    }
+
+    if let Some(https) = &proxy.https {
+        conf.push_str(&format!("Acquire::https::Proxy \"{}\";\n", https));
+    }
+
+    conf
+}
+
+/// Returns the dnf configuration.
+///
+/// dnf uses the same proxy for all protocols.
+fn dnf(proxy: &Proxy) -> String {
+    match proxy.https.as_ref().or(proxy.http.as_ref()) {
+        Some(url) => format!("proxy={}\n", url),
+        None => String::new(),
+    }
+}
+
+/// Returns the systemd drop-in for Docker.
+fn docker(proxy: &Proxy) -> String {
+    let mut drop_in = String::from("# generated by miniond\n[Service]\n");
+
+    for (name, value) in [("HTTP_PROXY", &proxy.http), ("HTTPS_PROXY", &proxy.https), ("NO_PROXY", &proxy.no_proxy)] {
+        if let Some(value) = value {
+            drop_in.push_str(&format!("Environment=\"{}={}\"\n", name, value));
+        }
+    }
+
+    drop_in
+}
@@ -0,0 +1,99 @@
+//! The `autorepair` applet.
+//!
+//! It periodically asks the other applets to compare what they last
+//! applied with the actual system, and to repair whatever drifted
+//! (e.g., users deleted by hand, unmounted shares, or a changed
+//! hostname). Long experiments accumulate manual changes and reboots
+//! that would otherwise silently break the node.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::error::Result;
+use super::{Applet, Sender, Message, send, recv};
+
+/// `autorepair` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutorepairConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// How often to check for drift, in seconds.
+    interval: u64,
+}
+
+impl Default for AutorepairConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            interval: 600,
+        }
+    }
+}
+
+/// The `autorepair` applet.
+#[derive(Debug)]
+pub struct Autorepair {
+    config: Config,
+    tx: Sender,
+}
+
+impl Autorepair {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autorepair {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autorepair.enable {
+            log::info!("autorepair applet disabled in config");
+            return Ok(());
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.autorepair.interval.max(1)));
+
+        // Everything was just applied
+        interval.tick().await;
+
+        loop {
+            let message = tokio::select! {
+                message = recv(&mut rx) => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = interval.tick() => {
+                    log::debug!("Checking for drift...");
+                    send(&self.tx, Message::CheckDrift);
+                    continue;
+                }
+            };
+
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::DriftRepaired(applet, fixes) => {
+                    for fix in fixes {
+                        log::warn!("Repaired drift ({}): {}", applet, fix);
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
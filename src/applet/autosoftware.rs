@@ -0,0 +1,307 @@
+//! The `autosoftware` applet.
+//!
+//! It installs the tarballs and RPMs of the experiment (`tarballs`
+//! and `rpms`), like `install-tarfile` and `install-rpm` of the
+//! official clientside. They are read from shared file systems
+//! (e.g., `/proj`), so we wait for the mounts, or downloaded with
+//! `curl` if they're URLs.
+//!
+//! What was installed is saved to the state directory along with its
+//! checksum, so unchanged software isn't installed again on every
+//! reload or after a reboot. Once everything is installed,
+//! `UpdateSoftwareOk` is broadcast, so the startup command can wait
+//! for it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use tokio::fs::create_dir_all;
+use tokio::process::Command;
+
+use crate::blocking;
+use crate::command::run_command;
+use crate::config::Config;
+use crate::error::{Error, FileSnafu, Result};
+use crate::plan::{self, Action};
+use crate::state;
+use crate::tmcc::{Rpm, Tarball};
+use super::{Applet, Sender, Message, send, recv};
+
+/// The delay before the first download retry, doubled each time.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// `autosoftware` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutosoftwareConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Number of times to retry a failed download.
+    retries: u32,
+
+    /// Expected SHA-256 digests of tarballs and RPMs, in hex, by
+    /// path or URL.
+    checksums: HashMap<String, String>,
+
+    /// Directory to download tarballs and RPMs to.
+    #[serde(rename = "download-dir")]
+    download_dir: PathBuf,
+}
+
+impl Default for AutosoftwareConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            retries: 3,
+            checksums: HashMap::new(),
+            download_dir: PathBuf::from("/var/cache/miniond/software"),
+        }
+    }
+}
+
+/// Software of the experiment.
+#[derive(Debug, Default)]
+pub struct Software {
+    pub tarballs: Vec<Tarball>,
+    pub rpms: Vec<Rpm>,
+}
+
+/// A tarball or RPM we installed, as saved in the state directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Installed {
+    source: String,
+
+    /// Directory the tarball was unpacked into, or `None` for RPMs.
+    dir: Option<PathBuf>,
+
+    sha256: String,
+}
+
+/// The `autosoftware` applet.
+#[derive(Debug)]
+pub struct Autosoftware {
+    config: Config,
+    tx: Sender,
+
+    /// Software we installed.
+    installed: Mutex<Vec<Installed>>,
+
+    /// Software waiting for the mounts to be applied.
+    pending: Mutex<Option<Arc<Software>>>,
+
+    /// Whether shared file systems may be mounted.
+    mounts_ready: AtomicBool,
+}
+
+impl Autosoftware {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        let installed = if config.autosoftware.enable {
+            state::load(&config.state, "software").await
+        } else {
+            Vec::new()
+        };
+
+        // Software usually lives on `/proj`, mounted by `automount`
+        let mounts_ready = !config.automount.enable;
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            installed: Mutex::new(installed),
+            pending: Mutex::new(None),
+            mounts_ready: AtomicBool::new(mounts_ready),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autosoftware {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autosoftware.enable {
+            log::info!("autosoftware applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateSoftware(software) => {
+                    if self.mounts_ready.load(Ordering::Relaxed) {
+                        self.install(&software).await?;
+                    } else {
+                        log::debug!("Waiting for the mounts before installing software");
+                        *self.pending.lock().unwrap() = Some(software);
+                    }
+                }
+
+                Message::UpdateMountsOk => {
+                    self.mounts_ready.store(true, Ordering::Relaxed);
+
+                    let pending = self.pending.lock().unwrap().take();
+                    if let Some(software) = pending {
+                        self.install(&software).await?;
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autosoftware {
+    /// Install all tarballs and RPMs.
+    async fn install(&self, software: &Software) -> Result<()> {
+        let _lock = state::lock(&self.config.state, "software").await?;
+
+        for tarball in &software.tarballs {
+            self.install_one(&tarball.source, Some(&tarball.dir)).await?;
+        }
+
+        for rpm in &software.rpms {
+            self.install_one(&rpm.source, None).await?;
+        }
+
+        let installed = self.installed.lock().unwrap().clone();
+        state::save(&self.config.state, "software", &installed).await?;
+
+        send(&self.tx, Message::UpdateSoftwareOk);
+
+        Ok(())
+    }
+
+    /// Install a tarball into a directory, or an RPM.
+    async fn install_one(&self, source: &str, dir: Option<&Path>) -> Result<()> {
+        if plan::is_dry_run() {
+            plan::record(match dir {
+                Some(dir) => Action::InstallTarball { source: source.to_string(), dir: dir.to_path_buf() },
+                None => Action::InstallRpm { source: source.to_string() },
+            });
+
+            return Ok(());
+        }
+
+        let install_error = |reason| Error::SoftwareInstall { package: source.to_string(), reason };
+
+        let path = self.fetch(source).await?;
+        let sha256 = sha256_file(path.clone()).await?;
+
+        if let Some(expected) = self.config.autosoftware.checksums.get(source) {
+            if !expected.eq_ignore_ascii_case(&sha256) {
+                return Err(install_error(format!("SHA-256 mismatch (expected {}, got {})", expected, sha256)));
+            }
+        }
+
+        let installed = Installed {
+            source: source.to_string(),
+            dir: dir.map(Path::to_path_buf),
+            sha256,
+        };
+
+        if self.installed.lock().unwrap().contains(&installed) {
+            log::debug!("{} is already installed", source);
+            return Ok(());
+        }
+
+        match dir {
+            Some(dir) => {
+                log::info!("Unpacking {} into {:?}...", source, dir);
+
+                create_dir_all(dir).await
+                    .context(FileSnafu { action: "create", path: dir })?;
+
+                // tar detects the compression on its own
+                run_command(Command::new("tar").arg("-xf").arg(&path).arg("-C").arg(dir)).await
+                    .map_err(install_error)?;
+            }
+            None => {
+                log::info!("Installing {}...", source);
+
+                run_command(Command::new("rpm").args(["-U", "--replacepkgs"]).arg(&path)).await
+                    .map_err(install_error)?;
+            }
+        }
+
+        let mut all = self.installed.lock().unwrap();
+        all.retain(|other| other.source != installed.source || other.dir != installed.dir);
+        all.push(installed);
+
+        Ok(())
+    }
+
+    /// Returns the local path of a tarball or RPM, downloading it if
+    /// it's a URL.
+    async fn fetch(&self, source: &str) -> Result<PathBuf> {
+        if !is_url(source) {
+            return Ok(PathBuf::from(source));
+        }
+
+        let dir = &self.config.autosoftware.download_dir;
+        create_dir_all(dir).await
+            .context(FileSnafu { action: "create", path: dir })?;
+
+        let name = source.rsplit('/').next()
+            .filter(|name| !name.is_empty())
+            .unwrap_or("download");
+        let path = dir.join(name);
+
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            log::info!("Downloading {}...", source);
+
+            let res = run_command(Command::new("curl").args(["-fsSL", "-o"]).arg(&path).arg(source)).await;
+
+            match res {
+                Ok(()) => return Ok(path),
+                Err(reason) if attempt < self.config.autosoftware.retries => {
+                    log::warn!("Failed to download {}: {} - Retrying in {:?}", source, reason, delay);
+                    tokio::time::sleep(delay).await;
+
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(reason) => return Err(Error::SoftwareInstall { package: source.to_string(), reason }),
+            }
+        }
+    }
+}
+
+/// Returns whether a source is a URL instead of a path.
+fn is_url(source: &str) -> bool {
+    ["http://", "https://", "ftp://"].iter().any(|scheme| source.starts_with(scheme))
+}
+
+/// Returns the SHA-256 digest of a file, in hex.
+async fn sha256_file(path: PathBuf) -> Result<String> {
+    blocking::run("sha256", move || {
+        let mut hasher = Sha256::new();
+        let mut file = File::open(&path)
+            .context(FileSnafu { action: "read", path: &path })?;
+        io::copy(&mut file, &mut hasher)
+            .context(FileSnafu { action: "read", path: &path })?;
+
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }).await
+}
@@ -0,0 +1,226 @@
+//! The `autossh` applet.
+//!
+//! It manages a drop-in sshd configuration, so all nodes of the
+//! testbed share the same sshd policy regardless of what the image
+//! ships. sshd is reloaded after the configuration changes, once
+//! `sshd -t` accepts it. A drop-in that sshd rejects is rolled back,
+//! since sshd would refuse to start with it.
+//!
+//! The drop-in is only read if the main `sshd_config` includes
+//! `sshd_config.d`, which is the default on most distributions.
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use snafu::ResultExt;
+use tokio::fs;
+use tokio::process::Command;
+use which::which;
+
+use crate::config::Config;
+use crate::command::run_command;
+use crate::error::{Error, FileSnafu, Result};
+use crate::overlay;
+use crate::plan::{self, Action};
+use crate::tmcc::AllocationStatus;
+use super::{Applet, Sender, Message, recv};
+
+/// `autossh` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutosshConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Path to the drop-in configuration to manage.
+    #[serde(rename = "drop-in")]
+    drop_in: PathBuf,
+
+    /// Value of `PermitRootLogin` (e.g., `prohibit-password`).
+    #[serde(rename = "permit-root-login")]
+    permit_root_login: Option<String>,
+
+    /// Value of `AuthorizedKeysFile`.
+    #[serde(rename = "authorized-keys-file")]
+    authorized_keys_file: Option<String>,
+
+    /// Path to the banner with experiment information.
+    ///
+    /// If unset, no banner is shown.
+    banner: Option<PathBuf>,
+
+    /// The sshd systemd unit to reload.
+    unit: String,
+}
+
+impl Default for AutosshConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            drop_in: PathBuf::from("/etc/ssh/sshd_config.d/50-miniond.conf"),
+            permit_root_login: None,
+            authorized_keys_file: None,
+            banner: None,
+            unit: "sshd.service".to_string(),
+        }
+    }
+}
+
+/// The `autossh` applet.
+#[derive(Debug)]
+pub struct Autossh {
+    config: Config,
+    tx: Sender,
+}
+
+impl Autossh {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        if config.autossh.enable && !plan::is_dry_run() {
+            for binary in ["systemctl", "sshd"] {
+                if which(binary).is_err() {
+                    log::error!("The `{}` binary must be in PATH", binary);
+                    return Err(Error::UnmetSystemRequirements);
+                }
+            }
+        }
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autossh {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autossh.enable {
+            log::info!("autossh applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateAllocation(allocation) => {
+                    self.apply(Some(&allocation)).await?;
+                }
+
+                Message::Swapout => {
+                    self.apply(None).await?;
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Autossh {
+    /// Write the drop-in and banner, reloading sshd if they changed.
+    async fn apply(&self, allocation: Option<&AllocationStatus>) -> Result<()> {
+        let config = &self.config.autossh;
+        let mut changed = false;
+
+        if let Some(banner) = &config.banner {
+            changed |= overlay::update_file(&self.config.overlay, banner, render_banner(allocation)).await?;
+        }
+
+        let previous = match fs::read_to_string(&config.drop_in).await {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context(FileSnafu { action: "read", path: &config.drop_in }),
+        };
+
+        changed |= overlay::update_file(&self.config.overlay, &config.drop_in, self.render_drop_in()).await?;
+
+        if !changed {
+            log::debug!("sshd configuration unchanged");
+            return Ok(());
+        }
+
+        if !plan::is_dry_run() {
+            if let Err(reason) = run_command(Command::new("sshd").arg("-t")).await {
+                log::warn!("sshd rejected {:?} - Rolling back", config.drop_in);
+                self.roll_back(previous).await?;
+
+                return Err(Error::SshdConfig { path: config.drop_in.clone(), reason });
+            }
+        }
+
+        log::info!("Reloading {}...", config.unit);
+
+        if plan::is_dry_run() {
+            plan::record(Action::ReloadService {
+                unit: config.unit.clone(),
+            });
+            return Ok(());
+        }
+
+        // Does nothing if sshd isn't running
+        run_command(Command::new("systemctl").arg("try-reload-or-restart").arg(&config.unit)).await
+            .map_err(|reason| Error::ServiceReload { unit: config.unit.clone(), reason })?;
+
+        Ok(())
+    }
+
+    /// Restore the drop-in we replaced, or remove it if there was none.
+    async fn roll_back(&self, previous: Option<String>) -> Result<()> {
+        let path = &self.config.autossh.drop_in;
+
+        match previous {
+            Some(previous) => overlay::write_file(&self.config.overlay, path, previous.into_bytes()).await,
+            None => {
+                match fs::remove_file(path).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context(FileSnafu { action: "remove", path }),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Returns the contents of the drop-in.
+    fn render_drop_in(&self) -> String {
+        let config = &self.config.autossh;
+        let mut drop_in = String::from("# generated by miniond\n");
+
+        if let Some(permit_root_login) = &config.permit_root_login {
+            drop_in.push_str(&format!("PermitRootLogin {}\n", permit_root_login));
+        }
+
+        if let Some(authorized_keys_file) = &config.authorized_keys_file {
+            drop_in.push_str(&format!("AuthorizedKeysFile {}\n", authorized_keys_file));
+        }
+
+        if let Some(banner) = &config.banner {
+            drop_in.push_str(&format!("Banner {}\n", banner.display()));
+        }
+
+        drop_in
+    }
+}
+
+/// Returns the banner shown before login.
+fn render_banner(allocation: Option<&AllocationStatus>) -> String {
+    match allocation {
+        Some(allocation) => {
+            format!("This node ({}) is allocated to experiment {}.\n", allocation.node_name, allocation.experiment)
+        }
+        None => {
+            "This node is not allocated to any experiment.\n".to_string()
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! The `autostartup` applet.
+//!
+//! It runs the startup command of the experiment (`startupcmd`) as
+//! the user who set it, like `runstartup` of the official clientside,
+//! and reports its exit status to the testbed with `startstatus`.
+//!
+//! The command only runs once the accounts, the mounts, the software
+//! and the blockstores of the experiment are in place, since it usually relies
+//! on all of them. It runs in its own transient scope, with its output
+//! going to a log file, and is only run again if it changes or the
+//! experiment is swapped back in.
+
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use nix::libc;
+use nix::unistd;
+use serde::Deserialize;
+use snafu::ResultExt;
+use users::os::unix::UserExt;
+
+use crate::blocking;
+use crate::config::Config;
+use crate::error::{Error, FileSnafu, Result};
+use crate::plan::{self, Action};
+use crate::scope::ScopedCommand;
+use crate::tmcc::StartupCommand;
+use super::{Applet, Sender, Message, send, recv};
+
+/// `autostartup` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutostartupConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// File to write the output of the command to.
+    ///
+    /// It's opened as root, so it must not be a symlink or belong to
+    /// another user.
+    log: PathBuf,
+}
+
+impl Default for AutostartupConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            log: PathBuf::from("/var/log/miniond/runstartup.debug"),
+        }
+    }
+}
+
+/// The `autostartup` applet.
+#[derive(Debug)]
+pub struct Autostartup {
+    config: Config,
+    tx: Sender,
+
+    /// The command waiting for the node to be ready.
+    pending: Mutex<Option<StartupCommand>>,
+
+    /// The command we last started.
+    started: Mutex<Option<StartupCommand>>,
+
+    accounts_ready: AtomicBool,
+    mounts_ready: AtomicBool,
+    software_ready: AtomicBool,
+    storage_ready: AtomicBool,
+}
+
+impl Autostartup {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        let accounts_ready = !config.autouser.enable;
+        let mounts_ready = !config.automount.enable;
+        let software_ready = !config.autosoftware.enable;
+        let storage_ready = !config.autodisk.enable;
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            pending: Mutex::new(None),
+            started: Mutex::new(None),
+            accounts_ready: AtomicBool::new(accounts_ready),
+            mounts_ready: AtomicBool::new(mounts_ready),
+            software_ready: AtomicBool::new(software_ready),
+            storage_ready: AtomicBool::new(storage_ready),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autostartup {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autostartup.enable {
+            log::info!("autostartup applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateStartup(command) => {
+                    *self.pending.lock().unwrap() = Some(command);
+                }
+
+                Message::UpdateAccountsOk => {
+                    self.accounts_ready.store(true, Ordering::Relaxed);
+                }
+
+                Message::UpdateMountsOk => {
+                    self.mounts_ready.store(true, Ordering::Relaxed);
+                }
+
+                Message::UpdateSoftwareOk => {
+                    self.software_ready.store(true, Ordering::Relaxed);
+                }
+
+                Message::UpdateStorageOk => {
+                    self.storage_ready.store(true, Ordering::Relaxed);
+                }
+
+                Message::Swapin => {
+                    // Run it again with the new allocation
+                    self.started.lock().unwrap().take();
+                    continue;
+                }
+
+                _ => continue,
+            }
+
+            self.try_start().await;
+        }
+
+        Ok(())
+    }
+}
+
+impl Autostartup {
+    /// Start the pending command if the node is ready.
+    async fn try_start(&self) {
+        let ready = self.accounts_ready.load(Ordering::Relaxed)
+            && self.mounts_ready.load(Ordering::Relaxed)
+            && self.software_ready.load(Ordering::Relaxed)
+            && self.storage_ready.load(Ordering::Relaxed);
+
+        if !ready {
+            return;
+        }
+
+        let command = match self.pending.lock().unwrap().take() {
+            Some(command) => command,
+            None => return,
+        };
+
+        if self.started.lock().unwrap().as_ref() == Some(&command) {
+            log::debug!("Startup command is unchanged");
+            return;
+        }
+
+        *self.started.lock().unwrap() = Some(command.clone());
+
+        if let Err(e) = self.start(&command).await {
+            log::error!("Failed to run the startup command: {}", e);
+            send(&self.tx, Message::StartupFinished(1));
+        }
+    }
+
+    /// Start a command, reporting its exit status when it exits.
+    async fn start(&self, command: &StartupCommand) -> Result<()> {
+        log::info!("Running startup command as {}: {}", command.user, command.command);
+
+        if plan::is_dry_run() {
+            plan::record(Action::RunStartup {
+                user: command.user.clone(),
+                command: command.command.clone(),
+            });
+
+            return Ok(());
+        }
+
+        let (uid, gid, home) = resolve_user(&command.user).await?;
+
+        let log = &self.config.autostartup.log;
+        let stdout = open_log(log)?;
+        let stderr = stdout.try_clone()
+            .context(FileSnafu { action: "open", path: log })?;
+
+        let mut child = ScopedCommand::new("startup", "/bin/sh")
+            .arg("-c")
+            .arg(&command.command)
+            .env("HOME", &home)
+            .env("USER", &command.user)
+            .env("LOGNAME", &command.user)
+            .current_dir(home)
+            .user(uid, gid)
+            .spawn(&self.config.scope, Stdio::null(), Stdio::from(stdout), Stdio::from(stderr)).await?;
+
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let status = match child.wait().await {
+                Ok(status) => status.code().unwrap_or(1),
+                Err(e) => {
+                    log::error!("Failed to wait for the startup command: {}", e);
+                    1
+                }
+            };
+
+            log::info!("Startup command exited with status {}", status);
+            send(&tx, Message::StartupFinished(status));
+        });
+
+        Ok(())
+    }
+}
+
+/// Open the log of the startup command for appending.
+///
+/// Symlinks and files of other users are refused, so the log can't
+/// be used to make us append to arbitrary files.
+fn open_log(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o755).create(parent)
+            .context(FileSnafu { action: "create", path: parent })?;
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .context(FileSnafu { action: "open", path })?;
+
+    let uid = file.metadata()
+        .context(FileSnafu { action: "stat", path })?
+        .uid();
+
+    if uid != unistd::geteuid().as_raw() {
+        return Err(Error::ForeignFile { path: path.to_path_buf(), uid });
+    }
+
+    Ok(file)
+}
+
+/// Returns the UID, GID and home directory of a user.
+async fn resolve_user(login: &str) -> Result<(u32, u32, PathBuf)> {
+    let login = login.to_string();
+
+    blocking::run("resolve-user", move || {
+        let user = users::get_user_by_name(&login)
+            .ok_or(Error::UnknownOwner { name: login })?;
+
+        Ok((user.uid(), user.primary_group_id(), user.home_dir().to_path_buf()))
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log/runstartup.debug");
+
+        open_log(&log).unwrap();
+        assert!(log.is_file());
+
+        // Refuses to follow symlinks
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        std::fs::write(&target, "").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert!(matches!(open_log(&link), Err(Error::FileError { .. })));
+
+        // Refuses files of other users
+        if unistd::geteuid().is_root() {
+            unistd::chown(&target, Some(unistd::Uid::from_raw(65534)), None).unwrap();
+            assert!(matches!(open_log(&target), Err(Error::ForeignFile { uid: 65534, .. })));
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! The `autoswap` applet.
+//!
+//! It handles the experiment being swapped out and back in. The
+//! `tmcc` applet detects the changes in allocation, and this applet
+//! periodically asks it to check. On swapout, programs we run on
+//! behalf of the experiment are stopped, and accounts and mounts
+//! may be removed.
+//!
+//! If the testbed wants the node to boot something else (e.g., to
+//! reload the disk), it can also reboot the node.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::config::Config;
+use crate::command::run_command;
+use crate::error::{Error, Result};
+use crate::plan::{self, Action};
+use crate::scope;
+use crate::tmcc::BootWhat;
+use super::{Applet, Sender, Message, send, recv};
+
+/// `autoswap` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AutoswapConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// How often to check the allocation status, in seconds.
+    interval: u64,
+
+    /// Whether to remove accounts on swapout.
+    #[serde(rename = "remove-accounts")]
+    pub(super) remove_accounts: bool,
+
+    /// Whether to remove mounts on swapout.
+    #[serde(rename = "remove-mounts")]
+    remove_mounts: bool,
+
+    /// Whether to reboot when the testbed wants the node to boot a
+    /// memory file system or to reboot.
+    reboot: bool,
+}
+
+impl Default for AutoswapConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            interval: 60,
+            remove_accounts: false,
+            remove_mounts: false,
+            reboot: false,
+        }
+    }
+}
+
+/// The `autoswap` applet.
+#[derive(Debug)]
+pub struct Autoswap {
+    config: Config,
+    tx: Sender,
+}
+
+impl Autoswap {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Autoswap {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.autoswap.enable {
+            log::info!("autoswap applet disabled in config");
+            return Ok(());
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.autoswap.interval.max(1)));
+
+        // The initial reload already checks the allocation
+        interval.tick().await;
+
+        loop {
+            let message = tokio::select! {
+                message = recv(&mut rx) => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = interval.tick() => {
+                    send(&self.tx, Message::CheckAllocation);
+                    continue;
+                }
+            };
+
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::Swapout => {
+                    log::info!("Experiment swapped out - Tearing down...");
+
+                    scope::stop_all(&self.config.scope).await?;
+
+                    if self.config.autoswap.remove_accounts {
+                        send(&self.tx, Message::RemoveAccounts);
+                    }
+
+                    if self.config.autoswap.remove_mounts {
+                        send(&self.tx, Message::RemoveMounts);
+                    }
+                }
+
+                Message::UpdateBootInfo(boot) if self.config.autoswap.reboot => {
+                    if let BootWhat::Mfs(_) | BootWhat::Reboot = boot.what {
+                        log::warn!("The testbed wants the node to boot {:?} - Rebooting...", boot.what);
+                        reboot().await?;
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reboot the node.
+async fn reboot() -> Result<()> {
+    if plan::is_dry_run() {
+        plan::record(Action::Reboot);
+        return Ok(());
+    }
+
+    run_command(&mut Command::new("reboot")).await
+        .map_err(|reason| Error::Reboot { reason })
+}
@@ -1,36 +1,126 @@
 //! The `autouser` applet.
 //!
 //! It creates and configures users and groups.
+//!
+//! What was applied is saved to the state directory. Users whose
+//! serial has not changed since are skipped, and users and groups
+//! that are no longer part of the experiment are removed if `prune`
+//! is enabled. Nothing is removed after a batch that was empty or had
+//! chunks go missing, since it doesn't tell who left.
+//!
+//! If the applet falls behind on the bus, it may have missed an update,
+//! so the batch being applied is dropped and the testbed is reloaded.
+//!
+//! A user or group that fails to apply does not hold up the others.
+//! Failures are collected and reported together once the batch is
+//! finished, and only fail the applet past `max-failures`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
 use futures::future::join_all;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use which::which;
 
+use crate::apparmor;
+use crate::blocking;
+use crate::overlay;
+use crate::plan;
+use crate::state;
 use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::account::SystemConfiguration;
-use super::{Applet, Sender, Message};
+use crate::account::{self, Backend, HomeConfig, KeyOptionsConfig, QuotaConfig, ShellConfig, SystemConfiguration, User, Group};
+use crate::tmcc::RootKeypair;
+use super::{Applet, Sender, ChunkReceiver, Message, send, recv_or_lag};
 
 /// `autouser` applet configuration.
 #[derive(Debug, Deserialize)]
 #[serde(default)]
 pub struct AutouserConfig {
     /// Whether to enable the applet or not.
-    enable: bool,
+    pub(super) enable: bool,
+
+    /// How accounts are applied to the system.
+    backend: Backend,
 
     /// Name of the admin group.
     ///
     /// If unset, one will be automatically discovered (`wheel`, `sudo`, `root`).
     #[serde(rename = "admin-group")]
     admin_group: Option<String>,
+
+    /// Whether to remove users and groups that are no longer part of
+    /// the experiment.
+    prune: bool,
+
+    /// Whether to also remove the home directories of removed users.
+    ///
+    /// Homes are usually shared over NFS, so they are kept by default.
+    #[serde(rename = "remove-home")]
+    remove_home: bool,
+
+    /// Whether to install the root SSH keypair of the experiment, so
+    /// root can log into the other nodes without a password.
+    #[serde(rename = "root-keypair")]
+    pub(super) root_keypair: bool,
+
+    /// Whether to set the passwords of users from the testbed, so
+    /// they can log in on the console.
+    ///
+    /// Users without a password keep the one they have.
+    #[serde(rename = "apply-passwords")]
+    apply_passwords: bool,
+
+    /// Number of users and groups that may fail to apply in a batch
+    /// before the applet fails.
+    ///
+    /// If unset, failures are only reported.
+    #[serde(rename = "max-failures")]
+    max_failures: Option<usize>,
+
+    /// Whether to grant passwordless sudo to users with root
+    /// privileges in a sudoers drop-in.
+    ///
+    /// Some images don't grant it to the admin group.
+    sudoers: bool,
+
+    /// Path of the sudoers drop-in.
+    #[serde(rename = "sudoers-file")]
+    sudoers_file: PathBuf,
+
+    /// Login shell resolution.
+    shells: ShellConfig,
+
+    /// Quotas to apply to users on local file systems.
+    quotas: Vec<QuotaConfig>,
+
+    /// Permissions of home directories.
+    home: HomeConfig,
+
+    /// Options of SSH keys in `authorized_keys`.
+    #[serde(rename = "key-options")]
+    key_options: KeyOptionsConfig,
 }
 
 impl Default for AutouserConfig {
     fn default() -> Self {
         Self {
             enable: true,
+            backend: Backend::Commands,
             admin_group: None,
+            prune: false,
+            remove_home: false,
+            root_keypair: false,
+            apply_passwords: false,
+            max_failures: None,
+            sudoers: false,
+            sudoers_file: PathBuf::from("/etc/sudoers.d/90-miniond"),
+            shells: ShellConfig::default(),
+            quotas: Vec::new(),
+            home: HomeConfig::default(),
+            key_options: KeyOptionsConfig::default(),
         }
     }
 }
@@ -41,21 +131,96 @@ pub struct Autouser {
     config: Config,
     system: SystemConfiguration,
     tx: Sender,
+
+    /// Account chunks from `tmcc`.
+    chunk_rx: tokio::sync::Mutex<ChunkReceiver>,
+
+    /// Users and groups we applied.
+    applied: Mutex<Applied>,
+
+    /// Users and groups in the batch being applied.
+    batch: Mutex<Option<Applied>>,
+
+    /// The root SSH keypair of the experiment.
+    root_keypair: Mutex<Option<Arc<RootKeypair>>>,
+
+    /// Failures in the batch being applied.
+    report: Mutex<Report>,
+
+    /// Users we applied, to check for drift.
+    desired: Mutex<Vec<User>>,
+
+    /// Users in the batch being applied.
+    pending: Mutex<Vec<User>>,
+
+    /// Lock on applying accounts, held until the batch is finished.
+    cycle: Mutex<Option<state::Lock>>,
+
+    /// Index of the next chunk of the batch being applied, or `None`
+    /// if a chunk went missing.
+    next_chunk: Mutex<Option<usize>>,
+}
+
+/// Outcome of applying a batch of accounts.
+#[derive(Debug, Default)]
+struct Report {
+    /// Number of users and groups we tried to apply or remove.
+    total: usize,
+
+    /// Why each failing user or group failed.
+    failures: Vec<Error>,
+}
+
+/// Users and groups we applied, as saved in the state directory.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Applied {
+    /// Serials of users, by login.
+    users: BTreeMap<String, String>,
+
+    /// Names of groups.
+    groups: BTreeSet<String>,
 }
 
 impl Autouser {
-    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
-        if !check_requirements() {
-            return Err(Error::UnmetSystemRequirements);
+    pub(super) async fn new(config: Config, tx: Sender, chunk_rx: ChunkReceiver) -> Result<Box<dyn Applet>> {
+        if config.autouser.enable && !plan::is_dry_run() {
+            if !check_requirements(config.autouser.backend, config.autoswap.remove_accounts || config.autouser.prune, config.autouser.sudoers) {
+                return Err(Error::UnmetSystemRequirements);
+            }
+
+            // Only diagnostics, which must not keep accounts from being applied
+            if let Err(e) = apparmor::check(&config).await {
+                log::warn!("Failed to check AppArmor profiles: {}", e);
+            }
         }
 
         let admin_group = config.autouser.admin_group.clone();
-        let system = SystemConfiguration::new(admin_group).await?;
+        let mut system = SystemConfiguration::new(admin_group, &config.autouser.shells, &config.autouser.quotas, &config.autouser.home, &config.autouser.key_options, config.autouser.apply_passwords, config.autouser.backend).await?;
+
+        // Edits lock and replace files next to the databases
+        if config.autouser.enable && config.autouser.backend == Backend::Files && !plan::is_dry_run() {
+            system.set_database_dir(overlay::writable_dir(&config.overlay, Path::new(account::ETC_DIR)).await?);
+        }
+
+        let applied = if config.autouser.enable {
+            state::load(&config.state, "accounts").await
+        } else {
+            Applied::default()
+        };
 
         Ok(Box::new(Self {
             config,
             system,
             tx,
+            chunk_rx: tokio::sync::Mutex::new(chunk_rx),
+            applied: Mutex::new(applied),
+            batch: Mutex::new(None),
+            root_keypair: Mutex::new(None),
+            report: Mutex::new(Report::default()),
+            desired: Mutex::new(Vec::new()),
+            pending: Mutex::new(Vec::new()),
+            cycle: Mutex::new(None),
+            next_chunk: Mutex::new(Some(0)),
         }))
     }
 }
@@ -70,8 +235,44 @@ impl Applet for Autouser {
             return Ok(());
         }
 
+        let mut chunk_rx = self.chunk_rx.lock().await;
+
         loop {
-            let message = rx.recv().await.unwrap();
+            // Messages sent before a chunk (e.g., the root keypair) go first
+            let message = tokio::select! {
+                biased;
+                message = recv_or_lag(&mut rx) => match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(missed)) => {
+                        // An update of the accounts may be among them
+                        log::warn!("Fell behind on the bus - Missed {} messages, reloading the accounts", missed);
+                        self.abort();
+                        send(&self.tx, Message::ReloadTestbed);
+                        continue;
+                    }
+                    None => break,
+                },
+                Some(chunk) = chunk_rx.recv() => {
+                    log::info!("Got a chunk of account configurations (Users: {}, Groups: {})", chunk.users.len(), chunk.groups.len());
+
+                    if chunk.index == 0 {
+                        self.begin();
+                    }
+
+                    self.lock().await?;
+                    self.receive(chunk.index);
+                    self.apply(chunk.groups.iter(), chunk.users.iter()).await?;
+
+                    if chunk.last {
+                        let complete = self.next_chunk.lock().unwrap().replace(0).is_some();
+                        self.finish(complete).await?;
+                        send(&self.tx, Message::UpdateAccountsOk);
+                    }
+
+                    continue;
+                }
+            };
+
             match message {
                 Message::Shutdown(_) => {
                     break;
@@ -80,50 +281,409 @@ impl Applet for Autouser {
                 Message::UpdateAccounts(accounts) => {
                     log::info!("Got new account configurations (Users: {}, Groups: {})", accounts.users.len(), accounts.groups.len());
 
-                    {
-                        let mut futures = Vec::new();
+                    self.begin();
+                    self.lock().await?;
+                    self.apply(accounts.groups.values(), accounts.users.values()).await?;
+
+                    self.finish(true).await?;
+                    send(&self.tx, Message::UpdateAccountsOk);
+                }
+
+                // Root keeps trusting a keypair installed by the tmcc applet
+                Message::UpdateRootKeypair(keypair) if self.config.autouser.root_keypair || self.config.tmcc.install_root_keys => {
+                    *self.root_keypair.lock().unwrap() = Some(keypair);
+                }
 
-                        for group in accounts.groups.values() {
-                            futures.push(group.apply());
-                        }
+                Message::RemoveAccounts => {
+                    self.remove().await?;
+                }
 
-                        for res in join_all(futures).await {
-                            res?;
-                        }
+                Message::CheckDrift => {
+                    let fixes = self.repair().await?;
+                    if !fixes.is_empty() {
+                        send(&self.tx, Message::DriftRepaired("autouser", fixes));
                     }
+                }
 
-                    {
-                        let mut futures = Vec::new();
+                _ => {}
+            }
+        }
 
-                        for user in accounts.users.values() {
-                            futures.push(user.apply(&self.system));
-                        }
+        Ok(())
+    }
+}
 
-                        for res in join_all(futures).await {
-                            res?;
-                        }
-                    }
+impl Autouser {
+    /// Lock applying accounts, unless the batch already holds the lock.
+    async fn lock(&self) -> Result<()> {
+        if self.cycle.lock().unwrap().is_none() {
+            let lock = state::lock(&self.config.state, "accounts").await?;
+            *self.cycle.lock().unwrap() = Some(lock);
+        }
 
-                    log::info!("Successfully applied account configurations");
+        Ok(())
+    }
 
-                    self.tx.send(Message::UpdateAccountsOk).unwrap();
-                }
+    /// Release the lock on applying accounts, once dropped.
+    fn unlock(&self) -> Option<state::Lock> {
+        self.cycle.lock().unwrap().take()
+    }
 
-                _ => {}
+    /// Start a new batch, dropping what's left of one that was never
+    /// finished (e.g., its last chunk never came).
+    fn begin(&self) {
+        if self.batch.lock().unwrap().is_some() {
+            log::warn!("The previous batch of accounts was never finished");
+            self.abort();
+        }
+
+        *self.next_chunk.lock().unwrap() = Some(0);
+    }
+
+    /// Drop the batch being applied, without removing anyone or
+    /// saving it.
+    ///
+    /// What was applied so far stays on the system, and is picked up
+    /// by the next batch.
+    fn abort(&self) {
+        log::warn!("Aborting the batch of accounts being applied");
+
+        // The rest of a chunked batch still comes, but can't be complete
+        let started = self.batch.lock().unwrap().take().is_some();
+        *self.next_chunk.lock().unwrap() = if started { None } else { Some(0) };
+
+        self.pending.lock().unwrap().clear();
+        *self.report.lock().unwrap() = Report::default();
+        self.unlock();
+    }
+
+    /// Keep track of the chunks of the batch being applied.
+    fn receive(&self, index: usize) {
+        let mut next = self.next_chunk.lock().unwrap();
+
+        match *next {
+            Some(expected) if expected == index => *next = Some(index + 1),
+            Some(expected) => {
+                log::warn!("Expected chunk {} of the accounts, got chunk {}", expected, index);
+                *next = None;
+            }
+            None => {}
+        }
+    }
+
+    /// Apply groups, then users.
+    ///
+    /// Users and groups that fail to apply are recorded in the report
+    /// instead of stopping the others.
+    async fn apply<'a>(&self, groups: impl Iterator<Item = &'a Group>, users: impl Iterator<Item = &'a User>) -> Result<()> {
+        let groups: Vec<&Group> = groups.collect();
+        let users: Vec<&User> = users.collect();
+
+        let mut failures = Vec::new();
+        let mut failed_groups = BTreeSet::new();
+        let mut failed_users = BTreeSet::new();
+
+        let results = join_all(groups.iter().map(|group| account::apply_group(group, &self.system))).await;
+        for (group, res) in groups.iter().zip(results) {
+            if let Err(e) = res {
+                log::error!("{}", e);
+                failed_groups.insert(group.name());
+                failures.push(e);
+            }
+        }
+
+        let unchanged = self.unchanged_users(&users).await?;
+        if !unchanged.is_empty() {
+            log::debug!("Skipping {} unchanged users", unchanged.len());
+        }
+
+        let keypair = self.root_keypair.lock().unwrap().clone();
+
+        // Root trusts the experiment keypair
+        let root: Option<User> = keypair.as_ref().and_then(|keypair| {
+            let mut root = (*users.iter().find(|user| user.uid() == 0)?).clone();
+            if let Some(public_key) = &keypair.public_key {
+                root.add_ssh_key(public_key.clone());
+            }
+            Some(root)
+        });
+
+        let changed: Vec<&User> = users.iter()
+            .map(|user| match &root {
+                Some(root) if user.uid() == 0 => root,
+                _ => *user,
+            })
+            .filter(|user| !unchanged.contains(user.login()))
+            .collect();
+        let results = join_all(changed.iter().map(|user| account::apply_user(user, &self.system))).await;
+        for (user, res) in changed.iter().zip(results) {
+            if let Err(e) = res {
+                log::error!("{}", e);
+                failed_users.insert(user.login());
+                failures.push(e);
             }
         }
 
+        if let (Some(root), Some(keypair), true) = (&root, &keypair, self.config.autouser.root_keypair) {
+            if let Err(e) = account::install_keypair(root, keypair).await {
+                log::error!("{}", e);
+                failures.push(e);
+            }
+        }
+
+        {
+            let mut report = self.report.lock().unwrap();
+            report.total += groups.len() + changed.len();
+            report.failures.extend(failures);
+        }
+
+        // Root is never created, so there is little to repair
+        self.pending.lock().unwrap().extend(users.iter()
+            .filter(|user| user.uid() != 0)
+            .map(|user| (*user).clone()));
+
+        let applied = self.applied.lock().unwrap();
+        let mut batch = self.batch.lock().unwrap();
+        let batch = batch.get_or_insert_with(Applied::default);
+
+        // Whatever failed is kept as it was applied before, so it's
+        // neither pruned nor recorded as up to date
+        batch.groups.extend(groups.iter()
+            .map(|group| group.name())
+            .filter(|name| !failed_groups.contains(name) || applied.groups.contains(*name))
+            .map(str::to_string));
+
+        // The root account is only updated, never created
+        batch.users.extend(users.iter()
+            .filter(|user| user.uid() != 0)
+            .filter_map(|user| if failed_users.contains(user.login()) {
+                let serial = applied.users.get(user.login())?;
+                Some((user.login().to_string(), serial.clone()))
+            } else {
+                Some((user.login().to_string(), user.serial().to_string()))
+            }));
+
         Ok(())
     }
+
+    /// Returns the logins of users that were applied with the same
+    /// serial and still exist.
+    async fn unchanged_users(&self, users: &[&User]) -> Result<BTreeSet<String>> {
+        let candidates: Vec<String> = {
+            let applied = self.applied.lock().unwrap();
+            users.iter()
+                .filter(|user| applied.users.get(user.login()).map(String::as_str) == Some(user.serial()))
+                .map(|user| user.login().to_string())
+                .collect()
+        };
+
+        if candidates.is_empty() {
+            return Ok(BTreeSet::new());
+        }
+
+        Ok(account::existing_logins(candidates, &self.system).await?
+            .into_iter()
+            .collect())
+    }
+
+    /// Remove all users and groups we applied.
+    async fn remove(&self) -> Result<()> {
+        self.lock().await?;
+        let _lock = self.unlock();
+
+        let applied = std::mem::take(&mut *self.applied.lock().unwrap());
+        self.batch.lock().unwrap().take();
+        self.desired.lock().unwrap().clear();
+        self.pending.lock().unwrap().clear();
+
+        for login in applied.users.keys() {
+            account::remove_user(login, self.config.autouser.remove_home, &self.system).await?;
+        }
+
+        for name in &applied.groups {
+            account::remove_group(name, &self.system).await?;
+        }
+
+        if self.config.autouser.sudoers {
+            account::apply_sudoers(&self.sudoers_file().await?, &[]).await?;
+        }
+
+        state::save(&self.config.state, "accounts", &Applied::default()).await
+    }
+
+    /// Finish applying a batch of accounts.
+    ///
+    /// Users and groups we applied before but are not in the batch
+    /// are removed, and the batch is saved. Then, failures in the
+    /// batch are reported.
+    ///
+    /// A batch that is empty or `complete` is not set for doesn't tell
+    /// who left, so it's only added to what we applied before.
+    async fn finish(&self, complete: bool) -> Result<()> {
+        let _lock = self.unlock();
+
+        let batch = self.batch.lock().unwrap().take().unwrap_or_default();
+        let desired = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut report = std::mem::take(&mut *self.report.lock().unwrap());
+
+        let complete = complete && !desired.is_empty();
+        if !complete {
+            log::warn!("Received an incomplete or empty account list - Not removing anyone");
+        }
+
+        *self.desired.lock().unwrap() = desired.clone();
+        let (previous, batch) = {
+            let mut applied = self.applied.lock().unwrap();
+            let batch = settle(&applied, batch, complete);
+            (std::mem::replace(&mut *applied, batch.clone()), batch)
+        };
+
+        if self.config.autouser.prune && complete {
+            for login in previous.users.keys().filter(|login| !batch.users.contains_key(*login)) {
+                log::info!("User {} is no longer part of the experiment", login);
+                report.total += 1;
+                if let Err(e) = account::remove_user(login, self.config.autouser.remove_home, &self.system).await {
+                    log::error!("{}", e);
+                    report.failures.push(e);
+                }
+            }
+
+            for name in previous.groups.difference(&batch.groups) {
+                log::info!("Group {} is no longer part of the experiment", name);
+                report.total += 1;
+                if let Err(e) = account::remove_group(name, &self.system).await {
+                    log::error!("{}", e);
+                    report.failures.push(e);
+                }
+            }
+        }
+
+        if self.config.autouser.sudoers && complete {
+            // Users that lose root privileges are left out
+            let logins: Vec<&str> = desired.iter()
+                .filter(|user| user.is_root() && batch.users.contains_key(user.login()))
+                .map(|user| user.login())
+                .collect();
+
+            report.total += 1;
+            let res = async {
+                account::apply_sudoers(&self.sudoers_file().await?, &logins).await
+            }.await;
+
+            if let Err(e) = res {
+                log::error!("{}", e);
+                report.failures.push(e);
+            }
+        }
+
+        let stats = blocking::stats();
+        log::debug!("Blocking pool: {} tasks completed in {:?}, {} in flight",
+            stats.completed, stats.total_time, stats.in_flight);
+
+        state::save(&self.config.state, "accounts", &batch).await?;
+
+        if report.failures.is_empty() {
+            log::info!("Successfully applied account configurations");
+            return Ok(());
+        }
+
+        let error = Error::AccountsFailed {
+            failed: report.failures.len(),
+            total: report.total,
+            failures: report.failures.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; "),
+        };
+
+        match self.config.autouser.max_failures {
+            Some(max) if report.failures.len() > max => Err(error),
+            _ => {
+                log::warn!("{}", error);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the path to write the sudoers drop-in to, which may be
+    /// redirected if `/etc/sudoers.d` is read-only.
+    async fn sudoers_file(&self) -> Result<PathBuf> {
+        let path = &self.config.autouser.sudoers_file;
+
+        match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) if !plan::is_dry_run() => {
+                Ok(overlay::writable_dir(&self.config.overlay, dir).await?.join(name))
+            }
+            _ => Ok(path.clone()),
+        }
+    }
+
+    /// Reapply users that drifted from what we applied.
+    ///
+    /// Returns what was repaired.
+    async fn repair(&self) -> Result<Vec<String>> {
+        // A batch is being applied right now
+        if self.batch.lock().unwrap().is_some() {
+            return Ok(Vec::new());
+        }
+
+        self.lock().await?;
+        let _lock = self.unlock();
+
+        let users = self.desired.lock().unwrap().clone();
+        let mut fixes = Vec::new();
+
+        for user in &users {
+            let drift = match account::check_user(user, &self.system).await? {
+                Some(drift) => drift,
+                None => continue,
+            };
+
+            log::warn!("Drift detected: {} - Repairing...", drift);
+            match account::apply_user(user, &self.system).await {
+                Ok(()) => fixes.push(drift),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        Ok(fixes)
+    }
+}
+
+/// Returns what was applied after a batch.
+///
+/// An incomplete batch only updates what was applied before, so users
+/// and groups missing from it are neither removed nor forgotten.
+fn settle(previous: &Applied, batch: Applied, complete: bool) -> Applied {
+    if complete {
+        return batch;
+    }
+
+    let mut applied = previous.clone();
+    applied.users.extend(batch.users);
+    applied.groups.extend(batch.groups);
+    applied
 }
 
-fn check_requirements() -> bool {
-    let commands = vec![
-        "useradd",
-        "groupadd",
-        "usermod",
-        "groupmod",
-    ];
+fn check_requirements(backend: Backend, remove: bool, sudoers: bool) -> bool {
+    let mut commands = Vec::new();
+
+    if backend == Backend::Commands {
+        commands.extend([
+            "useradd",
+            "groupadd",
+            "usermod",
+            "groupmod",
+        ]);
+
+        if remove {
+            commands.extend(["userdel", "groupdel"]);
+        }
+    }
+
+    if sudoers {
+        commands.push("visudo");
+    }
 
     let mut check = true;
 
@@ -136,3 +696,154 @@ fn check_requirements() -> bool {
 
     check
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::*;
+
+    /// Returns the applet keeping its state in `dir`, with more
+    /// configuration from `extra`.
+    async fn autouser(dir: &Path, extra: &str) -> Autouser {
+        let config: crate::config::ConfigInner = toml::from_str(&format!("{}\n[state]\ndir = {:?}\n", extra, dir))
+            .expect("Failed to parse config");
+        let system = SystemConfiguration::new(None, &ShellConfig::default(), &[], &HomeConfig::default(), &KeyOptionsConfig::default(), false, Backend::Commands).await
+            .unwrap();
+        let (tx, _) = broadcast::channel(1);
+        let (_, chunk_rx) = mpsc::channel(1);
+
+        Autouser {
+            config: Arc::new(config),
+            system,
+            tx,
+            chunk_rx: tokio::sync::Mutex::new(chunk_rx),
+            applied: Mutex::new(Applied::default()),
+            batch: Mutex::new(None),
+            root_keypair: Mutex::new(None),
+            report: Mutex::new(Report::default()),
+            desired: Mutex::new(Vec::new()),
+            pending: Mutex::new(Vec::new()),
+            cycle: Mutex::new(None),
+            next_chunk: Mutex::new(Some(0)),
+        }
+    }
+
+    fn applied(users: &[(&str, &str)], groups: &[&str]) -> Applied {
+        Applied {
+            users: users.iter().map(|(login, serial)| (login.to_string(), serial.to_string())).collect(),
+            groups: groups.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_settle() {
+        let previous = applied(&[("alice", "1"), ("bob", "1")], &["project-pg0", "old"]);
+        let batch = applied(&[("alice", "2")], &["project-pg0"]);
+
+        let complete = settle(&previous, batch.clone(), true);
+        assert_eq!(batch.users, complete.users);
+        assert_eq!(batch.groups, complete.groups);
+
+        // Bob and the old group are kept for when it's complete again
+        let incomplete = settle(&previous, batch, false);
+        assert_eq!(Some("2"), incomplete.users.get("alice").map(String::as_str));
+        assert_eq!(Some("1"), incomplete.users.get("bob").map(String::as_str));
+        assert!(incomplete.groups.contains("old"));
+    }
+
+    #[tokio::test]
+    async fn test_abort() {
+        let dir = tempfile::tempdir().unwrap();
+        let autouser = autouser(dir.path(), "").await;
+
+        // Halfway through a chunked batch
+        autouser.begin();
+        autouser.lock().await.unwrap();
+        autouser.receive(0);
+        *autouser.batch.lock().unwrap() = Some(applied(&[("alice", "1")], &["project-pg0"]));
+        autouser.pending.lock().unwrap().push(User::new("alice".to_string(), 20001, 6000, "1".to_string()));
+        autouser.report.lock().unwrap().total = 2;
+
+        autouser.abort();
+        assert!(autouser.batch.lock().unwrap().is_none());
+        assert!(autouser.pending.lock().unwrap().is_empty());
+        assert_eq!(0, autouser.report.lock().unwrap().total);
+        assert!(autouser.cycle.lock().unwrap().is_none());
+        assert!(autouser.applied.lock().unwrap().users.is_empty());
+        assert!(!dir.path().join("accounts.json").exists());
+
+        // The rest of it can't be complete
+        autouser.receive(1);
+        assert_eq!(None, *autouser.next_chunk.lock().unwrap());
+
+        // The next batch starts over
+        autouser.begin();
+        autouser.receive(0);
+        assert_eq!(Some(1), *autouser.next_chunk.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_begin() {
+        let dir = tempfile::tempdir().unwrap();
+        let autouser = autouser(dir.path(), "").await;
+
+        // A batch whose last chunk never came
+        autouser.lock().await.unwrap();
+        autouser.receive(0);
+        *autouser.batch.lock().unwrap() = Some(applied(&[("alice", "1")], &[]));
+        autouser.report.lock().unwrap().total = 1;
+
+        autouser.begin();
+        assert!(autouser.batch.lock().unwrap().is_none());
+        assert_eq!(0, autouser.report.lock().unwrap().total);
+        assert!(autouser.cycle.lock().unwrap().is_none());
+        assert_eq!(Some(0), *autouser.next_chunk.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let autouser = autouser(dir.path(), "").await;
+
+        // The batch keeps holding the lock across chunks
+        autouser.lock().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), autouser.lock()).await
+            .expect("Timed out relocking")
+            .unwrap();
+
+        // Other cycles wait for the batch to finish
+        let other = state::lock(&autouser.config.state, "accounts");
+        tokio::pin!(other);
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut other).await.is_err());
+
+        drop(autouser.unlock());
+        tokio::time::timeout(Duration::from_secs(5), other).await
+            .expect("Timed out waiting for the lock")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sudoers_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let sudoers_dir = dir.path().join("sudoers.d");
+        let overlay_dir = dir.path().join("overlay");
+
+        let direct = autouser(dir.path(), &format!("[autouser]\nsudoers-file = {:?}\n", sudoers_dir.join("90-miniond"))).await;
+        assert_eq!(sudoers_dir.join("90-miniond"), direct.sudoers_file().await.unwrap());
+
+        // Redirected to the overlay
+        let redirected = autouser(dir.path(), &format!(
+            "[autouser]\nsudoers-file = {:?}\n[overlay]\nmode = \"always\"\nmethod = \"symlink\"\ndir = {:?}\n",
+            sudoers_dir.join("90-miniond"), overlay_dir,
+        )).await;
+
+        let path = redirected.sudoers_file().await.unwrap();
+        assert!(path.starts_with(&overlay_dir), "{:?}", path);
+        assert!(path.ends_with("sudoers.d/90-miniond"), "{:?}", path);
+        assert_eq!(path.parent().unwrap(), std::fs::read_link(&sudoers_dir).unwrap());
+    }
+}
@@ -0,0 +1,194 @@
+//! The `cloudinit` applet.
+//!
+//! It writes a cloud-init NoCloud seed from the testbed information,
+//! so unmodified cloud images can provision themselves with
+//! cloud-init instead of running the other applets.
+//!
+//! The seed consists of `meta-data` with the identity of the node,
+//! and `user-data` with its hostname and the experiment users and
+//! their SSH keys. Both are written as JSON, which cloud-init reads
+//! as YAML. Networking is left to the image, since the control
+//! interface is not part of the testbed information.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::account::User;
+use crate::config::Config;
+use crate::error::Result;
+use crate::overlay;
+use super::{Applet, Sender, Message, recv};
+
+/// `cloudinit` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CloudinitConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Directory to write the seed to.
+    #[serde(rename = "seed-dir")]
+    seed_dir: PathBuf,
+}
+
+impl Default for CloudinitConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            seed_dir: PathBuf::from("/var/lib/cloud/seed/nocloud"),
+        }
+    }
+}
+
+/// The `cloudinit` applet.
+#[derive(Debug)]
+pub struct Cloudinit {
+    config: Config,
+    tx: Sender,
+}
+
+/// Information the seed is generated from.
+#[derive(Debug, Default)]
+struct Seed {
+    experiment: Option<String>,
+    node: Option<String>,
+    fqdn: Option<String>,
+    users: BTreeMap<String, User>,
+}
+
+impl Cloudinit {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Cloudinit {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.cloudinit.enable {
+            log::info!("cloudinit applet disabled in config");
+            return Ok(());
+        }
+
+        let mut seed = Seed::default();
+
+        // Accounts being received in chunks
+        let mut chunks: Option<BTreeMap<String, User>> = None;
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateAllocation(allocation) => {
+                    seed.experiment = Some(allocation.experiment);
+                    seed.node = Some(allocation.node_name);
+                }
+
+                Message::UpdateCanonical(fqdn, _) => {
+                    seed.fqdn = Some(fqdn);
+                }
+
+                Message::UpdateAccounts(accounts) => {
+                    seed.users = accounts.users.into_iter().collect();
+                }
+
+                Message::UpdateAccountsChunk(chunk) => {
+                    let users = chunks.get_or_insert_with(BTreeMap::new);
+                    users.extend(chunk.users.iter().map(|user| (user.login().to_string(), user.clone())));
+
+                    if chunk.last {
+                        seed.users = chunks.take().unwrap();
+                    }
+                }
+
+                Message::ReloadTestbedOk => {
+                    if seed.experiment.is_none() {
+                        log::debug!("Not writing a cloud-init seed for an unallocated node");
+                        continue;
+                    }
+
+                    let seed_dir = &self.config.cloudinit.seed_dir;
+
+                    let meta_data = format!("{:#}\n", seed.meta_data());
+                    overlay::update_file(&self.config.overlay, &seed_dir.join("meta-data"), meta_data).await?;
+
+                    let user_data = format!("#cloud-config\n{:#}\n", seed.user_data());
+                    overlay::update_file(&self.config.overlay, &seed_dir.join("user-data"), user_data).await?;
+                }
+
+                Message::Swapout => {
+                    seed = Seed::default();
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Seed {
+    /// Returns the `meta-data` of the seed.
+    ///
+    /// cloud-init runs once per instance ID, so a new allocation
+    /// gets a new one.
+    fn meta_data(&self) -> Value {
+        let instance_id = format!("{}/{}",
+            self.experiment.as_deref().unwrap_or_default(),
+            self.node.as_deref().unwrap_or_default());
+
+        json!({
+            "instance-id": instance_id,
+            "local-hostname": self.fqdn.as_ref().or(self.node.as_ref()),
+        })
+    }
+
+    /// Returns the `user-data` of the seed.
+    fn user_data(&self) -> Value {
+        let users: Vec<Value> = self.users.values()
+            .filter(|user| user.uid() != 0)
+            .map(|user| {
+                let mut entry = json!({
+                    "name": user.login(),
+                    "uid": user.uid(),
+                    "homedir": user.home_dir(),
+                    "lock_passwd": true,
+                    "ssh_authorized_keys": user.ssh_keys(),
+                });
+
+                if user.is_root() {
+                    entry["sudo"] = json!("ALL=(ALL) NOPASSWD:ALL");
+                }
+
+                entry
+            })
+            .collect();
+
+        let mut user_data = json!({
+            "users": users,
+        });
+
+        if let Some(fqdn) = &self.fqdn {
+            user_data["fqdn"] = json!(fqdn);
+            user_data["hostname"] = json!(fqdn.split('.').next().unwrap_or(fqdn));
+        }
+
+        user_data
+    }
+}
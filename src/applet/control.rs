@@ -0,0 +1,275 @@
+//! The `control` applet.
+//!
+//! A Unix domain socket for operators on the node. Each connection
+//! sends one command on a line and gets one line of JSON back:
+//!
+//! - `status`: What the node is doing, and what it reported to the testbed
+//! - `reload`: Reload information from the testbed
+//! - `accounts`: Accounts applied to the system
+//! - `mounts`: Mounts applied to the system
+//!
+//! ```text
+//! echo status | socat - UNIX-CONNECT:/run/miniond.sock
+//! ```
+//!
+//! The socket is accessible to root and `group`.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::blocking;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use super::api::Applied;
+use super::{Applet, Sender, Message, send, recv};
+
+/// Maximum size of a command.
+const MAX_COMMAND_SIZE: u64 = 1024;
+
+/// How long to wait for a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `control` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Path to the socket.
+    pub(crate) socket: PathBuf,
+
+    /// Group allowed to use the socket.
+    ///
+    /// If unset, only root can use it.
+    group: Option<String>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            socket: PathBuf::from("/run/miniond.sock"),
+            group: None,
+        }
+    }
+}
+
+/// The node status, as returned by `status`.
+#[derive(Debug, Clone, Serialize)]
+struct Status {
+    /// What miniond is doing (`starting`, `reloading`, `ready` or `free`).
+    phase: &'static str,
+
+    /// The last state reported to the testbed (e.g., `ISUP`).
+    state: Option<String>,
+
+    experiment: Option<String>,
+    node: Option<String>,
+    fqdn: Option<String>,
+
+    /// The last failure of each applet.
+    failures: BTreeMap<&'static str, String>,
+}
+
+/// State shared with the connections.
+#[derive(Debug)]
+struct State {
+    tx: Sender,
+    status: Mutex<Status>,
+    applied: Applied,
+}
+
+/// The `control` applet.
+#[derive(Debug)]
+pub struct Control {
+    config: Config,
+    tx: Sender,
+}
+
+impl Control {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Control {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.control.enable {
+            log::info!("control applet disabled in config");
+            return Ok(());
+        }
+
+        let config = &self.config.control;
+        let listener = bind(config).await?;
+
+        log::info!("Serving the control socket at {:?}", config.socket);
+
+        let state = Arc::new(State {
+            tx: self.tx.clone(),
+            status: Mutex::new(Status {
+                phase: "starting",
+                state: None,
+                experiment: None,
+                node: None,
+                fqdn: None,
+                failures: BTreeMap::new(),
+            }),
+            applied: Applied::default(),
+        });
+
+        let server = {
+            let state = state.clone();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream, &state).await {
+                            log::debug!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+            })
+        };
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+
+            state.applied.update(&message);
+
+            let mut status = state.status.lock().unwrap();
+
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::ReloadTestbed => {
+                    status.phase = "reloading";
+                }
+
+                Message::ReloadTestbedOk => {
+                    status.phase = "ready";
+                }
+
+                Message::StateReported(reported) => {
+                    status.state = Some(reported.as_ref().to_string());
+                }
+
+                Message::UpdateAllocation(allocation) => {
+                    status.experiment = Some(allocation.experiment);
+                    status.node = Some(allocation.node_name);
+                }
+
+                Message::UpdateCanonical(fqdn, _) => {
+                    status.fqdn = Some(fqdn);
+                }
+
+                Message::AppletFailed(applet, error, _) => {
+                    status.failures.insert(applet, error);
+                }
+
+                Message::Swapout => {
+                    status.phase = "free";
+                    status.experiment = None;
+                    status.node = None;
+                }
+
+                _ => {}
+            }
+        }
+
+        server.abort();
+
+        if let Err(e) = tokio::fs::remove_file(&config.socket).await {
+            log::debug!("Failed to remove {:?}: {}", config.socket, e);
+        }
+
+        Ok(())
+    }
+}
+
+/// Bind the socket, replacing a stale one.
+async fn bind(config: &ControlConfig) -> Result<UnixListener> {
+    match tokio::fs::remove_file(&config.socket).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    if let Some(parent) = config.socket.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let listener = UnixListener::bind(&config.socket)?;
+
+    let group = config.group.clone();
+    let socket = config.socket.clone();
+    blocking::run("chown-socket", move || {
+        if let Some(group) = group {
+            let gid = users::get_group_by_name(&group)
+                .map(|g| g.gid())
+                .ok_or(Error::UnknownOwner { name: group })?;
+
+            std::os::unix::fs::chown(&socket, Some(0), Some(gid))?;
+        }
+
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o660))?;
+        Ok(())
+    }).await?;
+
+    Ok(listener)
+}
+
+/// Handle a connection.
+async fn handle(stream: UnixStream, state: &State) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader.take(MAX_COMMAND_SIZE));
+    let mut line = String::new();
+
+    match tokio::time::timeout(COMMAND_TIMEOUT, reader.read_line(&mut line)).await {
+        Ok(res) => res?,
+        Err(_) => return Ok(()),
+    };
+
+    let response = match line.trim() {
+        "status" => serde_json::to_value(&*state.status.lock().unwrap())
+            .expect("Failed to serialize status"),
+        "reload" => {
+            log::info!("Reload requested over the control socket");
+
+            send(&state.tx, Message::ReloadTestbed);
+            json!({ "status": "accepted" })
+        }
+        "accounts" => serde_json::to_value(state.applied.accounts())
+            .expect("Failed to serialize accounts"),
+        "mounts" => serde_json::to_value(state.applied.mounts())
+            .expect("Failed to serialize mounts"),
+        command => json!({ "error": format!("unknown command: {}", command) }),
+    };
+
+    writer.write_all(format!("{}\n", response).as_bytes()).await?;
+    writer.shutdown().await?;
+
+    Ok(())
+}
@@ -0,0 +1,307 @@
+//! The `dbus` applet.
+//!
+//! Only available with the `dbus` feature. It exposes the state of
+//! the node as `org.marsresearch.Miniond` on D-Bus, so other tools
+//! can check on miniond and trigger a reload without signals:
+//!
+//! ```text
+//! busctl call org.marsresearch.Miniond /org/marsresearch/Miniond org.marsresearch.Miniond1 Reload
+//! busctl get-property org.marsresearch.Miniond /org/marsresearch/Miniond org.marsresearch.Miniond1 Experiment
+//! ```
+//!
+//! Owning the name on the system bus needs the policy in
+//! `dbus/org.marsresearch.Miniond.conf`.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use zbus::{connection, fdo, interface};
+
+use crate::config::Config;
+use crate::error::Result;
+use super::{Applet, Sender, Message, recv};
+
+/// Well-known name of the service.
+const NAME: &str = "org.marsresearch.Miniond";
+
+/// Path of the object.
+const PATH: &str = "/org/marsresearch/Miniond";
+
+/// `dbus` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DbusConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Bus to connect to.
+    bus: BusType,
+}
+
+impl Default for DbusConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            bus: BusType::System,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum BusType {
+    #[serde(rename = "system")]
+    System,
+
+    /// The session bus, mostly for testing.
+    #[serde(rename = "session")]
+    Session,
+}
+
+/// The `dbus` applet.
+#[derive(Debug)]
+pub struct Dbus {
+    config: Config,
+    tx: Sender,
+}
+
+impl Dbus {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Dbus {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.dbus.enable {
+            log::info!("dbus applet disabled in config");
+            return Ok(());
+        }
+
+        let builder = match self.config.dbus.bus {
+            BusType::System => connection::Builder::system()?,
+            BusType::Session => connection::Builder::session()?,
+        };
+
+        let service = Service {
+            tx: self.tx.clone(),
+            state: "starting".to_string(),
+            experiment: String::new(),
+            node: String::new(),
+            fqdn: String::new(),
+            expires: String::new(),
+            boot: String::new(),
+        };
+
+        let connection = builder
+            .name(NAME)?
+            .serve_at(PATH, service)?
+            .build().await?;
+
+        let service = connection.object_server()
+            .interface::<_, Service>(PATH).await?;
+
+        log::info!("Serving {} on D-Bus", NAME);
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            if let Message::Shutdown(_) = message {
+                break;
+            }
+
+            let mut service_ref = service.get_mut().await;
+            let emitter = service.signal_emitter();
+
+            for property in service_ref.update(message) {
+                match property {
+                    Property::State => service_ref.state_changed(emitter).await?,
+                    Property::Experiment => service_ref.experiment_changed(emitter).await?,
+                    Property::Node => service_ref.node_changed(emitter).await?,
+                    Property::Fqdn => service_ref.fqdn_changed(emitter).await?,
+                    Property::Expires => service_ref.expires_changed(emitter).await?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The `org.marsresearch.Miniond1` interface.
+struct Service {
+    tx: Sender,
+    state: String,
+    experiment: String,
+    node: String,
+    fqdn: String,
+    expires: String,
+    boot: String,
+}
+
+/// A property of the interface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Property {
+    State,
+    Experiment,
+    Node,
+    Fqdn,
+    Expires,
+}
+
+impl Service {
+    /// Apply a message, returning the properties that changed.
+    fn update(&mut self, message: Message) -> Vec<Property> {
+        match message {
+            Message::UpdateAllocation(allocation) => {
+                self.experiment = allocation.experiment;
+                self.node = allocation.node_name;
+                vec![Property::Experiment, Property::Node]
+            }
+
+            Message::UpdateCanonical(fqdn, _) => {
+                self.fqdn = fqdn;
+                vec![Property::Fqdn]
+            }
+
+            Message::UpdateExpiration(expires) => {
+                self.expires = expires;
+                vec![Property::Expires]
+            }
+
+            Message::UpdateBootInfo(boot) => {
+                self.boot = serde_json::to_string(&boot.what)
+                    .expect("Failed to serialize boot information");
+                Vec::new()
+            }
+
+            Message::ReloadTestbed => {
+                self.state = "reloading".to_string();
+                vec![Property::State]
+            }
+
+            Message::ReloadTestbedOk => {
+                self.state = "ready".to_string();
+                vec![Property::State]
+            }
+
+            Message::Swapout => {
+                self.experiment.clear();
+                self.node.clear();
+                self.expires.clear();
+                self.state = "free".to_string();
+                vec![Property::Experiment, Property::Node, Property::Expires, Property::State]
+            }
+
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[interface(name = "org.marsresearch.Miniond1")]
+impl Service {
+    /// Reload information from the testbed.
+    async fn reload(&self) -> fdo::Result<()> {
+        log::info!("Reload requested over D-Bus");
+
+        self.tx.send(Message::ReloadTestbed)
+            .map_err(|_| fdo::Error::Failed("miniond is shutting down".to_string()))?;
+
+        Ok(())
+    }
+
+    /// Returns all properties as a dictionary.
+    async fn status(&self) -> HashMap<String, String> {
+        HashMap::from([
+            ("State".to_string(), self.state.clone()),
+            ("Experiment".to_string(), self.experiment.clone()),
+            ("Node".to_string(), self.node.clone()),
+            ("Fqdn".to_string(), self.fqdn.clone()),
+            ("Expires".to_string(), self.expires.clone()),
+            ("BootWhat".to_string(), self.boot.clone()),
+        ])
+    }
+
+    /// State of the daemon (`starting`, `reloading`, `ready`, or `free`).
+    #[zbus(property)]
+    async fn state(&self) -> String {
+        self.state.clone()
+    }
+
+    /// Experiment the node is allocated to (e.g., `project/experiment`).
+    #[zbus(property)]
+    async fn experiment(&self) -> String {
+        self.experiment.clone()
+    }
+
+    /// Name of the node in the experiment.
+    #[zbus(property)]
+    async fn node(&self) -> String {
+        self.node.clone()
+    }
+
+    /// Fully-qualified domain name of the node.
+    #[zbus(property)]
+    async fn fqdn(&self) -> String {
+        self.fqdn.clone()
+    }
+
+    /// When the experiment expires, if known.
+    #[zbus(property)]
+    async fn expires(&self) -> String {
+        self.expires.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use crate::tmcc::AllocationStatus;
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let (tx, _) = broadcast::channel(1);
+        let mut service = Service {
+            tx,
+            state: "starting".to_string(),
+            experiment: String::new(),
+            node: String::new(),
+            fqdn: String::new(),
+            expires: String::new(),
+            boot: String::new(),
+        };
+
+        let allocation = AllocationStatus {
+            experiment: "project/experiment".to_string(),
+            node_name: "node0".to_string(),
+        };
+        assert_eq!(vec![Property::Experiment, Property::Node], service.update(Message::UpdateAllocation(allocation)));
+        assert_eq!("project/experiment", service.experiment);
+        assert_eq!("node0", service.node);
+
+        assert_eq!(vec![Property::Fqdn], service.update(Message::UpdateCanonical("node0.example.net".to_string(), Vec::new())));
+        assert_eq!("node0.example.net", service.fqdn);
+
+        assert_eq!(vec![Property::State], service.update(Message::ReloadTestbed));
+        assert_eq!("reloading", service.state);
+        assert_eq!(vec![Property::State], service.update(Message::ReloadTestbedOk));
+        assert_eq!("ready", service.state);
+
+        assert!(service.update(Message::CheckDrift).is_empty());
+
+        let changed = service.update(Message::Swapout);
+        assert!(changed.contains(&Property::Experiment) && changed.contains(&Property::State));
+        assert!(service.experiment.is_empty());
+        assert_eq!("free", service.state);
+    }
+}
@@ -0,0 +1,248 @@
+//! The `hooks` applet.
+//!
+//! It runs user-configured programs when messages are sent on the
+//! bus, so sites can react to lifecycle events without writing Rust:
+//!
+//! ```toml
+//! [hooks.on]
+//! UpdateCanonical = ["/etc/miniond/hooks/hostname.sh"]
+//! UpdateMountsOk = ["/etc/miniond/hooks/mounts.sh"]
+//! ```
+//!
+//! The message is passed as JSON on stdin, and its name in the
+//! `MINIOND_MESSAGE` environment variable. Hooks are run one at a
+//! time in the order of the messages, inside transient scopes.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::plan::{self, Action};
+use crate::scope::ScopedCommand;
+use super::{Applet, Sender, Message, recv};
+
+/// `hooks` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Programs to run for each message type.
+    on: BTreeMap<String, Vec<PathBuf>>,
+
+    /// How long a hook may run, in seconds.
+    timeout: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            on: BTreeMap::new(),
+            timeout: 60,
+        }
+    }
+}
+
+/// The `hooks` applet.
+#[derive(Debug)]
+pub struct Hooks {
+    config: Config,
+    tx: Sender,
+}
+
+impl Hooks {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Hooks {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.hooks.enable || self.config.hooks.on.is_empty() {
+            log::info!("hooks applet disabled in config");
+            return Ok(());
+        }
+
+        // Slow hooks must not hold up the bus
+        let (queue, mut pending) = mpsc::unbounded_channel();
+
+        let listen = async move {
+            loop {
+                let message = match recv(&mut rx).await {
+                    Some(message) => message,
+                    None => break,
+                };
+                let name = message.name();
+
+                if self.config.hooks.on.contains_key(name) {
+                    queue.send((name, payload(&message))).unwrap();
+                }
+
+                if let Message::Shutdown(_) = message {
+                    break;
+                }
+            }
+        };
+
+        let run = async {
+            while let Some((name, payload)) = pending.recv().await {
+                for program in &self.config.hooks.on[name] {
+                    if let Err(e) = self.run_hook(name, program, &payload).await {
+                        log::warn!("Hook {:?} for {} failed: {}", program, name, e);
+                    }
+                }
+            }
+        };
+
+        tokio::join!(listen, run);
+
+        Ok(())
+    }
+}
+
+impl Hooks {
+    /// Run a hook, waiting for it to exit.
+    async fn run_hook(&self, name: &str, program: &PathBuf, payload: &Value) -> Result<()> {
+        log::info!("Running hook {:?} for {}...", program, name);
+
+        if plan::is_dry_run() {
+            plan::record(Action::RunHook {
+                message: name.to_string(),
+                program: program.clone(),
+            });
+            return Ok(());
+        }
+
+        let scope = format!("hook-{}", name);
+        let mut child = ScopedCommand::new(&scope, program)
+            .env("MINIOND_MESSAGE", name)
+            .spawn(&self.config.scope, Stdio::piped(), Stdio::inherit(), Stdio::inherit()).await?;
+
+        let mut stdin = child.stdin().unwrap();
+        let input = serde_json::to_vec(payload).expect("Failed to serialize hook payload");
+
+        // The hook may not read its input at all
+        if let Err(e) = stdin.write_all(&input).await {
+            log::debug!("Hook {:?} did not read its input: {}", program, e);
+        }
+        drop(stdin);
+
+        let timeout = Duration::from_secs(self.config.hooks.timeout);
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => {
+                let status = status?;
+                if !status.success() {
+                    log::warn!("Hook {:?} for {} exited with {}", program, name, status);
+                }
+            }
+            Err(_) => {
+                log::warn!("Hook {:?} for {} timed out - Killing it...", program, name);
+                child.kill().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the JSON payload for a message.
+///
+/// Secrets are left out, since hooks are not necessarily trusted
+/// with them.
+fn payload(message: &Message) -> Value {
+    match message {
+        Message::Shutdown(reason) => json!({ "reason": format!("{:?}", reason) }),
+        Message::UpdateAccounts(accounts) => json!({
+            "users": accounts.users.keys().collect::<Vec<_>>(),
+            "groups": accounts.groups.keys().collect::<Vec<_>>(),
+        }),
+        Message::UpdateAccountsChunk(chunk) => json!({
+            "users": chunk.users.iter().map(|u| u.login()).collect::<Vec<_>>(),
+            "groups": chunk.groups.iter().map(|g| g.name()).collect::<Vec<_>>(),
+            "last": chunk.last,
+        }),
+        Message::UpdateMounts(mounts) => {
+            Value::Array(mounts.iter().map(|m| json!({ "remote": m.remote(), "local": m.local() })).collect())
+        }
+        Message::UpdateCanonical(fqdn, addresses) => json!({
+            "fqdn": fqdn,
+            "ipv4": addresses.iter().find(|address| address.is_ipv4()),
+            "ipv6": addresses.iter().find(|address| address.is_ipv6()),
+        }),
+        Message::UpdateAllocation(allocation) => json!({
+            "experiment": allocation.experiment,
+            "node": allocation.node_name,
+        }),
+        Message::UpdateLocalization(localization) => json!(localization),
+        Message::UpdateExpiration(expires) => json!({ "expires": expires }),
+        Message::UpdateEnvironment(env) => {
+            Value::Object(env.iter().map(|(k, v)| (k.clone(), Value::String(v.clone()))).collect())
+        }
+        Message::UpdateSecrets(secrets) => json!({
+            "key": secrets.key.is_some(),
+            "certificate": secrets.certificate.is_some(),
+        }),
+        Message::UpdateSoftware(software) => json!({
+            "tarballs": software.tarballs,
+            "rpms": software.rpms,
+        }),
+        Message::UpdateStorage(volumes) => json!(volumes.as_slice()),
+        Message::UpdateStartup(command) => json!(command),
+        Message::StartupFinished(status) => json!({ "status": status }),
+        Message::UpdateBlob(blob) => json!({
+            "name": blob.name,
+            "size": blob.contents.len(),
+        }),
+        Message::UpdateInterfaces(interfaces) => json!(interfaces),
+        Message::UpdatePeers(peers) => {
+            Value::Array(peers.iter().map(|p| json!({ "link": p.link, "node": p.node, "address": p.address })).collect())
+        }
+        Message::LinkTestResults(results) => json!(results),
+        Message::UpdateTipline(tipline) => json!({
+            "name": tipline.name,
+            "server": tipline.server,
+            "port": tipline.port,
+        }),
+        Message::UpdateTiptunnels(tunnels) => {
+            Value::Array(tunnels.iter().map(|t| json!({ "name": t.name, "server": t.server, "port": t.port })).collect())
+        }
+        Message::StateReported(state) => json!({ "state": format!("{:?}", state) }),
+        Message::AppletFailed(applet, error, severity) => json!({ "applet": applet, "error": error, "severity": severity }),
+        Message::UpdateBootInfo(boot) => json!(boot),
+        // Never the private key
+        Message::UpdateRootKeypair(keypair) => json!({ "public_key": keypair.public_key }),
+        Message::DriftRepaired(applet, fixes) => json!({ "applet": applet, "fixes": fixes }),
+        Message::UpdateSyncServer(server) => json!({ "server": server.server, "is_server": server.is_server }),
+        Message::UpdateAccountsOk
+        | Message::UpdateMountsOk
+        | Message::UpdateCanonicalOk
+        | Message::UpdateInterfacesOk
+        | Message::UpdateSoftwareOk
+        | Message::UpdateStorageOk
+        | Message::ReloadTestbed
+        | Message::ReloadTestbedOk
+        | Message::CheckAllocation
+        | Message::CheckDrift
+        | Message::Swapout
+        | Message::Swapin
+        | Message::RemoveAccounts
+        | Message::RemoveMounts => json!({}),
+    }
+}
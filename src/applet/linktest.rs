@@ -0,0 +1,247 @@
+//! The `linktest` applet.
+//!
+//! A lightweight version of Emulab's linktest. Once the testbed
+//! information is applied and `autonet` has configured the
+//! interfaces, it pings the other nodes on each experiment link from
+//! the GENI manifest, giving users quick feedback on whether their
+//! topology came up. The test runs in the background, so the applet
+//! keeps up with the bus in the meantime.
+//!
+//! Results are sent on the bus, logged, and written to a status
+//! file as JSON. The `tmcc` applet can upload them to the testbed.
+
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use which::which;
+
+use miniond_core::geni::Peer;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::overlay;
+use crate::plan;
+use super::{Applet, Sender, Message, send, recv};
+
+/// `linktest` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LinktestConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// How long to wait for links to come up before testing, in seconds.
+    delay: u64,
+
+    /// Number of pings to send to each peer.
+    count: u32,
+
+    /// How long to wait for each reply, in seconds.
+    timeout: u32,
+
+    /// Path to write the results to.
+    #[serde(rename = "status-file")]
+    status_file: Option<PathBuf>,
+
+    /// Whether to upload the results to the testbed as the boot log.
+    pub(super) report: bool,
+}
+
+impl Default for LinktestConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            delay: 10,
+            count: 3,
+            timeout: 1,
+            status_file: Some(PathBuf::from("/run/miniond/linktest.json")),
+            report: false,
+        }
+    }
+}
+
+/// Reachability of a peer.
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkResult {
+    pub link: String,
+    pub node: String,
+    pub address: Ipv4Addr,
+    pub reachable: bool,
+}
+
+/// The `linktest` applet.
+#[derive(Debug)]
+pub struct Linktest {
+    config: Config,
+    tx: Sender,
+
+    /// Peers from the last manifest.
+    peers: Mutex<Vec<Peer>>,
+
+    /// The test that is scheduled or running.
+    test: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Linktest {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        if config.linktest.enable && which("ping").is_err() {
+            log::error!("The `ping` binary must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+            peers: Mutex::new(Vec::new()),
+            test: Mutex::new(None),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Linktest {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.linktest.enable {
+            log::info!("linktest applet disabled in config");
+            return Ok(());
+        }
+
+        // Whether the reload finished, and whether autonet is still
+        // configuring the interfaces
+        let mut reloaded = false;
+        let mut configuring = false;
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdatePeers(peers) => {
+                    *self.peers.lock().unwrap() = peers;
+                }
+
+                Message::UpdateInterfaces(_) if self.config.autonet.enable => {
+                    configuring = true;
+                }
+
+                Message::UpdateInterfacesOk => {
+                    configuring = false;
+                }
+
+                Message::ReloadTestbedOk => {
+                    reloaded = true;
+                }
+
+                _ => {}
+            }
+
+            if reloaded && !configuring {
+                reloaded = false;
+                self.schedule();
+            }
+        }
+
+        if let Some(test) = self.test.lock().unwrap().take() {
+            test.abort();
+        }
+
+        Ok(())
+    }
+}
+
+impl Linktest {
+    /// Test the peers after the delay, replacing a pending test.
+    fn schedule(&self) {
+        let peers = self.peers.lock().unwrap().clone();
+        if peers.is_empty() {
+            return;
+        }
+
+        let config = self.config.clone();
+        let tx = self.tx.clone();
+
+        let test = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(config.linktest.delay)).await;
+
+            if let Err(e) = run(&config, &tx, peers).await {
+                log::warn!("Failed to test connectivity: {}", e);
+            }
+        });
+
+        if let Some(previous) = self.test.lock().unwrap().replace(test) {
+            previous.abort();
+        }
+    }
+}
+
+/// Ping all peers and report the results.
+async fn run(config: &Config, tx: &Sender, peers: Vec<Peer>) -> Result<()> {
+    let linktest = &config.linktest;
+
+    log::info!("Testing connectivity to {} peers...", peers.len());
+
+    let results: Vec<LinkResult> = join_all(peers.into_iter().map(|peer| async move {
+        let reachable = ping(peer.address, linktest.count, linktest.timeout).await;
+
+        LinkResult {
+            link: peer.link,
+            node: peer.node,
+            address: peer.address,
+            reachable,
+        }
+    })).await;
+
+    let unreachable = results.iter().filter(|r| !r.reachable).count();
+
+    for result in results.iter().filter(|r| !r.reachable) {
+        log::warn!("{} ({}) is unreachable on {}", result.node, result.address, result.link);
+    }
+
+    if unreachable == 0 {
+        log::info!("All {} peers are reachable", results.len());
+    } else {
+        log::warn!("{} of {} peers are unreachable", unreachable, results.len());
+    }
+
+    if let Some(status_file) = &linktest.status_file {
+        let json = serde_json::to_string_pretty(&results)
+            .expect("Failed to serialize linktest results");
+
+        overlay::update_file(&config.overlay, status_file, json + "\n").await?;
+    }
+
+    send(tx, Message::LinkTestResults(results));
+
+    Ok(())
+}
+
+/// Returns whether a host answers pings.
+async fn ping(address: Ipv4Addr, count: u32, timeout: u32) -> bool {
+    if plan::is_dry_run() {
+        return true;
+    }
+
+    let status = Command::new("ping")
+        .arg("-q")
+        .arg("-c").arg(count.to_string())
+        .arg("-W").arg(timeout.to_string())
+        .arg(address.to_string())
+        .stdout(Stdio::null())
+        .status().await;
+
+    matches!(status, Ok(status) if status.success())
+}
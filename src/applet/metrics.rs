@@ -0,0 +1,224 @@
+//! The `metrics` applet.
+//!
+//! It serves metrics in the Prometheus text format on `GET /metrics`,
+//! so the health of miniond can be watched across a fleet of nodes:
+//!
+//! - Users, groups and mounts applied to the system
+//! - Reloads from the testbed, and when the last one finished
+//! - Failures and respawns of applets
+//! - Durations and failures of TMCD requests
+//!
+//! Metrics are not authenticated, so they are only served on the
+//! loopback interface by default. Any other listen address should
+//! only be reachable from the monitoring network.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::metrics::{self, Sample};
+use super::api::{read_head, Applied};
+use super::{Applet, Sender, Message, recv};
+
+/// How long to wait for a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `metrics` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Address to listen on.
+    listen: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 9780)),
+        }
+    }
+}
+
+/// Metrics tracked from the bus.
+#[derive(Debug, Default)]
+struct State {
+    applied: Applied,
+
+    reloads: Mutex<u64>,
+
+    /// Seconds since the epoch.
+    last_reload: Mutex<Option<u64>>,
+
+    /// Failures by applet and severity.
+    failures: Mutex<BTreeMap<(&'static str, String), u64>>,
+}
+
+impl State {
+    fn samples(&self) -> Vec<Sample> {
+        let (users, groups, mounts) = self.applied.counts();
+
+        let gauge = |name, help, value: f64| Sample {
+            name,
+            help,
+            kind: "gauge",
+            values: vec![(String::new(), value)],
+        };
+
+        let mut samples = vec![
+            gauge("miniond_users", "Users applied to the system.", users as f64),
+            gauge("miniond_groups", "Groups applied to the system.", groups as f64),
+            gauge("miniond_mounts", "Mounts applied to the system.", mounts as f64),
+            Sample {
+                name: "miniond_reloads_total",
+                help: "Reloads from the testbed that finished.",
+                kind: "counter",
+                values: vec![(String::new(), *self.reloads.lock().unwrap() as f64)],
+            },
+        ];
+
+        if let Some(last_reload) = *self.last_reload.lock().unwrap() {
+            samples.push(gauge("miniond_last_reload_timestamp_seconds", "When the last reload from the testbed finished.", last_reload as f64));
+        }
+
+        samples.push(Sample {
+            name: "miniond_applet_failures_total",
+            help: "Failures of applets.",
+            kind: "counter",
+            values: self.failures.lock().unwrap().iter()
+                .map(|((applet, severity), count)| {
+                    (format!("applet=\"{}\",severity=\"{}\"", metrics::escape(applet), metrics::escape(severity)), *count as f64)
+                })
+                .collect(),
+        });
+
+        samples
+    }
+}
+
+/// The `metrics` applet.
+#[derive(Debug)]
+pub struct Metrics {
+    config: Config,
+    tx: Sender,
+}
+
+impl Metrics {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Metrics {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.metrics.enable {
+            log::info!("metrics applet disabled in config");
+            return Ok(());
+        }
+
+        let listener = TcpListener::bind(self.config.metrics.listen).await?;
+
+        log::info!("Serving metrics on {}", self.config.metrics.listen);
+
+        let state = Arc::new(State::default());
+
+        let server = {
+            let state = state.clone();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream, &state).await {
+                            log::debug!("Metrics connection failed: {}", e);
+                        }
+                    });
+                }
+            })
+        };
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+
+            state.applied.update(&message);
+
+            match message {
+                Message::Shutdown(_) => {
+                    server.abort();
+                    break;
+                }
+
+                Message::ReloadTestbedOk => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+
+                    *state.reloads.lock().unwrap() += 1;
+                    *state.last_reload.lock().unwrap() = Some(now);
+                }
+
+                Message::AppletFailed(applet, _, severity) => {
+                    *state.failures.lock().unwrap().entry((applet, format!("{:?}", severity))).or_default() += 1;
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Handle a connection.
+async fn handle(mut stream: TcpStream, state: &State) -> Result<()> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()),
+    };
+
+    let request_line = head.as_deref()
+        .and_then(|head| head.lines().next())
+        .unwrap_or_default()
+        .to_string();
+    let mut request_line = request_line.split_whitespace();
+
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics::render(&state.samples());
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body,
+            )
+        }
+        (Some(_), Some("/metrics")) => {
+            "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
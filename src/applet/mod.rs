@@ -5,7 +5,7 @@
 //! with each other via a Tokio broadcast channel (think of it
 //! as a shared bus), sending typed Rust values.
 //!
-//! ```
+//! ```text
 //! [tmcc] -- [autouser]
 //!     \---- [automount]
 //! ```
@@ -13,57 +13,350 @@
 //! In the above example, the `tmcc` applet may request account
 //! information from the testbed and then send a `Message::UpdateAccount`
 //! message through the channel.
+//!
+//! Downstream projects can build their own daemon with additional
+//! applets through [`Runner`], implementing [`Applet`] and talking
+//! to the built-in applets with [`Message`]s. Going through [`send`]
+//! and [`recv`] keeps an applet from crashing the daemon when the
+//! others have already gone away during shutdown.
+//!
+//! Account chunks are the exception: `tmcc` hands them to `autouser`
+//! over a bounded channel of their own, so a large project is read
+//! from TMCD only as fast as it is applied. They are also sent on the
+//! bus for applets that only observe them.
 
 mod autouser;
 mod automount;
 mod autohost;
+mod autoswap;
+mod autorepair;
+mod watchdog;
+mod autoconsole;
+mod automotd;
+mod autolocale;
+mod autoproxy;
+mod linktest;
+mod autoenv;
+mod autocert;
+mod autoblob;
+mod autosoftware;
+mod autodisk;
+mod autonet;
+mod autostartup;
+mod api;
+mod control;
+mod hooks;
+mod templates;
+mod webhooks;
+mod cloudinit;
+mod syncserver;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod autossh;
+mod metrics;
 mod tmcc;
 mod signal;
 
 // use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
-use tokio::sync::broadcast;
+use futures::future::join_all;
+use miniond_core::geni::Peer;
+use miniond_core::net::InterfaceConfig;
+use miniond_core::redact;
+use tokio::sync::{broadcast, mpsc};
 
-use crate::mount::NfsMount;
+use crate::mount::Mount;
 use crate::account::Accounts;
-use crate::config::Config;
-use crate::error::Result;
+use crate::command;
+use crate::config::{Config, ConfigInner};
+use crate::error::{Error, Result, Severity};
+use crate::fault;
+use crate::plan::{self, Action};
+use crate::tmcc::{AccountsChunk, AllocationStatus, Blob, BootInfo, LocalVolume, Localization, RootKeypair, StartupCommand, State, SyncServer, Tipline};
 
 pub use autouser::{Autouser, AutouserConfig};
 pub use automount::{Automount, AutomountConfig};
 pub use autohost::{Autohost, AutohostConfig};
+pub use autoswap::{Autoswap, AutoswapConfig};
+pub use autorepair::{Autorepair, AutorepairConfig};
+pub use watchdog::{Watchdog, WatchdogConfig};
+pub use autossh::{Autossh, AutosshConfig};
+pub use autoconsole::{Autoconsole, AutoconsoleConfig};
+pub use automotd::{Automotd, AutomotdConfig};
+pub use autolocale::{Autolocale, AutolocaleConfig};
+pub use autoproxy::{Autoproxy, AutoproxyConfig};
+pub use linktest::{Linktest, LinktestConfig, LinkResult};
+pub use autoenv::{Autoenv, AutoenvConfig};
+pub use autocert::{Autocert, AutocertConfig, Secrets};
+pub use autoblob::{Autoblob, AutoblobConfig};
+pub use autosoftware::{Autosoftware, AutosoftwareConfig, Software};
+pub use autodisk::{Autodisk, AutodiskConfig};
+pub use autonet::{Autonet, AutonetConfig};
+pub use autostartup::{Autostartup, AutostartupConfig};
+pub use api::{Api, ApiConfig};
+pub use metrics::{Metrics, MetricsConfig};
+pub use control::{Control, ControlConfig};
+pub use hooks::{Hooks, HooksConfig};
+pub use templates::{Templates, TemplatesConfig};
+pub use webhooks::{Webhooks, WebhooksConfig};
+pub use cloudinit::{Cloudinit, CloudinitConfig};
+pub use syncserver::{Syncserver, SyncserverConfig};
+pub use crate::sync::Barrier;
+#[cfg(feature = "dbus")]
+pub use dbus::{Dbus, DbusConfig};
 pub use tmcc::{Tmcc, TmccConfig};
 pub use signal::Signal;
 
 const CHANNEL_CAPACITY: usize = 100;
 
-type Sender = broadcast::Sender<Message>;
+/// Number of account chunks that may wait for `autouser`.
+const CHUNK_CAPACITY: usize = 1;
+
+/// The sending half of the account chunk channel.
+type ChunkSender = mpsc::Sender<Arc<AccountsChunk>>;
+
+/// The receiving half of the account chunk channel.
+type ChunkReceiver = mpsc::Receiver<Arc<AccountsChunk>>;
+
+/// The sending half of the bus.
+pub type Sender = broadcast::Sender<Message>;
+
+/// The receiving half of the bus.
+pub type Receiver = broadcast::Receiver<Message>;
+
+/// Send a message through the bus.
+///
+/// Nobody may be listening anymore while the daemon shuts down, in
+/// which case the message is dropped.
+pub fn send(tx: &Sender, message: Message) {
+    if let Err(broadcast::error::SendError(message)) = tx.send(message) {
+        log::debug!("No applet is listening - Dropping {}", message.name());
+    }
+}
+
+/// Receive the next message from the bus.
+///
+/// Receivers that fall behind skip the messages they missed. Returns
+/// `None` once every sender is gone.
+pub async fn recv(rx: &mut Receiver) -> Option<Message> {
+    loop {
+        match recv_or_lag(rx).await? {
+            Ok(message) => return Some(message),
+            Err(missed) => {
+                log::warn!("Fell behind on the bus - Skipping {} messages", missed);
+            }
+        }
+    }
+}
+
+/// Receive the next message from the bus, or how many messages were
+/// missed if the receiver fell behind.
+///
+/// Applets that can't afford to miss messages use this to recover
+/// (e.g., with a reload). Returns `None` once every sender is gone.
+pub async fn recv_or_lag(rx: &mut Receiver) -> Option<std::result::Result<Message, u64>> {
+    match rx.recv().await {
+        Ok(message) => Some(Ok(message)),
+        Err(broadcast::error::RecvError::Lagged(missed)) => Some(Err(missed)),
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
+}
+
+/// Compare two byte strings without leaking where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
 /// A message.
+///
+/// Variants are added as applets gain features, so matches
+/// outside of this crate need a wildcard arm.
 #[derive(Debug, Clone)]
-enum Message {
+#[non_exhaustive]
+pub enum Message {
     /// Shut down the daemon.
     Shutdown(ShutdownReason),
 
     /// Update accounts on the system.
     UpdateAccounts(Accounts),
 
+    /// Update a chunk of accounts on the system.
+    ///
+    /// The last chunk has `last` set. This is only informational,
+    /// since `autouser` receives chunks over its own channel.
+    UpdateAccountsChunk(Arc<AccountsChunk>),
+
     /// Account update was successful.
     UpdateAccountsOk,
 
+    /// Install the root SSH keypair of the experiment.
+    ///
+    /// This is sent before the accounts.
+    UpdateRootKeypair(Arc<RootKeypair>),
+
     /// Update NFS mounts on the system.
-    UpdateMounts(Vec<NfsMount>),
+    UpdateMounts(Vec<Mount>),
 
     /// Mount update was successful.
     UpdateMountsOk,
 
-    /// Update FQDN and its associated IP of the system.
-    UpdateCanonical(String, Ipv4Addr),
+    /// Update FQDN and its associated IPv4 and/or IPv6 addresses of the system.
+    UpdateCanonical(String, Vec<IpAddr>),
+
+    /// Hostname update was successful.
+    UpdateCanonicalOk,
+
+    /// The node is allocated to an experiment.
+    UpdateAllocation(AllocationStatus),
+
+    /// Update the cluster-wide settings (e.g., timezone).
+    UpdateLocalization(Localization),
+
+    /// The experiment expires at the given time.
+    UpdateExpiration(String),
+
+    /// Update environment variables for the experiment.
+    UpdateEnvironment(Vec<(String, String)>),
+
+    /// Install the key and certificate of the experiment.
+    UpdateSecrets(Arc<Secrets>),
+
+    /// Place a blob from the testbed.
+    UpdateBlob(Arc<Blob>),
+
+    /// Install the tarballs and RPMs of the experiment.
+    UpdateSoftware(Arc<Software>),
+
+    /// The tarballs and RPMs of the experiment have been installed.
+    UpdateSoftwareOk,
+
+    /// Set up the local blockstores of the experiment.
+    UpdateStorage(Arc<Vec<LocalVolume>>),
+
+    /// The local blockstores of the experiment are mounted.
+    UpdateStorageOk,
+
+    /// Run the startup command of the experiment.
+    UpdateStartup(StartupCommand),
+
+    /// The startup command exited with the given status.
+    StartupFinished(i32),
+
+    /// Configure the experiment interfaces of the node.
+    UpdateInterfaces(Vec<InterfaceConfig>),
+
+    /// Interface update was successful.
+    UpdateInterfacesOk,
+
+    /// Other nodes on the experiment links.
+    UpdatePeers(Vec<Peer>),
+
+    /// Results of a connectivity test to the peers.
+    LinkTestResults(Vec<LinkResult>),
+
+    /// Serve the console line of the node.
+    UpdateTipline(Tipline),
+
+    /// Tunnel the console lines of other nodes.
+    UpdateTiptunnels(Vec<Tipline>),
+
+    /// What the node is supposed to boot.
+    UpdateBootInfo(BootInfo),
+
+    /// The barrier synchronization server of the experiment.
+    UpdateSyncServer(SyncServer),
 
     /// Reload information from the testbed.
     ReloadTestbed,
+
+    /// All information from the testbed was sent out.
+    ReloadTestbedOk,
+
+    /// Check whether the node is still allocated to the same experiment.
+    CheckAllocation,
+
+    /// The experiment was swapped out.
+    Swapout,
+
+    /// The node was allocated to an experiment again.
+    Swapin,
+
+    /// Compare what was applied with the system, and repair drift.
+    CheckDrift,
+
+    /// Drift from what was applied was repaired by an applet.
+    DriftRepaired(&'static str, Vec<String>),
+
+    /// Remove accounts created for the experiment.
+    RemoveAccounts,
+
+    /// Remove mounts created for the experiment.
+    RemoveMounts,
+
+    /// A state was reported to the testbed.
+    ///
+    /// `MFSSETUP` is reported at startup before the applets run,
+    /// so it's only sent on swapin.
+    StateReported(State),
+
+    /// An applet exited with an error.
+    ///
+    /// Applets are restarted after transient errors. Fatal errors are
+    /// reported to the testbed as `TBFAILED` instead.
+    AppletFailed(&'static str, String, Severity),
+}
+
+impl Message {
+    /// Returns the name of the message type (e.g., `UpdateCanonical`).
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Shutdown(_) => "Shutdown",
+            Self::UpdateAccounts(_) => "UpdateAccounts",
+            Self::UpdateAccountsChunk(_) => "UpdateAccountsChunk",
+            Self::UpdateAccountsOk => "UpdateAccountsOk",
+            Self::UpdateRootKeypair(_) => "UpdateRootKeypair",
+            Self::UpdateMounts(_) => "UpdateMounts",
+            Self::UpdateMountsOk => "UpdateMountsOk",
+            Self::UpdateCanonical(_, _) => "UpdateCanonical",
+            Self::UpdateCanonicalOk => "UpdateCanonicalOk",
+            Self::UpdateAllocation(_) => "UpdateAllocation",
+            Self::UpdateLocalization(_) => "UpdateLocalization",
+            Self::UpdateExpiration(_) => "UpdateExpiration",
+            Self::UpdateEnvironment(_) => "UpdateEnvironment",
+            Self::UpdateSecrets(_) => "UpdateSecrets",
+            Self::UpdateBlob(_) => "UpdateBlob",
+            Self::UpdateSoftware(_) => "UpdateSoftware",
+            Self::UpdateSoftwareOk => "UpdateSoftwareOk",
+            Self::UpdateStorage(_) => "UpdateStorage",
+            Self::UpdateStorageOk => "UpdateStorageOk",
+            Self::UpdateStartup(_) => "UpdateStartup",
+            Self::StartupFinished(_) => "StartupFinished",
+            Self::UpdateInterfaces(_) => "UpdateInterfaces",
+            Self::UpdateInterfacesOk => "UpdateInterfacesOk",
+            Self::UpdatePeers(_) => "UpdatePeers",
+            Self::LinkTestResults(_) => "LinkTestResults",
+            Self::UpdateTipline(_) => "UpdateTipline",
+            Self::UpdateTiptunnels(_) => "UpdateTiptunnels",
+            Self::UpdateBootInfo(_) => "UpdateBootInfo",
+            Self::UpdateSyncServer(_) => "UpdateSyncServer",
+            Self::ReloadTestbed => "ReloadTestbed",
+            Self::ReloadTestbedOk => "ReloadTestbedOk",
+            Self::CheckAllocation => "CheckAllocation",
+            Self::Swapout => "Swapout",
+            Self::Swapin => "Swapin",
+            Self::CheckDrift => "CheckDrift",
+            Self::DriftRepaired(_, _) => "DriftRepaired",
+            Self::RemoveAccounts => "RemoveAccounts",
+            Self::RemoveMounts => "RemoveMounts",
+            Self::StateReported(_) => "StateReported",
+            Self::AppletFailed(_, _, _) => "AppletFailed",
+        }
+    }
 }
 
 /// A shutdown reason.
@@ -73,7 +366,8 @@ enum Message {
 ///
 /// This reason also determines the exit code.
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum ShutdownReason {
+#[non_exhaustive]
+pub enum ShutdownReason {
     /// Received a non-interactive terminating signal.
     ///
     /// We will report to the testbed that we are shutting down.
@@ -81,26 +375,65 @@ enum ShutdownReason {
 
     /// Received an interactive terminating signal (e.g., Ctrl-C).
     InteractiveSignal,
+
+    /// The simulation is finished.
+    Simulated,
 }
 
 /// An applet.
+///
+/// Applets should subscribe to the bus before the first `.await`
+/// in `main`, so they don't miss messages sent at startup.
 #[async_trait]
-trait Applet {
+pub trait Applet: Send + Sync {
     /// Entry point of the applet.
     async fn main(&self) -> Result<()>;
 }
 
 /// Run a single applet with automatic restart.
-async fn run_applet(name: &'static str, applet: Box<dyn Applet>) {
+///
+/// Applets failing for a transient reason are respawned with the
+/// backoff of `tmcc`, until its respawn budget runs out.
+async fn run_applet(tx: &Sender, config: &ConfigInner, name: &'static str, applet: Box<dyn Applet>) {
+    let mut backoff = config.tmcc.backoff();
+    let mut respawns = 0;
+
     loop {
+        let started = Instant::now();
+
         match applet.main().await {
             Ok(()) => {
                 log::debug!("Applet {} exited.", name);
                 break;
             }
             Err(e) => {
-                log::error!("Applet {} exited with error: {}", name, e);
-                log::warn!("Trying to respawn...");
+                let severity = e.severity();
+
+                log::error!("Applet {} exited with {:?} error: {}", name, severity, e);
+                send(tx, Message::AppletFailed(name, e.to_string(), severity));
+
+                if severity == Severity::Fatal {
+                    log::error!("Not respawning applet {}", name);
+                    break;
+                }
+
+                // Applets that ran for a while start over
+                if started.elapsed() > backoff.max_delay() {
+                    backoff.reset();
+                    respawns = 0;
+                }
+
+                if config.tmcc.respawn_retries.is_some_and(|retries| respawns >= retries) {
+                    log::error!("Applet {} failed {} times in a row - Not respawning", name, respawns + 1);
+                    send(tx, Message::AppletFailed(name, e.to_string(), Severity::Fatal));
+                    break;
+                }
+
+                let delay = backoff.next_delay();
+                log::warn!("Trying to respawn in {:?}...", delay);
+                tokio::time::sleep(delay).await;
+                respawns += 1;
+                crate::metrics::applet_respawned(name);
             }
         }
     }
@@ -108,25 +441,306 @@ async fn run_applet(name: &'static str, applet: Box<dyn Applet>) {
 
 /// Run all applets.
 pub async fn run(config: Config) -> Result<()> {
-    let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
-    drop(rx);
+    Runner::new(config).run().await
+}
+
+/// Runs the built-in applets alongside custom ones.
+///
+/// ```no_run
+/// # async fn example(config: miniond::config::Config, custom: Box<dyn miniond::applet::Applet>) {
+/// use miniond::applet::Runner;
+///
+/// let runner = Runner::new(config);
+///
+/// // Custom applets are constructed with `runner.sender()`
+/// runner
+///     .applet("custom", custom)
+///     .run().await
+///     .unwrap();
+/// # }
+/// ```
+pub struct Runner {
+    config: Config,
+    tx: Sender,
+    applets: Vec<(&'static str, Box<dyn Applet>)>,
+}
+
+impl Runner {
+    /// Create a runner with only the built-in applets.
+    pub fn new(config: Config) -> Self {
+        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+        drop(rx);
+
+        Self {
+            config,
+            tx,
+            applets: Vec::new(),
+        }
+    }
+
+    /// Returns the bus shared by all applets.
+    pub fn sender(&self) -> Sender {
+        self.tx.clone()
+    }
+
+    /// Add a custom applet.
+    pub fn applet(mut self, name: &'static str, applet: Box<dyn Applet>) -> Self {
+        self.applets.push((name, applet));
+        self
+    }
+
+    /// Run all applets until shutdown.
+    #[cfg_attr(not(feature = "dbus"), allow(unused_mut))]
+    pub async fn run(self) -> Result<()> {
+        let Self { config, tx, mut applets } = self;
+
+        fault::init(&config);
+        command::init(&config);
+
+        let signal = Signal::new(tx.clone());
+        let (chunk_tx, chunk_rx) = mpsc::channel(CHUNK_CAPACITY);
 
-    let signal = Signal::new(tx.clone());
-    let autouser = Autouser::new(config.clone(), tx.clone()).await?;
-    let automount = Automount::new(config.clone(), tx.clone()).await?;
-    let autohost = Autohost::new(config.clone(), tx.clone()).await?;
-    let tmcc = Tmcc::new(config.clone(), tx.clone()).await?;
+        // Discovering the boss node may go through several DNS timeouts,
+        // so we perform the local checks of other applets in the meantime.
+        let (tmcc, autouser, automount, autohost, autoswap, autorepair, watchdog, autossh, autoconsole, automotd, autolocale, autoproxy, linktest, autoenv, autocert, autoblob, autosoftware, autodisk, autonet, autostartup, api, metrics, control, hooks, templates, webhooks, cloudinit, syncserver) = tokio::try_join!(
+            Tmcc::new(config.clone(), tx.clone(), chunk_tx),
+            Autouser::new(config.clone(), tx.clone(), chunk_rx),
+            Automount::new(config.clone(), tx.clone()),
+            Autohost::new(config.clone(), tx.clone()),
+            Autoswap::new(config.clone(), tx.clone()),
+            Autorepair::new(config.clone(), tx.clone()),
+            Watchdog::new(config.clone(), tx.clone()),
+            Autossh::new(config.clone(), tx.clone()),
+            Autoconsole::new(config.clone(), tx.clone()),
+            Automotd::new(config.clone(), tx.clone()),
+            Autolocale::new(config.clone(), tx.clone()),
+            Autoproxy::new(config.clone(), tx.clone()),
+            Linktest::new(config.clone(), tx.clone()),
+            Autoenv::new(config.clone(), tx.clone()),
+            Autocert::new(config.clone(), tx.clone()),
+            Autoblob::new(config.clone(), tx.clone()),
+            Autosoftware::new(config.clone(), tx.clone()),
+            Autodisk::new(config.clone(), tx.clone()),
+            Autonet::new(config.clone(), tx.clone()),
+            Autostartup::new(config.clone(), tx.clone()),
+            Api::new(config.clone(), tx.clone()),
+            Metrics::new(config.clone(), tx.clone()),
+            Control::new(config.clone(), tx.clone()),
+            Hooks::new(config.clone(), tx.clone()),
+            Templates::new(config.clone(), tx.clone()),
+            Webhooks::new(config.clone(), tx.clone()),
+            Cloudinit::new(config.clone(), tx.clone()),
+            Syncserver::new(config.clone(), tx.clone()),
+        )?;
 
-    log::info!("Starting all applets...");
+        log::info!("Starting all applets...");
 
-    tokio::join!(
-        run_applet("signal", signal),
+        #[cfg(feature = "dbus")]
+        applets.push(("dbus", Dbus::new(config.clone(), tx.clone()).await?));
 
-        run_applet("tmcc", tmcc),
-        run_applet("autouser", autouser),
-        run_applet("automount", automount),
-        run_applet("autohost", autohost),
-    );
+        let custom = join_all(applets.into_iter().map(|(name, applet)| run_applet(&tx, &config, name, applet)));
+
+        tokio::join!(
+            run_applet(&tx, &config, "signal", signal),
+
+            run_applet(&tx, &config, "tmcc", tmcc),
+            run_applet(&tx, &config, "autouser", autouser),
+            run_applet(&tx, &config, "automount", automount),
+            run_applet(&tx, &config, "autohost", autohost),
+            run_applet(&tx, &config, "autoswap", autoswap),
+            run_applet(&tx, &config, "autorepair", autorepair),
+            run_applet(&tx, &config, "watchdog", watchdog),
+            run_applet(&tx, &config, "autossh", autossh),
+            run_applet(&tx, &config, "autoconsole", autoconsole),
+            run_applet(&tx, &config, "automotd", automotd),
+            run_applet(&tx, &config, "autolocale", autolocale),
+            run_applet(&tx, &config, "autoproxy", autoproxy),
+            run_applet(&tx, &config, "linktest", linktest),
+            run_applet(&tx, &config, "autoenv", autoenv),
+            run_applet(&tx, &config, "autocert", autocert),
+            run_applet(&tx, &config, "autoblob", autoblob),
+            run_applet(&tx, &config, "autosoftware", autosoftware),
+            run_applet(&tx, &config, "autodisk", autodisk),
+            run_applet(&tx, &config, "autonet", autonet),
+            run_applet(&tx, &config, "autostartup", autostartup),
+            run_applet(&tx, &config, "api", api),
+            run_applet(&tx, &config, "metrics", metrics),
+            run_applet(&tx, &config, "control", control),
+            run_applet(&tx, &config, "hooks", hooks),
+            run_applet(&tx, &config, "templates", templates),
+            run_applet(&tx, &config, "webhooks", webhooks),
+            run_applet(&tx, &config, "cloudinit", cloudinit),
+            run_applet(&tx, &config, "syncserver", syncserver),
+
+            custom,
+        );
+
+        Ok(())
+    }
+}
+
+/// Report a state to the testbed.
+///
+/// This lets other tools (e.g., the image loader in a reload MFS)
+/// report their progress with our configuration.
+pub async fn report(config: ConfigInner, state: State) -> Result<()> {
+    let tmcc = tmcc::client(&config).await?;
+
+    log::info!("Reporting {} to the testbed...", state.as_ref());
+    tmcc.state(&state).await?;
 
     Ok(())
 }
+
+/// Find the boss node, returning its host name and resolved address.
+pub async fn discover_boss(config: ConfigInner) -> Result<(String, SocketAddr)> {
+    tmcc::resolve_boss(&config).await
+}
+
+/// Information that can be queried from the testbed.
+#[derive(Debug, Clone, Copy)]
+pub enum Query {
+    Accounts,
+    Mounts,
+}
+
+impl Query {
+    /// Returns the TMCD command.
+    fn command(&self) -> &'static str {
+        match self {
+            Self::Accounts => "accounts",
+            Self::Mounts => "mounts",
+        }
+    }
+}
+
+/// Query the testbed, returning the response as received.
+///
+/// Secrets are redacted unless disabled with `tmcc.redact`.
+pub async fn query_raw(config: ConfigInner, query: Query) -> Result<String> {
+    let tmcc = tmcc::client(&config).await?;
+
+    let response = tmcc.raw(query.command(), &[]).await?
+        .iter()
+        .map(|response| format!("{}\n", redact::line(&response.line)))
+        .collect();
+
+    Ok(response)
+}
+
+/// Query the testbed, returning the parsed response as JSON.
+pub async fn query_json(config: ConfigInner, query: Query) -> Result<String> {
+    let tmcc = tmcc::client(&config).await?;
+
+    let json = match query {
+        Query::Accounts => serde_json::to_string_pretty(&tmcc.accounts().await?),
+        Query::Mounts => serde_json::to_string_pretty(&tmcc.mounts().await?),
+    };
+
+    Ok(json.expect("Failed to serialize response"))
+}
+
+/// Wait at a barrier, returning the error code everyone was
+/// released with.
+///
+/// Unless given, the sync server is looked up from the testbed.
+pub async fn sync(config: ConfigInner, barrier: Barrier, server: Option<String>) -> Result<i32> {
+    let server = match server {
+        Some(server) => server,
+        None => {
+            let tmcc = tmcc::client(&config).await?;
+            tmcc.syncserver().await?
+                .ok_or(Error::SyncNoServer)?
+                .server
+        }
+    };
+
+    crate::sync::wait(&server, config.syncserver.port, &barrier).await
+}
+
+/// Run the applets once in dry-run mode.
+///
+/// TMCD responses are served from `fixtures`, a directory containing
+/// a file for each command (e.g., `accounts.txt`) or a recording made
+/// with `tmcc.record-dir`. Instead of changing the system, the applets
+/// record the actions they would have taken, which are returned.
+pub async fn simulate(mut config: ConfigInner, fixtures: PathBuf) -> Result<Vec<Action>> {
+    plan::enable();
+    config.tmcc.replay_dir = Some(fixtures);
+
+    fault::init(&config);
+    command::init(&config);
+
+    let config = Arc::new(config);
+
+    let (tx, mut rx) = broadcast::channel(CHANNEL_CAPACITY);
+    let (chunk_tx, chunk_rx) = mpsc::channel(CHUNK_CAPACITY);
+
+    let (tmcc, autouser, automount, autohost) = tokio::try_join!(
+        Tmcc::new(config.clone(), tx.clone(), chunk_tx),
+        Autouser::new(config.clone(), tx.clone(), chunk_rx),
+        Automount::new(config.clone(), tx.clone()),
+        Autohost::new(config.clone(), tx.clone()),
+    )?;
+
+    // Wait for the applets to finish applying everything sent
+    // during the reload, then shut them down
+    let watch = async {
+        let mut pending = 0;
+        let mut reloaded = false;
+
+        while !reloaded || pending > 0 {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+
+            match message {
+                Message::UpdateAccounts(_) if config.autouser.enable => pending += 1,
+                Message::UpdateAccountsChunk(chunk) if chunk.last && config.autouser.enable => pending += 1,
+                Message::UpdateMounts(_) if config.automount.enable => pending += 1,
+                Message::UpdateCanonical(_, _) if config.autohost.enable => pending += 1,
+                Message::UpdateAccountsOk | Message::UpdateMountsOk | Message::UpdateCanonicalOk => pending -= 1,
+                Message::ReloadTestbedOk => reloaded = true,
+                _ => {}
+            }
+        }
+
+        send(&tx, Message::Shutdown(ShutdownReason::Simulated));
+
+        Result::Ok(())
+    };
+
+    // Replayed responses are available immediately, so the other
+    // applets must subscribe before `tmcc` starts sending
+    tokio::try_join!(
+        watch,
+        autouser.main(),
+        automount.main(),
+        autohost.main(),
+        tmcc.main(),
+    )?;
+
+    Ok(plan::actions())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recv_or_lag() {
+        let (tx, mut rx) = broadcast::channel(2);
+        for _ in 0..3 {
+            send(&tx, Message::ReloadTestbed);
+        }
+        send(&tx, Message::ReloadTestbedOk);
+
+        assert_eq!(Some(Err(2)), recv_or_lag(&mut rx).await.map(|res| res.map(|message| message.name())));
+        assert!(matches!(recv_or_lag(&mut rx).await, Some(Ok(Message::ReloadTestbed))));
+        assert!(matches!(recv(&mut rx).await, Some(Message::ReloadTestbedOk)));
+
+        drop(tx);
+        assert!(recv_or_lag(&mut rx).await.is_none());
+    }
+}
@@ -4,13 +4,13 @@ use async_trait::async_trait;
 use tokio::signal::unix::{SignalKind, signal};
 
 use crate::error::Result;
-use super::{Applet, Sender, Message, ShutdownReason};
+use super::{Applet, Sender, Message, ShutdownReason, send};
 
 async fn watch(kind: SignalKind, message: Message, tx: Sender) {
     signal(kind).unwrap().recv().await;
 
     log::info!("Received signal {:?}. Broadcasting {:?} to applets...", kind, message);
-    tx.send(message).unwrap();
+    send(&tx, message);
 }
 
 pub struct Signal {
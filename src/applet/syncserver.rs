@@ -0,0 +1,132 @@
+//! The `syncserver` applet.
+//!
+//! It serves barriers to the other nodes of the experiment when the
+//! testbed designates this node as the sync server, taking the role
+//! of `emulab-syncd`. Nodes wait at barriers with `miniond sync` or
+//! `emulab-sync`.
+
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::plan;
+use crate::sync::{self, SYNC_PORT};
+use super::{Applet, Sender, Message, recv};
+
+/// `syncserver` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SyncserverConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Port of the sync server.
+    pub(super) port: u16,
+}
+
+impl Default for SyncserverConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            port: SYNC_PORT,
+        }
+    }
+}
+
+/// The `syncserver` applet.
+#[derive(Debug)]
+pub struct Syncserver {
+    config: Config,
+    tx: Sender,
+
+    /// The running server.
+    serving: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Syncserver {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+            serving: Mutex::new(None),
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Syncserver {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.syncserver.enable {
+            log::info!("syncserver applet disabled in config");
+            return Ok(());
+        }
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    if let Some(task) = self.serving.lock().unwrap().take() {
+                        task.abort();
+                    }
+                    break;
+                }
+
+                Message::UpdateSyncServer(server) => {
+                    let serving = self.serving.lock().unwrap().is_some();
+
+                    if server.is_server && !serving {
+                        self.serve().await?;
+                    } else if !server.is_server && serving {
+                        log::info!("No longer the sync server - Stopping");
+                        self.serving.lock().unwrap().take().unwrap().abort();
+                    }
+                }
+
+                Message::Swapout => {
+                    if let Some(task) = self.serving.lock().unwrap().take() {
+                        task.abort();
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Syncserver {
+    /// Start serving barriers.
+    async fn serve(&self) -> Result<()> {
+        let port = self.config.syncserver.port;
+
+        if plan::is_dry_run() {
+            log::info!("Would serve barriers on port {}", port);
+            return Ok(());
+        }
+
+        log::info!("This node is the sync server - Serving barriers on port {}...", port);
+
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        let task = tokio::spawn(async move {
+            if let Err(e) = sync::serve(listener).await {
+                log::error!("Sync server failed: {}", e);
+            }
+        });
+
+        *self.serving.lock().unwrap() = Some(task);
+
+        Ok(())
+    }
+}
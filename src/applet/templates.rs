@@ -0,0 +1,301 @@
+//! The `templates` applet.
+//!
+//! It renders templates supplied by the operator to files, so
+//! configurations that depend on the experiment can be generated on
+//! every node (e.g., an MPI hostfile):
+//!
+//! ```toml
+//! [[templates.files]]
+//! template = "/etc/miniond/templates/hostfile.j2"
+//! path = "/etc/mpi/hostfile"
+//! ```
+//!
+//! Templates use the Jinja syntax and are rendered once the testbed
+//! information is applied. Files are only written if they changed.
+//!
+//! It can also write the experiment metadata as JSON (e.g., to
+//! `/run/miniond/experiment.json`), so experiment software can
+//! discover the topology without talking to TMCD.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use minijinja::Environment;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::fs;
+
+use miniond_core::geni::Peer;
+use crate::account::{User, Group};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::mount::Mount;
+use crate::net::InterfaceConfig;
+use crate::overlay;
+use crate::tmcc::Localization;
+use super::{Applet, Sender, Message, recv};
+
+/// `templates` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TemplatesConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// Templates to render.
+    files: Vec<TemplateFile>,
+
+    /// Where to write the experiment metadata as JSON, if anywhere.
+    metadata: Option<PathBuf>,
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            files: Vec::new(),
+            metadata: None,
+        }
+    }
+}
+
+/// A template to render.
+#[derive(Debug, Deserialize)]
+pub struct TemplateFile {
+    /// Path to the template.
+    template: PathBuf,
+
+    /// Path to write the result to.
+    path: PathBuf,
+}
+
+/// The `templates` applet.
+#[derive(Debug)]
+pub struct Templates {
+    config: Config,
+    tx: Sender,
+}
+
+/// Information available to templates.
+#[derive(Debug, Default)]
+struct Inventory {
+    experiment: Option<String>,
+    node: Option<String>,
+    fqdn: Option<String>,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    expires: Option<String>,
+    peers: Vec<Peer>,
+    interfaces: Vec<InterfaceConfig>,
+    users: BTreeMap<String, User>,
+    groups: BTreeMap<String, Group>,
+    mounts: Vec<Mount>,
+    env: BTreeMap<String, String>,
+    localization: Localization,
+}
+
+impl Templates {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Templates {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        let templates = &self.config.templates;
+        if !templates.enable || (templates.files.is_empty() && templates.metadata.is_none()) {
+            log::info!("templates applet disabled in config");
+            return Ok(());
+        }
+
+        let mut inventory = Inventory::default();
+
+        // Accounts being received in chunks
+        let mut chunks: Option<(BTreeMap<String, User>, BTreeMap<String, Group>)> = None;
+
+        loop {
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
+            match message {
+                Message::Shutdown(_) => {
+                    break;
+                }
+
+                Message::UpdateAllocation(allocation) => {
+                    inventory.experiment = Some(allocation.experiment);
+                    inventory.node = Some(allocation.node_name);
+                }
+
+                Message::UpdateCanonical(fqdn, addresses) => {
+                    inventory.fqdn = Some(fqdn);
+                    inventory.ipv4 = addresses.iter().find_map(|address| match address {
+                        IpAddr::V4(address) => Some(*address),
+                        IpAddr::V6(_) => None,
+                    });
+                    inventory.ipv6 = addresses.iter().find_map(|address| match address {
+                        IpAddr::V6(address) => Some(*address),
+                        IpAddr::V4(_) => None,
+                    });
+                }
+
+                Message::UpdateExpiration(expires) => {
+                    inventory.expires = Some(expires);
+                }
+
+                Message::UpdatePeers(peers) => {
+                    inventory.peers = peers;
+                }
+
+                Message::UpdateInterfaces(interfaces) => {
+                    inventory.interfaces = interfaces;
+                }
+
+                Message::UpdateAccounts(accounts) => {
+                    inventory.users = accounts.users.into_iter().collect();
+                    inventory.groups = accounts.groups.into_iter().collect();
+                }
+
+                Message::UpdateAccountsChunk(chunk) => {
+                    let (users, groups) = chunks.get_or_insert_with(Default::default);
+                    users.extend(chunk.users.iter().map(|user| (user.login().to_string(), user.clone())));
+                    groups.extend(chunk.groups.iter().map(|group| (group.name().to_string(), group.clone())));
+
+                    if chunk.last {
+                        let (users, groups) = chunks.take().unwrap();
+                        inventory.users = users;
+                        inventory.groups = groups;
+                    }
+                }
+
+                Message::UpdateMounts(mounts) => {
+                    inventory.mounts = mounts;
+                }
+
+                Message::UpdateLocalization(localization) => {
+                    inventory.localization = localization;
+                }
+
+                Message::UpdateEnvironment(env) => {
+                    inventory.env = env.into_iter().collect();
+                }
+
+                Message::ReloadTestbedOk => {
+                    let context = inventory.context();
+
+                    for file in &self.config.templates.files {
+                        if let Err(e) = self.render(file, &context).await {
+                            log::warn!("{}", e);
+                        }
+                    }
+
+                    if let Some(path) = &self.config.templates.metadata {
+                        let contents = format!("{:#}\n", inventory.metadata());
+                        if let Err(e) = overlay::update_file(&self.config.overlay, path, contents).await {
+                            log::warn!("{}", e);
+                        }
+                    }
+                }
+
+                Message::Swapout => {
+                    inventory = Inventory::default();
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Templates {
+    /// Render a template, writing the result if it changed.
+    async fn render(&self, file: &TemplateFile, context: &Value) -> Result<()> {
+        let source = fs::read_to_string(&file.template).await?;
+
+        let mut env = Environment::new();
+        env.set_keep_trailing_newline(true);
+
+        let contents = env.render_str(&source, context)
+            .map_err(|error| Error::TemplateError {
+                template: file.template.clone(),
+                error,
+            })?;
+
+        overlay::update_file(&self.config.overlay, &file.path, contents).await?;
+
+        Ok(())
+    }
+}
+
+impl Inventory {
+    /// Returns the experiment metadata.
+    fn metadata(&self) -> Value {
+        // The experiment is named `project/experiment`
+        let (project, experiment) = match self.experiment.as_deref().and_then(|name| name.split_once('/')) {
+            Some((project, experiment)) => (Some(project), Some(experiment)),
+            None => (None, self.experiment.as_deref()),
+        };
+
+        json!({
+            "project": project,
+            "experiment": experiment,
+            "node": self.node,
+            "fqdn": self.fqdn,
+            "control_ip": self.ipv4,
+            "control_ipv6": self.ipv6,
+            "peers": self.peers.iter()
+                .map(|p| json!({ "link": p.link, "node": p.node, "address": p.address }))
+                .collect::<Vec<_>>(),
+            "mounts": self.mounts.iter()
+                .map(|m| json!({ "remote": m.remote(), "local": m.local(), "type": m.filesystem().fs_type() }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// Returns the context templates are rendered with.
+    fn context(&self) -> Value {
+        json!({
+            "node": {
+                "experiment": self.experiment,
+                "name": self.node,
+                "fqdn": self.fqdn,
+                "ipv4": self.ipv4,
+                "ipv6": self.ipv6,
+                "expires": self.expires,
+            },
+            "peers": self.peers.iter()
+                .map(|p| json!({ "link": p.link, "node": p.node, "address": p.address }))
+                .collect::<Vec<_>>(),
+            "interfaces": self.interfaces,
+            "users": self.users.values()
+                .map(|u| json!({
+                    "login": u.login(),
+                    "uid": u.uid(),
+                    "gid": u.gid(),
+                    "home": u.home_dir(),
+                    "shell": u.login_shell(),
+                    "root": u.is_root(),
+                }))
+                .collect::<Vec<_>>(),
+            "groups": self.groups.values()
+                .map(|g| json!({ "name": g.name(), "gid": g.gid() }))
+                .collect::<Vec<_>>(),
+            "mounts": self.mounts.iter()
+                .map(|m| json!({ "remote": m.remote(), "local": m.local(), "type": m.filesystem().fs_type() }))
+                .collect::<Vec<_>>(),
+            "env": self.env,
+            "localization": self.localization,
+        })
+    }
+}
@@ -3,15 +3,38 @@
 //! This applet uses `crate::tmcc` to communicate with the Testbed
 //! Management Control Daemon (TMCD).
 
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use serde::Deserialize;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use miniond_core::backoff::Backoff;
+use miniond_core::geni::Peer;
+use miniond_core::net::InterfaceConfig;
+use miniond_core::redact;
 
-use crate::config::Config;
-use crate::tmcc::{Tmcc as TmccClient, State, BossNode, TMCD_PORT};
-use crate::error::{Error, Result};
-use super::{Applet, Sender, Message, ShutdownReason};
+use crate::account::{self, Accounts};
+use crate::clock;
+use crate::config::{Config, ConfigInner};
+use crate::fault;
+use crate::metrics;
+use crate::mount::{Filesystem, Mount};
+use crate::state;
+use crate::tmcc::{Tmcc as TmccClient, AllocationStatus, RootKeypair, State, BossNode, TMCD_PORT, TMCD_TLS_PORT, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_RESPONSE_SIZE, DEFAULT_CONNECT_RETRIES, DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT, DEFAULT_RETRY_DELAY, DEFAULT_MAX_RETRY_DELAY};
+use crate::error::{Result, Severity};
+use super::{Applet, Sender, Receiver, ChunkSender, Message, Secrets, Software, ShutdownReason, send, recv};
+
+/// How long to wait before retrying an unreachable boss at first.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long to wait before retrying an unreachable boss at most.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(120);
 
 #[derive(Debug, Deserialize)]
 #[serde(default)]
@@ -24,9 +47,209 @@ pub struct TmccConfig {
     /// The TMCD port.
     port: u16,
 
+    /// The virtual node to act as.
+    ///
+    /// By default, we act as the physical node.
+    pub(crate) vnode: Option<String>,
+
     /// Whether to report shutdowns to the testbed.
     #[serde(rename = "report-shutdown")]
     report_shutdown: bool,
+
+    /// Whether we are running from a reload MFS.
+    ///
+    /// The disk is reloaded by other tools, which report `RELOADDONE`
+    /// with `miniond report reload-done` when they are finished.
+    #[serde(rename = "reload-mfs")]
+    reload_mfs: bool,
+
+    /// The states to report to the testbed.
+    pub(super) states: StatesConfig,
+
+    /// Whether to connect to the boss over IPv6 if it has both IPv4
+    /// and IPv6 addresses.
+    #[serde(rename = "prefer-ipv6")]
+    prefer_ipv6: bool,
+
+    /// Maximum number of concurrent connections to the boss.
+    #[serde(rename = "max-connections")]
+    max_connections: usize,
+
+    /// Number of times to retry a connection to the boss that failed
+    /// for a transient reason.
+    #[serde(rename = "connect-retries")]
+    connect_retries: u32,
+
+    /// How long to wait for a connection to the boss, in seconds.
+    #[serde(rename = "connect-timeout")]
+    connect_timeout: u64,
+
+    /// How long to wait for a response to make progress, in seconds.
+    #[serde(rename = "read-timeout")]
+    read_timeout: u64,
+
+    /// Delay before the first retry, in milliseconds.
+    ///
+    /// It doubles with each consecutive failure, up to the maximum.
+    /// This applies to connections to the boss and to respawns of
+    /// failed applets.
+    #[serde(rename = "retry-delay-ms")]
+    retry_delay_ms: u64,
+
+    /// Maximum delay between retries, in seconds.
+    #[serde(rename = "max-retry-delay")]
+    max_retry_delay: u64,
+
+    /// Whether to randomize delays between retries.
+    #[serde(rename = "retry-jitter")]
+    retry_jitter: bool,
+
+    /// Number of times to respawn an applet that keeps failing for a
+    /// transient reason before giving up on it.
+    ///
+    /// By default, applets are respawned forever.
+    #[serde(rename = "respawn-retries")]
+    pub(super) respawn_retries: Option<u32>,
+
+    /// Whether to send state reports and status polls over UDP.
+    udp: bool,
+
+    /// How long to wait for a UDP response before using TCP, in
+    /// milliseconds.
+    #[serde(rename = "udp-timeout-ms")]
+    udp_timeout_ms: u64,
+
+    /// Maximum size of a response, in bytes.
+    #[serde(rename = "max-response-size")]
+    max_response_size: u64,
+
+    /// Maximum sizes of responses to specific commands, in bytes.
+    #[serde(rename = "command-max-response-size")]
+    command_max_response_size: HashMap<String, u64>,
+
+    /// Number of users to apply at a time while accounts are being received.
+    ///
+    /// By default, all accounts are received before they are applied.
+    #[serde(rename = "account-chunk-size")]
+    account_chunk_size: Option<usize>,
+
+    /// How long to reuse the GENI manifest of an unchanged allocation, in seconds.
+    #[serde(rename = "manifest-ttl")]
+    manifest_ttl: u64,
+
+    /// Directory to record all TMCD requests and responses to.
+    #[serde(rename = "record-dir")]
+    record_dir: Option<PathBuf>,
+
+    /// Directory to replay recorded TMCD responses from, instead of contacting the boss.
+    #[serde(rename = "replay-dir")]
+    pub(super) replay_dir: Option<PathBuf>,
+
+    /// Directory to dump responses that fail to parse to, for bug reports.
+    #[serde(rename = "dump-dir")]
+    dump_dir: Option<PathBuf>,
+
+    /// Whether to mask secrets (e.g., password hashes and private keys)
+    /// in TMCD responses that end up in logs, errors and dumps.
+    ///
+    /// Disable for full verbosity in lab settings.
+    redact: bool,
+
+    /// TMCD over TLS.
+    tls: TlsConfig,
+
+    /// Whether to cache the last accounts, mounts and canonical host
+    /// received from the testbed, and replay them at boot if the boss
+    /// is unreachable.
+    cache: bool,
+
+    /// Whether to install the root SSH keypair of the experiment into
+    /// root's `~/.ssh`, so root can log into the other nodes like with
+    /// the official client-side.
+    #[serde(rename = "install-root-keys")]
+    pub(super) install_root_keys: bool,
+}
+
+/// TMCD over TLS configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// When to use TLS.
+    mode: TlsMode,
+
+    /// The port of TMCD over TLS.
+    port: u16,
+
+    /// The client certificate and key of the node.
+    certificate: PathBuf,
+
+    /// The CA certificate of the testbed.
+    ca: PathBuf,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            mode: TlsMode::Off,
+            port: TMCD_TLS_PORT,
+            certificate: PathBuf::from("/etc/emulab/client.pem"),
+            ca: PathBuf::from("/etc/emulab/emulab.pem"),
+        }
+    }
+}
+
+/// The states reported to the testbed at each step.
+///
+/// The boss expects different states depending on the operating mode
+/// of the node (e.g., `TBSETUP` when booting a disk image in the
+/// `NORMALv2` mode), and times out nodes that don't report them.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct StatesConfig {
+    /// Reported as soon as the boss is found, before the setup state.
+    pub(super) boot: Option<State>,
+
+    /// Reported when setting up.
+    ///
+    /// By default, `RELOADSETUP` in a reload MFS and `MFSSETUP`
+    /// otherwise.
+    pub(super) setup: Option<State>,
+
+    /// Reported once the accounts are in place.
+    pub(super) up: State,
+
+    /// Reported when shutting down.
+    pub(super) shutdown: State,
+
+    /// Reported when an applet fails for good.
+    pub(super) failed: State,
+}
+
+impl Default for StatesConfig {
+    fn default() -> Self {
+        Self {
+            boot: None,
+            setup: None,
+            up: State::Up,
+            shutdown: State::Shutdown,
+            failed: State::Failed,
+        }
+    }
+}
+
+/// When to use TMCD over TLS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Always use plaintext TCP.
+    Off,
+
+    /// Always use TLS.
+    On,
+
+    /// Use TLS if the node has a certificate, and fall back to
+    /// plaintext TCP if TLS fails.
+    Auto,
 }
 
 impl Default for TmccConfig {
@@ -34,99 +257,760 @@ impl Default for TmccConfig {
         Self {
             boss: None,
             port: TMCD_PORT,
+            vnode: None,
             report_shutdown: true,
+            reload_mfs: false,
+            states: StatesConfig::default(),
+            prefer_ipv6: false,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT.as_secs(),
+            read_timeout: DEFAULT_READ_TIMEOUT.as_secs(),
+            retry_delay_ms: DEFAULT_RETRY_DELAY.as_millis() as u64,
+            max_retry_delay: DEFAULT_MAX_RETRY_DELAY.as_secs(),
+            retry_jitter: true,
+            respawn_retries: None,
+            udp: false,
+            udp_timeout_ms: 500,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            command_max_response_size: HashMap::new(),
+            account_chunk_size: None,
+            manifest_ttl: 3600,
+            record_dir: None,
+            replay_dir: None,
+            dump_dir: None,
+            redact: true,
+            tls: TlsConfig::default(),
+            cache: false,
+            install_root_keys: false,
+        }
+    }
+}
+
+impl TmccConfig {
+    /// Returns the delays between retries.
+    pub(super) fn backoff(&self) -> Backoff {
+        Backoff::new(Duration::from_millis(self.retry_delay_ms), Duration::from_secs(self.max_retry_delay))
+            .jitter(self.retry_jitter)
+    }
+
+    /// Returns the state to report when setting up.
+    pub(super) fn setup_state(&self) -> State {
+        match self.states.setup {
+            Some(state) => state,
+            None if self.reload_mfs => State::ReloadSetup,
+            None => State::Setup,
+        }
+    }
+}
+
+/// Find the boss node as configured, returning its host name and
+/// resolved address.
+pub(super) async fn resolve_boss(config: &ConfigInner) -> Result<(String, SocketAddr)> {
+    let boss = if let Some(boss) = &config.tmcc.boss {
+        BossNode::host_port(boss, config.tmcc.port)
+    } else {
+        log::info!("Looking for the boss node...");
+        BossNode::discover().await?
+    };
+
+    let BossNode::HostPort((host, _)) = &boss;
+    let host = host.clone();
+    let addr = boss.resolve(config.tmcc.prefer_ipv6).await?;
+
+    Ok((host, addr))
+}
+
+/// Create a TMCD client as configured.
+pub(super) async fn client(config: &ConfigInner) -> Result<TmccClient> {
+    redact::set_enabled(config.tmcc.redact);
+
+    let tmcc = if let Some(dir) = &config.tmcc.replay_dir {
+        TmccClient::replay(dir)?
+    } else {
+        let (_, addr) = resolve_boss(config).await?;
+        let tmcc = TmccClient::from_addr(addr);
+        let tls = &config.tmcc.tls;
+
+        match tls.mode {
+            TlsMode::Off => tmcc,
+            TlsMode::On => tmcc.tls(&tls.certificate, &tls.ca, tls.port, false)?,
+            TlsMode::Auto if !tls.certificate.exists() || !tls.ca.exists() => {
+                log::debug!("No TMCD client certificate - Using plaintext TCP");
+                tmcc
+            }
+            TlsMode::Auto => match tmcc.tls(&tls.certificate, &tls.ca, tls.port, true) {
+                Ok(tmcc) => tmcc,
+                Err(e) => {
+                    log::warn!("{} - Using plaintext TCP", e);
+                    TmccClient::from_addr(addr)
+                }
+            },
+        }
+    };
+
+    let mut tmcc = tmcc
+        .max_connections(config.tmcc.max_connections)
+        .connect_retries(config.tmcc.connect_retries)
+        .retry_backoff(config.tmcc.backoff())
+        .connect_timeout(Duration::from_secs(config.tmcc.connect_timeout))
+        .read_timeout(Duration::from_secs(config.tmcc.read_timeout))
+        .max_response_size(config.tmcc.max_response_size)
+        .layer(fault::wrap)
+        .observe(metrics::observe_tmcd);
+
+    for (command, max) in &config.tmcc.command_max_response_size {
+        tmcc = tmcc.command_max_response_size(command, *max);
+    }
+
+    if config.tmcc.udp {
+        tmcc = tmcc.udp(Duration::from_millis(config.tmcc.udp_timeout_ms));
+    }
+
+    if let Some(vnode) = &config.tmcc.vnode {
+        log::info!("Acting as virtual node {}", vnode);
+        tmcc = tmcc.vnode(vnode.clone());
+    }
+
+    if let Some(dir) = &config.tmcc.record_dir {
+        tmcc = tmcc.record_dir(dir.clone())?;
+    }
+
+    if let Some(dir) = &config.tmcc.dump_dir {
+        tmcc = tmcc.dump_dir(dir.clone());
+    }
+
+    Ok(tmcc)
+}
+
+/// Create a TMCD client and tell the testbed that we have booted.
+async fn connect(config: &ConfigInner) -> Result<TmccClient> {
+    let tmcc = client(config).await?;
+
+    // A badly skewed clock breaks TLS to the boss
+    if let Some(addr) = tmcc.boss_addr() {
+        if let Err(e) = clock::step(&config.clock, addr.ip()).await {
+            log::warn!("{}", e);
         }
     }
+
+    // Report as soon as the boss is known, without waiting
+    // for the other applets to be ready
+    log::info!("Informing testbed that we have booted...");
+    if let Some(boot) = &config.tmcc.states.boot {
+        tmcc.state(boot).await?;
+    }
+    tmcc.state(&config.tmcc.setup_state()).await?;
+
+    Ok(tmcc)
 }
 
 /// The `tmcc` applet.
 pub struct Tmcc {
     config: Config,
-    tmcc: TmccClient,
+
+    /// The TMCD client.
+    ///
+    /// This is empty while the boss is unreachable at boot.
+    tmcc: OnceCell<TmccClient>,
+
     tx: Sender,
+
+    /// Account chunks for `autouser`.
+    chunk_tx: ChunkSender,
+
     account_initialized: AtomicBool,
+
+    /// Whether we have reported TBFAILED.
+    failure_reported: AtomicBool,
+
+    /// Information derived from the last GENI manifest we fetched.
+    manifest_cache: Mutex<Option<ManifestCache>>,
+
+    /// The last allocation status we saw.
+    ///
+    /// This is `None` until the first check.
+    allocation: Mutex<Option<Option<AllocationStatus>>>,
+
+    /// What the current reload received, for the testbed cache.
+    received: Mutex<TestbedCache>,
+}
+
+/// The last good information received from the testbed.
+///
+/// Mount credentials are never serialized, so they aren't cached.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TestbedCache {
+    accounts: Option<Accounts>,
+    mounts: Option<Vec<Mount>>,
+    canonical: Option<(String, Vec<IpAddr>)>,
+}
+
+/// A cached GENI manifest.
+struct ManifestCache {
+    /// The allocation the manifest was fetched for.
+    allocation: AllocationStatus,
+
+    /// When the manifest was fetched.
+    fetched: Instant,
+
+    info: ManifestInfo,
+}
+
+/// Information derived from a GENI manifest.
+#[derive(Clone)]
+struct ManifestInfo {
+    fqdn: String,
+    addresses: Vec<IpAddr>,
+    expires: Option<String>,
+    peers: Vec<Peer>,
+    interfaces: Vec<InterfaceConfig>,
 }
 
 impl Tmcc {
-    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
-        let tmcc = if let Some(boss) = &config.tmcc.boss {
-            let port = config.tmcc.port;
-            let boss = BossNode::HostPort((boss.to_string(), port));
-            TmccClient::new(boss).await?
-        } else {
-            log::info!("Looking for the boss node...");
-            TmccClient::discover().await?
-        };
+    pub(super) async fn new(config: Config, tx: Sender, chunk_tx: ChunkSender) -> Result<Box<dyn Applet>> {
+        let tmcc = OnceCell::new();
+
+        match connect(&config).await {
+            Ok(client) => {
+                tmcc.set(client).ok();
+            }
+            Err(e) if config.tmcc.cache && e.is_transient() => {
+                log::warn!("{} - Using the testbed cache until the boss is reachable", e);
+            }
+            Err(e) => return Err(e),
+        }
 
         Ok(Box::new(Self {
             config,
             tmcc,
             tx,
+            chunk_tx,
             account_initialized: AtomicBool::new(false),
+            failure_reported: AtomicBool::new(false),
+            manifest_cache: Mutex::new(None),
+            allocation: Mutex::new(None),
+            received: Mutex::new(TestbedCache::default()),
         }))
     }
+
+    /// Returns the TMCD client.
+    fn tmcc(&self) -> &TmccClient {
+        self.tmcc.get().expect("Not connected to the boss")
+    }
+
+    /// Replay the testbed cache to the other applets.
+    async fn replay_cache(&self) {
+        let cache: TestbedCache = state::load(&self.config.state, "testbed").await;
+
+        if let Some(accounts) = cache.accounts {
+            log::info!("Replaying cached accounts...");
+            send(&self.tx, Message::UpdateAccounts(accounts));
+        }
+
+        if let Some(mounts) = cache.mounts {
+            if mounts.iter().any(|mount| *mount.filesystem() == Filesystem::Smb) {
+                log::warn!("Cached mounts need credentials - Not replaying them");
+            } else {
+                log::info!("Replaying cached mounts...");
+                send(&self.tx, Message::UpdateMounts(mounts));
+            }
+        }
+
+        if let Some((fqdn, addresses)) = cache.canonical {
+            log::info!("Replaying cached FQDN: {} -> {:?}", fqdn, addresses);
+            send(&self.tx, Message::UpdateCanonical(fqdn, addresses));
+        }
+    }
+
+    /// Connect to the boss, retrying until it's reachable.
+    ///
+    /// Returns `false` if we are asked to shut down first.
+    async fn reconnect(&self, rx: &mut Receiver) -> Result<bool> {
+        let mut backoff = Backoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY)
+            .jitter(self.config.tmcc.retry_jitter);
+        let mut delay = backoff.next_delay();
+
+        loop {
+            let retry = tokio::time::sleep(delay);
+            tokio::pin!(retry);
+
+            loop {
+                tokio::select! {
+                    message = recv(rx) => match message {
+                        Some(Message::Shutdown(_)) | None => return Ok(false),
+                        _ => {}
+                    },
+                    _ = &mut retry => break,
+                }
+            }
+
+            match connect(&self.config).await {
+                Ok(client) => {
+                    log::info!("The boss node is reachable again");
+                    self.tmcc.set(client).ok();
+                    return Ok(true);
+                }
+                Err(e) if e.is_transient() => {
+                    delay = backoff.next_delay();
+                    log::warn!("{} - Retrying in {:?}", e, delay);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns information about the current node from the GENI manifest.
+    ///
+    /// Fetching and parsing the GENI manifest is expensive, and its
+    /// content rarely changes during an allocation. Therefore, we only
+    /// refetch it when the allocation changes or the cache expires.
+    async fn manifest_info(&self, allocation: AllocationStatus) -> Result<ManifestInfo> {
+        let ttl = Duration::from_secs(self.config.tmcc.manifest_ttl);
+
+        if let Some(cache) = &*self.manifest_cache.lock().unwrap() {
+            if cache.allocation.same_as(&allocation) && cache.fetched.elapsed() < ttl {
+                log::debug!("Allocation unchanged - Using cached manifest information");
+                return Ok(cache.info.clone());
+            }
+        }
+
+        let manifest = self.tmcc().geni_manifest().await?;
+        let current_node = manifest.get_node(&allocation.node_name)
+            .ok_or(miniond_core::Error::GeniNoSuchNode)?;
+
+        let info = ManifestInfo {
+            fqdn: current_node.fqdn(),
+            addresses: current_node.addresses(),
+            expires: manifest.expires().map(str::to_string),
+            peers: manifest.peers(&allocation.node_name),
+            interfaces: manifest.interfaces(&allocation.node_name),
+        };
+
+        *self.manifest_cache.lock().unwrap() = Some(ManifestCache {
+            allocation,
+            fetched: Instant::now(),
+            info: info.clone(),
+        });
+
+        Ok(info)
+    }
+
+    /// Retrieve the root keypair of the experiment.
+    ///
+    /// Older testbeds only send it with the localization, as `ROOTKEY`.
+    async fn root_keypair(&self) -> Option<RootKeypair> {
+        let keypair = match self.tmcc().root_keypair().await {
+            Ok(Some(keypair)) => Ok(Some(keypair)),
+            Ok(None) => self.tmcc().localization().await
+                .map(|localization| localization.root_keypair),
+            Err(e) => Err(e),
+        };
+
+        match keypair {
+            Ok(Some(keypair)) => Some(keypair),
+            Ok(None) => {
+                log::warn!("The experiment has no root keypair");
+                None
+            }
+            Err(e) => {
+                log::warn!("Failed to retrieve the root keypair: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Record the allocation status, announcing swapouts and swapins.
+    fn track_allocation(&self, current: Option<AllocationStatus>) {
+        let previous = self.allocation.lock().unwrap().replace(current.clone());
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return,
+        };
+
+        let (swapout, swapin) = match (&previous, &current) {
+            (Some(previous), Some(current)) => {
+                let changed = !previous.same_as(current);
+                (changed, changed)
+            }
+            (Some(_), None) => (true, false),
+            (None, Some(_)) => (false, true),
+            (None, None) => (false, false),
+        };
+
+        if swapout {
+            log::warn!("The current node is no longer allocated to {}", previous.unwrap().experiment);
+            send(&self.tx, Message::Swapout);
+        }
+
+        if swapin {
+            log::info!("The current node is now allocated to {}", current.unwrap().experiment);
+            send(&self.tx, Message::Swapin);
+        }
+    }
 }
 
 #[async_trait]
 impl Applet for Tmcc {
     async fn main(&self) -> Result<()> {
+        let result = self.run().await;
+
+        if let Err(e) = &result {
+            if !e.is_transient() {
+                self.report_failure().await;
+            }
+        }
+
+        result
+    }
+}
+
+impl Tmcc {
+    /// Tell the testbed that setup failed for good.
+    ///
+    /// This is only done once, so the first failure is what the
+    /// testbed sees.
+    async fn report_failure(&self) {
+        if self.failure_reported.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let tmcc = match self.tmcc.get() {
+            Some(tmcc) => tmcc,
+            None => {
+                log::warn!("Cannot report failure - The boss node is unreachable");
+                return;
+            }
+        };
+
+        log::info!("Informing testbed that setup failed...");
+        let failed = self.config.tmcc.states.failed;
+        if let Err(e) = tmcc.state(&failed).await {
+            log::warn!("Failed to report failure: {}", e);
+            return;
+        }
+
+        send(&self.tx, Message::StateReported(failed));
+    }
+
+    async fn run(&self) -> Result<()> {
         let mut rx = self.tx.subscribe();
 
-        log::info!("Informing testbed that we have booted...");
-        self.tmcc.state(&State::Setup).await?;
+        if self.tmcc.get().is_none() {
+            self.replay_cache().await;
 
-        self.tx.send(Message::ReloadTestbed).unwrap();
+            if !self.reconnect(&mut rx).await? {
+                return Ok(());
+            }
+        }
+
+        send(&self.tx, Message::ReloadTestbed);
 
         loop {
-            let message = rx.recv().await.unwrap();
+            let message = match recv(&mut rx).await {
+                Some(message) => message,
+                None => break,
+            };
 
             match message {
                 Message::Shutdown(reason) => {
                     if reason == ShutdownReason::Signal && self.config.tmcc.report_shutdown {
                         log::info!("Informing testbed that we are shutting down...");
-                        self.tmcc.state(&State::Shutdown).await.unwrap();
+                        self.tmcc().state(&self.config.tmcc.states.shutdown).await.unwrap();
                     }
                     break;
                 }
+                Message::AppletFailed(applet, _, Severity::Fatal) => {
+                    log::error!("The {} applet failed for good", applet);
+                    self.report_failure().await;
+                }
+                Message::UpdateAccountsOk if self.config.tmcc.reload_mfs => {
+                    // The disk is still being reloaded
+                    log::debug!("Running from a reload MFS - Not reporting ISUP");
+                }
                 Message::UpdateAccountsOk => {
                     if !self.account_initialized.load(Ordering::Relaxed) {
                         log::info!("Informing testbed that we are ready...");
-                        self.tmcc.state(&State::Up).await?;
+                        let up = self.config.tmcc.states.up;
+                        self.tmcc().state(&up).await?;
+                        send(&self.tx, Message::StateReported(up));
                         self.account_initialized.store(true, Ordering::Relaxed);
                     }
                 }
+                Message::LinkTestResults(results) if self.config.linktest.report => {
+                    let log: Vec<String> = results.iter()
+                        .map(|r| format!("linktest: {} {} via {}: {}", r.node, r.address, r.link,
+                            if r.reachable { "reachable" } else { "UNREACHABLE" }))
+                        .collect();
+
+                    log::info!("Uploading linktest results to the testbed...");
+                    self.tmcc().bootlog(&log.join("\n")).await?;
+                }
+                Message::StartupFinished(status) => {
+                    log::info!("Reporting the exit status of the startup command to the testbed...");
+                    self.tmcc().startstatus(status).await?;
+                }
+                Message::CheckAllocation => {
+                    let allocation = self.tmcc().allocation_status().await?;
+                    self.track_allocation(allocation);
+                }
+                Message::Swapout if self.config.autoswap.enable => {
+                    *self.manifest_cache.lock().unwrap() = None;
+
+                    log::info!("Informing testbed that we are shutting down...");
+                    let shutdown = self.config.tmcc.states.shutdown;
+                    self.tmcc().state(&shutdown).await?;
+                    send(&self.tx, Message::StateReported(shutdown));
+                }
+                Message::Swapin if self.config.autoswap.enable => {
+                    // Go through the full setup again
+                    log::info!("Informing testbed that we have booted...");
+                    let setup = self.config.tmcc.setup_state();
+                    self.tmcc().state(&setup).await?;
+                    send(&self.tx, Message::StateReported(setup));
+
+                    self.account_initialized.store(false, Ordering::Relaxed);
+                    send(&self.tx, Message::ReloadTestbed);
+                }
                 Message::ReloadTestbed => {
                     log::info!("Reloading information from testbed...");
+                    *self.received.lock().unwrap() = TestbedCache::default();
 
-                    let (accounts, mounts, hostinfo) = tokio::join!(
+                    let (accounts, mounts, hostinfo, bootwhat, syncserver, tipline, localization, userenv, secrets, blobs, software, storage, startup) = tokio::join!(
                         async {
-                            let accounts = self.tmcc.accounts().await?;
-                            self.tx.send(Message::UpdateAccounts(accounts)).unwrap();
+                            // Root's keys are applied along with the accounts
+                            let autouser_keys = self.config.autouser.enable && self.config.autouser.root_keypair;
+                            if autouser_keys || self.config.tmcc.install_root_keys {
+                                if let Some(keypair) = self.root_keypair().await {
+                                    if self.config.tmcc.install_root_keys {
+                                        if let Err(e) = account::install_root_keypair(&keypair).await {
+                                            log::warn!("Failed to install the root keypair: {}", e);
+                                        }
+                                    }
+
+                                    if self.config.autouser.enable {
+                                        send(&self.tx, Message::UpdateRootKeypair(Arc::new(keypair)));
+                                    }
+                                }
+                            }
+
+                            if let Some(chunk_size) = self.config.tmcc.account_chunk_size {
+                                let (tx, mut rx) = mpsc::channel(1);
+
+                                let (res, _) = tokio::join!(
+                                    self.tmcc().stream_accounts(chunk_size, tx),
+                                    async {
+                                        while let Some(chunk) = rx.recv().await {
+                                            if self.config.tmcc.cache {
+                                                let mut received = self.received.lock().unwrap();
+                                                let accounts = received.accounts.get_or_insert_with(Accounts::new);
+                                                for user in &chunk.users {
+                                                    accounts.users.insert(user.login().to_string(), user.clone());
+                                                }
+                                                for group in &chunk.groups {
+                                                    accounts.groups.insert(group.name().to_string(), group.clone());
+                                                }
+                                            }
+                                            let chunk = Arc::new(chunk);
+                                            send(&self.tx, Message::UpdateAccountsChunk(chunk.clone()));
+
+                                            // Wait until autouser catches up before reading on
+                                            if self.config.autouser.enable && self.chunk_tx.send(chunk).await.is_err() {
+                                                log::debug!("autouser is gone - Dropping account chunk");
+                                            }
+                                        }
+                                    },
+                                );
+                                res?;
+                            } else {
+                                let accounts = self.tmcc().accounts().await?;
+                                if self.config.tmcc.cache {
+                                    self.received.lock().unwrap().accounts = Some(accounts.clone());
+                                }
+                                send(&self.tx, Message::UpdateAccounts(accounts));
+                            }
 
                             Result::Ok(())
                         },
                         async {
-                            let mounts = self.tmcc.mounts().await?;
-                            self.tx.send(Message::UpdateMounts(mounts)).unwrap();
+                            let mounts = self.tmcc().mounts().await?;
+                            if self.config.tmcc.cache {
+                                self.received.lock().unwrap().mounts = Some(mounts.clone());
+                            }
+                            send(&self.tx, Message::UpdateMounts(mounts));
 
                             Result::Ok(())
                         },
                         async {
-                            match self.tmcc.allocation_status().await? {
+                            let allocation = self.tmcc().allocation_status().await?;
+                            self.track_allocation(allocation.clone());
+
+                            match allocation {
                                 Some(allocation) => {
-                                    let manifest = self.tmcc.geni_manifest().await?;
-                                    let current_node = manifest.get_node(&allocation.node_name)
-                                        .ok_or(Error::GeniNoSuchNode)?;
+                                    send(&self.tx, Message::UpdateAllocation(allocation.clone()));
+
+                                    let ManifestInfo { fqdn, addresses, expires, peers, interfaces } = self.manifest_info(allocation).await?;
+
+                                    log::info!("Our FQDN: {} -> {:?}", fqdn, addresses);
+
+                                    if let Some(expires) = expires {
+                                        send(&self.tx, Message::UpdateExpiration(expires));
+                                    }
+
+                                    send(&self.tx, Message::UpdatePeers(peers));
 
-                                    let fqdn = current_node.fqdn();
-                                    let ipv4 = current_node.ipv4();
+                                    // TMCD also knows about VLANs and MTUs, so the
+                                    // manifest is only a fallback
+                                    let interfaces = match self.tmcc().ifconfig().await {
+                                        Ok(ifconfig) if !ifconfig.is_empty() => ifconfig,
+                                        Ok(_) => interfaces,
+                                        Err(e) => {
+                                            log::warn!("Failed to retrieve interfaces from TMCD: {}", e);
+                                            interfaces
+                                        }
+                                    };
 
-                                    log::info!("Our FQDN: {} -> {}", fqdn, ipv4);
+                                    send(&self.tx, Message::UpdateInterfaces(interfaces));
 
-                                    self.tx.send(Message::UpdateCanonical(fqdn, ipv4)).unwrap();
+                                    if self.config.tmcc.cache {
+                                        self.received.lock().unwrap().canonical = Some((fqdn.clone(), addresses.clone()));
+                                    }
+                                    send(&self.tx, Message::UpdateCanonical(fqdn, addresses));
                                 }
                                 None => {
                                     log::warn!("The current node is (no longer) allocated!");
+                                    *self.manifest_cache.lock().unwrap() = None;
+                                }
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            // Not all testbeds support this
+                            match self.tmcc().bootwhat().await {
+                                Ok(Some(boot)) => {
+                                    if !boot.what.is_disk() {
+                                        log::warn!("The testbed wants the node to boot {:?} - A disk reload may be pending", boot.what);
+                                    }
+
+                                    send(&self.tx, Message::UpdateBootInfo(boot));
+                                }
+                                Ok(None) => {
+                                    log::debug!("The testbed doesn't know what the node should boot");
+                                }
+                                Err(e) => {
+                                    log::warn!("Failed to retrieve boot information: {}", e);
+                                }
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if !self.config.syncserver.enable {
+                                return Result::Ok(());
+                            }
+
+                            match self.tmcc().syncserver().await? {
+                                Some(server) => {
+                                    send(&self.tx, Message::UpdateSyncServer(server));
+                                }
+                                None => {
+                                    log::debug!("The experiment has no sync server");
+                                }
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if !self.config.autoconsole.enable {
+                                return Result::Ok(());
+                            }
+
+                            match self.tmcc().tipline_info().await? {
+                                Some(tipline) => {
+                                    send(&self.tx, Message::UpdateTipline(tipline));
+                                }
+                                None => {
+                                    log::warn!("The current node has no console line");
+                                }
+                            }
+
+                            if self.config.autoconsole.tunnel_dir.is_some() {
+                                let tunnels = self.tmcc().tiptunnels().await?;
+                                send(&self.tx, Message::UpdateTiptunnels(tunnels));
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if self.config.autolocale.enable || self.config.autoproxy.enable {
+                                let localization = self.tmcc().localization().await?;
+                                send(&self.tx, Message::UpdateLocalization(localization));
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if self.config.autoenv.enable {
+                                let env = self.tmcc().userenv().await?;
+                                send(&self.tx, Message::UpdateEnvironment(env));
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if !self.config.autocert.enable {
+                                return Result::Ok(());
+                            }
+
+                            // Not all testbeds provide these
+                            let key = self.tmcc().geni_key().await
+                                .map_err(|e| log::warn!("Failed to retrieve the experiment key: {}", e))
+                                .ok();
+                            let certificate = self.tmcc().geni_certificate().await
+                                .map_err(|e| log::warn!("Failed to retrieve the experiment certificate: {}", e))
+                                .ok();
+
+                            send(&self.tx, Message::UpdateSecrets(Arc::new(Secrets { key, certificate })));
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if !self.config.autoblob.enable {
+                                return Result::Ok(());
+                            }
+
+                            for file in &self.config.autoblob.files {
+                                match self.tmcc().blob(&file.name, file.sha256.as_deref()).await {
+                                    Ok(blob) => send(&self.tx, Message::UpdateBlob(Arc::new(blob))),
+                                    Err(e) => log::warn!("Failed to retrieve blob {}: {}", file.name, e),
+                                }
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if self.config.autosoftware.enable {
+                                let tarballs = self.tmcc().tarballs().await?;
+                                let rpms = self.tmcc().rpms().await?;
+                                send(&self.tx, Message::UpdateSoftware(Arc::new(Software { tarballs, rpms })));
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if self.config.autodisk.enable {
+                                let volumes = self.tmcc().storageconfig().await?;
+                                send(&self.tx, Message::UpdateStorage(Arc::new(volumes)));
+                            }
+
+                            Result::Ok(())
+                        },
+                        async {
+                            if self.config.autostartup.enable {
+                                if let Some(command) = self.tmcc().startupcmd().await? {
+                                    send(&self.tx, Message::UpdateStartup(command));
                                 }
                             }
 
@@ -134,7 +1018,16 @@ impl Applet for Tmcc {
                         },
                     );
 
-                    accounts?; mounts?; hostinfo?;
+                    accounts?; mounts?; hostinfo?; bootwhat?; syncserver?; tipline?; localization?; userenv?; secrets?; blobs?; software?; storage?; startup?;
+
+                    if self.config.tmcc.cache {
+                        let received = std::mem::take(&mut *self.received.lock().unwrap());
+                        if let Err(e) = state::save(&self.config.state, "testbed", &received).await {
+                            log::warn!("Failed to save the testbed cache: {}", e);
+                        }
+                    }
+
+                    send(&self.tx, Message::ReloadTestbedOk);
                 }
                 _ => {}
             }
@@ -0,0 +1,89 @@
+//! The `watchdog` applet.
+//!
+//! It periodically reloads information from the testbed, so changes
+//! made in the portal (e.g., new project members or mounts) reach
+//! the node without a `SIGHUP`. The allocation status is checked as
+//! well, so swapouts are noticed even without `autoswap` polling.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::error::Result;
+use super::{Applet, Sender, Message, send, recv};
+
+/// `watchdog` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// How often to reload information from the testbed, in seconds.
+    interval: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            interval: 300,
+        }
+    }
+}
+
+/// The `watchdog` applet.
+#[derive(Debug)]
+pub struct Watchdog {
+    config: Config,
+    tx: Sender,
+}
+
+impl Watchdog {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Watchdog {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.watchdog.enable {
+            log::info!("watchdog applet disabled in config");
+            return Ok(());
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.watchdog.interval.max(1)));
+
+        // The testbed was just reloaded
+        interval.tick().await;
+
+        loop {
+            let message = tokio::select! {
+                message = recv(&mut rx) => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = interval.tick() => {
+                    log::debug!("Polling the testbed for changes...");
+                    send(&self.tx, Message::CheckAllocation);
+                    send(&self.tx, Message::ReloadTestbed);
+                    continue;
+                }
+            };
+
+            if let Message::Shutdown(_) = message {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
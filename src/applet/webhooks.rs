@@ -0,0 +1,265 @@
+//! The `webhooks` applet.
+//!
+//! It notifies configured URLs when the node changes state, so
+//! experiment controllers and chat bots learn about node readiness
+//! without polling:
+//!
+//! ```toml
+//! [webhooks]
+//! urls = ["https://example.com/miniond"]
+//! ```
+//!
+//! Each notification is a JSON object `POST`ed with `curl`, with
+//! the `event` (`setup`, `up`, `failure`, or `shutdown`) and the
+//! identity of the node.
+
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use which::which;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::plan::{self, Action};
+use super::{Applet, Sender, Message, recv};
+
+/// `webhooks` applet configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    /// Whether to enable the applet or not.
+    pub(super) enable: bool,
+
+    /// URLs to notify.
+    urls: Vec<String>,
+
+    /// Events to notify about.
+    events: Vec<WebhookEvent>,
+
+    /// How long a notification may take, in seconds.
+    timeout: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            urls: Vec::new(),
+            events: vec![
+                WebhookEvent::Setup,
+                WebhookEvent::Up,
+                WebhookEvent::Failure,
+                WebhookEvent::Shutdown,
+            ],
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum WebhookEvent {
+    /// The node is being set up.
+    #[serde(rename = "setup")]
+    Setup,
+
+    /// The node is ready.
+    #[serde(rename = "up")]
+    Up,
+
+    /// An applet failed to apply the configuration.
+    #[serde(rename = "failure")]
+    Failure,
+
+    /// The experiment was swapped out, or miniond is stopping.
+    #[serde(rename = "shutdown")]
+    Shutdown,
+}
+
+impl WebhookEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Setup => "setup",
+            Self::Up => "up",
+            Self::Failure => "failure",
+            Self::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// The `webhooks` applet.
+#[derive(Debug)]
+pub struct Webhooks {
+    config: Config,
+    tx: Sender,
+}
+
+/// Identity of the node included in notifications.
+#[derive(Debug, Default)]
+struct Identity {
+    experiment: Option<String>,
+    node: Option<String>,
+    fqdn: Option<String>,
+}
+
+impl Webhooks {
+    pub(super) async fn new(config: Config, tx: Sender) -> Result<Box<dyn Applet>> {
+        let webhooks = &config.webhooks;
+
+        if webhooks.enable && !webhooks.urls.is_empty() && !plan::is_dry_run() && which("curl").is_err() {
+            log::error!("The `curl` binary must be in PATH");
+            return Err(Error::UnmetSystemRequirements);
+        }
+
+        Ok(Box::new(Self {
+            config,
+            tx,
+        }))
+    }
+}
+
+#[async_trait]
+impl Applet for Webhooks {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        if !self.config.webhooks.enable || self.config.webhooks.urls.is_empty() {
+            log::info!("webhooks applet disabled in config");
+            return Ok(());
+        }
+
+        // Slow endpoints must not hold up the bus
+        let (queue, mut pending) = mpsc::unbounded_channel();
+
+        let listen = async move {
+            let mut identity = Identity::default();
+
+            // MFSSETUP is reported before the applets start
+            self.fire(&queue, &identity, WebhookEvent::Setup, json!({}));
+
+            loop {
+                let message = match recv(&mut rx).await {
+                    Some(message) => message,
+                    None => break,
+                };
+                match message {
+                    Message::Shutdown(reason) => {
+                        self.fire(&queue, &identity, WebhookEvent::Shutdown, json!({ "reason": format!("{:?}", reason) }));
+                        break;
+                    }
+
+                    Message::UpdateAllocation(allocation) => {
+                        identity.experiment = Some(allocation.experiment);
+                        identity.node = Some(allocation.node_name);
+                    }
+
+                    Message::UpdateCanonical(fqdn, _) => {
+                        identity.fqdn = Some(fqdn);
+                    }
+
+                    Message::StateReported(state) if state == self.config.tmcc.setup_state() => {
+                        self.fire(&queue, &identity, WebhookEvent::Setup, json!({}));
+                    }
+
+                    Message::StateReported(state) if state == self.config.tmcc.states.up => {
+                        self.fire(&queue, &identity, WebhookEvent::Up, json!({}));
+                    }
+
+                    Message::StateReported(state) if state == self.config.tmcc.states.shutdown => {
+                        self.fire(&queue, &identity, WebhookEvent::Shutdown, json!({ "reason": "Swapout" }));
+                    }
+
+                    Message::AppletFailed(applet, error, severity) => {
+                        self.fire(&queue, &identity, WebhookEvent::Failure, json!({ "applet": applet, "error": error, "severity": severity }));
+                    }
+
+                    _ => {}
+                }
+            }
+        };
+
+        let run = async {
+            while let Some((event, payload)) = pending.recv().await {
+                let sends = self.config.webhooks.urls.iter()
+                    .map(|url| self.send(event, url, &payload));
+
+                for res in join_all(sends).await {
+                    if let Err(e) = res {
+                        log::warn!("Failed to send {} webhook: {}", event.as_str(), e);
+                    }
+                }
+            }
+        };
+
+        tokio::join!(listen, run);
+
+        Ok(())
+    }
+}
+
+impl Webhooks {
+    /// Queue a notification, if the event is wanted.
+    fn fire(&self, queue: &mpsc::UnboundedSender<(WebhookEvent, Value)>, identity: &Identity, event: WebhookEvent, detail: Value) {
+        if !self.config.webhooks.events.contains(&event) {
+            return;
+        }
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut payload = json!({
+            "event": event.as_str(),
+            "time": time,
+            "experiment": identity.experiment,
+            "node": identity.node,
+            "fqdn": identity.fqdn,
+        });
+
+        if let (Value::Object(payload), Value::Object(detail)) = (&mut payload, detail) {
+            payload.extend(detail);
+        }
+
+        queue.send((event, payload)).unwrap();
+    }
+
+    /// Send a notification to a URL.
+    async fn send(&self, event: WebhookEvent, url: &str, payload: &Value) -> Result<()> {
+        log::info!("Sending {} webhook to {}...", event.as_str(), url);
+
+        if plan::is_dry_run() {
+            plan::record(Action::SendWebhook {
+                event: event.as_str().to_string(),
+                url: url.to_string(),
+            });
+            return Ok(());
+        }
+
+        let mut child = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail"])
+            .arg("--max-time").arg(self.config.webhooks.timeout.to_string())
+            .args(["--header", "Content-Type: application/json"])
+            .args(["--data-binary", "@-"])
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&serde_json::to_vec(payload).expect("Failed to serialize webhook payload")).await?;
+        drop(stdin);
+
+        let status = child.wait().await?;
+        if !status.success() {
+            log::warn!("Webhook to {} failed with {}", url, status);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! Blocking work off the async runtime.
+//!
+//! Syscalls like `chown(2)` and NSS lookups are synchronous. Running
+//! them directly in async code stalls the runtime threads, which hurts
+//! when hundreds of users are applied concurrently. Instead, such work
+//! is sent to Tokio's blocking thread pool through [`run`], which also
+//! bounds the number of concurrent tasks and keeps some statistics.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use nix::unistd::{self, chown as nix_chown};
+use once_cell::sync::Lazy;
+use snafu::ResultExt;
+use tokio::sync::Semaphore;
+
+use crate::error::{FileSnafu, Result};
+
+/// Maximum number of blocking tasks we run at the same time.
+const MAX_CONCURRENT_TASKS: usize = 16;
+
+/// Tasks running longer than this are logged.
+const SLOW_TASK_THRESHOLD: Duration = Duration::from_secs(1);
+
+static PERMITS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(MAX_CONCURRENT_TASKS));
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+static TOTAL_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Statistics of the blocking task pool.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Number of tasks currently waiting or running.
+    pub in_flight: usize,
+
+    /// Number of tasks completed.
+    pub completed: u64,
+
+    /// Total time spent running tasks.
+    pub total_time: Duration,
+}
+
+/// Returns statistics of the blocking task pool.
+pub fn stats() -> Stats {
+    Stats {
+        in_flight: IN_FLIGHT.load(Ordering::Relaxed),
+        completed: COMPLETED.load(Ordering::Relaxed),
+        total_time: Duration::from_micros(TOTAL_MICROS.load(Ordering::Relaxed)),
+    }
+}
+
+/// Run a blocking closure on the blocking thread pool.
+pub async fn run<F, T>(name: &'static str, f: F) -> Result<T>
+    where F: FnOnce() -> Result<T> + Send + 'static,
+          T: Send + 'static,
+{
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+
+    let permit = PERMITS.acquire().await
+        .expect("Blocking pool semaphore closed");
+
+    let start = Instant::now();
+    let result = tokio::task::spawn_blocking(f).await
+        .expect("Blocking task panicked");
+    let elapsed = start.elapsed();
+
+    drop(permit);
+
+    IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    COMPLETED.fetch_add(1, Ordering::Relaxed);
+    TOTAL_MICROS.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+
+    if elapsed > SLOW_TASK_THRESHOLD {
+        log::warn!("Blocking task {} took {:?}", name, elapsed);
+    }
+
+    result
+}
+
+/// Change the ownership of files.
+pub async fn chown(paths: Vec<PathBuf>, uid: u32, gid: u32) -> Result<()> {
+    run("chown", move || {
+        let uid = unistd::Uid::from_raw(uid);
+        let gid = unistd::Gid::from_raw(gid);
+
+        for path in paths {
+            nix_chown(&path, Some(uid), Some(gid))
+                .map_err(io::Error::from)
+                .context(FileSnafu { action: "change the owner of", path: &path })?;
+        }
+
+        Ok(())
+    }).await
+}
+
+/// Write a file in one go.
+///
+/// The file is rewritten in place, so a crash may leave it partially
+/// written. Use [`write_atomic`] unless the inode must be kept (e.g.,
+/// for bind-mounted files).
+pub async fn write(path: PathBuf, contents: Vec<u8>) -> Result<()> {
+    run("write", move || {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&contents))
+            .context(FileSnafu { action: "write", path: &path })
+    }).await
+}
+
+/// Atomically replace a batch of files.
+///
+/// Each file is written to a temporary file next to it, synced, and
+/// renamed over the original. The parent directories are synced once
+/// all files are in place, so a crash leaves every file either in its
+/// old or its new state.
+///
+/// Replaced files keep their permissions and owner.
+pub async fn write_atomic(files: Vec<(PathBuf, Vec<u8>)>) -> Result<()> {
+    run("write-atomic", move || replace(&files)).await
+}
+
+/// Atomically replace a batch of files, from a blocking task.
+///
+/// See [`write_atomic`].
+pub fn replace(files: &[(PathBuf, Vec<u8>)]) -> Result<()> {
+    let mut renames = Vec::with_capacity(files.len());
+
+    let result = (|| {
+        for (path, contents) in files {
+            let tmp = temp_path(path);
+            let original = fs::metadata(path).ok();
+
+            // Replacements of private files (e.g., /etc/shadow) are
+            // never readable by others, even before they get the
+            // permissions of the original
+            let _ = fs::remove_file(&tmp);
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(if original.is_some() { 0o600 } else { 0o666 })
+                .open(&tmp)
+                .context(FileSnafu { action: "create", path: &tmp })?;
+
+            renames.push((tmp, path));
+
+            // Keep the permissions and owner of the file we replace
+            (|| {
+                if let Some(metadata) = &original {
+                    file.set_permissions(metadata.permissions())?;
+
+                    let created = file.metadata()?;
+                    if (created.uid(), created.gid()) != (metadata.uid(), metadata.gid()) {
+                        unistd::fchown(
+                            file.as_raw_fd(),
+                            Some(unistd::Uid::from_raw(metadata.uid())),
+                            Some(unistd::Gid::from_raw(metadata.gid())),
+                        )?;
+                    }
+                }
+
+                file.write_all(contents)?;
+                file.sync_data()
+            })().context(FileSnafu { action: "write", path })?;
+        }
+
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        for (tmp, _) in renames {
+            let _ = fs::remove_file(tmp);
+        }
+
+        return Err(e);
+    }
+
+    let mut dirs = HashSet::new();
+    for (tmp, path) in renames {
+        fs::rename(&tmp, path)
+            .context(FileSnafu { action: "replace", path })?;
+
+        if let Some(parent) = path.parent() {
+            dirs.insert(parent.to_path_buf());
+        }
+    }
+
+    for dir in dirs {
+        fs::File::open(&dir)
+            .and_then(|dir| dir.sync_all())
+            .context(FileSnafu { action: "sync", path: &dir })?;
+    }
+
+    Ok(())
+}
+
+/// Atomically replace a file with restricted permissions.
+///
+/// The temporary file gets its permissions and owner before anything
+/// is written, so the contents are never exposed to other users.
+pub async fn write_private(path: PathBuf, contents: Vec<u8>, mode: u32, uid: u32, gid: u32) -> Result<()> {
+    run("write-private", move || {
+        let tmp = temp_path(&path);
+        let _ = fs::remove_file(&tmp);
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp)
+            .context(FileSnafu { action: "create", path: &tmp })?;
+
+        let result = (|| {
+            unistd::fchown(file.as_raw_fd(), Some(unistd::Uid::from_raw(uid)), Some(unistd::Gid::from_raw(gid)))?;
+
+            // The mode we created the file with is subject to the umask
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+
+            file.write_all(&contents)?;
+            file.sync_data()?;
+
+            fs::rename(&tmp, &path)
+        })().context(FileSnafu { action: "write", path: &path });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+
+        result
+    }).await
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".miniond-tmp");
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+
+        fs::write(&path, "old\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomic(vec![(path.clone(), b"new\n".to_vec())]).await.unwrap();
+
+        assert_eq!("new\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(0o600, fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+        assert!(!temp_path(&path).exists());
+    }
+}
@@ -0,0 +1,163 @@
+//! Initial clock step.
+//!
+//! Freshly imaged nodes may boot with a badly skewed clock, which
+//! breaks TLS to the boss and makes NTP slow to converge. Before
+//! reporting to the testbed, we compare our clock with the `Date`
+//! header from the web server on the boss, and step it if the skew
+//! exceeds a threshold. NTP takes over from there.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::sys::time::TimeSpec;
+use nix::time::{clock_settime, ClockId};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::error::{Error, Result};
+use crate::plan::{self, Action};
+
+/// Clock configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ClockConfig {
+    /// Whether to step the clock on startup.
+    step: bool,
+
+    /// Port of the web server on the boss.
+    port: u16,
+
+    /// Skew to tolerate, in seconds.
+    threshold: u64,
+
+    /// How long to wait for the boss, in seconds.
+    timeout: u64,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            step: false,
+            port: 80,
+            threshold: 10,
+            timeout: 5,
+        }
+    }
+}
+
+/// Step the clock to the time of the boss, if it's too far off.
+pub async fn step(config: &ClockConfig, boss: IpAddr) -> Result<()> {
+    if !config.step {
+        return Ok(());
+    }
+
+    let addr = SocketAddr::new(boss, config.port);
+    let timeout = Duration::from_secs(config.timeout);
+
+    let boss_time = tokio::time::timeout(timeout, boss_time(addr)).await
+        .map_err(|_| Error::ClockStep { addr, reason: format!("timed out after {:?}", timeout) })??;
+
+    let now = SystemTime::now();
+    let skew = match boss_time.duration_since(now) {
+        Ok(ahead) => ahead,
+        Err(e) => e.duration(),
+    };
+
+    if skew.as_secs() <= config.threshold {
+        log::debug!("Clock skew to the boss is {:?}", skew);
+        return Ok(());
+    }
+
+    let since_epoch = boss_time.duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::ClockStep { addr, reason: "time is before the epoch".to_string() })?;
+
+    log::warn!("Clock is off by {:?} - Stepping to the time of the boss...", skew);
+
+    if plan::is_dry_run() {
+        plan::record(Action::StepClock {
+            timestamp: since_epoch.as_secs(),
+        });
+        return Ok(());
+    }
+
+    clock_settime(ClockId::CLOCK_REALTIME, TimeSpec::from_duration(since_epoch))
+        .map_err(|e| Error::ClockStep { addr, reason: e.to_string() })?;
+
+    Ok(())
+}
+
+/// Returns the time according to the web server at `addr`.
+async fn boss_time(addr: SocketAddr) -> Result<SystemTime> {
+    let io_error = |e: std::io::Error| Error::ClockStep { addr, reason: e.to_string() };
+
+    let mut stream = TcpStream::connect(addr).await.map_err(io_error)?;
+
+    stream.write_all(b"HEAD / HTTP/1.0\r\n\r\n").await.map_err(io_error)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(io_error)?;
+
+    let response = String::from_utf8_lossy(&response);
+
+    response.lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("date"))
+        .and_then(|(_, value)| parse_http_date(value.trim()))
+        .ok_or_else(|| Error::ClockStep { addr, reason: "no Date header in the response".to_string() })
+}
+
+/// Parse an HTTP date (e.g., `Sun, 06 Nov 1994 08:49:37 GMT`).
+///
+/// Only the preferred format from RFC 7231 is supported.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+
+    let day: u64 = parts[1].parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == parts[2])? as u64 + 1;
+    let year: u64 = parts[3].parse().ok()?;
+
+    let time: Vec<u64> = parts[4].split(':')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+
+    if time.len() != 3 || year < 1970 || !(1..=31).contains(&day) || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+
+    // Days since the epoch, from Howard Hinnant's `days_from_civil`
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_date() {
+        let time = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(784111777, time.duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+        let time = parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT").unwrap();
+        assert_eq!(1709164800, time.duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+        assert!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT").is_none());
+        assert!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST").is_none());
+        assert!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT").is_none());
+    }
+}
@@ -0,0 +1,201 @@
+//! System commands.
+//!
+//! Commands like `useradd` can hang indefinitely (e.g., on NSS lookups
+//! against a dead LDAP server), so each one is killed after a timeout.
+//! Their stderr is captured, so failures say what went wrong.
+
+use std::collections::HashMap;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::ConfigInner;
+use crate::fault;
+
+/// How much of the stderr of a failed command to keep, in bytes.
+const MAX_STDERR: usize = 1024;
+
+/// System command configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommandConfig {
+    /// Number of seconds a command may run before it's killed.
+    timeout: u64,
+
+    /// Timeouts of specific programs (e.g., `useradd`), in seconds.
+    timeouts: HashMap<String, u64>,
+}
+
+impl Default for CommandConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 300,
+            timeouts: HashMap::new(),
+        }
+    }
+}
+
+impl CommandConfig {
+    /// Returns how long a program may run.
+    fn timeout(&self, program: &str) -> Duration {
+        let secs = self.timeouts.get(program)
+            .copied()
+            .unwrap_or(self.timeout);
+
+        Duration::from_secs(secs)
+    }
+}
+
+static CONFIG: OnceCell<CommandConfig> = OnceCell::new();
+
+/// Set up system commands.
+pub fn init(config: &ConfigInner) {
+    CONFIG.set(config.commands.clone()).ok();
+}
+
+/// Run a command, returning why it failed if it did.
+///
+/// The reason names the program and how it failed, along with what
+/// it printed to stderr, for the `reason` of errors. Faults injected
+/// with `crate::fault` count as failures.
+pub(crate) async fn run_command(command: &mut Command) -> Result<(), String> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    let stdout = command_output(command).await?;
+
+    if !stdout.trim().is_empty() {
+        log::debug!("{}: {}", program, stdout.trim());
+    }
+
+    Ok(())
+}
+
+/// Run a command with some input on stdin, returning why it failed
+/// if it did.
+///
+/// The input is never logged, so it may be secret.
+pub(crate) async fn run_command_with_input(command: &mut Command, input: Vec<u8>) -> Result<(), String> {
+    execute(command, Some(input)).await?;
+    Ok(())
+}
+
+/// Run a command, returning what it printed to stdout.
+///
+/// Failures are reported like with `run_command`.
+pub(crate) async fn command_output(command: &mut Command) -> Result<String, String> {
+    execute(command, None).await
+}
+
+/// Run a command, returning what it printed to stdout, or `None` if it
+/// exited with one of `codes`.
+///
+/// Some programs (e.g., `blkid`) use an exit status to report that they
+/// found nothing. Other failures are reported like with `run_command`.
+pub(crate) async fn command_output_unless(command: &mut Command, codes: &[i32]) -> Result<Option<String>, String> {
+    let output = spawn(command, None).await?;
+
+    match output.status.code() {
+        Some(code) if codes.contains(&code) => Ok(None),
+        _ => check(command, output).map(Some),
+    }
+}
+
+/// Run a command, optionally feeding it some input.
+async fn execute(command: &mut Command, input: Option<Vec<u8>>) -> Result<String, String> {
+    let output = spawn(command, input).await?;
+    check(command, output)
+}
+
+/// Run a command to completion, optionally feeding it some input.
+async fn spawn(command: &mut Command, input: Option<Vec<u8>>) -> Result<Output, String> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+
+    if fault::command_fails(&program) {
+        return Err(format!("{} failed (injected fault)", program));
+    }
+
+    let timeout = CONFIG.get()
+        .map(|config| config.timeout(&program))
+        .unwrap_or_else(|| CommandConfig::default().timeout(&program));
+
+    let stdin = if input.is_some() { Stdio::piped() } else { Stdio::null() };
+    command
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let run = async {
+        let mut child = command.spawn()?;
+
+        if let Some(input) = input {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            stdin.write_all(&input).await?;
+        }
+
+        child.wait_with_output().await
+    };
+
+    match tokio::time::timeout(timeout, run).await {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(format!("failed to run {}: {}", program, e)),
+        Err(_) => Err(format!("{} timed out after {:?}", program, timeout)),
+    }
+}
+
+/// Returns what a finished command printed to stdout, or why it failed.
+fn check(command: &Command, output: Output) -> Result<String, String> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+    }
+
+    let reason = match output.status.code() {
+        Some(code) => format!("{} exited with status {}", program, code),
+        None => format!("{} was killed by a signal", program),
+    };
+
+    match stderr(&output.stderr) {
+        Some(stderr) => Err(format!("{}: {}", reason, stderr)),
+        None => Err(reason),
+    }
+}
+
+/// Returns the tail of what a command printed to stderr, on one line.
+fn stderr(bytes: &[u8]) -> Option<String> {
+    let start = bytes.len().saturating_sub(MAX_STDERR);
+    let stderr = String::from_utf8_lossy(&bytes[start..]);
+
+    let lines: Vec<&str> = stderr.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join(" / "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stderr() {
+        assert_eq!(None, stderr(b""));
+        assert_eq!(None, stderr(b"\n  \n"));
+        assert_eq!(
+            Some("useradd: user 'alice' already exists / useradd: try again".to_string()),
+            stderr(b"useradd: user 'alice' already exists\n\nuseradd: try again\n"),
+        );
+
+        let long = "x".repeat(MAX_STDERR * 2);
+        assert_eq!(MAX_STDERR, stderr(long.as_bytes()).unwrap().len());
+    }
+}
@@ -1,7 +1,7 @@
 //! Configuration.
 
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use serde::Deserialize;
@@ -10,8 +10,42 @@ use crate::applet::{
     AutouserConfig,
     AutomountConfig,
     AutohostConfig,
+    AutoswapConfig,
+    AutorepairConfig,
+    WatchdogConfig,
+    AutosshConfig,
+    AutoconsoleConfig,
+    AutomotdConfig,
+    AutolocaleConfig,
+    AutoproxyConfig,
+    LinktestConfig,
+    AutoenvConfig,
+    AutocertConfig,
+    AutoblobConfig,
+    AutosoftwareConfig,
+    AutodiskConfig,
+    AutonetConfig,
+    AutostartupConfig,
+    ApiConfig,
+    MetricsConfig,
+    ControlConfig,
+    HooksConfig,
+    TemplatesConfig,
+    WebhooksConfig,
+    CloudinitConfig,
+    SyncserverConfig,
     TmccConfig,
 };
+use crate::apparmor::AppArmorConfig;
+use crate::clock::ClockConfig;
+use crate::command::CommandConfig;
+#[cfg(feature = "dbus")]
+use crate::applet::DbusConfig;
+#[cfg(feature = "fault-injection")]
+use crate::fault::FaultConfig;
+use crate::overlay::OverlayConfig;
+use crate::scope::ScopeConfig;
+use crate::state::StateConfig;
 
 pub type Config = Arc<ConfigInner>;
 
@@ -29,6 +63,102 @@ pub struct ConfigInner {
     #[serde(default)]
     pub autohost: AutohostConfig,
 
+    /// `autoswap` applet configuration.
+    #[serde(default)]
+    pub autoswap: AutoswapConfig,
+
+    /// `autorepair` applet configuration.
+    #[serde(default)]
+    pub autorepair: AutorepairConfig,
+
+    /// `watchdog` applet configuration.
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+
+    /// `autossh` applet configuration.
+    #[serde(default)]
+    pub autossh: AutosshConfig,
+
+    /// `autoconsole` applet configuration.
+    #[serde(default)]
+    pub autoconsole: AutoconsoleConfig,
+
+    /// `automotd` applet configuration.
+    #[serde(default)]
+    pub automotd: AutomotdConfig,
+
+    /// `autolocale` applet configuration.
+    #[serde(default)]
+    pub autolocale: AutolocaleConfig,
+
+    /// `autoproxy` applet configuration.
+    #[serde(default)]
+    pub autoproxy: AutoproxyConfig,
+
+    /// `linktest` applet configuration.
+    #[serde(default)]
+    pub linktest: LinktestConfig,
+
+    /// `autoenv` applet configuration.
+    #[serde(default)]
+    pub autoenv: AutoenvConfig,
+
+    /// `autocert` applet configuration.
+    #[serde(default)]
+    pub autocert: AutocertConfig,
+
+    /// `autoblob` applet configuration.
+    #[serde(default)]
+    pub autoblob: AutoblobConfig,
+
+    /// `autosoftware` applet configuration.
+    #[serde(default)]
+    pub autosoftware: AutosoftwareConfig,
+
+    /// `autodisk` applet configuration.
+    #[serde(default)]
+    pub autodisk: AutodiskConfig,
+
+    /// `autonet` applet configuration.
+    #[serde(default)]
+    pub autonet: AutonetConfig,
+
+    /// `autostartup` applet configuration.
+    #[serde(default)]
+    pub autostartup: AutostartupConfig,
+
+    /// `api` applet configuration.
+    #[serde(default)]
+    pub api: ApiConfig,
+
+    /// `metrics` applet configuration.
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+
+    /// `control` applet configuration.
+    #[serde(default)]
+    pub control: ControlConfig,
+
+    /// `hooks` applet configuration.
+    #[serde(default)]
+    pub hooks: HooksConfig,
+
+    /// `templates` applet configuration.
+    #[serde(default)]
+    pub templates: TemplatesConfig,
+
+    /// `webhooks` applet configuration.
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+
+    /// `cloudinit` applet configuration.
+    #[serde(default)]
+    pub cloudinit: CloudinitConfig,
+
+    /// `syncserver` applet configuration.
+    #[serde(default)]
+    pub syncserver: SyncserverConfig,
+
     /// `tmcc` applet configuration.
     #[serde(default)]
     pub tmcc: TmccConfig,
@@ -36,6 +166,66 @@ pub struct ConfigInner {
     /// Systemd integration configuration.
     #[serde(default)]
     pub systemd: SystemdConfig,
+
+    /// System command configuration.
+    #[serde(default)]
+    pub commands: CommandConfig,
+
+    /// Transient scope configuration for spawned programs.
+    #[serde(default)]
+    pub scope: ScopeConfig,
+
+    /// AppArmor configuration.
+    #[serde(default)]
+    pub apparmor: AppArmorConfig,
+
+    /// Initial clock step configuration.
+    #[serde(default)]
+    pub clock: ClockConfig,
+
+    /// Read-only root overlay configuration.
+    #[serde(default)]
+    pub overlay: OverlayConfig,
+
+    /// Persistent state configuration.
+    #[serde(default)]
+    pub state: StateConfig,
+
+    /// D-Bus service configuration.
+    #[cfg(feature = "dbus")]
+    #[serde(default)]
+    pub dbus: DbusConfig,
+
+    /// Fault injection configuration.
+    #[cfg(feature = "fault-injection")]
+    #[serde(default)]
+    pub faults: FaultConfig,
+}
+
+impl ConfigInner {
+    /// Act as a virtual node hosted on this machine.
+    ///
+    /// The state and control socket of each virtual node are kept
+    /// separately, and the API listens on an ephemeral port, so several
+    /// instances can run side by side.
+    pub fn set_vnode(&mut self, vnode: String) {
+        self.state.dir = self.state.dir.join(&vnode);
+        self.control.socket = vnode_path(&self.control.socket, &vnode);
+        self.api.listen.set_port(0);
+        self.tmcc.vnode = Some(vnode);
+    }
+}
+
+/// Returns a path unique to a virtual node (e.g., `/run/miniond-pcvm1-1.sock`).
+fn vnode_path(path: &Path, vnode: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, vnode, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, vnode),
+    };
+
+    path.with_file_name(name)
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,7 +244,13 @@ impl Default for SystemdConfig {
 }
 
 pub fn get_config(path: Option<PathBuf>) -> Config {
-    let inner = match path {
+    Arc::new(load_config(path, None))
+}
+
+/// Load the configuration, acting as a virtual node if one is given
+/// here or in `tmcc.vnode`.
+pub fn load_config(path: Option<PathBuf>, vnode: Option<String>) -> ConfigInner {
+    let mut config: ConfigInner = match path {
         None => {
             ConfigInner::default()
         }
@@ -66,5 +262,26 @@ pub fn get_config(path: Option<PathBuf>) -> Config {
         }
     };
 
-    Arc::new(inner)
+    if let Some(vnode) = vnode.or_else(|| config.tmcc.vnode.take()) {
+        config.set_vnode(vnode);
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_vnode() {
+        let mut config = ConfigInner::default();
+        let state = config.state.dir.clone();
+        config.set_vnode("pcvm1-1".to_string());
+
+        assert_eq!(state.join("pcvm1-1"), config.state.dir);
+        assert_eq!(Path::new("/run/miniond-pcvm1-1.sock"), config.control.socket);
+        assert_eq!(0, config.api.listen.port());
+        assert_eq!(Some("pcvm1-1"), config.tmcc.vnode.as_deref());
+    }
 }
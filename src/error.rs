@@ -1,9 +1,13 @@
 //! Error types.
 
 use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use snafu::Snafu;
 
+pub use miniond_core::Severity;
+
 use crate::account::Uid;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -12,69 +16,88 @@ pub type Result<T> = std::result::Result<T, Error>;
 ///
 /// A bit too pedantic for my taste.
 #[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
 pub enum Error {
-    #[snafu(display("Malformed TMCD boss node specification: {}", host))]
-    TmcdBadBossNode { host: String },
+    #[snafu(display("{}", error))]
+    TmccError { error: miniond_core::Error },
 
-    #[snafu(display("Failed to discover TMCD boss node"))]
-    TmcdFailedToDiscoverBossNode,
+    #[snafu(display("Attempted to create user {} with non-unique UID {} (already used by {})", login, uid, existing_login))]
+    DuplicateUid { login: String, uid: Uid, existing_login: String },
 
-    #[snafu(display("Got Non-UTF8 TMCD response"))]
-    TmcdInvalidUtf8,
+    #[snafu(display("Invalid /etc/shells file"))]
+    InvalidShellsFile,
 
-    #[snafu(display("Bad TMCD response (position {}): {}", position, line))]
-    TmcdBadLine { line: String, position: usize },
+    #[snafu(display("No login shells are installed"))]
+    NoLoginShells,
 
-    #[snafu(display("Required key {} missing from TMCD response: {}", key, line))]
-    TmcdMissingKey { key: String, line: String },
+    #[snafu(display("Failed to create user {}: {}", login, reason))]
+    UserCreation { login: String, reason: String },
 
-    #[snafu(display("Duplicate user {} in TMCD response", login))]
-    TmcdDuplicateUser { login: String },
+    #[snafu(display("Failed to create group {}: {}", name, reason))]
+    GroupCreation { name: String, reason: String },
 
-    #[snafu(display("Duplicate group {} in TMCD response", name))]
-    TmcdDuplicateGroup { name: String },
+    #[snafu(display("Failed to update user {}: {}", login, reason))]
+    UserUpdate { login: String, reason: String },
 
-    #[snafu(display("Missing directive in TMCD response: {}", line))]
-    TmcdMissingDirective { line: String },
+    #[snafu(display("Failed to set up the home directory of {}: {}", login, reason))]
+    HomeSetup { login: String, reason: String },
 
-    #[snafu(display("Unknown directive {} in TMCD response: {}", directive, line))]
-    TmcdUnknownDirective { directive: String, line: String },
+    #[snafu(display("Failed to set quota of {} on {:?}: {}", login, filesystem, reason))]
+    QuotaSetting { login: String, filesystem: PathBuf, reason: String },
 
-    #[snafu(display("Invalid value {} from TMCD response: {}", value, parse_error))]
-    TmcdBadValue { value: String, parse_error: Box<dyn std::error::Error + Send + Sync> },
+    #[snafu(display("Failed to remove user {}: {}", login, reason))]
+    UserDeletion { login: String, reason: String },
 
-    #[snafu(display("Invalid user {} from TMCD response", login))]
-    TmcdNoSuchUser { login: String },
+    #[snafu(display("Failed to remove group {}: {}", name, reason))]
+    GroupDeletion { name: String, reason: String },
 
-    #[snafu(display("TMCD returned blank GENI response"))]
-    TmcdGeniBlankResponse,
+    #[snafu(display("Invalid sudoers drop-in {:?}: {}", path, reason))]
+    Sudoers { path: PathBuf, reason: String },
 
-    #[snafu(display("TMCD returned unknown GENI error"))]
-    TmcdGeniError,
+    #[snafu(display("Failed to apply {} of {} accounts: {}", failed, total, failures))]
+    AccountsFailed { failed: usize, total: usize, failures: String },
 
-    #[snafu(display("GENI parsing error: {}", error))]
-    GeniParseError { error: serde_xml_rs::Error },
+    #[snafu(display("No such user or group: {}", name))]
+    UnknownOwner { name: String },
 
-    #[snafu(display("The current node does not exist in the GENI manifest (has the reservastion expired?)"))]
-    GeniNoSuchNode,
+    #[snafu(display("Failed to set up FS-Cache: {}", reason))]
+    Fscache { reason: String },
 
-    #[snafu(display("Attempted to create user {} with non-unique UID {} (already used by {})", login, uid, existing_login))]
-    DuplicateUid { login: String, uid: Uid, existing_login: String },
+    #[snafu(display("Failed to install {}: {}", package, reason))]
+    SoftwareInstall { package: String, reason: String },
 
-    #[snafu(display("Invalid /etc/shells file"))]
-    InvalidShellsFile,
+    #[snafu(display("Failed to unmount {}: {}", locals, reason))]
+    Unmount { locals: String, reason: String },
+
+    #[snafu(display("Failed to step the clock to the time of {}: {}", addr, reason))]
+    ClockStep { addr: SocketAddr, reason: String },
+
+    #[snafu(display("Invalid timezone {:?}", timezone))]
+    InvalidTimezone { timezone: String },
+
+    #[snafu(display("Failed to set timezone to {}: {}", timezone, reason))]
+    Timezone { timezone: String, reason: String },
 
-    #[snafu(display("Failed to create user account."))]
-    UserCreation,
+    #[snafu(display("Failed to set locale to {}: {}", locale, reason))]
+    Locale { locale: String, reason: String },
 
-    #[snafu(display("Failed to create group account."))]
-    GroupCreation,
+    #[snafu(display("Failed to reload {}: {}", unit, reason))]
+    ServiceReload { unit: String, reason: String },
 
-    #[snafu(display("Failed to update user account."))]
-    UserUpdate,
+    #[snafu(display("Invalid sshd drop-in {:?}: {}", path, reason))]
+    SshdConfig { path: PathBuf, reason: String },
 
-    #[snafu(display("Failed to mount."))]
-    Mount,
+    #[snafu(display("Failed to reboot: {}", reason))]
+    Reboot { reason: String },
+
+    #[snafu(display("Failed to mount {}: {}", locals, reason))]
+    Mount { locals: String, reason: String },
+
+    #[snafu(display("Failed to set up blockstore {}: {}", name, reason))]
+    Storage { name: String, reason: String },
+
+    #[snafu(display("Failed to configure interface {}: {}", name, reason))]
+    Interface { name: String, reason: String },
 
     #[snafu(display("Changing UIDs is not supported"))]
     UidChangeUnsupported,
@@ -82,19 +105,32 @@ pub enum Error {
     #[snafu(display("Changing GIDs is not supported"))]
     GidChangeUnsupported,
 
+    #[snafu(display("Invalid resource limit for transient scope: {}", limit))]
+    ScopeBadLimit { limit: String },
+
+    #[snafu(display("Cannot redirect read-only path {:?} to a writable location", path))]
+    OverlayUnavailable { path: PathBuf },
+
+    #[snafu(display("Failed to render template {:?}: {}", template, error))]
+    TemplateError { template: PathBuf, error: minijinja::Error },
+
+    #[snafu(display("Invalid barrier name {:?}", name))]
+    SyncBadBarrier { name: String },
+
+    #[snafu(display("The experiment has no sync server"))]
+    SyncNoServer,
+
     #[snafu(display("Unmet system requirements"))]
     UnmetSystemRequirements,
 
-    /// The SRV record indicates that a boss node is definitely not available.
-    ///
-    /// This is returned when a SRV lookup returns "." as the result.
-    ///
-    /// - <https://datatracker.ietf.org/doc/html/rfc2782>
-    #[snafu(display("SRV record indicates the absence of a boss node"))]
-    EmulabBossSrvNotAvailable,
+    #[snafu(display("{:?} is accessible by other users (mode {:04o})", path, mode))]
+    InsecureFile { path: PathBuf, mode: u32 },
 
-    #[snafu(display("The supplied boss node cannot be resolved: {:?}", host_port))]
-    EmulabBossUnresolvable { host_port: (String, u16) },
+    #[snafu(display("{:?} is owned by another user (UID {})", path, uid))]
+    ForeignFile { path: PathBuf, uid: u32 },
+
+    #[snafu(display("Failed to {} {:?}: {}", action, path, source))]
+    FileError { action: &'static str, path: PathBuf, source: io::Error },
 
     #[snafu(display("I/O error: {}", error))]
     IoError { error: io::Error },
@@ -102,8 +138,40 @@ pub enum Error {
     #[snafu(display("OS error: {}", error))]
     NixError { error: nix::errno::Errno },
 
-    #[snafu(display("DNS lookup error: {}", error))]
-    DnsLookupError { error: trust_dns_resolver::error::ResolveError },
+    #[cfg(feature = "dbus")]
+    #[snafu(display("D-Bus error: {}", error))]
+    DbusError { error: zbus::Error },
+}
+
+impl Error {
+    /// Returns whether the error may go away by trying again.
+    ///
+    /// Besides network failures, mounts and clock steps are transient,
+    /// since they usually fail because a server is unreachable. Other
+    /// failing commands are fatal.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::TmccError { error } => error.severity(),
+            Self::IoError { error } => Severity::of_io(error),
+            Self::FileError { source, .. } => Severity::of_io(source),
+            Self::NixError { error } => match error {
+                nix::errno::Errno::EINTR | nix::errno::Errno::EAGAIN | nix::errno::Errno::ETIMEDOUT => Severity::Transient,
+                _ => Severity::Fatal,
+            },
+
+            Self::Mount { .. } | Self::Unmount { .. } | Self::ClockStep { .. } => Severity::Transient,
+
+            #[cfg(feature = "dbus")]
+            Self::DbusError { .. } => Severity::Transient,
+
+            _ => Severity::Fatal,
+        }
+    }
+
+    /// Returns whether the error may go away by trying again.
+    pub fn is_transient(&self) -> bool {
+        self.severity() == Severity::Transient
+    }
 }
 
 impl From<io::Error> for Error {
@@ -118,8 +186,15 @@ impl From<nix::errno::Errno> for Error {
     }
 }
 
-impl From<trust_dns_resolver::error::ResolveError> for Error {
-    fn from(error: trust_dns_resolver::error::ResolveError) -> Self {
-        Self::DnsLookupError { error }
+impl From<miniond_core::Error> for Error {
+    fn from(error: miniond_core::Error) -> Self {
+        Self::TmccError { error }
+    }
+}
+
+#[cfg(feature = "dbus")]
+impl From<zbus::Error> for Error {
+    fn from(error: zbus::Error) -> Self {
+        Self::DbusError { error }
     }
 }
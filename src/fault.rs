@@ -0,0 +1,226 @@
+//! Fault injection.
+//!
+//! With the `fault-injection` feature, the `[faults]` config section
+//! makes TMCD responses slow, dropped, or corrupted, and makes a
+//! fraction of system commands fail. This exercises the error paths
+//! in CI and on staging nodes.
+//!
+//! Without the feature, all hooks here are no-ops.
+
+#[cfg(feature = "fault-injection")]
+pub use imp::*;
+
+#[cfg(not(feature = "fault-injection"))]
+mod noop {
+    use crate::config::ConfigInner;
+    use crate::tmcc::Stream;
+
+    pub fn init(_config: &ConfigInner) {}
+
+    pub fn wrap(stream: Box<dyn Stream>) -> Box<dyn Stream> {
+        stream
+    }
+
+    pub fn command_fails(_program: &str) -> bool {
+        false
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+pub use noop::*;
+
+#[cfg(feature = "fault-injection")]
+mod imp {
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use once_cell::sync::OnceCell;
+    use serde::Deserialize;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::time::Sleep;
+
+    use crate::config::ConfigInner;
+    use crate::tmcc::Stream;
+
+    /// Fault injection configuration.
+    ///
+    /// Rates are probabilities between 0 and 1.
+    #[derive(Debug, Clone, Default, Deserialize)]
+    #[serde(default)]
+    pub struct FaultConfig {
+        /// Delay before each TMCD request, in milliseconds.
+        #[serde(rename = "response-delay")]
+        response_delay: u64,
+
+        /// Rate of TMCD responses that are dropped entirely.
+        #[serde(rename = "drop-rate")]
+        drop_rate: f64,
+
+        /// Rate of TMCD responses that have a byte corrupted.
+        #[serde(rename = "corrupt-rate")]
+        corrupt_rate: f64,
+
+        /// Rate of system commands (e.g., `useradd`) that fail.
+        #[serde(rename = "command-failure-rate")]
+        command_failure_rate: f64,
+
+        /// Seed of the random number generator, for reproducible runs.
+        seed: Option<u64>,
+    }
+
+    static CONFIG: OnceCell<FaultConfig> = OnceCell::new();
+    static RNG: AtomicU64 = AtomicU64::new(0);
+
+    /// Set up fault injection.
+    pub fn init(config: &ConfigInner) {
+        let faults = config.faults.clone();
+
+        let seed = faults.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+
+        // xorshift state must not be zero
+        RNG.store(seed | 1, Ordering::Relaxed);
+
+        log::warn!("Fault injection enabled: {:?}", faults);
+        CONFIG.set(faults).ok();
+    }
+
+    /// Returns a pseudo-random number in [0, 1).
+    fn random() -> f64 {
+        let mut x = RNG.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        RNG.store(x, Ordering::Relaxed);
+
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn roll(rate: f64) -> bool {
+        rate > 0.0 && random() < rate
+    }
+
+    /// Wrap a TMCD connection.
+    pub fn wrap(stream: Box<dyn Stream>) -> Box<dyn Stream> {
+        let config = match CONFIG.get() {
+            Some(config) => config,
+            None => return stream,
+        };
+
+        let delay = if config.response_delay > 0 {
+            Some(Box::pin(tokio::time::sleep(Duration::from_millis(config.response_delay))))
+        } else {
+            None
+        };
+
+        let drop = roll(config.drop_rate);
+        let corrupt = roll(config.corrupt_rate);
+
+        if drop {
+            log::warn!("Injected fault: Dropping TMCD response");
+        } else if corrupt {
+            log::warn!("Injected fault: Corrupting TMCD response");
+        }
+
+        Box::new(FaultyStream {
+            inner: stream,
+            delay,
+            drop,
+            corrupt,
+        })
+    }
+
+    /// Returns whether a system command should fail.
+    pub fn command_fails(program: &str) -> bool {
+        let fails = CONFIG.get()
+            .map(|config| roll(config.command_failure_rate))
+            .unwrap_or(false);
+
+        if fails {
+            log::warn!("Injected fault: Failing `{}`", program);
+        }
+
+        fails
+    }
+
+    /// A connection with faulty responses.
+    struct FaultyStream {
+        inner: Box<dyn Stream>,
+
+        /// Delay before the request goes out.
+        delay: Option<Pin<Box<Sleep>>>,
+
+        /// Whether to drop the response.
+        drop: bool,
+
+        /// Whether to corrupt a byte of the response.
+        corrupt: bool,
+    }
+
+    impl FaultyStream {
+        fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            if let Some(delay) = &mut self.delay {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+
+                self.delay = None;
+            }
+
+            Poll::Ready(())
+        }
+    }
+
+    impl AsyncRead for FaultyStream {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            if self.poll_delay(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            if self.drop {
+                return Poll::Ready(Ok(()));
+            }
+
+            let filled = buf.filled().len();
+            let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+            if let Poll::Ready(Ok(())) = res {
+                let new = &mut buf.filled_mut()[filled..];
+
+                if self.corrupt && !new.is_empty() {
+                    let pos = (random() * new.len() as f64) as usize;
+                    new[pos] = b'~';
+                    self.corrupt = false;
+                }
+            }
+
+            res
+        }
+    }
+
+    impl AsyncWrite for FaultyStream {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            if self.poll_delay(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+}
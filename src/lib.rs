@@ -0,0 +1,40 @@
+//! Alternative implementation of Emulab Clientside.
+
+#![deny(
+    unused_imports,
+    unused_must_use,
+    unreachable_patterns,
+)]
+
+// Applet constructors return boxed trait objects, most error
+// variants are named after what failed, and message handlers
+// read better as a flat match with nested conditions.
+#![allow(
+    clippy::new_ret_no_self,
+    clippy::enum_variant_names,
+    clippy::collapsible_match,
+)]
+
+pub mod applet;
+mod account;
+mod apparmor;
+mod blocking;
+mod clock;
+mod command;
+pub mod config;
+pub mod error;
+mod fault;
+mod metrics;
+mod mount;
+mod network;
+mod overlay;
+mod passwd;
+pub mod plan;
+mod scope;
+mod state;
+mod sync;
+
+pub use miniond_core::{net, tmcc};
+
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -1,23 +1,14 @@
-#![deny(
-    private_in_public,
-    unused_imports,
-    unused_must_use,
-    unreachable_patterns,
-)]
-
-mod applet;
-mod account;
-mod config;
-mod error;
-mod geni;
-mod mount;
-mod tmcc;
-
 use std::env;
+use std::fs;
 use std::error::Error;
 use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+
+use clap::{ArgEnum, Parser, Subcommand};
 
-use clap::Parser;
+use miniond::{applet, config, plan};
+use miniond::tmcc::State;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -32,8 +23,59 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         log::warn!("See <https://github.com/mars-research/miniond> for available options.");
     }
 
-    let config = config::get_config(opts.config);
-    applet::run(config).await.unwrap();
+    let config = config::load_config(opts.config, opts.vnode);
+
+    match opts.command {
+        None | Some(Command::Run) => {
+            applet::run(Arc::new(config)).await.unwrap();
+        }
+        Some(Command::Simulate { fixtures, format, output }) => {
+            let actions = applet::simulate(config, fixtures).await?;
+
+            let plan = match format {
+                Format::Text => {
+                    actions.iter()
+                        .map(|action| format!("{}\n", action))
+                        .collect::<String>()
+                }
+                Format::Json => plan::to_json(&actions) + "\n",
+            };
+
+            match output {
+                Some(path) => fs::write(path, plan)?,
+                None => print!("{}", plan),
+            }
+        }
+        Some(Command::Report { state }) => {
+            applet::report(config, state.into()).await?;
+        }
+        Some(Command::Query { query, format }) => {
+            let response = match format {
+                QueryFormat::Raw => applet::query_raw(config, query.into()).await?,
+                QueryFormat::Json => applet::query_json(config, query.into()).await? + "\n",
+            };
+
+            print!("{}", response);
+        }
+        Some(Command::DiscoverBoss) => {
+            let (host, addr) = applet::discover_boss(config).await?;
+            println!("{} {}", host, addr);
+        }
+        Some(Command::Sync { name, init, nowait, error, server }) => {
+            let barrier = applet::Barrier {
+                name,
+                init,
+                nowait,
+                error,
+            };
+
+            // Like `emulab-sync`, exit with the error code of the barrier
+            let code = applet::sync(config, barrier, server).await?;
+            if code != 0 {
+                process::exit(code);
+            }
+        }
+    }
 
     Ok(())
 }
@@ -57,4 +99,171 @@ struct Opts {
     /// Path to the config file.
     #[clap(short = 'f', long, global = true)]
     config: Option<PathBuf>,
+
+    /// Act as a virtual node hosted on this machine.
+    ///
+    /// Its state is kept in a subdirectory of the state directory.
+    #[clap(long, global = true)]
+    vnode: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the daemon (the default).
+    Run,
+
+    /// Run the applets once against saved TMCD responses, printing
+    /// the planned actions without changing the system.
+    Simulate {
+        /// Directory of TMCD responses.
+        ///
+        /// This contains a file for each command (e.g., `accounts.txt`),
+        /// or a recording made with `tmcc.record-dir`.
+        fixtures: PathBuf,
+
+        /// Format of the planned actions.
+        #[clap(long, arg_enum, default_value = "text")]
+        format: Format,
+
+        /// Write the planned actions to a file instead of stdout.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Report a state to the testbed.
+    ///
+    /// This is meant for other tools, like the image loader in a
+    /// reload MFS.
+    #[clap(alias = "state")]
+    Report {
+        #[clap(arg_enum)]
+        state: ReportState,
+    },
+
+    /// Query the testbed and print the response, like `tmcc`.
+    Query {
+        #[clap(arg_enum)]
+        query: QueryCommand,
+
+        /// Print the response as received, or parsed as JSON.
+        #[clap(long, arg_enum, default_value = "raw")]
+        format: QueryFormat,
+    },
+
+    /// Find the boss node and print its name and address.
+    DiscoverBoss,
+
+    /// Wait at a barrier on the sync server of the experiment.
+    ///
+    /// This is compatible with `emulab-sync`.
+    Sync {
+        /// Name of the barrier.
+        #[clap(short, long, default_value = "barrier")]
+        name: String,
+
+        /// Initialize the barrier for this many other nodes.
+        #[clap(short, long)]
+        init: Option<u32>,
+
+        /// Return right after initializing the barrier.
+        #[clap(short = 'a', long = "async", requires = "init")]
+        nowait: bool,
+
+        /// Error code to report to the other nodes.
+        #[clap(short, long, default_value = "0")]
+        error: i32,
+
+        /// Sync server to use, instead of looking it up.
+        #[clap(short, long)]
+        server: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum Format {
+    /// One action per line.
+    Text,
+
+    /// A JSON object with a list of actions.
+    Json,
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum QueryCommand {
+    /// Accounts to be configured (`accounts`).
+    Accounts,
+
+    /// File systems to be mounted (`mounts`).
+    Mounts,
+}
+
+impl From<QueryCommand> for applet::Query {
+    fn from(query: QueryCommand) -> Self {
+        match query {
+            QueryCommand::Accounts => Self::Accounts,
+            QueryCommand::Mounts => Self::Mounts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum QueryFormat {
+    /// The response lines, with secrets redacted.
+    Raw,
+
+    /// The parsed response.
+    Json,
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum ReportState {
+    /// The system is being set up from an MFS (`MFSSETUP`).
+    Setup,
+
+    /// The system is being set up from the disk (`TBSETUP`).
+    TbSetup,
+
+    /// The system is booting (`BOOTING`).
+    Booting,
+
+    /// The system is up (`ISUP`).
+    Up,
+
+    /// The system is shutting down (`SHUTDOWN`).
+    Shutdown,
+
+    /// The disk is about to be reloaded (`RELOADSETUP`).
+    ReloadSetup,
+
+    /// The disk is being reloaded (`RELOADING`).
+    Reloading,
+
+    /// The disk has been reloaded (`RELOADDONE`).
+    ReloadDone,
+
+    /// The node is waiting in PXE (`PXEWAIT`).
+    PxeWait,
+
+    /// The node failed to set up (`TBFAILED`).
+    Failed,
+}
+
+impl From<ReportState> for State {
+    fn from(state: ReportState) -> Self {
+        match state {
+            ReportState::Setup => Self::Setup,
+            ReportState::TbSetup => Self::TbSetup,
+            ReportState::Booting => Self::Booting,
+            ReportState::Up => Self::Up,
+            ReportState::Shutdown => Self::Shutdown,
+            ReportState::ReloadSetup => Self::ReloadSetup,
+            ReportState::Reloading => Self::Reloading,
+            ReportState::ReloadDone => Self::ReloadDone,
+            ReportState::PxeWait => Self::PxeWait,
+            ReportState::Failed => Self::Failed,
+        }
+    }
 }
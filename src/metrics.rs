@@ -0,0 +1,158 @@
+//! Process-wide metrics.
+//!
+//! Counters that are updated outside of the applet bus (TMCD requests
+//! and applet respawns) are collected here, and rendered along with
+//! the gauges of the `metrics` applet in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+/// Upper bounds of the TMCD request duration buckets, in seconds.
+const BUCKETS: [f64; 9] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(|| Mutex::new(Metrics::default()));
+
+#[derive(Debug, Default)]
+struct Metrics {
+    /// TMCD requests by command.
+    tmcd: BTreeMap<String, Requests>,
+
+    /// Respawns by applet.
+    respawns: BTreeMap<&'static str, u64>,
+}
+
+/// TMCD requests of one command.
+#[derive(Debug, Default)]
+struct Requests {
+    /// Number of requests in each bucket (not cumulative).
+    buckets: [u64; BUCKETS.len()],
+
+    count: u64,
+    sum: f64,
+    failures: u64,
+}
+
+/// A gauge or counter kept by the caller of [`render`].
+#[derive(Debug)]
+pub struct Sample {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: &'static str,
+
+    /// Values by label set (e.g., `applet="tmcc"`).
+    pub values: Vec<(String, f64)>,
+}
+
+/// Record a connection to TMCD.
+pub fn observe_tmcd(command: &str, duration: Duration, ok: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+    let requests = metrics.tmcd.entry(command.to_string()).or_default();
+
+    let seconds = duration.as_secs_f64();
+    if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+        requests.buckets[bucket] += 1;
+    }
+
+    requests.count += 1;
+    requests.sum += seconds;
+    if !ok {
+        requests.failures += 1;
+    }
+}
+
+/// Record the respawn of an applet.
+pub fn applet_respawned(name: &'static str) {
+    *METRICS.lock().unwrap().respawns.entry(name).or_default() += 1;
+}
+
+/// Returns the metrics in the Prometheus text format, after `samples`.
+pub fn render(samples: &[Sample]) -> String {
+    let mut out = String::new();
+
+    for sample in samples {
+        header(&mut out, sample.name, sample.help, sample.kind);
+        for (labels, value) in &sample.values {
+            if labels.is_empty() {
+                writeln!(out, "{} {}", sample.name, value).unwrap();
+            } else {
+                writeln!(out, "{}{{{}}} {}", sample.name, labels, value).unwrap();
+            }
+        }
+    }
+
+    let metrics = METRICS.lock().unwrap();
+
+    header(&mut out, "miniond_tmcd_request_duration_seconds", "Duration of TMCD requests.", "histogram");
+    for (command, requests) in &metrics.tmcd {
+        let command = escape(command);
+
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(requests.buckets) {
+            cumulative += count;
+            writeln!(out, "miniond_tmcd_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}", command, bound, cumulative).unwrap();
+        }
+        writeln!(out, "miniond_tmcd_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", command, requests.count).unwrap();
+        writeln!(out, "miniond_tmcd_request_duration_seconds_sum{{command=\"{}\"}} {}", command, requests.sum).unwrap();
+        writeln!(out, "miniond_tmcd_request_duration_seconds_count{{command=\"{}\"}} {}", command, requests.count).unwrap();
+    }
+
+    header(&mut out, "miniond_tmcd_request_failures_total", "TMCD requests that failed to connect or read.", "counter");
+    for (command, requests) in &metrics.tmcd {
+        writeln!(out, "miniond_tmcd_request_failures_total{{command=\"{}\"}} {}", escape(command), requests.failures).unwrap();
+    }
+
+    header(&mut out, "miniond_applet_respawns_total", "Respawns of failed applets.", "counter");
+    for (applet, respawns) in &metrics.respawns {
+        writeln!(out, "miniond_applet_respawns_total{{applet=\"{}\"}} {}", escape(applet), respawns).unwrap();
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Escape a label value.
+pub fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        observe_tmcd("mounts", Duration::from_millis(30), true);
+        observe_tmcd("mounts", Duration::from_millis(200), false);
+        observe_tmcd("mounts", Duration::from_secs(30), true);
+        applet_respawned("automount");
+
+        let out = render(&[Sample {
+            name: "miniond_users",
+            help: "Users applied to the system.",
+            kind: "gauge",
+            values: vec![(String::new(), 3.0)],
+        }]);
+
+        assert!(out.starts_with("# HELP miniond_users Users applied to the system.\n# TYPE miniond_users gauge\nminiond_users 3\n"), "{}", out);
+        assert!(out.contains("miniond_tmcd_request_duration_seconds_bucket{command=\"mounts\",le=\"0.01\"} 0\n"), "{}", out);
+        assert!(out.contains("miniond_tmcd_request_duration_seconds_bucket{command=\"mounts\",le=\"0.05\"} 1\n"), "{}", out);
+        assert!(out.contains("miniond_tmcd_request_duration_seconds_bucket{command=\"mounts\",le=\"0.25\"} 2\n"), "{}", out);
+        assert!(out.contains("miniond_tmcd_request_duration_seconds_bucket{command=\"mounts\",le=\"10\"} 2\n"), "{}", out);
+        assert!(out.contains("miniond_tmcd_request_duration_seconds_bucket{command=\"mounts\",le=\"+Inf\"} 3\n"), "{}", out);
+        assert!(out.contains("miniond_tmcd_request_duration_seconds_count{command=\"mounts\"} 3\n"), "{}", out);
+        assert!(out.contains("miniond_tmcd_request_failures_total{command=\"mounts\"} 1\n"), "{}", out);
+        assert!(out.contains("miniond_applet_respawns_total{applet=\"automount\"} 1\n"), "{}", out);
+
+        assert_eq!("a\\\"b\\\\c", escape("a\"b\\c"));
+    }
+}
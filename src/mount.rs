@@ -1,13 +1,175 @@
 //! Mount operations.
 
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
 
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
 use libsystemd::unit::escape_name;
-use tokio::fs::{OpenOptions, create_dir_all};
+use serde::Deserialize;
+use snafu::ResultExt;
+use tokio::fs::{create_dir_all, read, read_dir, read_to_string, remove_file};
 use tokio::process::Command;
-use tokio::io::AsyncWriteExt;
 
-use crate::error::{Error, Result};
+use crate::blocking;
+use crate::command::run_command;
+use crate::error::{Error, FileSnafu, Result};
+use crate::overlay::{self, OverlayConfig};
+use crate::plan::{self, Action};
+
+pub use miniond_core::mount::{Credentials, Filesystem, Mount};
+
+/// Mount points of the mount namespace we're in.
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Maximum number of mounts applied at the same time.
+const MAX_CONCURRENT_MOUNTS: usize = 8;
+
+/// Mode of credential files, which only root may read.
+const CREDENTIALS_MODE: u32 = 0o600;
+
+/// First line of the mount units we generate.
+const UNIT_MARKER: &str = "# This mount unit was automatically generated by miniond";
+
+/// Name of the section of `/etc/fstab` we manage.
+const FSTAB_SECTION: &str = "managed mounts";
+
+/// Configuration file of `cachefilesd`.
+const CACHEFILESD_CONF: &str = "/etc/cachefilesd.conf";
+
+/// FS-Cache configuration.
+///
+/// With FS-Cache, `cachefilesd` caches reads of NFS shares on local
+/// disk, which speeds up repeated reads of shared project data on
+/// slow fileservers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FscacheConfig {
+    /// Whether to mount NFS shares with FS-Cache (`fsc`).
+    pub enable: bool,
+
+    /// Directory of the cache.
+    dir: PathBuf,
+
+    /// Percentage of free space above which culling stops.
+    brun: u8,
+
+    /// Percentage of free space below which culling starts.
+    bcull: u8,
+
+    /// Percentage of free space below which caching stops.
+    bstop: u8,
+}
+
+impl Default for FscacheConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            dir: PathBuf::from("/var/cache/fscache"),
+            brun: 10,
+            bcull: 7,
+            bstop: 3,
+        }
+    }
+}
+
+/// NFS mount options.
+///
+/// Options that are unset are left to the defaults of the system
+/// (or of the testbed, if it sends any).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NfsOptions {
+    /// NFS protocol version (e.g., `3` or `4.2`).
+    version: Option<String>,
+
+    /// Transport protocol.
+    protocol: Option<NfsProtocol>,
+
+    /// Whether to give up on requests after retrying (`soft`) instead
+    /// of retrying forever (`hard`).
+    soft: Option<bool>,
+
+    /// Time to wait for a response before retrying, in tenths of a
+    /// second.
+    timeo: Option<u32>,
+
+    /// Whether to skip updating access times.
+    noatime: Option<bool>,
+
+    /// Whether to mount read-only.
+    #[serde(rename = "read-only")]
+    read_only: Option<bool>,
+}
+
+/// NFS transport protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NfsProtocol {
+    Tcp,
+    Udp,
+}
+
+/// NFS configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NfsConfig {
+    /// Options for all NFS mounts.
+    #[serde(flatten)]
+    options: NfsOptions,
+
+    /// Options for specific NFS mounts by mount point, overriding the
+    /// ones for all mounts.
+    mounts: BTreeMap<PathBuf, NfsOptions>,
+}
+
+impl NfsOptions {
+    /// Returns these options, with the unset ones taken from `defaults`.
+    fn or(&self, defaults: &Self) -> Self {
+        Self {
+            version: self.version.clone().or_else(|| defaults.version.clone()),
+            protocol: self.protocol.or(defaults.protocol),
+            soft: self.soft.or(defaults.soft),
+            timeo: self.timeo.or(defaults.timeo),
+            noatime: self.noatime.or(defaults.noatime),
+            read_only: self.read_only.or(defaults.read_only),
+        }
+    }
+
+    /// Returns the mount options, along with the prefixes of the ones
+    /// they replace.
+    fn to_options(&self) -> Vec<(String, &'static [&'static str])> {
+        let mut options: Vec<(String, &'static [&'static str])> = Vec::new();
+
+        if let Some(version) = &self.version {
+            options.push((format!("nfsvers={}", version), &["nfsvers=", "vers="]));
+        }
+        if let Some(protocol) = self.protocol {
+            let protocol = match protocol {
+                NfsProtocol::Tcp => "tcp",
+                NfsProtocol::Udp => "udp",
+            };
+            options.push((format!("proto={}", protocol), &["proto=", "tcp", "udp"]));
+        }
+        if let Some(soft) = self.soft {
+            options.push((if soft { "soft" } else { "hard" }.to_string(), &["soft", "hard"]));
+        }
+        if let Some(timeo) = self.timeo {
+            options.push((format!("timeo={}", timeo), &["timeo="]));
+        }
+        if let Some(noatime) = self.noatime {
+            options.push((if noatime { "noatime" } else { "atime" }.to_string(), &["noatime", "atime"]));
+        }
+        if let Some(read_only) = self.read_only {
+            options.push((if read_only { "ro" } else { "rw" }.to_string(), &["ro", "rw"]));
+        }
+
+        options
+    }
+}
 
 /// A mount backend.
 #[derive(Debug, Clone)]
@@ -17,75 +179,1068 @@ pub enum Backend {
     /// The path points to the unit file directory
     /// (usually `/etc/systemd/system`).
     Systemd(PathBuf),
+
+    /// Manage a block in `fstab` and run `mount` and `umount`
+    /// directly, for systems without systemd.
+    Fstab {
+        /// Path of `fstab` (usually `/etc/fstab`).
+        fstab: PathBuf,
+
+        /// Directory of credential files.
+        credentials_dir: PathBuf,
+    },
+
+    /// Mount on demand with autofs, through a direct map.
+    Autofs {
+        /// Path of the `auto.master` snippet (usually
+        /// `/etc/auto.master.d/miniond.autofs`).
+        master: PathBuf,
+
+        /// Path of the direct map.
+        map: PathBuf,
+
+        /// Directory of credential files.
+        credentials_dir: PathBuf,
+    },
 }
 
-/// An NFS mount.
-#[derive(Debug, Clone)]
-pub struct NfsMount {
-    remote: String,
-    local: PathBuf,
+/// Returns the name of the systemd mount unit.
+fn unit_name(mount: &Mount) -> String {
+    // Mount points from TMCD are always absolute
+    let unescaped = mount.local().strip_prefix("/").unwrap();
+    format!("{}.mount", escape_name(unescaped.to_str().unwrap()))
 }
 
-impl NfsMount {
-    pub fn new(remote: String, local: PathBuf) -> Self {
-        Self {
-            remote,
-            local,
+/// Returns the mount points of mounts, for errors.
+fn locals(mounts: &[Mount]) -> String {
+    mounts.iter()
+        .map(|mount| mount.local().display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the path of the credentials file for a mount unit.
+fn credentials_path(unit_dir: &Path, unit_name: &str) -> PathBuf {
+    unit_dir.join(format!("{}.credentials", unit_name))
+}
+
+/// Returns the path of the credentials file for a mount in `fstab`.
+fn fstab_credentials_path(credentials_dir: &Path, mount: &Mount) -> PathBuf {
+    credentials_path(credentials_dir, &unit_name(mount))
+}
+
+/// Returns the content of a credentials file for `mount.cifs`.
+fn credentials(credentials: &Credentials) -> String {
+    format!("username={}\npassword={}\n", credentials.username, credentials.password)
+}
+
+/// Returns the content of `cachefilesd.conf`.
+fn cachefilesd_conf(config: &FscacheConfig) -> String {
+    let mut conf = String::new();
+
+    conf.push_str("# This file was automatically generated by miniond\n\n");
+    conf.push_str(&format!("dir {}\n", config.dir.display()));
+    conf.push_str("tag miniond\n");
+    conf.push_str(&format!("brun {}%\n", config.brun));
+    conf.push_str(&format!("bcull {}%\n", config.bcull));
+    conf.push_str(&format!("bstop {}%\n", config.bstop));
+
+    conf
+}
+
+/// Configure and start `cachefilesd`.
+///
+/// It is only restarted if its configuration changed.
+pub async fn setup_fscache(overlay: &OverlayConfig, config: &FscacheConfig) -> Result<()> {
+    if !plan::is_dry_run() {
+        create_dir_all(&config.dir).await
+            .context(FileSnafu { action: "create", path: &config.dir })?;
+    }
+
+    let changed = overlay::update_file(overlay, Path::new(CACHEFILESD_CONF), cachefilesd_conf(config)).await?;
+
+    if plan::is_dry_run() {
+        if changed {
+            plan::record(Action::ReloadService {
+                unit: "cachefilesd.service".to_string(),
+            });
+        }
+
+        return Ok(());
+    }
+
+    let fscache_error = |reason| Error::Fscache { reason };
+
+    if changed {
+        log::info!("Restarting cachefilesd with the cache in {:?}...", config.dir);
+
+        run_command(Command::new("systemctl").args(["enable", "cachefilesd.service"])).await
+            .map_err(fscache_error)?;
+        run_command(Command::new("systemctl").args(["restart", "cachefilesd.service"])).await
+            .map_err(fscache_error)
+    } else {
+        run_command(Command::new("systemctl").args(["enable", "--now", "cachefilesd.service"])).await
+            .map_err(fscache_error)
+    }
+}
+
+/// Mount NFS shares with FS-Cache.
+pub fn enable_fscache(mounts: &mut [Mount]) {
+    for mount in mounts {
+        if *mount.filesystem() == Filesystem::Nfs && !mount.options().iter().any(|option| option == "fsc") {
+            mount.add_option("fsc".to_string());
+        }
+    }
+}
+
+/// Apply the configured options to NFS mounts.
+///
+/// They replace conflicting options from the testbed (e.g., `vers=3`
+/// is dropped with `version = "4.2"`).
+pub fn apply_nfs_options(mounts: &mut [Mount], config: &NfsConfig) {
+    for mount in mounts {
+        if *mount.filesystem() != Filesystem::Nfs {
+            continue;
+        }
+
+        let nfs = match config.mounts.get(mount.local()) {
+            Some(options) => options.or(&config.options),
+            None => config.options.clone(),
+        };
+
+        let mut options = mount.options().to_vec();
+        for (option, replaces) in nfs.to_options() {
+            options.retain(|existing| !replaces.iter().any(|prefix| {
+                if prefix.ends_with('=') {
+                    existing.starts_with(prefix)
+                } else {
+                    existing == prefix
+                }
+            }));
+            options.push(option);
+        }
+
+        mount.set_options(options);
+    }
+}
+
+/// Returns the options to mount with.
+fn options(mount: &Mount, credentials_path: &Path) -> Vec<String> {
+    let mut options = mount.options().to_vec();
+    if mount.credentials().is_some() {
+        options.push(format!("credentials={}", credentials_path.display()));
+    }
+
+    options
+}
+
+/// Returns the content of the systemd mount unit.
+fn unit(mount: &Mount, credentials_path: &Path) -> String {
+    let options = options(mount, credentials_path);
+
+    let mut unit = String::new();
+
+    unit.push_str(UNIT_MARKER);
+    unit.push_str("\n\n");
+
+    // Mounts nested in each other are ordered by systemd
+    if mount.filesystem().is_network() {
+        unit.push_str("[Unit]\n");
+        unit.push_str("Wants=network-online.target\n");
+        unit.push_str("After=network-online.target\n\n");
+    }
+
+    unit.push_str("[Mount]\n");
+    unit.push_str(&format!("What={}\n", mount.remote()));
+    unit.push_str(&format!("Where={:?}\n", mount.local()));
+    unit.push_str(&format!("Type={}\n", mount.filesystem().fs_type()));
+    if !options.is_empty() {
+        unit.push_str(&format!("Options={}\n", options.join(",")));
+    }
+    unit.push_str("TimeoutSec=30s\n");
+
+    unit
+}
+
+/// Returns the mount of a unit we generated.
+///
+/// Returns `None` if the unit wasn't generated by us.
+fn parse_unit(unit: &str) -> Option<Mount> {
+    let mut lines = unit.lines();
+    if lines.next() != Some(UNIT_MARKER) {
+        return None;
+    }
+
+    let mut remote = None;
+    let mut local = None;
+    let mut fs_type = None;
+    for line in lines {
+        match line.split_once('=') {
+            Some(("What", value)) => remote = Some(value.to_string()),
+            Some(("Where", value)) => {
+                // Written with the Debug format of the path
+                let value = value.strip_prefix('"')?.strip_suffix('"')?;
+                local = Some(PathBuf::from(value.replace("\\\"", "\"").replace("\\\\", "\\")));
+            }
+            Some(("Type", value)) => fs_type = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    let mut mount = Mount::new(remote?, local?);
+    mount.set_filesystem(match fs_type?.as_str() {
+        "nfs" => Filesystem::Nfs,
+        "cifs" => Filesystem::Smb,
+        other => Filesystem::Block(other.to_string()),
+    });
+
+    Some(mount)
+}
+
+/// Returns the mounts of units we generated in `unit_dir` that are
+/// not for any of `known`.
+///
+/// These are left behind if the saved state was lost (e.g., the state
+/// directory was wiped).
+pub async fn orphaned_units(unit_dir: &Path, known: &[Mount]) -> Result<Vec<Mount>> {
+    let known: Vec<String> = known.iter().map(unit_name).collect();
+    let mut orphans = Vec::new();
+
+    let mut entries = match read_dir(unit_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(orphans),
+        Err(e) => return Err(e).context(FileSnafu { action: "read", path: unit_dir }),
+    };
+
+    while let Some(entry) = entries.next_entry().await.context(FileSnafu { action: "read", path: unit_dir })? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.ends_with(".mount") || known.contains(&name) {
+            continue;
+        }
+
+        let mount = match read_to_string(entry.path()).await.ok().as_deref().and_then(parse_unit) {
+            Some(mount) => mount,
+            None => continue,
+        };
+
+        // Only remove what we would have named this way
+        if unit_name(&mount) == name {
+            orphans.push(mount);
+        }
+    }
+
+    Ok(orphans)
+}
+
+/// Escapes a field of `fstab`.
+///
+/// Whitespace would separate fields, so it's escaped as octal.
+fn fstab_field(field: &str) -> String {
+    field.replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011")
+        .replace('\n', "\\012")
+}
+
+/// Returns the `fstab` entry of a mount.
+fn fstab_entry(mount: &Mount, credentials_path: &Path) -> String {
+    let mut options = options(mount, credentials_path);
+    if mount.filesystem().is_network() {
+        options.push("_netdev".to_string());
+    }
+    if options.is_empty() {
+        options.push("defaults".to_string());
+    }
+
+    format!("{} {} {} {} 0 0",
+        fstab_field(mount.remote()),
+        fstab_field(&mount.local().to_string_lossy()),
+        mount.filesystem().fs_type(),
+        options.join(","))
+}
+
+/// Returns the key of a mount point in an autofs map.
+fn autofs_key(local: &Path) -> String {
+    let local = local.to_string_lossy();
+    if local.contains(char::is_whitespace) {
+        format!("\"{}\"", local)
+    } else {
+        local.to_string()
+    }
+}
+
+/// Returns the autofs map entry of a mount.
+fn autofs_entry(mount: &Mount, credentials_path: &Path) -> String {
+    let mut options = vec![format!("-fstype={}", mount.filesystem().fs_type())];
+    options.extend(self::options(mount, credentials_path));
+
+    // Locations other than NFS exports start with a colon
+    let location = match mount.filesystem() {
+        Filesystem::Nfs => mount.remote().replace(' ', "\\ "),
+        _ => format!(":{}", mount.remote().replace(' ', "\\ ")),
+    };
+
+    format!("{} {} {}", autofs_key(mount.local()), options.join(","), location)
+}
+
+/// Returns the autofs map with entries added and removed.
+///
+/// Entries with the mount points of `add` are replaced, and entries
+/// with mount points in `remove` are dropped.
+fn update_autofs_map(map: &str, add: &[String], remove: &[&Path]) -> String {
+    let key = |entry: &str| -> Option<String> {
+        let entry = entry.trim_start();
+        if let Some(quoted) = entry.strip_prefix('"') {
+            quoted.split_once('"').map(|(key, _)| format!("\"{}\"", key))
+        } else {
+            entry.split_whitespace().next().map(str::to_string)
+        }
+    };
+
+    let replaced: Vec<String> = add.iter().filter_map(|entry| key(entry)).collect();
+    let removed: Vec<String> = remove.iter().map(|local| autofs_key(local)).collect();
+
+    let mut updated = String::from("# This file was automatically generated by miniond\n");
+    for entry in map.lines() {
+        if entry.trim().is_empty() || entry.starts_with('#') {
+            continue;
+        }
+
+        if let Some(key) = key(entry) {
+            if !replaced.contains(&key) && !removed.contains(&key) {
+                updated.push_str(entry);
+                updated.push('\n');
+            }
+        }
+    }
+    for entry in add {
+        updated.push_str(entry);
+        updated.push('\n');
+    }
+
+    updated
+}
+
+/// Returns the `auto.master` snippet for a direct map.
+fn autofs_master(map: &Path) -> String {
+    format!("# This file was automatically generated by miniond\n/- {}\n", map.display())
+}
+
+/// Write the autofs files if they changed, and have autofs pick them up.
+async fn write_autofs(overlay: &OverlayConfig, master: &Path, map: &Path, updated_map: String) -> Result<()> {
+    let mut files = Vec::new();
+    for (path, contents) in [(master.to_path_buf(), autofs_master(map)), (map.to_path_buf(), updated_map)] {
+        if read(&path).await.ok().as_deref() != Some(contents.as_bytes()) {
+            files.push((path, contents));
+        }
+    }
+
+    let autofs_error = |reason| Error::ServiceReload { unit: "autofs.service".to_string(), reason };
+
+    if plan::is_dry_run() {
+        if !files.is_empty() {
+            for (path, contents) in files {
+                plan::record(Action::WriteFile { path, contents });
+            }
+
+            plan::record(Action::ReloadService {
+                unit: "autofs.service".to_string(),
+            });
+        }
+
+        return Ok(());
+    }
+
+    if files.is_empty() {
+        return run_command(Command::new("systemctl").args(["enable", "--now", "autofs.service"])).await
+            .map_err(autofs_error);
+    }
+
+    log::debug!("Writing the autofs map {:?}...", map);
+    for (path, contents) in files {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).await
+                .context(FileSnafu { action: "create", path: parent })?;
+        }
+
+        overlay::write_file(overlay, &path, contents.into_bytes()).await?;
+    }
+
+    run_command(Command::new("systemctl").args(["enable", "autofs.service"])).await
+        .map_err(autofs_error)?;
+    run_command(Command::new("systemctl").args(["reload-or-restart", "autofs.service"])).await
+        .map_err(autofs_error)
+}
+
+/// Write credential files for mounts that have credentials.
+async fn write_credentials(mounts: &[Mount], credentials_dir: &Path) -> Result<()> {
+    let secrets: Vec<(PathBuf, &Credentials)> = mounts.iter()
+        .filter_map(|mount| mount.credentials().map(|creds| (fstab_credentials_path(credentials_dir, mount), creds)))
+        .collect();
+
+    if plan::is_dry_run() {
+        for (path, creds) in secrets {
+            plan::record(Action::WriteFile {
+                path,
+                contents: credentials(&Credentials {
+                    username: creds.username.clone(),
+                    password: "********".to_string(),
+                }),
+            });
         }
+    } else if !secrets.is_empty() {
+        create_dir_all(credentials_dir).await
+            .context(FileSnafu { action: "create", path: credentials_dir })?;
+
+        for (path, creds) in secrets {
+            blocking::write_private(path, credentials(creds).into_bytes(), CREDENTIALS_MODE, 0, 0).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the managed section of `fstab` updated.
+///
+/// Entries with the mount points of `add` are replaced, and entries
+/// with mount points in `remove` are dropped.
+fn update_fstab(section: &str, add: &[String], remove: &[&Path]) -> String {
+    let mount_point = |entry: &str| entry.split_whitespace().nth(1).map(str::to_string);
+
+    let replaced: Vec<String> = add.iter().filter_map(|entry| mount_point(entry)).collect();
+    let removed: Vec<String> = remove.iter().map(|local| fstab_field(&local.to_string_lossy())).collect();
+
+    let mut updated = String::new();
+    let entries = section.lines()
+        .filter(|entry| match mount_point(entry) {
+            Some(point) => !replaced.contains(&point) && !removed.contains(&point),
+            None => false,
+        })
+        .chain(add.iter().map(String::as_str));
+
+    for entry in entries {
+        updated.push_str(entry);
+        updated.push('\n');
+    }
+
+    updated
+}
+
+/// Update the managed section of `fstab`.
+async fn write_fstab(overlay: &OverlayConfig, fstab: &Path, add: &[String], remove: &[&Path]) -> Result<()> {
+    let section = overlay::read_section(fstab, FSTAB_SECTION).await?;
+    overlay::update_section(overlay, fstab, FSTAB_SECTION, &update_fstab(&section, add, remove)).await?;
+
+    Ok(())
+}
+
+/// Returns the contents of a file we manage (`fstab` or an autofs
+/// map), which may not exist yet.
+async fn read_managed(path: &Path) -> Result<String> {
+    match read_to_string(path).await {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).context(FileSnafu { action: "read", path }),
+    }
+}
+
+/// Mount with `mount`.
+async fn mount_direct(mount: &Mount, credentials_dir: &Path) -> Result<()> {
+    if plan::is_dry_run() {
+        plan::record(Action::Mount {
+            remote: mount.remote().to_string(),
+            local: mount.local().to_path_buf(),
+        });
+
+        return Ok(());
+    }
+
+    create_dir_all(mount.local()).await
+        .context(FileSnafu { action: "create", path: mount.local() })?;
+
+    let options = options(mount, &fstab_credentials_path(credentials_dir, mount));
+
+    let mut command = Command::new("mount");
+    command.args(["-t", mount.filesystem().fs_type()]);
+    if !options.is_empty() {
+        command.args(["-o", &options.join(",")]);
+    }
+    command.arg(mount.remote()).arg(mount.local());
+
+    run_command(&mut command).await
+        .map_err(|reason| Error::Mount { locals: mount.local().display().to_string(), reason })
+}
+
+/// Run `f` on mounts concurrently, up to [`MAX_CONCURRENT_MOUNTS`] at
+/// a time.
+///
+/// All mounts are attempted, and the ones that failed are reported
+/// together.
+async fn for_each_mount<'a, F>(mounts: &'a [Mount], f: F) -> Result<()>
+    where F: Fn(&'a Mount) -> BoxFuture<'a, std::result::Result<(), String>>,
+{
+    let futures: Vec<BoxFuture<'a, (&'a Mount, std::result::Result<(), String>)>> = mounts.iter()
+        .map(|mount| f(mount).map(move |res| (mount, res)).boxed())
+        .collect();
+
+    let results: Vec<(&Mount, std::result::Result<(), String>)> = stream::iter(futures)
+        .buffer_unordered(MAX_CONCURRENT_MOUNTS)
+        .collect().await;
+
+    let mut failed: Vec<(&Mount, String)> = results.into_iter()
+        .filter_map(|(mount, res)| res.err().map(|reason| (mount, reason)))
+        .collect();
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    failed.sort_by(|a, b| a.0.local().cmp(b.0.local()));
+    for (mount, reason) in &failed {
+        log::error!("Failed to mount {:?}: {}", mount.local(), reason);
+    }
+
+    let (mounts, reasons): (Vec<&Mount>, Vec<String>) = failed.into_iter().unzip();
+    Err(Error::Mount {
+        locals: mounts.iter().map(|mount| mount.local().display().to_string()).collect::<Vec<_>>().join(", "),
+        reason: if reasons.len() == 1 {
+            reasons.into_iter().next().unwrap()
+        } else {
+            format!("{} mounts failed", reasons.len())
+        },
+    })
+}
+
+/// Start the mount units of mounts.
+///
+/// systemd orders nested mounts by itself, so all units are started at
+/// once.
+async fn start_units(mounts: &[Mount]) -> Result<()> {
+    for_each_mount(mounts, |mount| async move {
+        run_command(Command::new("systemctl").arg("start").arg(unit_name(mount))).await
+    }.boxed()).await
+}
+
+/// Mount with `mount`.
+///
+/// Mounts nested in others are mounted after them, by depth.
+async fn mount_all_direct(mounts: &[Mount], credentials_dir: &Path) -> Result<()> {
+    let mut levels: BTreeMap<usize, Vec<Mount>> = BTreeMap::new();
+    for mount in mounts {
+        levels.entry(mount.local().components().count()).or_default().push(mount.clone());
+    }
+
+    for level in levels.values() {
+        for_each_mount(level, |mount| async move {
+            mount_direct(mount, credentials_dir).await
+                .map_err(|e| match e {
+                    Error::Mount { reason, .. } => reason,
+                    e => e.to_string(),
+                })
+        }.boxed()).await?;
     }
 
-    /// Apply the configuration on the host.
-    pub async fn apply(&self, backend: Backend) -> Result<()> {
-        match backend {
-            Backend::Systemd(unit_dir) => {
-                // Create systemd mount unit
-                let unit_name = {
-                    let unescaped = self.local.strip_prefix("/").unwrap();
-                    format!("{}.mount", escape_name(unescaped.to_str().unwrap()))
-                };
+    Ok(())
+}
+
+/// Mount a local file system with `mount`, unless it's mounted
+/// already.
+///
+/// This is for file systems that are not managed with the other
+/// mounts (e.g., blockstores).
+pub async fn mount_local(mount: &Mount) -> Result<()> {
+    if unmounted(std::slice::from_ref(mount)).await?.is_empty() {
+        log::debug!("{:?} is already mounted", mount.local());
+        return Ok(());
+    }
+
+    log::info!("Mounting {} at {:?}...", mount.remote(), mount.local());
+
+    // Local file systems have no credentials
+    mount_direct(mount, Path::new("")).await
+}
+
+/// Apply a set of mounts on the host.
+///
+/// With the systemd backend, all unit files are written in one batch
+/// and systemd is only reloaded once. With the `fstab` backend, the
+/// managed block is updated once and the mounts that aren't mounted
+/// yet are mounted. With the `autofs` backend, the map is updated and
+/// autofs mounts them on first access.
+pub async fn apply_all(mounts: &[Mount], backend: Backend, overlay: &OverlayConfig) -> Result<()> {
+    match backend {
+        Backend::Systemd(unit_dir) => {
+            let mut units = BTreeMap::new();
+            let mut secrets = Vec::new();
+            for mount in mounts {
+                let unit_name = unit_name(mount);
+                log::info!("Mounting {} with systemd unit {}...", mount.remote(), unit_name);
+
+                let credentials_path = credentials_path(&unit_dir, &unit_name);
+                if let Some(creds) = mount.credentials() {
+                    secrets.push((credentials_path.clone(), creds));
+                }
+
+                units.insert(unit_name, unit(mount, &credentials_path));
+            }
+
+            if units.is_empty() {
+                return Ok(());
+            }
+
+            // Unchanged units are left alone
+            let mut files = Vec::new();
+            for (unit_name, unit) in &units {
+                let unit_path = unit_dir.join(unit_name);
+
+                if let Ok(existing) = read(&unit_path).await {
+                    if existing == unit.as_bytes() {
+                        continue;
+                    }
+                }
+
+                log::debug!("Writing systemd unit {}...", unit_name);
+                files.push((unit_path, unit.clone().into_bytes()));
+            }
+
+            if plan::is_dry_run() {
+                for (path, creds) in secrets {
+                    plan::record(Action::WriteFile {
+                        path,
+                        contents: credentials(&Credentials {
+                            username: creds.username.clone(),
+                            password: "********".to_string(),
+                        }),
+                    });
+                }
+
+                for (path, contents) in files {
+                    plan::record(Action::WriteFile {
+                        path,
+                        contents: String::from_utf8_lossy(&contents).to_string(),
+                    });
+                }
+
+                for mount in mounts {
+                    plan::record(Action::Mount {
+                        remote: mount.remote().to_string(),
+                        local: mount.local().to_path_buf(),
+                    });
+                }
+
+                return Ok(());
+            }
+
+            let mount_error = |reason| Error::Mount { locals: locals(mounts), reason };
 
-                log::info!("Creating systemd unit {} for {}...", unit_name, self.remote);
+            if !secrets.is_empty() {
+                create_dir_all(&unit_dir).await
+                    .context(FileSnafu { action: "create", path: &unit_dir })?;
 
+                for (path, creds) in secrets {
+                    blocking::write_private(path, credentials(creds).into_bytes(), CREDENTIALS_MODE, 0, 0).await?;
+                }
+            }
+
+            if !files.is_empty() {
                 // This directory may not exist yet.
-                create_dir_all(&unit_dir).await?;
-
-                let unit_path = unit_dir.join(&unit_name);
-                let mut file = OpenOptions::new()
-                    .read(false)
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&unit_path)
-                    .await?;
-
-                file.write_all("# This mount unit was automatically generated by miniond\n\n".as_bytes()).await?;
-                file.write_all("[Mount]\n".as_bytes()).await?;
-                file.write_all(format!("What={}\n", self.remote).as_bytes()).await?;
-                file.write_all(format!("Where={:?}\n", self.local).as_bytes()).await?;
-                file.write_all("Type=nfs\n".as_bytes()).await?;
-                file.write_all("TimeoutSec=30s\n".as_bytes()).await?;
-
-                // Start the mount
-                let status = Command::new("systemctl")
-                    .arg("daemon-reload")
-                    .status()
-                    .await?;
-
-                if !status.success() {
-                    return Err(Error::Mount);
+                create_dir_all(&unit_dir).await
+                    .context(FileSnafu { action: "create", path: &unit_dir })?;
+
+                blocking::write_atomic(files).await?;
+
+                run_command(Command::new("systemctl").arg("daemon-reload")).await
+                    .map_err(mount_error)?;
+            }
+
+            start_units(mounts).await
+        }
+        Backend::Fstab { fstab, credentials_dir } => {
+            if mounts.is_empty() {
+                return Ok(());
+            }
+
+            let entries: Vec<String> = mounts.iter()
+                .map(|mount| fstab_entry(mount, &fstab_credentials_path(&credentials_dir, mount)))
+                .collect();
+
+            write_credentials(mounts, &credentials_dir).await?;
+
+            write_fstab(overlay, &fstab, &entries, &[]).await?;
+
+            let unmounted = unmounted(mounts).await?;
+            for mount in &unmounted {
+                log::info!("Mounting {} at {:?}...", mount.remote(), mount.local());
+            }
+
+            mount_all_direct(&unmounted, &credentials_dir).await
+        }
+        Backend::Autofs { master, map, credentials_dir } => {
+            if mounts.is_empty() {
+                return Ok(());
+            }
+
+            let entries: Vec<String> = mounts.iter()
+                .map(|mount| {
+                    log::info!("Mounting {} at {:?} on demand...", mount.remote(), mount.local());
+                    autofs_entry(mount, &fstab_credentials_path(&credentials_dir, mount))
+                })
+                .collect();
+
+            write_credentials(mounts, &credentials_dir).await?;
+
+            let existing = read_managed(&map).await?;
+            write_autofs(overlay, &master, &map, update_autofs_map(&existing, &entries, &[])).await
+        }
+    }
+}
+
+/// Returns the mounts that are not mounted.
+pub async fn unmounted(mounts: &[Mount]) -> Result<Vec<Mount>> {
+    let mountinfo = read(MOUNTINFO).await
+        .context(FileSnafu { action: "read", path: MOUNTINFO })?;
+    let mounted = mount_points(&String::from_utf8_lossy(&mountinfo));
+
+    Ok(mounts.iter()
+        .filter(|mount| !mounted.iter().any(|point| point == mount.local()))
+        .cloned()
+        .collect())
+}
+
+/// Returns the mount points listed in `/proc/self/mountinfo`.
+///
+/// Spaces and other special characters are escaped as octal (e.g.,
+/// `\040`).
+fn mount_points(mountinfo: &str) -> Vec<PathBuf> {
+    mountinfo.lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|point| {
+            let mut bytes = Vec::with_capacity(point.len());
+            let mut rest = point.as_bytes();
+
+            while let Some((&byte, tail)) = rest.split_first() {
+                let octal = tail.get(..3)
+                    .and_then(|digits| std::str::from_utf8(digits).ok())
+                    .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+
+                match (byte, octal) {
+                    (b'\\', Some(escaped)) => {
+                        bytes.push(escaped);
+                        rest = &tail[3..];
+                    }
+                    _ => {
+                        bytes.push(byte);
+                        rest = tail;
+                    }
+                }
+            }
+
+            PathBuf::from(OsString::from_vec(bytes))
+        })
+        .collect()
+}
+
+/// Mount a set of mounts that were applied before.
+///
+/// With the systemd backend, the existing mount units are started.
+/// With the `fstab` backend, they are mounted with `mount`. With the
+/// `autofs` backend, autofs is restarted.
+pub async fn start_all(mounts: &[Mount], backend: Backend) -> Result<()> {
+    match backend {
+        Backend::Systemd(_) => {
+            if mounts.is_empty() {
+                return Ok(());
+            }
+
+            if plan::is_dry_run() {
+                for mount in mounts {
+                    plan::record(Action::Mount {
+                        remote: mount.remote().to_string(),
+                        local: mount.local().to_path_buf(),
+                    });
+                }
+
+                return Ok(());
+            }
+
+            start_units(mounts).await
+        }
+        Backend::Fstab { credentials_dir, .. } => mount_all_direct(mounts, &credentials_dir).await,
+        Backend::Autofs { .. } => {
+            if mounts.is_empty() {
+                return Ok(());
+            }
+
+            if plan::is_dry_run() {
+                plan::record(Action::ReloadService {
+                    unit: "autofs.service".to_string(),
+                });
+
+                return Ok(());
+            }
+
+            // Mount points are only missing if autofs isn't running
+            run_command(Command::new("systemctl").args(["restart", "autofs.service"])).await
+                .map_err(|reason| Error::Mount { locals: locals(mounts), reason })
+        }
+    }
+}
+
+/// Remove a set of mounts from the host.
+///
+/// With the systemd backend, the mount units are stopped and their
+/// unit files are removed. With the `fstab` and `autofs` backends,
+/// they are unmounted with `umount` and removed from the managed
+/// block or the map.
+pub async fn remove_all(mounts: &[Mount], backend: Backend, overlay: &OverlayConfig) -> Result<()> {
+    match backend {
+        Backend::Systemd(unit_dir) => {
+            if mounts.is_empty() {
+                return Ok(());
+            }
+
+            let units: Vec<String> = mounts.iter().map(unit_name).collect();
+
+            for mount in mounts {
+                log::info!("Unmounting {:?}...", mount.local());
+            }
+
+            if plan::is_dry_run() {
+                for mount in mounts {
+                    plan::record(Action::Unmount {
+                        local: mount.local().to_path_buf(),
+                    });
                 }
 
-                let status = Command::new("systemctl")
-                    .args(&["start", &unit_name])
-                    .status()
-                    .await?;
+                return Ok(());
+            }
 
-                if !status.success() {
-                    return Err(Error::Mount);
+            let unmount_error = |reason| Error::Unmount { locals: locals(mounts), reason };
+
+            run_command(Command::new("systemctl").arg("stop").args(&units)).await
+                .map_err(unmount_error)?;
+
+            for unit_name in &units {
+                let path = unit_dir.join(unit_name);
+                remove_file(&path).await
+                    .context(FileSnafu { action: "remove", path })?;
+            }
+
+            // Mounts loaded from the saved state have no credentials
+            for unit_name in &units {
+                let path = credentials_path(&unit_dir, unit_name);
+                match remove_file(&path).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(e).context(FileSnafu { action: "remove", path });
+                    }
+                    _ => {}
                 }
+            }
+
+            run_command(Command::new("systemctl").arg("daemon-reload")).await
+                .map_err(unmount_error)
+        }
+        Backend::Fstab { fstab, credentials_dir } => {
+            if mounts.is_empty() {
+                return Ok(());
+            }
+
+            unmount_direct(mounts).await?;
+
+            let locals: Vec<&Path> = mounts.iter().map(Mount::local).collect();
+            write_fstab(overlay, &fstab, &[], &locals).await?;
+
+            remove_credentials(mounts, &credentials_dir).await
+        }
+        Backend::Autofs { master, map, credentials_dir } => {
+            if mounts.is_empty() {
+                return Ok(());
+            }
+
+            // Stop autofs from mounting them again first
+            let locals: Vec<&Path> = mounts.iter().map(Mount::local).collect();
+            let existing = read_managed(&map).await?;
+            write_autofs(overlay, &master, &map, update_autofs_map(&existing, &[], &locals)).await?;
+
+            unmount_direct(mounts).await?;
+
+            remove_credentials(mounts, &credentials_dir).await
+        }
+    }
+}
+
+/// Unmount the mounts that are mounted with `umount`.
+async fn unmount_direct(mounts: &[Mount]) -> Result<()> {
+    let unmounted = unmounted(mounts).await?;
+    let mounted = mounts.iter()
+        .filter(|mount| !unmounted.iter().any(|other| other.local() == mount.local()));
+
+    for mount in mounted {
+        log::info!("Unmounting {:?}...", mount.local());
+
+        if plan::is_dry_run() {
+            plan::record(Action::Unmount {
+                local: mount.local().to_path_buf(),
+            });
+            continue;
+        }
+
+        run_command(Command::new("umount").arg(mount.local())).await
+            .map_err(|reason| Error::Unmount { locals: mount.local().display().to_string(), reason })?;
+    }
+
+    Ok(())
+}
 
-                Ok(())
+/// Remove the credential files of mounts, if any.
+async fn remove_credentials(mounts: &[Mount], credentials_dir: &Path) -> Result<()> {
+    if plan::is_dry_run() {
+        return Ok(());
+    }
+
+    // Mounts loaded from the saved state have no credentials
+    for mount in mounts {
+        let path = fstab_credentials_path(credentials_dir, mount);
+        match remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).context(FileSnafu { action: "remove", path });
             }
+            _ => {}
         }
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_points() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+95 22 0:45 / /proj/my\\040project rw,relatime shared:50 - nfs ops:/proj/my\\040project rw
+";
+
+        assert_eq!(
+            vec![PathBuf::from("/"), PathBuf::from("/proj/my project")],
+            mount_points(mountinfo),
+        );
+    }
+
+    #[test]
+    fn test_apply_nfs_options() {
+        let config: NfsConfig = toml::from_str(r#"
+            version = "4.2"
+            soft = true
+            timeo = 600
+
+            [mounts."/proj/myproj"]
+            version = "3"
+            protocol = "udp"
+            read-only = true
+        "#).unwrap();
+
+        let mut users = Mount::new("ops:/users/alice".to_string(), PathBuf::from("/users/alice"));
+        users.add_option("vers=3".to_string());
+        users.add_option("hard".to_string());
+
+        let mut mounts = vec![
+            users,
+            Mount::new("ops:/proj/myproj".to_string(), PathBuf::from("/proj/myproj")),
+        ];
+        apply_nfs_options(&mut mounts, &config);
+
+        assert_eq!(&["nfsvers=4.2", "soft", "timeo=600"], mounts[0].options());
+        assert_eq!(&["nfsvers=3", "proto=udp", "soft", "timeo=600", "ro"], mounts[1].options());
+    }
+
+    #[test]
+    fn test_parse_unit() {
+        let mut mount = Mount::new("ops:/proj/my\"project".to_string(), PathBuf::from("/proj/my\"project"));
+        mount.add_option("vers=3".to_string());
+
+        let unit = unit(&mount, Path::new("/etc/systemd/system/x.credentials"));
+        assert!(unit.contains("[Unit]\nWants=network-online.target\nAfter=network-online.target\n"), "{}", unit);
+
+        let parsed = parse_unit(&unit).unwrap();
+        assert_eq!(mount.remote(), parsed.remote());
+        assert_eq!(mount.local(), parsed.local());
+        assert_eq!(&Filesystem::Nfs, parsed.filesystem());
+
+        assert!(parse_unit("[Mount]\nWhat=/dev/sdb\nWhere=\"/data\"\nType=ext4\n").is_none());
+    }
+
+    #[test]
+    fn test_update_autofs_map() {
+        let mut nfs = Mount::new("ops:/proj/myproj".to_string(), PathBuf::from("/proj/myproj"));
+        nfs.add_option("vers=3".to_string());
+        let mut smb = Mount::new("//fs/my share".to_string(), PathBuf::from("/share/my share"));
+        smb.set_filesystem(Filesystem::Smb);
+
+        let credentials = Path::new("/etc/miniond/credentials/x");
+        let map = update_autofs_map("", &[autofs_entry(&nfs, credentials), autofs_entry(&smb, credentials)], &[]);
+        assert_eq!("\
+# This file was automatically generated by miniond
+/proj/myproj -fstype=nfs,vers=3 ops:/proj/myproj
+\"/share/my share\" -fstype=cifs ://fs/my\\ share
+", map);
+
+        let users = Mount::new("ops:/users/alice".to_string(), PathBuf::from("/users/alice"));
+        let map = update_autofs_map(&map, &[autofs_entry(&users, credentials)], &[Path::new("/share/my share")]);
+        assert_eq!("\
+# This file was automatically generated by miniond
+/proj/myproj -fstype=nfs,vers=3 ops:/proj/myproj
+/users/alice -fstype=nfs ops:/users/alice
+", map);
+    }
+
+    #[test]
+    fn test_update_fstab() {
+        let section = "\
+ops:/share /share nfs _netdev 0 0
+ops:/proj/old /proj/old nfs _netdev 0 0
+";
+
+        let mut mount = Mount::new("ops:/proj/my project".to_string(), PathBuf::from("/proj/my project"));
+        mount.add_option("ro".to_string());
+        let entry = fstab_entry(&mount, Path::new("/unused"));
+        assert_eq!("ops:/proj/my\\040project /proj/my\\040project nfs ro,_netdev 0 0", entry);
+
+        assert_eq!("\
+ops:/share /share nfs _netdev 0 0
+ops:/proj/my\\040project /proj/my\\040project nfs ro,_netdev 0 0
+", update_fstab(section, &[entry], &[Path::new("/proj/old")]));
+
+        assert_eq!("", update_fstab("", &[], &[]));
+        assert_eq!("", update_fstab(section, &[], &[Path::new("/share"), Path::new("/proj/old")]));
+    }
+
+    #[tokio::test]
+    async fn test_write_fstab() {
+        let dir = tempfile::tempdir().unwrap();
+        let fstab = dir.path().join("fstab");
+        let overlay: OverlayConfig = toml::from_str("mode = \"off\"").unwrap();
+
+        // A file that isn't UTF-8 is left alone rather than mangled
+        let mut contents = b"/dev/sda1 / ext4 defaults 0 1\n".to_vec();
+        contents.extend_from_slice(b"/dev/sdb1 /data\xff ext4 defaults 0 2\n");
+        std::fs::write(&fstab, &contents).unwrap();
+        assert!(write_fstab(&overlay, &fstab, &["ops:/share /share nfs _netdev 0 0".to_string()], &[]).await.is_err());
+        assert_eq!(contents, std::fs::read(&fstab).unwrap());
+
+        std::fs::write(&fstab, "/dev/sda1 / ext4 defaults 0 1\n").unwrap();
+        write_fstab(&overlay, &fstab, &["ops:/share /share nfs _netdev 0 0".to_string()], &[]).await.unwrap();
+        assert_eq!("\
+/dev/sda1 / ext4 defaults 0 1
+# BEGIN miniond managed mounts
+ops:/share /share nfs _netdev 0 0
+# END miniond managed mounts
+", std::fs::read_to_string(&fstab).unwrap());
+
+        // The section goes away with the last entry
+        write_fstab(&overlay, &fstab, &[], &[Path::new("/share")]).await.unwrap();
+        assert_eq!("/dev/sda1 / ext4 defaults 0 1\n", std::fs::read_to_string(&fstab).unwrap());
+    }
 }
@@ -0,0 +1,446 @@
+//! Experiment interface configuration.
+//!
+//! Interfaces from the testbed are identified by MAC address, so they
+//! are first resolved to the names of the links on the node. VLAN
+//! interfaces are named after their parent (e.g., `eth1.100`).
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::fs::{create_dir_all, read_dir, read_to_string, remove_file};
+use tokio::process::Command;
+
+use crate::blocking;
+use crate::command::run_command;
+use crate::error::{Error, FileSnafu, Result};
+use crate::net::{InterfaceAddress, InterfaceConfig};
+use crate::plan::{self, Action};
+
+/// Where the links of the node are listed.
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Prefix of the systemd-networkd files we manage.
+const UNIT_PREFIX: &str = "50-miniond-";
+
+/// Maximum length of an interface name.
+const MAX_NAME_LEN: usize = 15;
+
+/// How to configure interfaces.
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// Run `ip` directly.
+    Direct,
+
+    /// Write systemd-networkd files to a directory (usually
+    /// `/etc/systemd/network`) and reload it.
+    Networkd(PathBuf),
+}
+
+/// An experiment interface, resolved to a link on the node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    /// Name of the link (e.g., `eth1` or `eth1.100`).
+    pub name: String,
+
+    /// MAC address, if the testbed assigned one.
+    pub mac: Option<String>,
+
+    /// Name of the physical link, for VLAN interfaces.
+    pub parent: Option<String>,
+
+    /// VLAN tag, for VLAN interfaces.
+    pub vlan: Option<u16>,
+
+    pub addresses: Vec<String>,
+    pub mtu: Option<u32>,
+}
+
+impl Link {
+    fn is_vlan(&self) -> bool {
+        self.vlan.is_some()
+    }
+}
+
+/// Returns the links of the node by MAC address.
+pub async fn links() -> Result<HashMap<String, String>> {
+    let mut links = HashMap::new();
+
+    let mut entries = read_dir(SYS_CLASS_NET).await
+        .context(FileSnafu { action: "read", path: SYS_CLASS_NET })?;
+
+    while let Some(entry) = entries.next_entry().await.context(FileSnafu { action: "read", path: SYS_CLASS_NET })? {
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if let Ok(address) = read_to_string(entry.path().join("address")).await {
+            if let Some(mac) = crate::net::normalize_mac(address.trim()) {
+                // VLANs share the MAC of their parent, which comes first
+                links.entry(mac).or_insert(name);
+            }
+        }
+    }
+
+    Ok(links)
+}
+
+/// Resolve experiment interfaces to links on the node.
+///
+/// Interfaces that match no link are skipped.
+pub fn resolve(interfaces: &[InterfaceConfig], links: &HashMap<String, String>) -> Vec<Link> {
+    let mut resolved = Vec::new();
+
+    for interface in interfaces {
+        let addresses = interface.addresses.iter().map(format_address).collect();
+
+        match (interface.vlan, &interface.parent) {
+            (Some(vlan), Some(parent_mac)) => {
+                let parent = match links.get(parent_mac) {
+                    Some(parent) => parent.clone(),
+                    None => {
+                        log::warn!("No link with MAC {} for VLAN {} - Skipping", parent_mac, vlan);
+                        continue;
+                    }
+                };
+
+                let mut name = format!("{}.{}", parent, vlan);
+                if name.len() > MAX_NAME_LEN {
+                    name = format!("vlan{}", vlan);
+                }
+
+                resolved.push(Link {
+                    name,
+                    mac: interface.mac.clone(),
+                    parent: Some(parent),
+                    vlan: Some(vlan),
+                    addresses,
+                    mtu: interface.mtu,
+                });
+            }
+            _ => {
+                let name = interface.mac.as_ref()
+                    .and_then(|mac| links.get(mac))
+                    .or_else(|| interface.name.as_ref().filter(|name| links.values().any(|link| link == *name)));
+
+                match name {
+                    Some(name) => resolved.push(Link {
+                        name: name.clone(),
+                        mac: interface.mac.clone(),
+                        parent: None,
+                        vlan: None,
+                        addresses,
+                        mtu: interface.mtu,
+                    }),
+                    None => {
+                        log::warn!("No link for interface {} - Skipping",
+                            interface.mac.as_deref().or(interface.name.as_deref()).unwrap_or("(unknown)"));
+                    }
+                }
+            }
+        }
+    }
+
+    resolved
+}
+
+fn format_address(address: &InterfaceAddress) -> String {
+    format!("{}/{}", address.address, address.prefix_len)
+}
+
+/// Configure a set of links, and undo the links that were configured
+/// before but are no longer wanted.
+pub async fn apply_all(links: &[Link], stale: &[Link], backend: &Backend) -> Result<()> {
+    match backend {
+        Backend::Direct => {
+            for link in stale {
+                remove_direct(link).await;
+            }
+
+            for link in links {
+                apply_direct(link).await?;
+            }
+
+            Ok(())
+        }
+        Backend::Networkd(dir) => apply_networkd(links, dir).await,
+    }
+}
+
+/// Configure a link with `ip`.
+async fn apply_direct(link: &Link) -> Result<()> {
+    log::info!("Configuring {} ({})...", link.name, link.addresses.join(", "));
+
+    if plan::is_dry_run() {
+        plan::record(Action::ConfigureInterface {
+            name: link.name.clone(),
+            addresses: link.addresses.clone(),
+        });
+
+        return Ok(());
+    }
+
+    let interface_error = |reason| Error::Interface { name: link.name.clone(), reason };
+
+    if let (Some(parent), Some(vlan)) = (&link.parent, link.vlan) {
+        let exists = Path::new(SYS_CLASS_NET).join(&link.name).exists();
+
+        if !exists {
+            let mut ip = Command::new("ip");
+            ip.args(["link", "add", "link", parent, "name", &link.name]);
+            if let Some(mac) = &link.mac {
+                ip.args(["address", mac]);
+            }
+            ip.args(["type", "vlan", "id", &vlan.to_string()]);
+
+            run_command(&mut ip).await.map_err(interface_error)?;
+        }
+
+        run_command(Command::new("ip").args(["link", "set", "dev", parent, "up"])).await
+            .map_err(interface_error)?;
+    }
+
+    if let Some(mtu) = link.mtu {
+        run_command(Command::new("ip").args(["link", "set", "dev", &link.name, "mtu", &mtu.to_string()])).await
+            .map_err(interface_error)?;
+    }
+
+    run_command(Command::new("ip").args(["link", "set", "dev", &link.name, "up"])).await
+        .map_err(interface_error)?;
+
+    for address in &link.addresses {
+        run_command(Command::new("ip").args(["addr", "replace", address, "dev", &link.name])).await
+            .map_err(interface_error)?;
+    }
+
+    Ok(())
+}
+
+/// Undo the configuration of a link with `ip`.
+///
+/// Failures are only logged, since the link may be gone already.
+async fn remove_direct(link: &Link) {
+    log::info!("Removing the configuration of {}...", link.name);
+
+    if plan::is_dry_run() {
+        plan::record(Action::RemoveInterface {
+            name: link.name.clone(),
+        });
+
+        return;
+    }
+
+    let res = if link.is_vlan() {
+        run_command(Command::new("ip").args(["link", "del", "dev", &link.name])).await
+    } else {
+        let mut res = Ok(());
+        for address in &link.addresses {
+            res = res.and(run_command(Command::new("ip").args(["addr", "del", address, "dev", &link.name])).await);
+        }
+        res
+    };
+
+    if let Err(reason) = res {
+        log::warn!("Failed to remove the configuration of {}: {}", link.name, reason);
+    }
+}
+
+/// Configure links with systemd-networkd.
+///
+/// All files are rewritten at once, and the ones for links that are
+/// gone are removed.
+async fn apply_networkd(links: &[Link], dir: &Path) -> Result<()> {
+    let units = units(links);
+
+    let mut existing = Vec::new();
+    if let Ok(mut entries) = read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(UNIT_PREFIX) {
+                existing.push(name);
+            }
+        }
+    }
+
+    let mut files = Vec::new();
+    for (name, contents) in &units {
+        let path = dir.join(name);
+        if read_to_string(&path).await.ok().as_ref() != Some(contents) {
+            files.push((path, contents.clone()));
+        }
+    }
+
+    let stale: Vec<PathBuf> = existing.iter()
+        .filter(|name| !units.contains_key(*name))
+        .map(|name| dir.join(name))
+        .collect();
+
+    if files.is_empty() && stale.is_empty() {
+        log::debug!("Interface configuration is up to date");
+        return Ok(());
+    }
+
+    if plan::is_dry_run() {
+        for (path, contents) in files {
+            plan::record(Action::WriteFile { path, contents });
+        }
+        for path in stale {
+            plan::record(Action::RemoveFile { path });
+        }
+
+        return Ok(());
+    }
+
+    create_dir_all(dir).await
+        .context(FileSnafu { action: "create", path: dir })?;
+
+    for path in stale {
+        log::info!("Removing {:?}...", path);
+        remove_file(&path).await
+            .context(FileSnafu { action: "remove", path: &path })?;
+    }
+
+    if !files.is_empty() {
+        log::info!("Writing {} systemd-networkd files...", files.len());
+        blocking::write_atomic(files.into_iter().map(|(path, contents)| (path, contents.into_bytes())).collect()).await?;
+    }
+
+    run_command(Command::new("networkctl").arg("reload")).await
+        .map_err(|reason| Error::Interface { name: "(all)".to_string(), reason })
+}
+
+/// Returns the systemd-networkd files for a set of links, by file name.
+fn units(links: &[Link]) -> BTreeMap<String, String> {
+    let mut units = BTreeMap::new();
+
+    // VLANs are attached in the .network of their parent
+    let mut vlans: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for link in links.iter().filter(|link| link.is_vlan()) {
+        if let Some(parent) = &link.parent {
+            vlans.entry(parent).or_default().push(&link.name);
+        }
+    }
+
+    for link in links {
+        let mut network = String::from("# Managed by miniond\n[Match]\n");
+        match (&link.mac, link.is_vlan()) {
+            (Some(mac), false) => network.push_str(&format!("MACAddress={}\n", mac)),
+            _ => network.push_str(&format!("Name={}\n", link.name)),
+        }
+
+        if let Some(mtu) = link.mtu {
+            network.push_str(&format!("\n[Link]\nMTUBytes={}\n", mtu));
+        }
+
+        network.push_str("\n[Network]\nLinkLocalAddressing=no\n");
+        for address in &link.addresses {
+            network.push_str(&format!("Address={}\n", address));
+        }
+        for vlan in vlans.remove(link.name.as_str()).unwrap_or_default() {
+            network.push_str(&format!("VLAN={}\n", vlan));
+        }
+
+        units.insert(format!("{}{}.network", UNIT_PREFIX, link.name), network);
+
+        if let (Some(vlan), true) = (link.vlan, link.is_vlan()) {
+            let mut netdev = format!("# Managed by miniond\n[NetDev]\nName={}\nKind=vlan\n", link.name);
+            if let Some(mac) = &link.mac {
+                netdev.push_str(&format!("MACAddress={}\n", mac));
+            }
+            if let Some(mtu) = link.mtu {
+                netdev.push_str(&format!("MTUBytes={}\n", mtu));
+            }
+            netdev.push_str(&format!("\n[VLAN]\nId={}\n", vlan));
+
+            units.insert(format!("{}{}.netdev", UNIT_PREFIX, link.name), netdev);
+        }
+    }
+
+    // Parents that are not experiment interfaces themselves
+    for (parent, names) in vlans {
+        let mut network = format!("# Managed by miniond\n[Match]\nName={}\n\n[Network]\nLinkLocalAddressing=no\n", parent);
+        for name in names {
+            network.push_str(&format!("VLAN={}\n", name));
+        }
+
+        units.insert(format!("{}{}.network", UNIT_PREFIX, parent), network);
+    }
+
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_resolve_and_units() {
+        let links: HashMap<String, String> = vec![
+            ("00:02:b3:65:1e:1d".to_string(), "eth1".to_string()),
+            ("00:02:b3:65:1e:1e".to_string(), "eth2".to_string()),
+        ].into_iter().collect();
+
+        let address = |address: &str| InterfaceAddress {
+            address: address.parse::<IpAddr>().unwrap(),
+            prefix_len: 24,
+        };
+
+        let interfaces = vec![
+            InterfaceConfig {
+                name: None,
+                mac: Some("00:02:b3:65:1e:1d".to_string()),
+                addresses: vec![address("10.10.1.1")],
+                mtu: Some(9000),
+                vlan: None,
+                parent: None,
+                lan: Some("link-0".to_string()),
+            },
+            InterfaceConfig {
+                name: None,
+                mac: Some("02:00:00:00:00:01".to_string()),
+                addresses: vec![address("10.10.2.1")],
+                mtu: None,
+                vlan: Some(100),
+                parent: Some("00:02:b3:65:1e:1e".to_string()),
+                lan: Some("lan-0".to_string()),
+            },
+            InterfaceConfig {
+                name: None,
+                mac: Some("00:02:b3:00:00:00".to_string()),
+                addresses: vec![address("10.10.3.1")],
+                mtu: None,
+                vlan: None,
+                parent: None,
+                lan: None,
+            },
+        ];
+
+        let resolved = resolve(&interfaces, &links);
+        assert_eq!(2, resolved.len());
+        assert_eq!("eth1", resolved[0].name);
+        assert_eq!(vec!["10.10.1.1/24".to_string()], resolved[0].addresses);
+        assert_eq!("eth2.100", resolved[1].name);
+        assert_eq!(Some("eth2"), resolved[1].parent.as_deref());
+
+        let units = units(&resolved);
+        assert_eq!(
+            vec!["50-miniond-eth1.network", "50-miniond-eth2.100.netdev", "50-miniond-eth2.100.network", "50-miniond-eth2.network"],
+            units.keys().collect::<Vec<_>>(),
+        );
+        assert_eq!("\
+# Managed by miniond
+[Match]
+MACAddress=00:02:b3:65:1e:1d
+
+[Link]
+MTUBytes=9000
+
+[Network]
+LinkLocalAddressing=no
+Address=10.10.1.1/24
+", units["50-miniond-eth1.network"]);
+        assert!(units["50-miniond-eth2.network"].contains("VLAN=eth2.100\n"));
+        assert!(units["50-miniond-eth2.100.netdev"].contains("Kind=vlan\nMACAddress=02:00:00:00:00:01\n\n[VLAN]\nId=100\n"));
+    }
+}
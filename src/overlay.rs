@@ -0,0 +1,495 @@
+//! Writable overlays for read-only system directories.
+//!
+//! Some images (e.g., NixOS, appliance-style distributions) ship
+//! with a read-only `/etc`. To still be able to manage files like
+//! `/etc/hosts` or systemd units there, we redirect them to an
+//! alternate writable location and make the original path point
+//! to it:
+//!
+//! - Files are seeded with their original content and bind-mounted
+//!   (or symlinked) over the original path.
+//! - Directories get an overlayfs mount with the original directory
+//!   as the lower layer.
+//!
+//! Callers obtain the path to write to from [`writable_file`] or
+//! [`writable_dir`], or replace a file through [`write_file`].
+
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use nix::mount::{mount, MsFlags};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use serde::Deserialize;
+use snafu::ResultExt;
+use tokio::fs;
+
+use crate::blocking;
+use crate::error::{Error, FileSnafu, Result};
+use crate::plan::{self, Action};
+
+/// Overlay configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OverlayConfig {
+    /// When to redirect managed paths.
+    mode: OverlayMode,
+
+    /// How to make the original path point to the alternate location.
+    method: OverlayMethod,
+
+    /// Writable directory holding the alternate locations.
+    ///
+    /// A managed path `/etc/hosts` is redirected to `$dir/etc/hosts`.
+    dir: PathBuf,
+}
+
+impl Default for OverlayConfig {
+    fn default() -> Self {
+        Self {
+            mode: OverlayMode::Auto,
+            method: OverlayMethod::Bind,
+            dir: PathBuf::from("/run/miniond/overlay"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum OverlayMode {
+    /// Never redirect paths.
+    #[serde(rename = "off")]
+    Off,
+
+    /// Redirect paths that reside on a read-only filesystem.
+    #[serde(rename = "auto")]
+    Auto,
+
+    /// Always redirect paths.
+    #[serde(rename = "always")]
+    Always,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum OverlayMethod {
+    /// Bind-mount files and overlay-mount directories.
+    #[serde(rename = "bind")]
+    Bind,
+
+    /// Replace the original path with a symlink.
+    ///
+    /// With a read-only root, the symlinks must be created in the
+    /// image beforehand.
+    #[serde(rename = "symlink")]
+    Symlink,
+}
+
+/// Returns the path that should be written to in order to update the file at `path`.
+///
+/// If `path` does not need to be redirected, it's returned as-is.
+pub async fn writable_file(config: &OverlayConfig, path: &Path) -> Result<PathBuf> {
+    if !needs_redirection(config, path) {
+        return Ok(path.to_path_buf());
+    }
+
+    let alternate = alternate_path(config, path);
+
+    match config.method {
+        OverlayMethod::Bind => {
+            bind_file(path, &alternate).await?;
+        }
+        OverlayMethod::Symlink => {
+            symlink(path, &alternate, false).await?;
+        }
+    }
+
+    Ok(alternate)
+}
+
+/// Replaces the contents of the file at `path`.
+///
+/// The file is replaced atomically, unless it's bind-mounted (e.g., by
+/// us or by a container runtime). The bind mount refers to the inode
+/// of the file, so it must be rewritten in place. Symlinks at `path`
+/// are followed, so the file they point to is replaced instead.
+pub async fn write_file(config: &OverlayConfig, path: &Path, contents: Vec<u8>) -> Result<()> {
+    // Replace the file a symlink points to, not the symlink
+    let path = resolve_symlinks(config, path).await?;
+    let target = writable_file(config, &path).await?;
+
+    if is_mounted(&path).await {
+        blocking::write(target, contents).await
+    } else {
+        blocking::write_atomic(vec![(target, contents)]).await
+    }
+}
+
+/// Replaces the contents of the file at `path` if they differ.
+///
+/// Missing parent directories are created. Returns whether the file
+/// was written. In dry-run mode, the write is only recorded.
+pub async fn update_file(config: &OverlayConfig, path: &Path, contents: String) -> Result<bool> {
+    match fs::read_to_string(path).await {
+        Ok(existing) if existing == contents => return Ok(false),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(FileSnafu { action: "read", path }),
+    }
+
+    log::info!("Updating {:?}...", path);
+
+    if plan::is_dry_run() {
+        plan::record(Action::WriteFile {
+            path: path.to_path_buf(),
+            contents,
+        });
+    } else {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await
+                .context(FileSnafu { action: "create", path: parent })?;
+        }
+
+        write_file(config, path, contents.into_bytes()).await?;
+    }
+
+    Ok(true)
+}
+
+/// Returns the marker comments delimiting a named section.
+fn section_markers(name: &str) -> (String, String) {
+    (format!("# BEGIN miniond {}", name), format!("# END miniond {}", name))
+}
+
+/// Reads the file at `path`, which may not exist yet.
+///
+/// Files that aren't valid UTF-8 are refused rather than mangled.
+async fn read_existing(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path).await {
+        Ok(s) => Ok(Some(s)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context(FileSnafu { action: "read", path }),
+    }
+}
+
+/// Returns the contents of a named section of the file at `path`, as
+/// written by [`update_section`].
+///
+/// The section is empty if the file or the section doesn't exist.
+pub async fn read_section(path: &Path, name: &str) -> Result<String> {
+    let (begin, end) = section_markers(name);
+    let existing = read_existing(path).await?.unwrap_or_default();
+
+    let mut section = String::new();
+    let mut in_section = false;
+
+    for line in existing.lines() {
+        if !in_section {
+            in_section = line == begin;
+        } else if line == end {
+            break;
+        } else {
+            section.push_str(line);
+            section.push('\n');
+        }
+    }
+
+    Ok(section)
+}
+
+/// Replaces a named section of the file at `path`.
+///
+/// The section is delimited by marker comments, so several sections
+/// can share a file with content we don't manage. A new section is
+/// appended to the end of the file, and an empty one is removed.
+pub async fn update_section(config: &OverlayConfig, path: &Path, name: &str, section: &str) -> Result<bool> {
+    let (begin, end) = section_markers(name);
+
+    let existing = match read_existing(path).await? {
+        Some(existing) => existing,
+        None if section.is_empty() => return Ok(false),
+        None => String::new(),
+    };
+
+    let new_section = if section.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n{}{}\n", begin, section, end)
+    };
+
+    let mut contents = String::new();
+    let mut in_section = false;
+    let mut replaced = false;
+
+    for line in existing.lines() {
+        if line == begin {
+            in_section = true;
+        } else if in_section {
+            if line == end {
+                in_section = false;
+
+                if !replaced {
+                    contents.push_str(&new_section);
+                    replaced = true;
+                }
+            }
+        } else {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+    }
+
+    if !replaced {
+        contents.push_str(&new_section);
+    }
+
+    update_file(config, path, contents).await
+}
+
+/// Returns the path that should be written to in order to update files in the directory `path`.
+///
+/// If `path` does not need to be redirected, it's returned as-is.
+pub async fn writable_dir(config: &OverlayConfig, path: &Path) -> Result<PathBuf> {
+    if !needs_redirection(config, path) {
+        return Ok(path.to_path_buf());
+    }
+
+    let alternate = alternate_path(config, path);
+
+    match config.method {
+        OverlayMethod::Bind => {
+            overlay_dir(path, &alternate).await?;
+
+            // The original path is writable now
+            Ok(path.to_path_buf())
+        }
+        OverlayMethod::Symlink => {
+            symlink(path, &alternate, true).await?;
+            Ok(alternate)
+        }
+    }
+}
+
+fn alternate_path(config: &OverlayConfig, path: &Path) -> PathBuf {
+    config.dir.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn needs_redirection(config: &OverlayConfig, path: &Path) -> bool {
+    match config.mode {
+        OverlayMode::Off => false,
+        OverlayMode::Always => true,
+        OverlayMode::Auto if is_redirected(config, path) => true,
+        OverlayMode::Auto => {
+            // Check the closest existing ancestor
+            let existing = path.ancestors().find(|p| p.exists());
+
+            match existing.map(statvfs) {
+                Some(Ok(stat)) => stat.flags().contains(FsFlags::ST_RDONLY),
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Returns whether `path` is already a symlink to its alternate location.
+///
+/// The filesystem of the alternate location is writable, so its
+/// read-only original can't be told apart by checking the filesystem.
+fn is_redirected(config: &OverlayConfig, path: &Path) -> bool {
+    match std::fs::read_link(path) {
+        Ok(target) => target == alternate_path(config, path),
+        Err(_) => false,
+    }
+}
+
+/// Returns the path that symlinks at `path` finally point to.
+///
+/// Our own symlinks to alternate locations are kept, since writes
+/// through them are redirected anyway.
+async fn resolve_symlinks(config: &OverlayConfig, path: &Path) -> Result<PathBuf> {
+    // Like the kernel's limit on nested symlinks
+    const MAX_SYMLINKS: usize = 40;
+
+    let mut path = path.to_path_buf();
+
+    for _ in 0..MAX_SYMLINKS {
+        if config.mode != OverlayMode::Off && is_redirected(config, &path) {
+            return Ok(path);
+        }
+
+        match fs::read_link(&path).await {
+            Ok(target) => {
+                // Relative targets are relative to the symlink
+                path = match path.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::InvalidInput => {
+                return Ok(path);
+            }
+            Err(e) => return Err(e).context(FileSnafu { action: "read", path }),
+        }
+    }
+
+    Err(std::io::Error::from_raw_os_error(nix::libc::ELOOP))
+        .context(FileSnafu { action: "read", path })
+}
+
+/// Bind-mount a seeded copy of a file over the original.
+async fn bind_file(path: &Path, alternate: &Path) -> Result<()> {
+    if is_mounted(path).await {
+        return Ok(());
+    }
+
+    if let Some(parent) = alternate.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    if !alternate.exists() {
+        match fs::copy(path, alternate).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                fs::write(alternate, "").await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if !path.exists() {
+        // We can't create a mountpoint on a read-only filesystem
+        return Err(Error::OverlayUnavailable { path: path.to_path_buf() });
+    }
+
+    log::info!("Bind-mounting {:?} over read-only {:?}...", alternate, path);
+
+    mount(Some(alternate), path, None::<&str>, MsFlags::MS_BIND, None::<&str>)?;
+
+    Ok(())
+}
+
+/// Mount an overlayfs with the original directory as the lower layer.
+async fn overlay_dir(path: &Path, alternate: &Path) -> Result<()> {
+    if is_mounted(path).await {
+        return Ok(());
+    }
+
+    if !path.is_dir() {
+        return Err(Error::OverlayUnavailable { path: path.to_path_buf() });
+    }
+
+    let upper = alternate.join("upper");
+    let work = alternate.join("work");
+
+    fs::create_dir_all(&upper).await?;
+    fs::create_dir_all(&work).await?;
+
+    log::info!("Mounting writable overlay over read-only {:?}...", path);
+
+    let options = format!("lowerdir={},upperdir={},workdir={}",
+        path.display(), upper.display(), work.display());
+
+    mount(Some("overlay"), path, Some("overlay"), MsFlags::empty(), Some(options.as_str()))?;
+
+    Ok(())
+}
+
+/// Make sure the original path is a symlink to the alternate location.
+async fn symlink(path: &Path, alternate: &Path, is_dir: bool) -> Result<()> {
+    if let Ok(target) = fs::read_link(path).await {
+        if target == alternate {
+            if is_dir {
+                fs::create_dir_all(alternate).await?;
+            }
+            return Ok(());
+        }
+    }
+
+    if let Some(parent) = alternate.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    if is_dir {
+        // We won't throw away an existing directory
+        if path.exists() {
+            log::error!("{:?} must be a symlink to {:?} in the image", path, alternate);
+            return Err(Error::OverlayUnavailable { path: path.to_path_buf() });
+        }
+
+        fs::create_dir_all(alternate).await?;
+    } else if !alternate.exists() && path.exists() {
+        fs::copy(path, alternate).await?;
+    }
+
+    log::info!("Replacing {:?} with a symlink to {:?}...", path, alternate);
+
+    match fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(_) => return Err(Error::OverlayUnavailable { path: path.to_path_buf() }),
+    }
+
+    fs::symlink(alternate, path).await
+        .map_err(|_| Error::OverlayUnavailable { path: path.to_path_buf() })?;
+
+    Ok(())
+}
+
+/// Returns whether a path is a mountpoint.
+async fn is_mounted(path: &Path) -> bool {
+    let mountinfo = match fs::read_to_string("/proc/self/mountinfo").await {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+
+    let mountpoints: HashSet<&str> = mountinfo.lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .collect();
+
+    path.to_str().map(|p| mountpoints.contains(p)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mode: OverlayMode, dir: &Path) -> OverlayConfig {
+        OverlayConfig {
+            mode,
+            method: OverlayMethod::Symlink,
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_file_redirected() {
+        let dir = tempfile::tempdir().unwrap();
+        let overlay_dir = dir.path().join("overlay");
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, "original\n").unwrap();
+
+        write_file(&config(OverlayMode::Always, &overlay_dir), &path, b"first\n".to_vec()).await.unwrap();
+
+        let alternate = std::fs::read_link(&path).unwrap();
+        assert!(alternate.starts_with(&overlay_dir), "{:?}", alternate);
+        assert_eq!("first\n", std::fs::read_to_string(&alternate).unwrap());
+
+        // The alternate location is writable, but the symlink is kept
+        write_file(&config(OverlayMode::Auto, &overlay_dir), &path, b"second\n".to_vec()).await.unwrap();
+
+        assert_eq!(alternate, std::fs::read_link(&path).unwrap());
+        assert_eq!("second\n", std::fs::read_to_string(&alternate).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_write_file_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("resolv.conf.real");
+        let path = dir.path().join("resolv.conf");
+        std::fs::write(&target, "original\n").unwrap();
+        std::os::unix::fs::symlink("resolv.conf.real", &path).unwrap();
+
+        write_file(&config(OverlayMode::Off, dir.path()), &path, b"updated\n".to_vec()).await.unwrap();
+
+        assert_eq!(PathBuf::from("resolv.conf.real"), std::fs::read_link(&path).unwrap());
+        assert_eq!("updated\n", std::fs::read_to_string(&target).unwrap());
+    }
+}
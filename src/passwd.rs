@@ -0,0 +1,513 @@
+//! Local account databases.
+//!
+//! The `files` account backend edits `/etc/passwd`, `/etc/shadow`,
+//! `/etc/group` and `/etc/gshadow` directly instead of running
+//! shadow-utils, which minimal images (e.g., containers) may lack.
+//! Nothing goes through NSS, so only local accounts are seen.
+//!
+//! Edits hold the same lock as `lckpwdf(3)`, so they never interleave
+//! with shadow-utils, and the files are replaced atomically. Lines we
+//! don't touch (e.g., comments and NIS entries) are kept as they are.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::libc;
+use once_cell::sync::Lazy;
+use snafu::ResultExt;
+
+use crate::blocking;
+use crate::error::{FileSnafu, Result};
+
+/// Lock file of `lckpwdf(3)`, in the database directory.
+const LOCK_FILE: &str = ".pwd.lock";
+
+/// How long to wait for the lock, like `lckpwdf(3)`.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How often to retry taking the lock.
+const LOCK_RETRY: Duration = Duration::from_millis(100);
+
+/// Record locks belong to the process, so edits from this process are
+/// serialized here.
+static EDITS: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// A user as found in `/etc/passwd`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passwd {
+    pub uid: u32,
+    pub shell: PathBuf,
+}
+
+/// A file of colon-separated entries, keyed by their first field.
+#[derive(Debug, Clone, Default)]
+struct Table {
+    lines: Vec<String>,
+    changed: bool,
+}
+
+impl Table {
+    fn parse(contents: &str) -> Self {
+        Self {
+            lines: contents.lines().map(str::to_string).collect(),
+            changed: false,
+        }
+    }
+
+    fn render(&self) -> String {
+        self.lines.iter()
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+
+    /// Returns the fields of all entries.
+    fn entries(&self) -> impl Iterator<Item = Vec<&str>> {
+        self.lines.iter()
+            .filter(|line| is_entry(line))
+            .map(|line| line.split(':').collect())
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.lines.iter()
+            .position(|line| is_entry(line) && line.split(':').next() == Some(name))
+    }
+
+    fn get(&self, name: &str) -> Option<Vec<&str>> {
+        self.position(name)
+            .map(|i| self.lines[i].split(':').collect())
+    }
+
+    /// Set a field of an entry, if it exists.
+    fn set(&mut self, name: &str, index: usize, value: &str) {
+        let i = match self.position(name) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let mut fields: Vec<&str> = self.lines[i].split(':').collect();
+        if fields.get(index) == Some(&value) {
+            return;
+        }
+
+        if fields.len() <= index {
+            fields.resize(index + 1, "");
+        }
+        fields[index] = value;
+
+        self.lines[i] = fields.join(":");
+        self.changed = true;
+    }
+
+    fn push(&mut self, fields: &[&str]) {
+        self.lines.push(fields.join(":"));
+        self.changed = true;
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        match self.position(name) {
+            Some(i) => {
+                self.lines.remove(i);
+                self.changed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add or remove a member from the member list (the fourth field)
+    /// of each group entry, as `groups` says.
+    fn set_memberships(&mut self, login: &str, groups: &[String]) {
+        let names: Vec<String> = self.entries()
+            .map(|fields| fields[0].to_string())
+            .collect();
+
+        for name in names {
+            let fields = self.get(&name).unwrap();
+            let mut members: Vec<&str> = fields.get(3)
+                .map(|members| members.split(',').filter(|m| !m.is_empty()).collect())
+                .unwrap_or_default();
+
+            let is_member = members.contains(&login);
+            let should_be = groups.contains(&name);
+
+            if is_member == should_be {
+                continue;
+            }
+
+            if should_be {
+                members.push(login);
+            } else {
+                members.retain(|m| *m != login);
+            }
+
+            let members = members.join(",");
+            self.set(&name, 3, &members);
+        }
+    }
+}
+
+/// The account databases of a system.
+#[derive(Debug)]
+pub struct Database {
+    dir: PathBuf,
+    passwd: Table,
+    group: Table,
+
+    /// Systems without shadow passwords keep hashes in `passwd`.
+    shadow: Option<Table>,
+    gshadow: Option<Table>,
+}
+
+impl Database {
+    /// Read the databases in a directory (usually `/etc`).
+    ///
+    /// Files are only ever replaced atomically, so this doesn't need
+    /// the lock.
+    pub async fn read(dir: &Path) -> Result<Self> {
+        let dir = dir.to_path_buf();
+        blocking::run("passwd-read", move || Self::load(&dir)).await
+    }
+
+    /// Edit the databases in a directory under the lock.
+    ///
+    /// Changed files are replaced once `f` returns successfully.
+    pub async fn edit<F, T>(dir: &Path, f: F) -> Result<T>
+        where F: FnOnce(&mut Self) -> Result<T> + Send + 'static,
+              T: Send + 'static,
+    {
+        let dir = dir.to_path_buf();
+        blocking::run("passwd-edit", move || {
+            let _guard = EDITS.lock().unwrap();
+            let _lock = lock(&dir)?;
+
+            let mut database = Self::load(&dir)?;
+            let result = f(&mut database)?;
+            database.save()?;
+
+            Ok(result)
+        }).await
+    }
+
+    fn load(dir: &Path) -> Result<Self> {
+        let read = |name: &str| -> Result<Option<Table>> {
+            let path = dir.join(name);
+            match fs::read_to_string(&path) {
+                Ok(contents) => Ok(Some(Table::parse(&contents))),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e).context(FileSnafu { action: "read", path }),
+            }
+        };
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            passwd: read("passwd")?.unwrap_or_default(),
+            group: read("group")?.unwrap_or_default(),
+            shadow: read("shadow")?,
+            gshadow: read("gshadow")?,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let tables = [
+            ("passwd", Some(&self.passwd)),
+            ("group", Some(&self.group)),
+            ("shadow", self.shadow.as_ref()),
+            ("gshadow", self.gshadow.as_ref()),
+        ];
+
+        let files: Vec<(PathBuf, Vec<u8>)> = tables.iter()
+            .filter_map(|(name, table)| {
+                let table = table.filter(|table| table.changed)?;
+                Some((self.dir.join(name), table.render().into_bytes()))
+            })
+            .collect();
+
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        blocking::replace(&files)
+    }
+
+    /// Returns a user by login.
+    pub fn user(&self, login: &str) -> Option<Passwd> {
+        let fields = self.passwd.get(login)?;
+
+        Some(Passwd {
+            uid: fields.get(2)?.parse().ok()?,
+            shell: PathBuf::from(fields.get(6)?),
+        })
+    }
+
+    /// Returns the home directory of a user by login.
+    pub fn home(&self, login: &str) -> Option<PathBuf> {
+        self.passwd.get(login)?.get(5).map(PathBuf::from)
+    }
+
+    /// Returns the login of a user by UID.
+    pub fn login_by_uid(&self, uid: u32) -> Option<String> {
+        self.passwd.entries()
+            .find(|fields| fields.get(2).and_then(|f| f.parse().ok()) == Some(uid))
+            .map(|fields| fields[0].to_string())
+    }
+
+    /// Returns the GID of a group by name.
+    pub fn gid(&self, name: &str) -> Option<u32> {
+        self.group.get(name)?.get(2)?.parse().ok()
+    }
+
+    /// Returns the name of a group by GID.
+    pub fn group_name(&self, gid: u32) -> Option<String> {
+        self.group.entries()
+            .find(|fields| fields.get(2).and_then(|f| f.parse().ok()) == Some(gid))
+            .map(|fields| fields[0].to_string())
+    }
+
+    /// Returns the names of the supplementary groups of a user.
+    pub fn groups_of(&self, login: &str) -> Vec<String> {
+        self.group.entries()
+            .filter(|fields| fields.get(3).is_some_and(|members| members.split(',').any(|m| m == login)))
+            .map(|fields| fields[0].to_string())
+            .collect()
+    }
+
+    /// Add a user without a usable password.
+    pub fn add_user(&mut self, login: &str, uid: u32, gid: u32, home: &Path, shell: &Path) -> std::result::Result<(), String> {
+        check_name(login)?;
+        let home = home.to_str().ok_or("home directory is not UTF-8")?;
+        let shell = shell.to_str().ok_or("shell is not UTF-8")?;
+        check_field(home)?;
+        check_field(shell)?;
+
+        if self.passwd.position(login).is_some() {
+            return Err("user already exists".to_string());
+        }
+
+        let (uid, gid) = (uid.to_string(), gid.to_string());
+
+        // sshd without PAM refuses key logins to users locked with "!"
+        match &mut self.shadow {
+            Some(shadow) => {
+                shadow.remove(login);
+                shadow.push(&[login, "*", &days_since_epoch(), "0", "99999", "7", "", "", ""]);
+                self.passwd.push(&[login, "x", &uid, &gid, "", home, shell]);
+            }
+            None => {
+                self.passwd.push(&[login, "*", &uid, &gid, "", home, shell]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set the login shell of a user.
+    pub fn set_shell(&mut self, login: &str, shell: &Path) -> std::result::Result<(), String> {
+        let shell = shell.to_str().ok_or("shell is not UTF-8")?;
+        check_field(shell)?;
+
+        self.passwd.set(login, 6, shell);
+        Ok(())
+    }
+
+    /// Set the supplementary groups of a user, by name.
+    pub fn set_groups(&mut self, login: &str, groups: &[String]) {
+        self.group.set_memberships(login, groups);
+        if let Some(gshadow) = &mut self.gshadow {
+            gshadow.set_memberships(login, groups);
+        }
+    }
+
+    /// Set the password hash of a user.
+    pub fn set_password(&mut self, login: &str, hash: &str) -> std::result::Result<(), String> {
+        // The hash must not end up in errors
+        if hash.is_empty() || hash.contains([':', '\n']) {
+            return Err("invalid password hash".to_string());
+        }
+
+        match &mut self.shadow {
+            Some(shadow) => {
+                if shadow.get(login).map(|fields| fields.get(1) == Some(&hash)) == Some(true) {
+                    return Ok(());
+                }
+
+                shadow.set(login, 1, hash);
+                shadow.set(login, 2, &days_since_epoch());
+            }
+            None => self.passwd.set(login, 1, hash),
+        }
+
+        Ok(())
+    }
+
+    /// Remove a user and their group memberships.
+    pub fn remove_user(&mut self, login: &str) -> std::result::Result<(), String> {
+        if !self.passwd.remove(login) {
+            return Err("no such user".to_string());
+        }
+
+        if let Some(shadow) = &mut self.shadow {
+            shadow.remove(login);
+        }
+
+        self.set_groups(login, &[]);
+        Ok(())
+    }
+
+    /// Add a group without members.
+    pub fn add_group(&mut self, name: &str, gid: u32) -> std::result::Result<(), String> {
+        check_name(name)?;
+
+        if self.group.position(name).is_some() {
+            return Err("group already exists".to_string());
+        }
+
+        self.group.push(&[name, "x", &gid.to_string(), ""]);
+        if let Some(gshadow) = &mut self.gshadow {
+            gshadow.remove(name);
+            gshadow.push(&[name, "!", "", ""]);
+        }
+
+        Ok(())
+    }
+
+    /// Remove a group.
+    pub fn remove_group(&mut self, name: &str) -> std::result::Result<(), String> {
+        if !self.group.remove(name) {
+            return Err("no such group".to_string());
+        }
+
+        if let Some(gshadow) = &mut self.gshadow {
+            gshadow.remove(name);
+        }
+
+        Ok(())
+    }
+}
+
+/// Take the lock of `lckpwdf(3)` on the databases in a directory.
+///
+/// It's released when the file is closed.
+fn lock(dir: &Path) -> Result<File> {
+    let path = dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(&path)
+        .context(FileSnafu { action: "open", path: &path })?;
+
+    let flock = libc::flock {
+        l_type: libc::F_WRLCK as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
+    };
+
+    let start = Instant::now();
+    loop {
+        match fcntl(file.as_raw_fd(), FcntlArg::F_SETLK(&flock)) {
+            Ok(_) => return Ok(file),
+            Err(Errno::EACCES) | Err(Errno::EAGAIN) if start.elapsed() < LOCK_TIMEOUT => {
+                std::thread::sleep(LOCK_RETRY);
+            }
+            Err(Errno::EACCES) | Err(Errno::EAGAIN) => {
+                return Err(io::Error::new(ErrorKind::TimedOut, "held by another process"))
+                    .context(FileSnafu { action: "lock", path: &path });
+            }
+            Err(e) => {
+                return Err(io::Error::from(e))
+                    .context(FileSnafu { action: "lock", path: &path });
+            }
+        }
+    }
+}
+
+fn is_entry(line: &str) -> bool {
+    // NIS compat entries start with + or -
+    !line.is_empty() && !line.starts_with('#') && !line.starts_with('+') && !line.starts_with('-')
+}
+
+/// Make sure a value can't break the entry it goes into.
+fn check_field(value: &str) -> std::result::Result<(), String> {
+    if value.is_empty() || value.contains([':', '\n']) {
+        return Err(format!("invalid field {:?}", value));
+    }
+
+    Ok(())
+}
+
+/// Make sure a name can't break the entry or member lists it goes
+/// into.
+fn check_name(name: &str) -> std::result::Result<(), String> {
+    if name.contains(',') {
+        return Err(format!("invalid name {:?}", name));
+    }
+
+    check_field(name)
+}
+
+/// Returns the date of the last password change in `/etc/shadow`.
+fn days_since_epoch() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    (secs / 86400).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("passwd"), "root:x:0:0:root:/root:/bin/bash\n+@netgroup\n").unwrap();
+        fs::write(dir.path().join("shadow"), "root:*:19000:0:99999:7:::\n").unwrap();
+        fs::write(dir.path().join("group"), "root:x:0:\nwheel:x:10:root\n").unwrap();
+
+        Database::edit(dir.path(), |db| {
+            db.add_group("proj", 6000).unwrap();
+            db.add_user("alice", 20001, 6000, Path::new("/users/alice"), Path::new("/bin/bash")).unwrap();
+            db.set_groups("alice", &["wheel".to_string(), "proj".to_string()]);
+            db.set_password("alice", "$6$salt$hash").unwrap();
+            assert!(db.add_user("bob:0", 20002, 6000, Path::new("/users/bob"), Path::new("/bin/bash")).is_err());
+            Ok(())
+        }).await.unwrap();
+
+        let db = Database::read(dir.path()).await.unwrap();
+        assert_eq!(Some(Passwd { uid: 20001, shell: PathBuf::from("/bin/bash") }), db.user("alice"));
+        assert_eq!(Some("alice".to_string()), db.login_by_uid(20001));
+        assert_eq!(Some(6000), db.gid("proj"));
+        assert_eq!(vec!["wheel", "proj"], db.groups_of("alice"));
+
+        let passwd = fs::read_to_string(dir.path().join("passwd")).unwrap();
+        assert_eq!("root:x:0:0:root:/root:/bin/bash\n+@netgroup\nalice:x:20001:6000::/users/alice:/bin/bash\n", passwd);
+
+        let shadow = fs::read_to_string(dir.path().join("shadow")).unwrap();
+        assert!(shadow.contains("\nalice:$6$salt$hash:"), "{}", shadow);
+
+        let group = fs::read_to_string(dir.path().join("group")).unwrap();
+        assert_eq!("root:x:0:\nwheel:x:10:root,alice\nproj:x:6000:alice\n", group);
+
+        Database::edit(dir.path(), |db| {
+            db.set_groups("alice", &["proj".to_string()]);
+            db.remove_user("alice").unwrap();
+            db.remove_group("proj").unwrap();
+            Ok(())
+        }).await.unwrap();
+
+        assert_eq!("root:x:0:0:root:/root:/bin/bash\n+@netgroup\n", fs::read_to_string(dir.path().join("passwd")).unwrap());
+        assert_eq!("root:*:19000:0:99999:7:::\n", fs::read_to_string(dir.path().join("shadow")).unwrap());
+        assert_eq!("root:x:0:\nwheel:x:10:root\n", fs::read_to_string(dir.path().join("group")).unwrap());
+    }
+}
@@ -0,0 +1,313 @@
+//! Dry-run support.
+//!
+//! In dry-run mode, changes to the system are not applied. Instead,
+//! the actions that would have been taken are recorded, so they can
+//! be reviewed once the pipeline finishes. Read-only operations like
+//! NSS lookups still happen, so the plan reflects the current system.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::account::{Uid, Gid};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+static ACTIONS: Lazy<Mutex<Vec<Action>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// An action that changes the system.
+///
+/// In JSON, the type of the action is in the `action` field
+/// (e.g., `{"action": "Mount", "remote": ..., "local": ...}`).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action")]
+pub enum Action {
+    CreateGroup {
+        name: String,
+        gid: Gid,
+    },
+
+    CreateUser {
+        login: String,
+        uid: Uid,
+        gid: Gid,
+        home: PathBuf,
+        shell: PathBuf,
+        groups: Vec<String>,
+    },
+
+    ModifyUser {
+        login: String,
+        shell: PathBuf,
+        groups: Vec<String>,
+    },
+
+    SetPassword {
+        login: String,
+    },
+
+    SetOwner {
+        path: PathBuf,
+        uid: Uid,
+        gid: Gid,
+    },
+
+    SetMode {
+        path: PathBuf,
+        mode: u32,
+    },
+
+    SetAcl {
+        path: PathBuf,
+        acl: String,
+    },
+
+    SetQuota {
+        login: String,
+        filesystem: PathBuf,
+        block_soft: u64,
+        block_hard: u64,
+        inode_soft: u64,
+        inode_hard: u64,
+    },
+
+    SetProjectQuota {
+        path: PathBuf,
+        project: u32,
+        filesystem: PathBuf,
+        block_soft: u64,
+        block_hard: u64,
+        inode_soft: u64,
+        inode_hard: u64,
+    },
+
+    RemoveUser {
+        login: String,
+        home: bool,
+    },
+
+    RemoveGroup {
+        name: String,
+    },
+
+    WriteFile {
+        path: PathBuf,
+        contents: String,
+    },
+
+    Mount {
+        remote: String,
+        local: PathBuf,
+    },
+
+    Unmount {
+        local: PathBuf,
+    },
+
+    FormatDisk {
+        device: PathBuf,
+        fs_type: String,
+    },
+
+    ConfigureInterface {
+        name: String,
+        addresses: Vec<String>,
+    },
+
+    RemoveInterface {
+        name: String,
+    },
+
+    RemoveFile {
+        path: PathBuf,
+    },
+
+    StopPrograms,
+
+    SetHostname {
+        hostname: String,
+    },
+
+    ReloadService {
+        unit: String,
+    },
+
+    SetTimezone {
+        timezone: String,
+    },
+
+    SetLocale {
+        locale: String,
+    },
+
+    StepClock {
+        timestamp: u64,
+    },
+
+    InstallTarball {
+        source: String,
+        dir: PathBuf,
+    },
+
+    InstallRpm {
+        source: String,
+    },
+
+    RunStartup {
+        user: String,
+        command: String,
+    },
+
+    RunHook {
+        message: String,
+        program: PathBuf,
+    },
+
+    SendWebhook {
+        event: String,
+        url: String,
+    },
+
+    Reboot,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CreateGroup { name, gid } => {
+                write!(f, "create group {} (GID {})", name, gid)
+            }
+            Self::CreateUser { login, uid, gid, home, shell, groups } => {
+                write!(f, "create user {} (UID {}, GID {}, home {:?}, shell {:?}, groups [{}])",
+                    login, uid, gid, home, shell, groups.join(","))
+            }
+            Self::ModifyUser { login, shell, groups } => {
+                write!(f, "modify user {} (shell {:?}, groups [{}])", login, shell, groups.join(","))
+            }
+            Self::SetPassword { login } => {
+                write!(f, "set password of {}", login)
+            }
+            Self::SetOwner { path, uid, gid } => {
+                write!(f, "recursively change owner of {:?} to {}:{}", path, uid, gid)
+            }
+            Self::SetMode { path, mode } => {
+                write!(f, "change mode of {:?} to {:o}", path, mode)
+            }
+            Self::SetAcl { path, acl } => {
+                write!(f, "set ACL {} on {:?}", acl, path)
+            }
+            Self::SetQuota { login, filesystem, block_soft, block_hard, inode_soft, inode_hard } => {
+                write!(f, "set quota of {} on {:?} (blocks {}/{} KiB, inodes {}/{})",
+                    login, filesystem, block_soft, block_hard, inode_soft, inode_hard)
+            }
+            Self::SetProjectQuota { path, project, filesystem, block_soft, block_hard, inode_soft, inode_hard } => {
+                write!(f, "set project quota {} of {:?} on {:?} (blocks {}/{} KiB, inodes {}/{})",
+                    project, path, filesystem, block_soft, block_hard, inode_soft, inode_hard)
+            }
+            Self::RemoveUser { login, home } => {
+                if *home {
+                    write!(f, "remove user {} and their home directory", login)
+                } else {
+                    write!(f, "remove user {}", login)
+                }
+            }
+            Self::RemoveGroup { name } => {
+                write!(f, "remove group {}", name)
+            }
+            Self::WriteFile { path, contents } => {
+                write!(f, "write {:?} ({} bytes)", path, contents.len())
+            }
+            Self::Mount { remote, local } => {
+                write!(f, "mount {} on {:?}", remote, local)
+            }
+            Self::Unmount { local } => {
+                write!(f, "unmount {:?}", local)
+            }
+            Self::FormatDisk { device, fs_type } => {
+                write!(f, "create a {} file system on {:?}", fs_type, device)
+            }
+            Self::ConfigureInterface { name, addresses } => {
+                write!(f, "configure interface {} (addresses [{}])", name, addresses.join(","))
+            }
+            Self::RemoveInterface { name } => {
+                write!(f, "remove the configuration of interface {}", name)
+            }
+            Self::RemoveFile { path } => {
+                write!(f, "remove {:?}", path)
+            }
+            Self::StopPrograms => {
+                write!(f, "stop all programs run for the experiment")
+            }
+            Self::SetHostname { hostname } => {
+                write!(f, "set hostname to {}", hostname)
+            }
+            Self::ReloadService { unit } => {
+                write!(f, "reload {}", unit)
+            }
+            Self::SetTimezone { timezone } => {
+                write!(f, "set timezone to {}", timezone)
+            }
+            Self::SetLocale { locale } => {
+                write!(f, "set locale to {}", locale)
+            }
+            Self::StepClock { timestamp } => {
+                write!(f, "step clock to {}", timestamp)
+            }
+            Self::InstallTarball { source, dir } => {
+                write!(f, "unpack {} into {:?}", source, dir)
+            }
+            Self::InstallRpm { source } => {
+                write!(f, "install RPM {}", source)
+            }
+            Self::RunStartup { user, command } => {
+                write!(f, "run startup command as {}: {}", user, command)
+            }
+            Self::RunHook { message, program } => {
+                write!(f, "run hook {:?} for {}", program, message)
+            }
+            Self::SendWebhook { event, url } => {
+                write!(f, "send {} webhook to {}", event, url)
+            }
+            Self::Reboot => {
+                write!(f, "reboot")
+            }
+        }
+    }
+}
+
+/// A machine-readable plan.
+#[derive(Debug, Serialize)]
+struct Plan<'a> {
+    actions: &'a [Action],
+}
+
+/// Returns the plan of actions as JSON.
+pub fn to_json(actions: &[Action]) -> String {
+    serde_json::to_string_pretty(&Plan { actions })
+        .expect("Failed to serialize plan")
+}
+
+/// Enable dry-run mode.
+pub fn enable() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether changes should only be planned.
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Record an action that would have been taken.
+pub fn record(action: Action) {
+    log::info!("Would {}", action);
+    ACTIONS.lock().unwrap().push(action);
+}
+
+/// Returns the actions recorded so far.
+pub fn actions() -> Vec<Action> {
+    ACTIONS.lock().unwrap().clone()
+}
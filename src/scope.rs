@@ -0,0 +1,461 @@
+//! Transient scopes for spawned programs.
+//!
+//! Programs we run on behalf of the experiment (startup commands,
+//! event-driven programs) are placed in their own transient scope
+//! with resource limits, so that runaway experiment software cannot
+//! starve miniond or sshd.
+//!
+//! With systemd, we simply wrap the program in `systemd-run --scope`.
+//! Without systemd, we create a cgroup v2 group ourselves, and the
+//! program moves itself into it before it's executed. The group is
+//! removed once the program exits.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+
+use nix::unistd::{self, Gid, Uid};
+use serde::Deserialize;
+use tokio::fs;
+use tokio::process::{Child, ChildStdin, Command};
+use which::which;
+
+use crate::command::run_command;
+use crate::error::{Error, Result};
+use crate::plan::{self, Action};
+
+/// Scope configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ScopeConfig {
+    /// How to confine spawned programs.
+    backend: ScopeBackend,
+
+    /// The systemd slice to place scopes in.
+    slice: String,
+
+    /// Parent cgroup for the `cgroup` backend.
+    #[serde(rename = "cgroup-root")]
+    cgroup_root: PathBuf,
+
+    /// Maximum memory usage (e.g., `8G`).
+    #[serde(rename = "memory-max")]
+    memory_max: Option<String>,
+
+    /// CPU quota in percent of a single CPU (e.g., `200` for two CPUs).
+    #[serde(rename = "cpu-quota")]
+    cpu_quota: Option<u32>,
+
+    /// Maximum number of tasks.
+    #[serde(rename = "tasks-max")]
+    tasks_max: Option<u32>,
+}
+
+impl Default for ScopeConfig {
+    fn default() -> Self {
+        Self {
+            backend: ScopeBackend::Auto,
+            slice: "miniond.slice".to_string(),
+            cgroup_root: PathBuf::from("/sys/fs/cgroup/miniond"),
+            memory_max: None,
+            cpu_quota: None,
+            tasks_max: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum ScopeBackend {
+    /// Use `systemd-run` if available, otherwise cgroup v2.
+    #[serde(rename = "auto")]
+    Auto,
+
+    /// Use `systemd-run --scope`.
+    #[serde(rename = "systemd")]
+    Systemd,
+
+    /// Manage a cgroup v2 group directly.
+    #[serde(rename = "cgroup")]
+    Cgroup,
+
+    /// Run programs without confinement.
+    #[serde(rename = "none")]
+    None,
+}
+
+impl ScopeConfig {
+    fn resolve_backend(&self) -> ScopeBackend {
+        match self.backend {
+            ScopeBackend::Auto => {
+                if which("systemd-run").is_ok() {
+                    ScopeBackend::Systemd
+                } else if PathBuf::from("/sys/fs/cgroup/cgroup.controllers").exists() {
+                    ScopeBackend::Cgroup
+                } else {
+                    ScopeBackend::None
+                }
+            }
+            backend => backend,
+        }
+    }
+}
+
+/// Stop all programs run in transient scopes.
+pub async fn stop_all(config: &ScopeConfig) -> Result<()> {
+    if plan::is_dry_run() {
+        plan::record(Action::StopPrograms);
+        return Ok(());
+    }
+
+    match config.resolve_backend() {
+        ScopeBackend::Systemd => {
+            log::info!("Stopping all programs in {}...", config.slice);
+
+            if let Err(reason) = run_command(Command::new("systemctl").arg("stop").arg(&config.slice)).await {
+                log::warn!("Failed to stop {}: {}", config.slice, reason);
+            }
+        }
+        ScopeBackend::Cgroup => {
+            log::info!("Stopping all programs in {:?}...", config.cgroup_root);
+
+            // Needs Linux 5.14+
+            let kill = config.cgroup_root.join("cgroup.kill");
+            if kill.exists() {
+                fs::write(kill, "1").await?;
+            } else {
+                log::warn!("Cannot stop programs without cgroup.kill");
+            }
+        }
+        _ => {
+            log::warn!("Programs are run without confinement and cannot be stopped");
+        }
+    }
+
+    Ok(())
+}
+
+/// A program to be run inside a transient scope.
+#[derive(Debug, Clone)]
+pub struct ScopedCommand {
+    /// Name of the scope.
+    name: String,
+
+    program: OsString,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    current_dir: Option<PathBuf>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl ScopedCommand {
+    /// Create a new command.
+    ///
+    /// `name` identifies the scope and should be unique among
+    /// concurrently-running programs.
+    pub fn new<S: AsRef<OsStr>>(name: &str, program: S) -> Self {
+        Self {
+            name: sanitize_name(name),
+            program: program.as_ref().to_owned(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            current_dir: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Add an argument.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Set an environment variable.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self {
+        self.envs.push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    /// Set the working directory.
+    pub fn current_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.current_dir = Some(dir);
+        self
+    }
+
+    /// Run the program as a specific user and group.
+    pub fn user(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.uid = Some(uid);
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Spawn the program inside a transient scope.
+    pub async fn spawn(&self, config: &ScopeConfig, stdin: Stdio, stdout: Stdio, stderr: Stdio) -> Result<ScopedChild> {
+        let backend = config.resolve_backend();
+
+        // Programs whose cgroup cannot be set up still run, unconfined
+        let mut cgroup = None;
+        let mut command = match backend {
+            ScopeBackend::Systemd => self.systemd_run(config),
+            ScopeBackend::Cgroup => match self.create_cgroup(config).await {
+                Ok((path, procs)) => {
+                    cgroup = Some(path);
+                    self.in_cgroup(procs)
+                }
+                Err(e) => {
+                    log::warn!("Failed to create the cgroup of {} - Running it unconfined: {}", self.name, e);
+                    self.direct()
+                }
+            },
+            _ => self.direct(),
+        };
+
+        command
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(stderr)
+            .envs(self.envs.iter().map(|(k, v)| (k, v)));
+
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+
+        log::debug!("Spawning {:?} in scope {} ({:?})", self.program, self.name, backend);
+
+        let child = command.spawn()?;
+
+        Ok(ScopedChild { child, cgroup })
+    }
+
+    fn systemd_run(&self, config: &ScopeConfig) -> Command {
+        let mut command = Command::new("systemd-run");
+
+        command
+            .arg("--scope")
+            .arg("--quiet")
+            .arg("--collect")
+            .arg(format!("--unit=miniond-{}", self.name))
+            .arg(format!("--slice={}", config.slice));
+
+        if let Some(memory_max) = &config.memory_max {
+            command.arg(format!("--property=MemoryMax={}", memory_max));
+        }
+
+        if let Some(cpu_quota) = config.cpu_quota {
+            command.arg(format!("--property=CPUQuota={}%", cpu_quota));
+        }
+
+        if let Some(tasks_max) = config.tasks_max {
+            command.arg(format!("--property=TasksMax={}", tasks_max));
+        }
+
+        if let Some(uid) = self.uid {
+            command.arg(format!("--uid={}", uid));
+        }
+
+        if let Some(gid) = self.gid {
+            command.arg(format!("--gid={}", gid));
+        }
+
+        command
+            .arg("--")
+            .arg(&self.program)
+            .args(&self.args);
+
+        command
+    }
+
+    fn direct(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+
+        if let Some(gid) = self.gid {
+            command.gid(gid);
+        }
+
+        if let Some(uid) = self.uid {
+            command.uid(uid);
+        }
+
+        command
+    }
+
+    /// Returns a command that moves itself into a cgroup before the
+    /// program is executed, so neither the program nor anything it
+    /// forks ever runs outside of the limits.
+    ///
+    /// `procs` is the open `cgroup.procs` file of the cgroup.
+    fn in_cgroup(&self, procs: File) -> Command {
+        let (uid, gid) = (self.uid, self.gid);
+
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+
+        // Privileges are dropped here instead of with `uid()` and
+        // `gid()`, since those apply before `pre_exec` and an
+        // unprivileged process may not move itself.
+        //
+        // Safety: only plain system calls run between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                // "0" is the writing process
+                unistd::write(procs.as_raw_fd(), b"0")?;
+
+                if uid.is_some() {
+                    unistd::setgroups(&[])?;
+                }
+
+                if let Some(gid) = gid {
+                    unistd::setgid(Gid::from_raw(gid))?;
+                }
+
+                if let Some(uid) = uid {
+                    unistd::setuid(Uid::from_raw(uid))?;
+                }
+
+                Ok(())
+            });
+        }
+
+        command
+    }
+
+    /// Create the cgroup of the scope with the configured limits.
+    ///
+    /// Returns the cgroup along with its open `cgroup.procs` file.
+    async fn create_cgroup(&self, config: &ScopeConfig) -> Result<(PathBuf, File)> {
+        let root = &config.cgroup_root;
+        let cgroup = root.join(&self.name);
+
+        fs::create_dir_all(&cgroup).await?;
+
+        // Delegate controllers to our children. This fails if the parent
+        // does not have them enabled, in which case we do our best.
+        if let Err(e) = fs::write(root.join("cgroup.subtree_control"), "+memory +cpu +pids").await {
+            log::debug!("Failed to enable cgroup controllers under {:?}: {}", root, e);
+        }
+
+        if let Some(memory_max) = &config.memory_max {
+            let bytes = parse_size(memory_max).ok_or_else(|| Error::ScopeBadLimit {
+                limit: memory_max.clone(),
+            })?;
+            fs::write(cgroup.join("memory.max"), bytes.to_string()).await?;
+        }
+
+        if let Some(cpu_quota) = config.cpu_quota {
+            // cpu.max is "$MAX $PERIOD" in microseconds
+            let period = 100_000u64;
+            let max = period * cpu_quota as u64 / 100;
+            fs::write(cgroup.join("cpu.max"), format!("{} {}", max, period)).await?;
+        }
+
+        if let Some(tasks_max) = config.tasks_max {
+            fs::write(cgroup.join("pids.max"), tasks_max.to_string()).await?;
+        }
+
+        let procs = fs::OpenOptions::new()
+            .write(true)
+            .open(cgroup.join("cgroup.procs")).await?
+            .into_std().await;
+
+        Ok((cgroup, procs))
+    }
+}
+
+/// A program running in a transient scope.
+#[derive(Debug)]
+pub struct ScopedChild {
+    child: Child,
+
+    /// The cgroup we created for the program, if any.
+    cgroup: Option<PathBuf>,
+}
+
+impl ScopedChild {
+    /// Take the stdin of the program, if it was piped.
+    pub fn stdin(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
+    /// Wait for the program to exit, then remove its scope.
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait().await?;
+        self.remove_cgroup().await;
+
+        Ok(status)
+    }
+
+    /// Kill the program, then remove its scope.
+    pub async fn kill(&mut self) -> io::Result<()> {
+        self.child.kill().await?;
+        self.remove_cgroup().await;
+
+        Ok(())
+    }
+
+    async fn remove_cgroup(&mut self) {
+        let cgroup = match self.cgroup.take() {
+            Some(cgroup) => cgroup,
+            None => return,
+        };
+
+        // Fails while processes the program left behind are running
+        if let Err(e) = fs::remove_dir(&cgroup).await {
+            log::debug!("Failed to remove cgroup {:?}: {}", cgroup, e);
+        }
+    }
+}
+
+/// Make a name safe to use as a unit or cgroup name.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect()
+}
+
+/// Parse a size with an optional binary suffix (`K`, `M`, `G`, `T`).
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last()? {
+        'K' | 'k' => (&size[..size.len() - 1], 1u64 << 10),
+        'M' | 'm' => (&size[..size.len() - 1], 1u64 << 20),
+        'G' | 'g' => (&size[..size.len() - 1], 1u64 << 30),
+        'T' | 't' => (&size[..size.len() - 1], 1u64 << 40),
+        _ => (size, 1),
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(Some(512), parse_size("512"));
+        assert_eq!(Some(4 << 10), parse_size("4K"));
+        assert_eq!(Some(8 << 30), parse_size(" 8G "));
+        assert_eq!(Some(2 << 20), parse_size("2m"));
+        assert_eq!(Some(1 << 40), parse_size("1T"));
+
+        assert_eq!(None, parse_size(""));
+        assert_eq!(None, parse_size("G"));
+        assert_eq!(None, parse_size("1.5G"));
+        assert_eq!(None, parse_size("8X"));
+        assert_eq!(None, parse_size("-1"));
+        assert_eq!(None, parse_size("99999999999T"));
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!("startup", sanitize_name("startup"));
+        assert_eq!("event-prog_1", sanitize_name("event-prog_1"));
+        assert_eq!("event-usr-bin-foo", sanitize_name("event/usr/bin/foo"));
+        assert_eq!("a-b--", sanitize_name("a b.é"));
+    }
+}
@@ -0,0 +1,203 @@
+//! Persistent state.
+//!
+//! Applets remember what they last applied in small JSON files under
+//! the state directory, so that after a reboot we can skip work that
+//! is already done and clean up things that disappeared from the
+//! experiment while the node was down.
+//!
+//! Each applet owns its own file. Nothing is read or written in
+//! dry-run mode.
+//!
+//! Applying state (e.g., accounts) is guarded by a [`lock`] of the
+//! same name, so overlapping triggers in this process or another
+//! instance never interleave their changes.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as StdMutex};
+
+use nix::fcntl::{flock, FlockArg};
+use once_cell::sync::Lazy;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::fs;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::blocking;
+use crate::error::{FileSnafu, Result};
+use crate::plan;
+
+/// State configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct StateConfig {
+    /// Directory to keep the state in.
+    pub(crate) dir: PathBuf,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("/var/lib/miniond"),
+        }
+    }
+}
+
+impl StateConfig {
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    fn lock_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.lock", name))
+    }
+}
+
+/// Locks held in this process, by name.
+static LOCKS: Lazy<StdMutex<HashMap<String, Arc<Mutex<()>>>>> = Lazy::new(Default::default);
+
+/// An exclusive lock on applying the state named `name`.
+///
+/// It's released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    _guard: OwnedMutexGuard<()>,
+
+    /// The locked file, unless in dry-run mode.
+    _file: Option<File>,
+}
+
+/// Lock applying the state named `name`.
+///
+/// This waits for other holders in this process, then for other
+/// processes holding the lock file in the state directory.
+pub async fn lock(config: &StateConfig, name: &str) -> Result<Lock> {
+    let mutex = LOCKS.lock().unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .clone();
+
+    let guard = match mutex.clone().try_lock_owned() {
+        Ok(guard) => guard,
+        Err(_) => {
+            log::info!("Waiting for another {} cycle to finish...", name);
+            mutex.lock_owned().await
+        }
+    };
+
+    if plan::is_dry_run() {
+        return Ok(Lock { _guard: guard, _file: None });
+    }
+
+    fs::create_dir_all(&config.dir).await
+        .context(FileSnafu { action: "create", path: &config.dir })?;
+
+    let path = config.lock_path(name);
+    let file = blocking::run("flock", move || {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(FileSnafu { action: "open", path: &path })?;
+
+        if flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+            log::info!("Waiting for another process holding {:?}...", path);
+
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)
+                .map_err(io::Error::from)
+                .context(FileSnafu { action: "lock", path: &path })?;
+        }
+
+        Ok(file)
+    }).await?;
+
+    Ok(Lock { _guard: guard, _file: Some(file) })
+}
+
+/// Load the state named `name`.
+///
+/// Returns the default if there is no state yet. A corrupted state
+/// file is ignored, since it will simply be overwritten later.
+pub async fn load<T: DeserializeOwned + Default>(config: &StateConfig, name: &str) -> T {
+    if plan::is_dry_run() {
+        return T::default();
+    }
+
+    let path = config.path(name);
+    let contents = match fs::read(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return T::default(),
+        Err(e) => {
+            log::warn!("Failed to read state from {:?}: {}", path, e);
+            return T::default();
+        }
+    };
+
+    match serde_json::from_slice(&contents) {
+        Ok(state) => {
+            log::debug!("Loaded state from {:?}", path);
+            state
+        }
+        Err(e) => {
+            log::warn!("Ignoring invalid state in {:?}: {}", path, e);
+            T::default()
+        }
+    }
+}
+
+/// Save the state named `name`.
+pub async fn save<T: Serialize>(config: &StateConfig, name: &str, state: &T) -> Result<()> {
+    if plan::is_dry_run() {
+        return Ok(());
+    }
+
+    let contents = serde_json::to_vec_pretty(state)
+        .expect("Failed to serialize state");
+
+    fs::create_dir_all(&config.dir).await
+        .context(FileSnafu { action: "create", path: &config.dir })?;
+    blocking::write_atomic(vec![(config.path(name), contents)]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StateConfig { dir: dir.path().to_path_buf() };
+
+        // Held in this process
+        let held = lock(&config, "test-lock").await.unwrap();
+        assert!(config.lock_path("test-lock").exists());
+        assert!(tokio::time::timeout(Duration::from_millis(100), lock(&config, "test-lock")).await.is_err());
+
+        drop(held);
+        let held = tokio::time::timeout(Duration::from_secs(5), lock(&config, "test-lock")).await
+            .expect("Timed out waiting for the lock")
+            .unwrap();
+        drop(held);
+
+        // Held by another process
+        let file = File::open(config.lock_path("test-lock")).unwrap();
+        flock(file.as_raw_fd(), FlockArg::LockExclusive).unwrap();
+
+        let other = lock(&config, "test-lock");
+        tokio::pin!(other);
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut other).await.is_err());
+
+        drop(file);
+        tokio::time::timeout(Duration::from_secs(5), other).await
+            .expect("Timed out waiting for the lock file")
+            .unwrap();
+    }
+}
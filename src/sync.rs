@@ -0,0 +1,270 @@
+//! Barrier synchronization.
+//!
+//! This speaks the protocol of `emulab-sync` and `emulab-syncd`, so
+//! nodes running miniond can wait at the same barriers as nodes
+//! running the official clientside.
+//!
+//! One node initializes a barrier with the number of other nodes
+//! that will wait at it. Everyone is released with the largest error
+//! code reported once that many nodes are waiting.
+//!
+//! Requests are C structs in host byte order:
+//!
+//! ```text
+//! char name[64];
+//! int  request;  /* 1 = init, 2 = wait */
+//! int  flags;    /* 1 = the initializer doesn't wait */
+//! int  count;    /* number of waiters, for init */
+//! int  error;    /* error code to report */
+//! ```
+//!
+//! The reply is a single `int`, the error code.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::{Error, Result};
+
+/// The default port of the sync server.
+pub const SYNC_PORT: u16 = 16534;
+
+const NAME_SIZE: usize = 64;
+const REQUEST_SIZE: usize = NAME_SIZE + 4 * 4;
+
+const REQUEST_INIT: i32 = 1;
+const REQUEST_WAIT: i32 = 2;
+
+const FLAG_NOWAIT: i32 = 0x1;
+
+/// Error code returned to a second initializer of the same barrier.
+const ERROR_ALREADY_INITIALIZED: i32 = -1;
+
+/// A barrier to wait at.
+#[derive(Debug, Clone)]
+pub struct Barrier {
+    /// Name of the barrier.
+    pub name: String,
+
+    /// Number of other nodes that will wait, if we initialize it.
+    pub init: Option<u32>,
+
+    /// Whether to return right after initializing.
+    pub nowait: bool,
+
+    /// Error code to report to the other nodes.
+    pub error: i32,
+}
+
+/// A decoded request.
+#[derive(Debug, Clone, PartialEq)]
+struct Request {
+    name: String,
+    request: i32,
+    flags: i32,
+    count: i32,
+    error: i32,
+}
+
+impl Request {
+    fn encode(&self) -> [u8; REQUEST_SIZE] {
+        let mut bytes = [0; REQUEST_SIZE];
+
+        // NUL-terminated
+        let name = self.name.as_bytes();
+        bytes[..name.len()].copy_from_slice(name);
+
+        for (i, value) in [self.request, self.flags, self.count, self.error].iter().enumerate() {
+            let offset = NAME_SIZE + i * 4;
+            bytes[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8; REQUEST_SIZE]) -> Self {
+        let name = &bytes[..NAME_SIZE];
+        let end = name.iter().position(|&b| b == 0).unwrap_or(NAME_SIZE);
+
+        let field = |i: usize| {
+            let offset = NAME_SIZE + i * 4;
+            i32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+
+        Self {
+            name: String::from_utf8_lossy(&name[..end]).to_string(),
+            request: field(0),
+            flags: field(1),
+            count: field(2),
+            error: field(3),
+        }
+    }
+}
+
+/// Wait at a barrier on the sync server, returning the error code
+/// everyone was released with.
+pub async fn wait(server: &str, port: u16, barrier: &Barrier) -> Result<i32> {
+    // Leave room for the NUL
+    if barrier.name.is_empty() || barrier.name.len() >= NAME_SIZE || barrier.name.contains('\0') {
+        return Err(Error::SyncBadBarrier { name: barrier.name.clone() });
+    }
+
+    let request = match barrier.init {
+        Some(count) => Request {
+            name: barrier.name.clone(),
+            request: REQUEST_INIT,
+            flags: if barrier.nowait { FLAG_NOWAIT } else { 0 },
+            count: count as i32,
+            error: barrier.error,
+        },
+        None => Request {
+            name: barrier.name.clone(),
+            request: REQUEST_WAIT,
+            flags: 0,
+            count: 0,
+            error: barrier.error,
+        },
+    };
+
+    log::info!("Waiting at barrier {} on {}...", barrier.name, server);
+
+    let mut stream = TcpStream::connect((server, port)).await?;
+    stream.write_all(&request.encode()).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+
+    Ok(i32::from_ne_bytes(reply))
+}
+
+/// State of a barrier on the server.
+#[derive(Debug, Default)]
+struct BarrierState {
+    /// Number of waiters to release at, once initialized.
+    count: Option<usize>,
+
+    /// Connections waiting to be released.
+    waiters: Vec<TcpStream>,
+
+    /// Whether the initializer is among `waiters`.
+    initializer_waiting: bool,
+
+    /// Largest error code reported so far.
+    error: i32,
+}
+
+impl BarrierState {
+    /// Returns whether everyone is here.
+    fn is_complete(&self) -> bool {
+        let waiters = self.waiters.len() - usize::from(self.initializer_waiting);
+        self.count.is_some_and(|count| waiters >= count)
+    }
+}
+
+/// Serve barriers to other nodes until the listener fails.
+pub async fn serve(listener: TcpListener) -> Result<()> {
+    let barriers: Arc<Mutex<HashMap<String, BarrierState>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let barriers = barriers.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &barriers).await {
+                log::warn!("Sync request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Handle a request, releasing the barrier if it's complete.
+async fn handle(mut stream: TcpStream, barriers: &Mutex<HashMap<String, BarrierState>>) -> Result<()> {
+    let mut bytes = [0; REQUEST_SIZE];
+    stream.read_exact(&mut bytes).await?;
+
+    let request = Request::decode(&bytes);
+
+    // The reply to send right away, if any
+    let (reply, complete) = {
+        let mut barriers = barriers.lock().unwrap();
+        let state = barriers.entry(request.name.clone()).or_default();
+
+        let reply = match request.request {
+            REQUEST_INIT if state.count.is_some() => {
+                log::warn!("Barrier {} is already initialized", request.name);
+                Some((stream, ERROR_ALREADY_INITIALIZED))
+            }
+            REQUEST_INIT => {
+                log::info!("Barrier {} initialized for {} waiters", request.name, request.count);
+                state.count = Some(request.count.max(0) as usize);
+                state.error = state.error.max(request.error);
+
+                if request.flags & FLAG_NOWAIT != 0 {
+                    Some((stream, 0))
+                } else {
+                    state.waiters.push(stream);
+                    state.initializer_waiting = true;
+                    None
+                }
+            }
+            REQUEST_WAIT => {
+                state.error = state.error.max(request.error);
+                state.waiters.push(stream);
+                None
+            }
+            other => {
+                log::warn!("Unknown sync request {} for barrier {}", other, request.name);
+                None
+            }
+        };
+
+        let complete = if state.is_complete() {
+            barriers.remove(&request.name)
+        } else {
+            None
+        };
+
+        (reply, complete)
+    };
+
+    if let Some((mut stream, error)) = reply {
+        stream.write_all(&error.to_ne_bytes()).await?;
+    }
+
+    if let Some(state) = complete {
+        log::info!("Releasing {} waiters at barrier {}", state.waiters.len(), request.name);
+
+        for mut waiter in state.waiters {
+            // Waiters that gave up don't hold up the others
+            if let Err(e) = waiter.write_all(&state.error.to_ne_bytes()).await {
+                log::debug!("Failed to release a waiter at barrier {}: {}", request.name, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_roundtrip() {
+        let request = Request {
+            name: "setup-done".to_string(),
+            request: REQUEST_INIT,
+            flags: FLAG_NOWAIT,
+            count: 4,
+            error: 2,
+        };
+
+        let bytes = request.encode();
+        assert_eq!(REQUEST_SIZE, bytes.len());
+        assert_eq!(0, bytes["setup-done".len()]);
+        assert_eq!(request, Request::decode(&bytes));
+    }
+}
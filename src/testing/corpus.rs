@@ -0,0 +1,109 @@
+//! Corpus of raw TMCD responses.
+//!
+//! A corpus directory contains a subdirectory for each cluster, and
+//! a capture of the raw response to each command in it, named after
+//! the command:
+//!
+//! ```text
+//! corpus/
+//!   utah/
+//!     accounts.txt
+//!     mounts.txt
+//!     geni_manifest.xml
+//!   wisc/
+//!     ...
+//! ```
+//!
+//! Captures can be made with `tmcc.record-dir` or by hand with
+//! `tmcc`. Each of them must parse the same way as in the client.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use miniond_core::Error;
+use crate::tmcc;
+
+/// A captured response.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    /// Name of the cluster.
+    pub cluster: String,
+
+    /// The command.
+    pub command: String,
+
+    /// Path to the capture.
+    pub path: PathBuf,
+
+    /// The raw response.
+    pub response: Vec<u8>,
+}
+
+/// Result of checking a capture.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The capture parses.
+    Ok,
+
+    /// Responses to this command aren't parsed by the client.
+    Unsupported,
+
+    /// The capture fails to parse.
+    Failed(String),
+}
+
+impl Capture {
+    /// Check that the capture parses.
+    pub fn check(&self) -> Outcome {
+        match tmcc::validate(&self.command, &self.response) {
+            Ok(()) => Outcome::Ok,
+            Err(Error::TmcdUnsupportedCommand { .. }) => Outcome::Unsupported,
+            Err(e) => Outcome::Failed(e.to_string()),
+        }
+    }
+}
+
+/// Load all captures in a corpus directory.
+pub fn load(dir: &Path) -> io::Result<Vec<Capture>> {
+    let mut captures = Vec::new();
+
+    for cluster in sorted_entries(dir)? {
+        if !cluster.is_dir() {
+            continue;
+        }
+
+        let cluster_name = file_stem(&cluster);
+
+        for path in sorted_entries(&cluster)? {
+            if !path.is_file() {
+                continue;
+            }
+
+            captures.push(Capture {
+                cluster: cluster_name.clone(),
+                command: file_stem(&path),
+                response: fs::read(&path)?,
+                path,
+            });
+        }
+    }
+
+    Ok(captures)
+}
+
+fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    entries.sort();
+
+    Ok(entries)
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
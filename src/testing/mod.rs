@@ -0,0 +1,234 @@
+//! Testing utilities.
+//!
+//! This module is only available with the `testing` feature. It
+//! provides [`MockTmcd`], a minimal TMCD simulator that serves canned
+//! responses over TCP, so the client and the applets can be exercised
+//! end-to-end without a testbed, as well as a loader for a [`corpus`]
+//! of responses captured on real testbeds.
+
+pub mod corpus;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::tmcc::BossNode;
+
+/// Canned responses of the mock server, keyed by command.
+#[derive(Debug, Clone)]
+pub struct Fixtures {
+    responses: HashMap<String, Vec<u8>>,
+}
+
+impl Fixtures {
+    /// Returns a set of fixtures without any response.
+    ///
+    /// Unknown commands get an empty response, like the real TMCD.
+    pub fn empty() -> Self {
+        Self {
+            responses: HashMap::new(),
+        }
+    }
+
+    /// Set the response to a command.
+    pub fn set(&mut self, command: &str, response: impl Into<Vec<u8>>) -> &mut Self {
+        self.responses.insert(command.to_string(), response.into());
+        self
+    }
+
+    /// Returns the response to a command.
+    pub fn get(&self, command: &str) -> &[u8] {
+        self.responses.get(command)
+            .map(|r| r.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Fixtures {
+    /// Returns fixtures for a node allocated to a small experiment.
+    fn default() -> Self {
+        let mut fixtures = Self::empty();
+
+        fixtures
+            .set("accounts", include_str!("fixtures/accounts.txt"))
+            .set("mounts", include_str!("fixtures/mounts.txt"))
+            .set("status", include_str!("fixtures/status.txt"))
+            .set("bootwhat", include_str!("fixtures/bootwhat.txt"))
+            .set("localization", include_str!("fixtures/localization.txt"))
+            .set("geni_manifest", include_str!("fixtures/geni_manifest.xml"));
+
+        fixtures
+    }
+}
+
+/// A mock TMCD server.
+///
+/// The server is stopped when this is dropped.
+pub struct MockTmcd {
+    addr: SocketAddr,
+    fixtures: Arc<Mutex<Fixtures>>,
+    requests: Arc<Mutex<Vec<String>>>,
+    udp_requests: Arc<Mutex<Vec<String>>>,
+    received: Arc<Notify>,
+    task: JoinHandle<()>,
+    udp_task: JoinHandle<()>,
+}
+
+impl MockTmcd {
+    /// Start a server on a random local port.
+    pub async fn start(fixtures: Fixtures) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let socket = UdpSocket::bind(addr).await?;
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let udp_requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::new(Notify::new());
+        let fixtures = Arc::new(Mutex::new(fixtures));
+
+        let task = {
+            let fixtures = fixtures.clone();
+            let requests = requests.clone();
+            let received = received.clone();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let fixtures = fixtures.clone();
+                    let requests = requests.clone();
+                    let received = received.clone();
+
+                    tokio::spawn(async move {
+                        match serve(stream, &fixtures).await {
+                            Ok(request) => {
+                                requests.lock().unwrap().push(request);
+                                received.notify_waiters();
+                            }
+                            Err(e) => {
+                                log::warn!("Mock TMCD failed to serve request: {}", e);
+                            }
+                        }
+                    });
+                }
+            })
+        };
+
+        let udp_task = {
+            let fixtures = fixtures.clone();
+            let udp_requests = udp_requests.clone();
+            let received = received.clone();
+
+            tokio::spawn(async move {
+                let mut buf = vec![0; 4096];
+                while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                    let request = parse_request(&buf[..len]);
+                    let command = request.split(' ').next().unwrap_or_default();
+                    let response = fixtures.lock().unwrap().get(command).to_vec();
+
+                    if let Err(e) = socket.send_to(&response, peer).await {
+                        log::warn!("Mock TMCD failed to serve UDP request: {}", e);
+                        continue;
+                    }
+
+                    udp_requests.lock().unwrap().push(request);
+                    received.notify_waiters();
+                }
+            })
+        };
+
+        Ok(Self {
+            addr,
+            fixtures,
+            requests,
+            udp_requests,
+            received,
+            task,
+            udp_task,
+        })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the boss node to connect to this server.
+    pub fn boss(&self) -> BossNode {
+        BossNode::HostPort((self.addr.ip().to_string(), self.addr.port()))
+    }
+
+    /// Set the response to a command while the server is running.
+    pub fn set(&self, command: &str, response: impl Into<Vec<u8>>) {
+        self.fixtures.lock().unwrap().set(command, response);
+    }
+
+    /// Returns the requests served so far, without the `VERSION`.
+    ///
+    /// Each request is the command followed by its arguments
+    /// (e.g., `state MFSSETUP`).
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Returns the requests served over UDP so far, like [`MockTmcd::requests`].
+    pub fn udp_requests(&self) -> Vec<String> {
+        self.udp_requests.lock().unwrap().clone()
+    }
+
+    /// Wait until a request starting with `prefix` is served.
+    pub async fn wait_for(&self, prefix: &str) {
+        self.wait_for_count(prefix, 1).await;
+    }
+
+    /// Wait until `count` requests starting with `prefix` are served.
+    pub async fn wait_for_count(&self, prefix: &str, count: usize) {
+        loop {
+            let received = self.received.notified();
+
+            if self.requests().iter().filter(|r| r.starts_with(prefix)).count() >= count {
+                return;
+            }
+
+            received.await;
+        }
+    }
+}
+
+impl Drop for MockTmcd {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.udp_task.abort();
+    }
+}
+
+/// Serve a single request, returning it.
+async fn serve(mut stream: TcpStream, fixtures: &Mutex<Fixtures>) -> io::Result<String> {
+    // Commands are not terminated, but the client sends each of
+    // them in a single write
+    let mut buf = vec![0; 4096];
+    let len = stream.read(&mut buf).await?;
+    let request = parse_request(&buf[..len]);
+
+    let command = request.split(' ').next().unwrap_or_default();
+
+    let response = fixtures.lock().unwrap().get(command).to_vec();
+    stream.write_all(&response).await?;
+    stream.shutdown().await?;
+
+    Ok(request)
+}
+
+/// Returns a raw request without the options.
+fn parse_request(raw: &[u8]) -> String {
+    // Options like `VERSION=44` precede the command
+    String::from_utf8_lossy(raw)
+        .split_whitespace()
+        .filter(|token| !token.contains('='))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,23 @@
+//! Checks the corpus of captured TMCD responses under `tests/corpus`.
+
+use std::path::PathBuf;
+
+use miniond::testing::corpus::{self, Outcome};
+
+#[test]
+fn test_corpus() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let captures = corpus::load(&dir).expect("Failed to load corpus");
+
+    assert!(!captures.is_empty());
+
+    let mut failures = Vec::new();
+    for capture in &captures {
+        match capture.check() {
+            Outcome::Ok | Outcome::Unsupported => {}
+            Outcome::Failed(e) => failures.push(format!("{}/{}: {}", capture.cluster, capture.command, e)),
+        }
+    }
+
+    assert!(failures.is_empty(), "Captures failed to parse:\n{}", failures.join("\n"));
+}
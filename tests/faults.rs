@@ -0,0 +1,22 @@
+//! Fault injection tests.
+
+use std::path::PathBuf;
+
+use miniond::applet;
+use miniond::config::ConfigInner;
+
+fn fixtures() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testing/fixtures")
+}
+
+#[tokio::test]
+async fn test_dropped_responses() {
+    let config: ConfigInner = toml::from_str(r#"
+        [faults]
+        drop-rate = 1.0
+        seed = 42
+    "#).expect("Failed to parse config");
+
+    // The `status` response is empty and can't be parsed
+    assert!(applet::simulate(config, fixtures()).await.is_err());
+}
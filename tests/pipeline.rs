@@ -0,0 +1,548 @@
+//! Applet pipeline tests against the mock TMCD server.
+//!
+//! Applets that modify the system are disabled, so these only
+//! exercise the interaction with the testbed.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use miniond::applet::{self, Applet, Message, Receiver, Runner, Sender};
+use miniond::error::Result;
+use miniond::config::ConfigInner;
+use miniond::testing::{Fixtures, MockTmcd};
+
+/// Returns a configuration pointed at the TMCD server at `addr`.
+///
+/// `extra` follows the `[tmcc]` table, so it can start with more
+/// TMCC options before opening other tables.
+fn pipeline_config(addr: SocketAddr, extra: &str) -> String {
+    format!(r#"
+        [autouser]
+        enable = false
+
+        [automount]
+        enable = false
+
+        [autohost]
+        enable = false
+
+        [tmcc]
+        boss = "{}"
+        port = {}
+        {}
+    "#, addr.ip(), addr.port(), extra)
+}
+
+/// Starts a mock TMCD server, returning it with a configuration pointed at it.
+async fn start_pipeline(extra: &str) -> (MockTmcd, ConfigInner) {
+    let server = MockTmcd::start(Fixtures::default()).await.unwrap();
+    let config = toml::from_str(&pipeline_config(server.addr(), extra)).expect("Failed to parse config");
+
+    (server, config)
+}
+
+#[tokio::test]
+async fn test_boot() {
+    let (server, config) = start_pipeline("").await;
+
+    let reload = tokio::time::timeout(Duration::from_secs(10), async {
+        for request in ["accounts", "localization", "mounts", "status", "geni_manifest"] {
+            server.wait_for(request).await;
+        }
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = reload => res.expect("Timed out waiting for the testbed reload"),
+    }
+
+    let requests = server.requests();
+    assert_eq!("state MFSSETUP", requests[0]);
+}
+
+#[tokio::test]
+async fn test_boot_states() {
+    let (server, config) = start_pipeline(r#"
+        [tmcc.states]
+        boot = "BOOTING"
+        setup = "TBSETUP"
+    "#).await;
+
+    let reload = tokio::time::timeout(Duration::from_secs(10), async {
+        server.wait_for("geni_manifest").await;
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = reload => res.expect("Timed out waiting for the testbed reload"),
+    }
+
+    let requests = server.requests();
+    assert_eq!(vec!["state BOOTING", "state TBSETUP"], requests[..2]);
+}
+
+#[tokio::test]
+async fn test_swapout_swapin() {
+    let (server, config) = start_pipeline(r#"
+        [autoswap]
+        interval = 1
+
+        [scope]
+        backend = "none"
+    "#).await;
+
+    let lifecycle = tokio::time::timeout(Duration::from_secs(10), async {
+        server.wait_for("geni_manifest").await;
+
+        server.set("status", "FREE");
+        server.wait_for("state SHUTDOWN").await;
+
+        server.set("status", "ALLOCATED=project-PG0/other NICKNAME=node0");
+        server.wait_for_count("state MFSSETUP", 2).await;
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = lifecycle => res.expect("Timed out waiting for the swapout and swapin"),
+    }
+}
+
+/// A custom applet reporting when the testbed was reloaded.
+struct Reloaded {
+    tx: Sender,
+    done: mpsc::UnboundedSender<()>,
+}
+
+#[async_trait]
+impl Applet for Reloaded {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+
+        loop {
+            match rx.recv().await.unwrap() {
+                Message::ReloadTestbedOk => {
+                    self.done.send(()).unwrap();
+                }
+                Message::Shutdown(_) => break,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_custom_applet() {
+    let (_server, config) = start_pipeline("").await;
+
+    let (done, mut reloaded) = mpsc::unbounded_channel();
+
+    let runner = Runner::new(Arc::new(config));
+    let custom = Reloaded {
+        tx: runner.sender(),
+        done,
+    };
+
+    tokio::select! {
+        res = runner.applet("reloaded", Box::new(custom)).run() => panic!("Daemon exited early: {:?}", res),
+        res = tokio::time::timeout(Duration::from_secs(10), reloaded.recv()) => {
+            res.expect("Timed out waiting for the custom applet");
+        }
+    }
+}
+
+/// Send a request to the API, returning the response.
+async fn api_request(addr: &str, request: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // The API may not be listening yet
+    let mut stream = loop {
+        match tokio::net::TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_api() {
+    let dir = tempfile::tempdir().unwrap();
+    let token_file = dir.path().join("api-token");
+    std::fs::write(&token_file, "secret\n").unwrap();
+    std::fs::set_permissions(&token_file, std::os::unix::fs::PermissionsExt::from_mode(0o600)).unwrap();
+
+    let addr = "127.0.0.1:17780";
+
+    let (server, config) = start_pipeline(&format!(r#"
+        [api]
+        enable = true
+        listen = "{}"
+        token-file = "{}"
+    "#, addr, token_file.display())).await;
+
+    let requests = tokio::time::timeout(Duration::from_secs(10), async {
+        server.wait_for("geni_manifest").await;
+
+        let response = api_request(addr, "GET /mounts HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+
+        let response = api_request(addr, "POST /reload HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 202"), "{}", response);
+
+        server.wait_for_count("mounts", 2).await;
+
+        let response = api_request(addr, "GET /mounts HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("[]"), "{}", response);
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = requests => res.expect("Timed out waiting for the API"),
+    }
+}
+
+/// Send a command to the control socket, returning the response.
+async fn control_request(socket: &std::path::Path, command: &str) -> serde_json::Value {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // The socket may not be bound yet
+    let mut stream = loop {
+        match tokio::net::UnixStream::connect(socket).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    stream.write_all(format!("{}\n", command).as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[tokio::test]
+async fn test_control() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("miniond.sock");
+
+    let (server, config) = start_pipeline(&format!(r#"
+        [control]
+        enable = true
+        socket = "{}"
+    "#, socket.display())).await;
+
+    let requests = tokio::time::timeout(Duration::from_secs(10), async {
+        server.wait_for("geni_manifest").await;
+
+        let response = control_request(&socket, "reload").await;
+        assert_eq!("accepted", response["status"]);
+
+        server.wait_for_count("mounts", 2).await;
+
+        let response = control_request(&socket, "status").await;
+        assert_eq!("node0", response["node"], "{}", response);
+
+        let response = control_request(&socket, "mounts").await;
+        assert_eq!(serde_json::json!([]), response);
+
+        let response = control_request(&socket, "frobnicate").await;
+        assert!(response["error"].is_string());
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = requests => res.expect("Timed out waiting for the control socket"),
+    }
+}
+
+#[tokio::test]
+async fn test_hooks() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("output.json");
+    let hook = dir.path().join("hook.sh");
+    std::fs::write(&hook, format!("#!/bin/sh\n[ \"$MINIOND_MESSAGE\" = UpdateAllocation ] && cat > {}.tmp && mv {}.tmp {}\n", output.display(), output.display(), output.display())).unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let (_server, config) = start_pipeline(&format!(r#"
+        [hooks.on]
+        UpdateAllocation = ["{}"]
+
+        [scope]
+        backend = "none"
+    "#, hook.display())).await;
+
+    let hooked = tokio::time::timeout(Duration::from_secs(10), async {
+        while !output.exists() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = hooked => res.expect("Timed out waiting for the hook"),
+    }
+
+    let payload: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    assert_eq!("project-PG0/experiment", payload["experiment"]);
+    assert_eq!("node0", payload["node"]);
+}
+
+#[tokio::test]
+async fn test_templates() {
+    let dir = tempfile::tempdir().unwrap();
+    let template = dir.path().join("hosts.j2");
+    let output = dir.path().join("hosts");
+    let metadata = dir.path().join("experiment.json");
+    std::fs::write(&template, concat!(
+        "{{ node.fqdn }} {{ node.ipv4 }}\n",
+        "{% for user in users %}{{ user.login }}\n{% endfor %}",
+    )).unwrap();
+
+    let (_server, config) = start_pipeline(&format!(r#"
+        [templates]
+        metadata = "{}"
+
+        [[templates.files]]
+        template = "{}"
+        path = "{}"
+    "#, metadata.display(), template.display(), output.display())).await;
+
+    let rendered = tokio::time::timeout(Duration::from_secs(10), async {
+        while !output.exists() || !metadata.exists() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = rendered => res.expect("Timed out waiting for the template"),
+    }
+
+    assert_eq!(
+        "node0.experiment.project-pg0.emulab.net 10.0.0.1\nalice\nbob\nroot\n",
+        std::fs::read_to_string(&output).unwrap(),
+    );
+
+    let metadata: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&metadata).unwrap()).unwrap();
+    assert_eq!("project-PG0", metadata["project"]);
+    assert_eq!("experiment", metadata["experiment"]);
+    assert_eq!("node0", metadata["node"]);
+    assert_eq!("10.0.0.1", metadata["control_ip"]);
+}
+
+#[tokio::test]
+async fn test_webhooks() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let endpoint = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let (_server, config) = start_pipeline(&format!(r#"
+        [webhooks]
+        urls = ["http://{}/hook"]
+        events = ["setup"]
+    "#, endpoint.local_addr().unwrap())).await;
+
+    let notified = tokio::time::timeout(Duration::from_secs(10), async {
+        let (mut stream, _) = endpoint.accept().await.unwrap();
+
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !request.ends_with(b"}") {
+            let len = stream.read(&mut buf).await.unwrap();
+            assert_ne!(0, len, "Connection closed early");
+            request.extend_from_slice(&buf[..len]);
+        }
+
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+
+        String::from_utf8(request).unwrap()
+    });
+
+    let request = tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = notified => res.expect("Timed out waiting for the webhook"),
+    };
+
+    assert!(request.starts_with("POST /hook "), "{}", request);
+
+    let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+    let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!("setup", payload["event"]);
+}
+
+#[tokio::test]
+async fn test_cloudinit() {
+    let dir = tempfile::tempdir().unwrap();
+    let user_data = dir.path().join("user-data");
+
+    let (_server, config) = start_pipeline(&format!(r#"
+        [cloudinit]
+        enable = true
+        seed-dir = "{}"
+    "#, dir.path().display())).await;
+
+    let seeded = tokio::time::timeout(Duration::from_secs(10), async {
+        while !user_data.exists() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    });
+
+    tokio::select! {
+        res = applet::run(Arc::new(config)) => panic!("Daemon exited early: {:?}", res),
+        res = seeded => res.expect("Timed out waiting for the seed"),
+    }
+
+    let meta_data: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.path().join("meta-data")).unwrap()).unwrap();
+    assert_eq!("project-PG0/experiment/node0", meta_data["instance-id"]);
+    assert_eq!("node0.experiment.project-pg0.emulab.net", meta_data["local-hostname"]);
+
+    let user_data = std::fs::read_to_string(&user_data).unwrap();
+    let user_data: serde_json::Value = serde_json::from_str(user_data.strip_prefix("#cloud-config\n").unwrap()).unwrap();
+    assert_eq!("node0", user_data["hostname"]);
+
+    let users: Vec<&str> = user_data["users"].as_array().unwrap().iter()
+        .map(|user| user["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(vec!["alice", "bob"], users);
+}
+
+#[tokio::test]
+async fn test_syncserver() {
+    let mut fixtures = Fixtures::default();
+    fixtures.set("syncserver", "SYNCSERVER SERVER='node0.example.net' ISSERVER=1\n");
+
+    let server = MockTmcd::start(fixtures).await.unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap()
+        .port();
+
+    let config = pipeline_config(server.addr(), &format!(r#"
+        [syncserver]
+        port = {}
+    "#, port));
+
+    let wait = |init: Option<u32>, error: i32| {
+        let config = config.clone();
+        let barrier = applet::Barrier {
+            name: "ready".to_string(),
+            init,
+            nowait: false,
+            error,
+        };
+
+        async move {
+            // The server may not be listening yet
+            loop {
+                let config: ConfigInner = toml::from_str(&config).expect("Failed to parse config");
+                match applet::sync(config, barrier.clone(), Some("127.0.0.1".to_string())).await {
+                    Ok(code) => return code,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        }
+    };
+
+    let daemon_config: ConfigInner = toml::from_str(&config).expect("Failed to parse config");
+
+    let released = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(wait(Some(1), 0), wait(None, 3))
+    });
+
+    let (initializer, waiter) = tokio::select! {
+        res = applet::run(Arc::new(daemon_config)) => panic!("Daemon exited early: {:?}", res),
+        res = released => res.expect("Timed out waiting at the barrier"),
+    };
+
+    assert_eq!(3, initializer);
+    assert_eq!(3, waiter);
+}
+
+/// A custom applet reporting the canonical host it receives.
+///
+/// It subscribes when created, so it sees replayed messages.
+struct Canonical {
+    rx: std::sync::Mutex<Option<Receiver>>,
+    done: mpsc::UnboundedSender<String>,
+}
+
+#[async_trait]
+impl Applet for Canonical {
+    async fn main(&self) -> Result<()> {
+        let mut rx = self.rx.lock().unwrap().take().unwrap();
+
+        loop {
+            match rx.recv().await.unwrap() {
+                Message::UpdateCanonical(fqdn, _) => {
+                    self.done.send(fqdn).unwrap();
+                }
+                Message::Shutdown(_) => break,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_cache() {
+    let server = MockTmcd::start(Fixtures::default()).await.unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let config = |addr: SocketAddr| -> ConfigInner {
+        toml::from_str(&pipeline_config(addr, &format!(r#"
+            connect-retries = 0
+            cache = true
+
+            [state]
+            dir = "{}"
+        "#, dir.path().display()))).expect("Failed to parse config")
+    };
+
+    // Fill the cache from a reachable boss
+    let (done, mut reloaded) = mpsc::unbounded_channel();
+    let runner = Runner::new(Arc::new(config(server.addr())));
+    let custom = Reloaded {
+        tx: runner.sender(),
+        done,
+    };
+
+    tokio::select! {
+        res = runner.applet("reloaded", Box::new(custom)).run() => panic!("Daemon exited early: {:?}", res),
+        res = tokio::time::timeout(Duration::from_secs(10), reloaded.recv()) => {
+            res.expect("Timed out waiting for the testbed reload");
+        }
+    }
+
+    assert!(dir.path().join("testbed.json").exists());
+
+    // Replay it while nothing listens on the boss port
+    let unreachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let (done, mut canonical) = mpsc::unbounded_channel();
+    let runner = Runner::new(Arc::new(config(unreachable)));
+    let custom = Canonical {
+        rx: std::sync::Mutex::new(Some(runner.sender().subscribe())),
+        done,
+    };
+
+    tokio::select! {
+        res = runner.applet("canonical", Box::new(custom)).run() => panic!("Daemon exited early: {:?}", res),
+        res = tokio::time::timeout(Duration::from_secs(10), canonical.recv()) => {
+            let fqdn = res.expect("Timed out waiting for the cached canonical host");
+            assert!(fqdn.unwrap().contains('.'));
+        }
+    }
+}
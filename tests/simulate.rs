@@ -0,0 +1,166 @@
+//! Dry-run tests against the bundled fixtures.
+
+use std::path::PathBuf;
+
+use miniond::applet;
+use miniond::config::ConfigInner;
+use miniond::error::Error;
+use miniond::plan::{self, Action};
+
+#[tokio::test]
+async fn test_simulate() {
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testing/fixtures");
+
+    let actions = applet::simulate(ConfigInner::default(), fixtures).await
+        .expect("Simulation failed");
+
+    assert!(actions.iter().any(|a| matches!(a, Action::SetHostname { hostname } if hostname == "node0.experiment.project-pg0.emulab.net")));
+    assert!(actions.iter().any(|a| matches!(a, Action::Mount { remote, .. } if remote == "ops.emulab.net:/users/alice")));
+    assert!(actions.iter().any(|a| matches!(a, Action::WriteFile { path, contents } if path == &PathBuf::from("/etc/hosts") && contents.contains("10.0.0.1"))));
+}
+
+#[tokio::test]
+async fn test_simulate_chunked() {
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testing/fixtures");
+    let config: ConfigInner = toml::from_str("[tmcc]\naccount-chunk-size = 1\n")
+        .expect("Failed to parse config");
+
+    let actions = applet::simulate(config, fixtures).await
+        .expect("Simulation failed");
+
+    assert!(actions.iter().any(|a| matches!(a, Action::CreateUser { login, .. } if login == "alice")));
+    assert!(actions.iter().any(|a| matches!(a, Action::CreateUser { login, .. } if login == "bob")));
+}
+
+#[tokio::test]
+async fn test_simulate_fscache() {
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testing/fixtures");
+    let config: ConfigInner = toml::from_str("[automount.fscache]\nenable = true\n")
+        .expect("Failed to parse config");
+
+    let actions = applet::simulate(config, fixtures).await
+        .expect("Simulation failed");
+
+    assert!(actions.iter().any(|a| matches!(a, Action::WriteFile { path, contents } if path == &PathBuf::from("/etc/cachefilesd.conf") && contents.contains("tag miniond"))));
+    assert!(actions.iter().any(|a| matches!(a, Action::ReloadService { unit } if unit == "cachefilesd.service")));
+}
+
+/// Returns the bundled fixtures with more lines in `accounts.txt`.
+fn fixtures_with(accounts: &[&str]) -> tempfile::TempDir {
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testing/fixtures");
+    let dir = tempfile::tempdir().unwrap();
+
+    for entry in std::fs::read_dir(&fixtures).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), dir.path().join(entry.file_name())).unwrap();
+    }
+
+    let mut contents = std::fs::read_to_string(fixtures.join("accounts.txt")).unwrap();
+    for line in accounts {
+        contents.push_str(line);
+        contents.push('\n');
+    }
+    std::fs::write(dir.path().join("accounts.txt"), contents).unwrap();
+
+    dir
+}
+
+/// Returns fixtures with an extra user, `carol`, who fails to apply.
+///
+/// Her UID already belongs to another local user.
+fn failing_fixtures() -> tempfile::TempDir {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap();
+    let uid = passwd.lines()
+        .filter_map(|line| line.split(':').nth(2)?.parse::<u32>().ok())
+        .find(|uid| *uid != 0)
+        .expect("No local user other than root");
+
+    fixtures_with(&[
+        &format!(r#"ADDUSER LOGIN=carol PSWD=* UID={} GID=6418 ROOT=0 NAME="Carol" HOMEDIR=/users/carol GLIST="" SERIAL=1630039459 EMAIL="carol@localhost" SHELL=bash"#, uid),
+    ])
+}
+
+#[tokio::test]
+async fn test_simulate_failure() {
+    let fixtures = failing_fixtures();
+
+    let actions = applet::simulate(ConfigInner::default(), fixtures.path().to_path_buf()).await
+        .expect("Simulation failed");
+
+    // The others are still applied
+    assert!(actions.iter().any(|a| matches!(a, Action::CreateUser { login, .. } if login == "alice")));
+    assert!(actions.iter().any(|a| matches!(a, Action::CreateUser { login, .. } if login == "bob")));
+}
+
+#[tokio::test]
+async fn test_simulate_max_failures() {
+    let fixtures = failing_fixtures();
+    let config: ConfigInner = toml::from_str("[autouser]\nmax-failures = 0\n")
+        .expect("Failed to parse config");
+
+    match applet::simulate(config, fixtures.path().to_path_buf()).await {
+        Err(Error::AccountsFailed { failed, total, failures }) => {
+            assert_eq!(1, failed);
+            assert!(total >= 3, "{}", total);
+            assert!(failures.contains("carol"), "{}", failures);
+        }
+        res => panic!("Expected the accounts to fail: {:?}", res.map(|actions| actions.len())),
+    }
+}
+
+#[tokio::test]
+async fn test_simulate_shells() {
+    let fixtures = fixtures_with(&[
+        r#"ADDUSER LOGIN=dave PSWD=* UID=20004 GID=6418 ROOT=0 NAME="Dave" HOMEDIR=/users/dave GLIST="" SERIAL=1630039460 EMAIL="dave@localhost" SHELL=nosuchsh"#,
+    ]);
+    let config: ConfigInner = toml::from_str(r#"
+        [autouser.shells]
+        fallback = "/sbin/nologin"
+        map = { tcsh = "/opt/tcsh/bin/tcsh" }
+    "#).expect("Failed to parse config");
+
+    let actions = applet::simulate(config, fixtures.path().to_path_buf()).await
+        .expect("Simulation failed");
+
+    assert!(actions.iter().any(|a| matches!(a, Action::CreateUser { login, shell, .. } | Action::ModifyUser { login, shell, .. }
+        if login == "bob" && shell == &PathBuf::from("/opt/tcsh/bin/tcsh"))));
+
+    // The shell isn't installed
+    assert!(actions.iter().any(|a| matches!(a, Action::CreateUser { login, shell, .. } | Action::ModifyUser { login, shell, .. }
+        if login == "dave" && shell == &PathBuf::from("/sbin/nologin"))));
+}
+
+#[tokio::test]
+async fn test_simulate_key_options() {
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testing/fixtures");
+    let config: ConfigInner = toml::from_str(r#"
+        [autouser.key-options]
+        non-root = "restrict"
+    "#).expect("Failed to parse config");
+
+    let actions = applet::simulate(config, fixtures).await
+        .expect("Simulation failed");
+
+    let keys = |login: &str| PathBuf::from(format!("/users/{}/.ssh/authorized_keys", login));
+
+    // Bob has no root privileges, unlike Alice
+    assert!(actions.iter().any(|a| matches!(a, Action::WriteFile { path, contents }
+        if path == &keys("bob") && contents.contains("\nrestrict ssh-ed25519 "))));
+    assert!(actions.iter().any(|a| matches!(a, Action::WriteFile { path, contents }
+        if path == &keys("alice") && !contents.contains("restrict"))));
+}
+
+#[test]
+fn test_json() {
+    let actions = vec![
+        Action::Mount {
+            remote: "ops.emulab.net:/share".to_string(),
+            local: PathBuf::from("/share"),
+        },
+    ];
+
+    let json = plan::to_json(&actions);
+
+    assert!(json.contains(r#""action": "Mount""#));
+    assert!(json.contains(r#""local": "/share""#));
+}